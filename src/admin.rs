@@ -15,13 +15,17 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
-use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+use clap::{CommandFactory, Parser, Subcommand};
 use tracing_subscriber;
 
-use bigiron::{vm, vm::VMSet};
+use bigiron::cli;
+use bigiron::models::{to_size, Machine, Size, Spec};
+use bigiron::vm::VMSet;
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(name = "bigiron-admin", author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 struct Cli {
     #[clap(subcommand)]
@@ -31,7 +35,22 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     List,
-    Define,
+    /// Define a VM from a spec file, flags, or both with flags taking precedence
+    Define {
+        /// Machine name and spec in YAML or JSON, the same spec `bigiron apply` takes
+        #[arg(long)]
+        spec: Option<PathBuf>,
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long)]
+        cpus: Option<u32>,
+        /// e.g. 512Mi or 2Gi
+        #[arg(long)]
+        memory: Option<String>,
+        /// disk image to boot, a local path or file:// url
+        #[arg(long)]
+        image: Option<PathBuf>,
+    },
     Undefine {
         #[arg(required(true))]
         id: String,
@@ -56,6 +75,26 @@ enum Commands {
         #[arg(required(true))]
         id: String,
     },
+    Balloon {
+        #[arg(required(true))]
+        id: String,
+        size: Option<String>,
+    },
+    /// Take the media out of a VM's cdrom drive, it boots without it from then on
+    Eject {
+        #[arg(required(true))]
+        id: String,
+    },
+    /// Show a VM's lifecycle events and qemu output
+    Logs {
+        #[arg(required(true))]
+        id: String,
+        /// keep printing new entries
+        #[arg(short, long)]
+        follow: bool,
+    },
+    #[command(flatten)]
+    Docs(cli::DocCommands),
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -72,14 +111,43 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 println!("{0: <36}  {1: <30}", vm.id(), vm.name());
             }
         }
-        Commands::Define => {
+        Commands::Define {
+            spec,
+            name,
+            cpus,
+            memory,
+            image,
+        } => {
+            let mut m: Machine = match spec {
+                // yaml is a superset of json, one parser reads both
+                Some(path) => serde_yaml::from_str(&std::fs::read_to_string(path)?)?,
+                None => Machine {
+                    name: String::new(),
+                    project: None,
+                    labels: Default::default(),
+                    status: None,
+                    spec: Spec {
+                        cpu: 2,
+                        memory: Size(512 * 1024 * 1024),
+                        ..Default::default()
+                    },
+                },
+            };
+            if let Some(name) = name {
+                m.name = name.clone();
+            }
+            if let Some(cpus) = cpus {
+                m.spec.cpu = *cpus;
+            }
+            if let Some(memory) = memory {
+                m.spec.memory = memory.parse()?;
+            }
+            if let Some(image) = image {
+                m.spec.image.url = Some(image.display().to_string());
+            }
+
             let c = VMSet::default();
-            let vm = c.define(vm::Spec {
-                name: "my-test-vm".into(),
-                cpus: 2,
-                memory_mb: 512,
-                image: "image.qcow2".into(),
-            });
+            let vm = c.define(m)?;
             println!("VM Created\n{}", vm.id());
         }
         Commands::Undefine { id } => {
@@ -107,13 +175,45 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         Commands::Status { id } => {
             let c = VMSet::default();
             let vm = c.get(&id).expect("no VM found");
+            if !vm.running() {
+                match vm.exit_status() {
+                    Some(status) => println!("stopped, qemu {}", status),
+                    None => println!("stopped"),
+                }
+                return Ok(());
+            }
             println!("{}", vm.status().unwrap());
+            if let Some(display) = vm.display() {
+                println!("display: {}", display);
+            }
         }
         Commands::Destroy { id } => {
             let c = VMSet::default();
             let vm = c.get(&id).expect("no VM found");
             vm.destroy()?;
         }
+        Commands::Docs(docs) => docs.run(Cli::command())?,
+        Commands::Balloon { id, size } => {
+            let c = VMSet::default();
+            let vm = c.get(id).expect("no VM found");
+            if let Some(size) = size {
+                vm.balloon(to_size(size)?)?;
+            }
+            println!("{}", vm.balloon_size()?);
+        }
+        Commands::Eject { id } => {
+            let c = VMSet::default();
+            let vm = c.get(id).expect("no VM found");
+            vm.eject()?;
+        }
+        Commands::Logs { id, follow } => {
+            let c = VMSet::default();
+            let vm = c.get(id).expect("no VM found");
+            match follow {
+                true => vm.follow_logs(|e| println!("{}", e))?,
+                false => vm.logs()?.iter().for_each(|e| println!("{}", e)),
+            }
+        }
     }
 
     Ok(())
@@ -16,20 +16,42 @@
 //  USA
 
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use hex;
+use serde::Serialize;
 use serde_yaml;
-use sha2::{Digest, Sha256};
-use tracing::error;
+use tracing::{error, info, warn};
 use url::Url;
 
+use crate::audit;
+use crate::baremetal;
+use crate::bus;
+use crate::config;
+use crate::crashlog;
+use crate::dhcp;
 use crate::dnsmasq::Dnsmasq;
-use crate::error::Error;
+use crate::error::{self, Error};
+use crate::freeze;
+use crate::hooks::{self, Hook};
+use crate::host;
+use crate::host::pci::{self, PciAddress};
+use crate::hostpower;
+use crate::imagecache;
+use crate::imagerepo;
 use crate::imagerepo::ImageRepo;
 use crate::libvirt;
+use crate::machinelog;
+use crate::mirror;
 use crate::models;
-use crate::models::to_size;
 use crate::network;
+use crate::placement;
+use crate::ports;
+use crate::qemu::{self, agent};
+use crate::sol;
+use crate::specfile;
+use crate::storage;
+use crate::store::{self, get_unique_id, StoreBackend};
+use crate::tpm;
 
 mod imgutil {
     use std::path::Path;
@@ -39,223 +61,2583 @@ mod imgutil {
 
     use crate::error::Error;
 
-    pub fn create<P: AsRef<Path>, B: AsRef<Path>>(
+    // standalone compressed copy of an image and its backing chain
+    pub fn compress<P: AsRef<Path>, D: AsRef<Path>>(
         filepath: P,
-        resize: Option<u64>,
-        backing_file: Option<B>,
+        format: &str,
+        dest: D,
     ) -> Result<(), Error> {
         let mut cmd = Command::new("/usr/bin/qemu-img");
-        cmd.arg("create");
+        cmd.arg("convert");
         cmd.arg("-q");
+        cmd.arg("-c");
+        cmd.arg("-f");
+        cmd.arg(format);
+        cmd.arg("-O");
+        cmd.arg("qcow2");
+        cmd.arg(filepath.as_ref());
+        cmd.arg(dest.as_ref());
+
+        debug!("Running: {:?}", cmd);
+        match cmd.status()?.success() {
+            true => Ok(()),
+            false => Err("failed to copy image".into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ApplyOptions {
+    // proceed even if the host or a machine's project is frozen
+    pub override_freeze: bool,
+    // print where machines would be placed and why, without creating them
+    pub plan_only: bool,
+    // block until created machines are ready, for at most this long
+    pub wait: Option<Duration>,
+    // make room for machines that don't fit, overrides the host config
+    pub preempt: Option<config::Preemption>,
+    // where to write the apply report, "-" for stdout
+    pub report: Option<PathBuf>,
+    // for the ${VAR}s in the spec file
+    pub values: specfile::Values,
+}
+
+// Apply a spec file, returning the names of the machines it created.
+pub fn apply_specfile<P: AsRef<Path>>(path: P, opts: &ApplyOptions) -> Result<Vec<String>, Error> {
+    let store = Store::new()?;
+
+    let docs = specfile::load(path.as_ref(), &opts.values)?;
+
+    if opts.plan_only {
+        print_plan(&store, &docs)?;
+        return Ok(Vec::new());
+    }
+
+    let mut report = ApplyReport::default();
+    let mut created = Vec::new();
+    let mut result = Ok(());
+    for (i, doc) in docs.into_iter().enumerate() {
+        let start = Instant::now();
+        let mut entry = DocumentReport {
+            index: i,
+            ..Default::default()
+        };
+        result = apply_document(&store, i, doc, opts, &mut entry, &mut created);
+        entry.duration_ms = start.elapsed().as_millis() as u64;
+        if let Err(e) = &result {
+            entry.fail(e);
+        }
+        report.documents.push(entry);
+        if result.is_err() {
+            break;
+        }
+    }
+
+    // written whether or not apply got through every document
+    if let Some(path) = &opts.report {
+        report.write(path)?;
+    }
+    result?;
+
+    if let Some(timeout) = opts.wait {
+        wait_ready(&created, timeout)?;
+    }
+
+    Ok(created)
+}
+
+// What apply did with each document, for CI jobs to archive instead of
+// scraping the output.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ApplyReport {
+    pub documents: Vec<DocumentReport>,
+}
+
+impl ApplyReport {
+    // as json to a file, or stdout for "-"
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        let buf = serde_json::to_string_pretty(self)?;
+        match path.to_str() {
+            Some("-") => println!("{}", buf),
+            _ => std::fs::write(path, buf + "\n")?,
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApplyAction {
+    Created,
+    // a machine of that name already exists, apply leaves it alone
+    #[default]
+    Unchanged,
+    Configured,
+    Registered,
+    Imported,
+    Failed,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentReport {
+    // position of the document in the spec file
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub action: ApplyAction,
+    // sha256 of the repo image a machine was created from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    pub duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // each machine of a template
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub machines: Vec<DocumentReport>,
+}
+
+impl DocumentReport {
+    fn fail<E: std::fmt::Display>(&mut self, e: E) {
+        self.action = ApplyAction::Failed;
+        self.error = Some(e.to_string());
+    }
+
+    // the address a resource was given, if it has a reservation
+    fn addresses(&mut self, hostname: &str) -> Result<(), Error> {
+        if let Some(r) = network::reservation(hostname)? {
+            self.ip = Some(r.ip).filter(|ip| !ip.is_empty());
+            self.mac = Some(r.mac);
+        }
+        Ok(())
+    }
+}
+
+// Apply one document. Machines that fail to create are reported and apply
+// moves on, anything else stops it.
+fn apply_document(
+    store: &Store,
+    i: usize,
+    doc: serde_yaml::Value,
+    opts: &ApplyOptions,
+    entry: &mut DocumentReport,
+    created: &mut Vec<String>,
+) -> Result<(), Error> {
+    let r = serde_yaml::from_value::<models::Resource>(doc)
+        .map_err(|e| format!("Error reading document at index {}: {}", i, e))?;
+
+    match r {
+        models::Resource::Machine(m) => {
+            entry.kind = Some("Machine");
+            apply_machine(store, m, opts, entry, created)?;
+        }
+        models::Resource::MachineTemplate(t) => {
+            entry.kind = Some("MachineTemplate");
+            entry.name = Some(t.name.clone());
+            for m in t.machines()? {
+                let mut machine_entry = DocumentReport {
+                    index: i,
+                    kind: Some("Machine"),
+                    ..Default::default()
+                };
+                apply_machine(store, m, opts, &mut machine_entry, created)?;
+                entry.machines.push(machine_entry);
+            }
+            let actions = || entry.machines.iter().map(|m| m.action);
+            if actions().any(|a| a == ApplyAction::Failed) {
+                entry.action = ApplyAction::Failed;
+                entry.error = Some("some of the template's machines failed to create".into());
+            } else if actions().any(|a| a == ApplyAction::Created) {
+                entry.action = ApplyAction::Created;
+            }
+        }
+        models::Resource::Network(n) => {
+            entry.kind = Some("Network");
+            entry.name = Some(n.name.clone());
+            freeze::check("apply", None, opts.override_freeze)?;
+            if store::unprivileged() {
+                return Err("networks can only be configured by root".into());
+            }
+            network::configure(&n.name, &n.spec)?;
+            Dnsmasq::new().reconfigure()?;
+            entry.action = ApplyAction::Configured;
+        }
+        models::Resource::BareMetal(b) => {
+            entry.kind = Some("BareMetal");
+            entry.name = Some(b.name.clone());
+            freeze::check("apply", None, opts.override_freeze)?;
+            if store::unprivileged() {
+                return Err("bare metal hosts can only be registered by root".into());
+            }
+            baremetal::register(&b)?;
+            entry.action = ApplyAction::Registered;
+            entry.addresses(&b.name)?;
+        }
+        models::Resource::Image(img) => {
+            entry.kind = Some("Image");
+            entry.name = Some(img.name.clone());
+            freeze::check("apply", None, opts.override_freeze)?;
+            entry.image = Some(import_catalog_image(&img)?);
+            entry.action = ApplyAction::Imported;
+        }
+    }
+    Ok(())
+}
+
+// Pull the image of an Image document into the repo and point its catalog
+// name at it, returning the image id. Importing an unchanged file again is
+// cheap, the repo remembers what it imported.
+fn import_catalog_image(img: &models::CatalogImage) -> Result<String, Error> {
+    let url = Url::parse(&img.spec.url)?;
+    if let (Some(expected), "file") = (&img.spec.format, url.scheme()) {
+        let path = url
+            .to_file_path()
+            .map_err(|_| format!("invalid file url {}", url))?;
+        let detected = qemu::Image { path }.format()?;
+        if &detected != expected {
+            return Err(format!(
+                "image {} is {}, not {} as declared",
+                img.name, detected, expected
+            )
+            .into());
+        }
+    }
+
+    let repo = ImageRepo::new()?;
+    let image = repo.add_from_url(url, img.spec.arch.as_deref())?;
+    models::Image {
+        sha256: img.spec.sha256.clone(),
+        ..Default::default()
+    }
+    .check_digest(Some(&image.id))?;
+    repo.tag(&img.name, &image.id)?;
+    println!("image {} -> {}", img.name, image.id);
+    Ok(image.id)
+}
+
+// Place a machine on the first host with room for it. If none has, preempt
+// lower priority machines on the local host to make room there. Without a
+// preemption policy the machine is started locally anyway, overcommitting
+// the host as before.
+fn make_room(store: &Store, machine: &models::Machine, opts: &ApplyOptions) -> Result<(), Error> {
+    let sched = config::load()?.scheduling;
+    let (priority, _) = placement::priority(&machine.spec, &sched)?;
+
+    let running: Vec<models::Machine> = store
+        .list_machines()?
+        .into_iter()
+        .filter(|m| libvirt::is_active(&m.name).unwrap_or(false))
+        .collect();
+    let mut hosts = placement::hosts(&running)?;
+    let mut decision = placement::place(machine, &mut hosts);
+    // a host powered down to save energy may have room
+    if decision.host.is_none() && hostpower::wake_for(machine)?.is_some() {
+        hosts = placement::hosts(&running)?;
+        decision = placement::place(machine, &mut hosts);
+    }
+    let remote = decision.host.as_deref().filter(|h| *h != hosts[0].name);
+    placement::Assignments::default().assign(&machine.name, remote)?;
+    if let Some(host) = remote {
+        info!("placing {} on host {}", machine.name, host);
+    }
+    if decision.host.is_some() {
+        return Ok(());
+    }
+    // started here anyway below, which the hints may rule out
+    if decision.candidates[0]
+        .1
+        .iter()
+        .any(|c| c.result.is_err() && placement::is_hint(c))
+    {
+        return Err(format!(
+            "no host fits the placement of {}\n{}",
+            machine.name, decision
+        )
+        .into());
+    }
+    // stopping machines only gives back memory
+    let failed: Vec<&str> = decision.candidates[0]
+        .1
+        .iter()
+        .filter(|c| c.result.is_err())
+        .map(|c| c.name)
+        .collect();
+    if failed != ["memory"] {
+        return Ok(());
+    }
+
+    let policy = match opts.preempt.or(sched.preemption) {
+        Some(p) => p,
+        None => {
+            warn!(
+                "{} doesn't fit in the free memory of this host",
+                machine.name
+            );
+            return Ok(());
+        }
+    };
+
+    let host = &hosts[0];
+    let need = machine
+        .spec
+        .memory
+        .bytes()
+        .saturating_sub(host.memory.saturating_sub(host.used_memory));
+    let remote = placement::Assignments::default().load()?;
+    let mut candidates = Vec::new();
+    for m in running.iter().filter(|m| !remote.contains_key(&m.name)) {
+        let (priority, preemptible) = placement::priority(&m.spec, &sched)?;
+        candidates.push(placement::Candidate {
+            name: m.name.clone(),
+            priority,
+            preemptible,
+            memory: m.spec.memory.bytes(),
+        });
+    }
+    let victims = placement::preemption_victims(need, priority, &candidates)
+        .ok_or("not enough memory, even with lower priority machines preempted")?;
+
+    for v in victims {
+        let victim = running.iter().find(|m| m.name == v);
+        let project = victim.and_then(|m| m.project.as_deref());
+        freeze::check("preempt", project, opts.override_freeze)?;
+        match policy {
+            config::Preemption::Stop => {
+                libvirt::destroy(&v)?;
+                bus::publish_action(bus::Kind::Machine, &v, bus::Action::Stopped);
+                if let Some(m) = victim {
+                    hooks::run(Hook::PostStop, m);
+                }
+                machinelog::record(
+                    &store.path_for_machine(&v),
+                    &format!("preempted by {}, powered off", machine.name),
+                );
+            }
+            config::Preemption::Delete => delete_machine(&v, opts.override_freeze)?,
+        }
+        audit::record(
+            "preempt",
+            &format!("machine={} for={} action={:?}", v, machine.name, policy),
+        );
+        println!("{}: preempted {} ({:?})", machine.name, v, policy);
+    }
+    Ok(())
+}
+
+// Wait for machines to come up, seen either as a dhcp lease for their
+// reservation or an answer from the guest agent.
+fn wait_ready(names: &[String], timeout: Duration) -> Result<(), Error> {
+    let start = Instant::now();
+    // lease changes wake us right away, the agent has to be polled
+    let mut sub = bus::Bus::default().subscribe()?;
+    let mut pending: Vec<&String> = names.iter().collect();
+    for name in &pending {
+        println!("{}: waiting for a dhcp lease or guest agent", name);
+    }
+
+    while !pending.is_empty() {
+        pending.retain(|name| {
+            if let Ok(Some(ip)) = network::leased_address(name) {
+                println!("{}: ready, leased {}", name, ip);
+                return false;
+            }
+            if libvirt::agent_ping(name).is_ok() {
+                println!("{}: ready, guest agent responding", name);
+                return false;
+            }
+            true
+        });
+        if pending.is_empty() {
+            break;
+        }
+
+        let elapsed = start.elapsed();
+        if elapsed >= timeout {
+            let names: Vec<&str> = pending.iter().map(|n| n.as_str()).collect();
+            return Err(format!(
+                "timed out after {}s waiting for: {}",
+                timeout.as_secs(),
+                names.join(", ")
+            )
+            .into());
+        }
+        sub.next(Some((timeout - elapsed).min(Duration::from_secs(2))))?;
+    }
+    Ok(())
+}
+
+fn print_plan(store: &Store, docs: &[serde_yaml::Value]) -> Result<(), Error> {
+    let existing = store.list_machines()?;
+    let mut hosts = placement::hosts(&existing)?;
+
+    for (i, doc) in docs.iter().enumerate() {
+        let machines = match serde_yaml::from_value::<models::Resource>(doc.clone()) {
+            Ok(models::Resource::Machine(m)) => vec![m],
+            Ok(models::Resource::MachineTemplate(t)) => t.machines()?,
+            Ok(models::Resource::Network(n)) => {
+                println!("network {} -> not placed\n", n.name);
+                continue;
+            }
+            Ok(models::Resource::BareMetal(b)) => {
+                println!("baremetal {} -> not placed\n", b.name);
+                continue;
+            }
+            Ok(models::Resource::Image(i)) => {
+                println!("image {} -> not placed\n", i.name);
+                continue;
+            }
+            Err(e) => return Err(format!("Error reading document at index {}: {}", i, e).into()),
+        };
+        for m in machines {
+            if existing.iter().any(|e| e.name == m.name) {
+                println!("{} -> exists, unchanged\n", m.name);
+                continue;
+            }
+            println!("{}", placement::place(&m, &mut hosts));
+        }
+    }
+    Ok(())
+}
+
+// Create a machine unless one of its name exists. Failing to create it is
+// reported in the entry, errors are left for the store and freezes.
+fn apply_machine(
+    store: &Store,
+    mut m: models::Machine,
+    opts: &ApplyOptions,
+    entry: &mut DocumentReport,
+    created: &mut Vec<String>,
+) -> Result<(), Error> {
+    entry.name = Some(m.name.clone());
+    if store.get_machine(&m.name)?.is_some() {
+        return Ok(());
+    }
+    if let Err(e) = models::check_name(&m.name) {
+        eprintln!("Failed to create VM: {}: {}", &m.name, e);
+        entry.fail(e);
+        return Ok(());
+    }
+    freeze::check("apply", m.project.as_deref(), opts.override_freeze)?;
+    if let Err(e) = make_room(store, &m, opts) {
+        eprintln!("Failed to create VM: {}: {}", &m.name, e);
+        entry.fail(e);
+        return Ok(());
+    }
+    store.add_machine(&m)?;
+    // one made beforehand with `network reserve` is kept if creating fails
+    let reserved = network::reservation(&m.name)?.is_some();
+    match create_machine(&mut m) {
+        Ok(image) => {
+            entry.action = ApplyAction::Created;
+            entry.image = image;
+            entry.addresses(&m.name)?;
+            created.push(m.name.clone());
+            hooks::run(Hook::PostCreate, &m);
+        }
+        Err(e) => {
+            delete_volumes(&m);
+            // the address goes back to the pool for the next machine
+            if !reserved {
+                if let Err(err) = network::remove_reservation(&m.name) {
+                    warn!("error while removing network reservation: {}", err);
+                }
+            }
+            store.remove_machine(&m.name)?;
+            eprintln!("Failed to create VM: {}", &m.name);
+            entry.fail(e);
+        }
+    }
+    Ok(())
+}
+
+// Create the disks of a new machine and start it, returning the digest of
+// its base image if that came from the image repo.
+fn create_machine(machine: &mut models::Machine) -> Result<Option<String>, Error> {
+    let s = Store::new()?;
+
+    check_host(machine)?;
+    // only checked here, start_domain picks the devices for real
+    resolve_devices(machine)?;
+
+    let pool = pin_storage_pool(&s, machine)?;
+
+    // resolve image
+    let base = resolve_base_image(&s, &machine.name, &machine.spec.image)?;
+    if let Err(e) = create_disks_and_start(&s, machine, pool.as_ref(), &base) {
+        // the cached copy of the image was claimed for this machine
+        release_cached_image(&machine.name);
+        return Err(e);
+    }
+    Ok(base.digest)
+}
+
+fn create_disks_and_start(
+    s: &Store,
+    machine: &models::Machine,
+    pool: &dyn storage::Pool,
+    base: &BaseImage,
+) -> Result<(), Error> {
+    check_arch(machine, base.arch.as_deref())?;
+
+    // create the boot disk from the base image
+    let imgpath = pool.create(
+        &machine.name,
+        storage::ROOT_VOLUME,
+        machine.spec.image.resize.as_ref().map(|s| s.bytes()),
+        Some((&base.path, base.format.as_str())),
+        None,
+    )?;
+    if storage::is_block(&imgpath) {
+        std::fs::write(
+            s.path_for_machine(&machine.name).join("rootdisk"),
+            imgpath.display().to_string(),
+        )?;
+    }
+    s.set_backing(&machine.name, base.digest.as_deref())?;
+
+    // create additional storage drives
+    for storage in machine.spec.storage.iter().flatten() {
+        match storage {
+            models::StorageKind::DiskFile(d) => {
+                pool.create(
+                    &machine.name,
+                    &d.local.display().to_string(),
+                    Some(d.size.bytes()),
+                    None,
+                    d.preallocation,
+                )?;
+            }
+        }
+    }
+
+    // installer media stays inserted until ejected, like media inserted later
+    if let Some(image) = &machine.spec.cdrom {
+        let path = media_path(image)?;
+        std::fs::write(media_state(&s, &machine.name), path.display().to_string())?;
+    }
+
+    start_domain(s, machine)?;
+    machinelog::record(&s.path_for_machine(&machine.name), "created and powered on");
+    Ok(())
+}
+
+fn release_cached_image(id: &str) {
+    match imagecache::Cache::open() {
+        Ok(Some(cache)) => {
+            if let Err(err) = cache.release(id) {
+                error!("error while releasing cached image: {}", err);
+            }
+        }
+        Ok(None) => {}
+        Err(err) => error!("error while releasing cached image: {}", err),
+    }
+}
+
+// The image cache may be on a tmpfs, whose copies are gone after a reboot.
+// A missing copy is made again, or the disk is pointed at the repo copy
+// when it no longer fits.
+fn rewarm_cached_image(s: &Store, id: &str, disk: &Path) -> Result<(), Error> {
+    let cache = match imagecache::Cache::open()? {
+        Some(cache) => cache,
+        None => return Ok(()),
+    };
+    let digest = match s.get_backing(id)? {
+        Some(digest) if !storage::is_block(disk) => digest,
+        _ => return Ok(()),
+    };
+    let disk = qemu::Image {
+        path: disk.to_path_buf(),
+    };
+    let backing = match disk.backing_file()? {
+        Some(b) if cache.holds(&b) && !b.exists() => b,
+        _ => return Ok(()),
+    };
+    let img = ImageRepo::new()?.get(&digest)?;
+    let path = cache.warm(&img, id)?;
+    if path != backing {
+        disk.rebase(&path, &img.format)?;
+    }
+    Ok(())
+}
+
+// The pool a new machine's disks go in. It is pinned in the spec, a later
+// change of the default must not lose the disks.
+fn pin_storage_pool(
+    s: &Store,
+    machine: &mut models::Machine,
+) -> Result<Box<dyn storage::Pool>, Error> {
+    if machine.spec.storage_pool.is_none() {
+        machine.spec.storage_pool = config::load()?.default_storage_pool;
+        if machine.spec.storage_pool.is_some() {
+            s.update_machine(machine)?;
+        }
+    }
+    storage::check(&machine.spec)?;
+    storage::pool(&machine.spec)
+}
+
+// host side requirements of a machine that can change between starts
+fn check_host(machine: &models::Machine) -> Result<(), Error> {
+    if store::unprivileged() {
+        check_unprivileged(&machine.spec)?;
+    } else {
+        // fail early, qemu gives a far less helpful error on a missing bridge
+        host::net::ensure_bridge(network::MANAGEMENT_BRIDGE)?;
+    }
+
+    if machine.spec.hugepages.is_some() || machine.spec.numa.is_some() {
+        host::HostAgent::new().topology()?.validate(&machine.spec)?;
+    }
+    check_shares(&machine.spec)?;
+    network::check_reservation(
+        &machine.name,
+        machine.spec.ip.as_deref(),
+        machine.spec.mac.as_deref(),
+    )
+}
+
+// Machines of unprivileged users run in their libvirt session on user-mode
+// networking, without the bridges, firewall rules and devices only root can
+// set up.
+fn check_unprivileged(spec: &models::Spec) -> Result<(), Error> {
+    let needs_root = |what: &str| -> Result<(), Error> {
+        Err(format!("{} can only be used by root", what).into())
+    };
+    if spec.network.as_ref().is_some_and(|n| !n.is_empty()) {
+        return needs_root("vlan networks");
+    }
+    if spec.port_forwards.as_ref().is_some_and(|f| !f.is_empty()) {
+        return needs_root("port forwards");
+    }
+    if spec.qos.as_ref().is_some_and(|q| q.net_bandwidth.is_some()) {
+        return needs_root("network bandwidth limits");
+    }
+    if spec.devices.as_ref().is_some_and(|d| !d.is_empty()) {
+        return needs_root("pci devices");
+    }
+    if spec.netboot.is_some() {
+        return needs_root("netboot");
+    }
+    Ok(())
+}
+
+// Start the domain of a machine whose disks already exist. Domains are
+// transient, so this is also how a powered off machine comes back.
+fn start_domain(s: &Store, machine: &models::Machine) -> Result<(), Error> {
+    let hostdevs = resolve_devices(machine)?;
+
+    let imgpath = root_disk(s, &machine.name)?;
+    let pool = storage::pool(&machine.spec)?;
+    let mut disks = Vec::new();
+    for storage in machine.spec.storage.iter().flatten() {
+        match storage {
+            models::StorageKind::DiskFile(d) => {
+                disks.push(pool.path(&machine.name, &d.local.display().to_string()));
+            }
+        }
+    }
+
+    // ensure bridged management network
+    // FIXME(mrodden): implement me
+    let bridge_name = match store::unprivileged() {
+        true => None,
+        false => Some(network::MANAGEMENT_BRIDGE),
+    };
+
+    // generate MAC and IP, or get back the ones reserved earlier
+    let netinfo = network::new_reservation(
+        &machine.name,
+        machine.spec.ip.as_deref(),
+        machine.spec.mac.as_deref(),
+    )?;
+    // relay networks get their address from the upstream dhcp server, and
+    // user-mode networking has its own
+    if !netinfo.ip.is_empty() && bridge_name.is_some() {
+        dhcp::registrar()?.add_host(&netinfo)?;
+    }
+
+    rewarm_cached_image(s, &machine.name, &imgpath)?;
+    if let Some(snapshot) = &machine.spec.revert_on_boot {
+        qemu::Image {
+            path: imgpath.clone(),
+        }
+        .revert_on_boot(snapshot)?;
+    }
+
+    // create libvirt XML definition
+    // create domain from XML definition
+    // start VM
+    let machine_dir = s.path_for_machine(&machine.name);
+    let serial = match &machine.spec.sol {
+        Some(sol) => {
+            ports::Registry::default().reserve(&machine.name, sol::SERVICE, sol.port)?;
+            Some(sol::socket_path(&machine_dir))
+        }
+        None => None,
+    };
+
+    let crash_console = match machine.spec.crash_console {
+        Some(true) => Some(crashlog::socket_path(&machine_dir)),
+        _ => None,
+    };
+    let tpm = match machine.spec.tpm {
+        Some(true) => Some(tpm::spawn(&machine_dir)?),
+        _ => None,
+    };
+
+    let vlans: Vec<u32> = machine
+        .spec
+        .network
+        .iter()
+        .flatten()
+        .map(|n| match n {
+            models::NetKind::Vlan(v) => v.vlan,
+        })
+        .collect();
+    let vlan_nics = host::vlan::attach(&machine.name, &vlans)?;
+    if let Some(forwards) = &machine.spec.port_forwards {
+        host::nat::add(&machine.name, &netinfo.ip, forwards)?;
+    }
+
+    let media = inserted_media(s, &machine.name)?;
+    libvirt::define(
+        machine,
+        &imgpath,
+        &disks,
+        &hostdevs,
+        bridge_name,
+        &netinfo.mac,
+        media.as_deref(),
+        serial.as_deref(),
+        crash_console.as_deref(),
+        tpm.as_deref(),
+        &vlan_nics,
+    )?;
+    bus::publish_action(bus::Kind::Machine, &machine.name, bus::Action::Started);
+    hooks::run(Hook::PostStart, machine);
+
+    if serial.is_some() {
+        sol::spawn(&machine.name, &machine_dir)?;
+    }
+    if crash_console.is_some() {
+        crashlog::spawn(&machine.name, &machine_dir)?;
+    }
+
+    if let Some(dir) = &machine.spec.mirror {
+        let target = mirror::target(dir, &machine.name);
+        if target == imgpath {
+            warn!(
+                "{} runs from its mirror, its disk has no second copy",
+                machine.name
+            );
+        } else if let Err(e) = mirror::start(&machine.name, &imgpath, &target) {
+            // the machine is up, `mirror status` shows it is unprotected
+            error!("error while starting mirror of {}: {}", machine.name, e);
+        }
+    }
+
+    Ok(())
+}
+
+fn check_shares(spec: &models::Spec) -> Result<(), Error> {
+    let mut tags = Vec::new();
+    for share in spec.shares.iter().flatten() {
+        if !share.source.is_dir() {
+            return Err(
+                format!("share source {} is not a directory", share.source.display()).into(),
+            );
+        }
+        // virtiofs limits tags to 36 bytes, 9p to 31
+        if share.tag.is_empty() || share.tag.len() > 31 {
+            return Err(format!("share tag '{}' must be 1 to 31 characters", share.tag).into());
+        }
+        if !share
+            .tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        {
+            return Err(format!(
+                "share tag '{}' may only contain letters, digits, '-', '_' and '.'",
+                share.tag
+            )
+            .into());
+        }
+        if tags.contains(&&share.tag) {
+            return Err(format!("share tag '{}' is used more than once", share.tag).into());
+        }
+        tags.push(&share.tag);
+    }
+    Ok(())
+}
+
+// Host pci addresses to pass through, picking a free virtual function for
+// each vfPool entry. Devices already attached to a domain are refused.
+fn resolve_devices(machine: &models::Machine) -> Result<Vec<PciAddress>, Error> {
+    let devices = match &machine.spec.devices {
+        Some(d) if !d.is_empty() => d,
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut in_use = libvirt::hostdevs_in_use()?;
+    let mut r = Vec::new();
+    for device in devices {
+        let addr = match device {
+            models::Device::Pci(d) => {
+                let addr = PciAddress::parse(&d.pci)?;
+                if in_use.contains(&addr) {
+                    return Err(format!("pci device {} is already in use", addr).into());
+                }
+                addr
+            }
+            models::Device::VfPool(p) => pci::virtual_functions(&p.vf_pool)?
+                .into_iter()
+                .find(|a| !in_use.contains(a))
+                .ok_or_else(|| format!("no free virtual functions left on {}", p.vf_pool))?,
+        };
+        pci::check_passthrough(&addr)?;
+        in_use.push(addr);
+        r.push(addr);
+    }
+    Ok(r)
+}
+
+struct BaseImage {
+    path: PathBuf,
+    // None if it couldn't be determined
+    arch: Option<String>,
+    // sha256 of images from the repo
+    digest: Option<String>,
+    format: String,
+}
+
+// The image a machine's disk is layered on, either imported into the
+// ImageRepo from a URL, named in its catalog, or exported from a snapshot
+// of another machine. Repo images are served from the image cache when
+// there is one.
+fn resolve_base_image(
+    store: &Store,
+    machine: &str,
+    image: &models::Image,
+) -> Result<BaseImage, Error> {
+    let repo_image = match (&image.name, &image.url) {
+        (Some(name), None) => Some(ImageRepo::new()?.lookup(name, image.arch.as_deref())?),
+        (None, Some(url)) => {
+            Some(ImageRepo::new()?.add_from_url(Url::parse(url)?, image.arch.as_deref())?)
+        }
+        (Some(_), Some(_)) => return Err("image needs only one of name or url".into()),
+        (None, None) => None,
+    };
+    match (repo_image, &image.from_machine, &image.snapshot) {
+        (Some(img), None, None) => {
+            image.check_digest(Some(&img.id))?;
+            let path = match imagecache::Cache::open()? {
+                Some(cache) => cache.warm(&img, machine)?,
+                None => img.path.clone(),
+            };
+            Ok(BaseImage {
+                path,
+                arch: img.arch,
+                digest: Some(img.id),
+                format: img.format,
+            })
+        }
+        (None, Some(source), Some(snapshot)) => {
+            image.check_digest(None)?;
+            let img = snapshot_base_image(store, source, snapshot)?;
+            Ok(BaseImage {
+                path: img.path,
+                arch: img.arch,
+                digest: Some(img.id),
+                format: img.format,
+            })
+        }
+        (None, Some(_), None) => Err("image.fromMachine requires image.snapshot".into()),
+        _ => Err("image needs exactly one of name, url or fromMachine".into()),
+    }
+}
+
+fn machine_arch(machine: &models::Machine) -> String {
+    imagerepo::guest_arch(machine.spec.arch.as_deref())
+}
+
+// A guest booted from an image of a different architecture just hangs with
+// no output, so refuse that outright. Foreign-arch guests that match their
+// image work, but only under slow emulation.
+fn check_arch(machine: &models::Machine, image_arch: Option<&str>) -> Result<(), Error> {
+    let arch = machine_arch(machine);
+    match image_arch {
+        Some(ia) if ia != arch => {
+            return Err(format!(
+                "machine '{}' is {} but its image is {}, set spec.arch or use a matching image",
+                machine.name, arch, ia
+            )
+            .into());
+        }
+        None => warn!(
+            "architecture of image for '{}' is unknown, assuming {}",
+            machine.name, arch
+        ),
+        _ => {}
+    }
+
+    if arch != imagerepo::host_arch() {
+        warn!(
+            "machine '{}' is {} on a {} host and will run under emulation",
+            machine.name,
+            arch,
+            imagerepo::host_arch()
+        );
+    }
+    Ok(())
+}
+
+// Snapshots are exported into the image repo, every clone then shares the
+// exported image as its backing file and outlives the source machine.
+fn snapshot_base_image(
+    store: &Store,
+    source: &str,
+    snapshot: &str,
+) -> Result<imagerepo::Image, Error> {
+    let src = store.get_machine(source)?.ok_or_else(|| {
+        error::not_found(format!("No machine with id='{}' to clone from", source))
+    })?;
+
+    let root = clonable_disk(store, source)?;
+    // clones inherit the architecture of the machine they are cut from
+    ImageRepo::new()?.add_snapshot(source, &root, snapshot, Some(machine_arch(&src)))
+}
+
+// the root disk of a machine to clone, which needs qcow2 snapshots
+fn clonable_disk(store: &Store, source: &str) -> Result<PathBuf, Error> {
+    let root = root_disk(store, source)?;
+    if storage::is_block(&root) {
+        return Err(format!(
+            "'{}' has its disks in a block storage pool, which has no qcow2 snapshots to clone from",
+            source
+        )
+        .into());
+    }
+    Ok(root)
+}
+
+// Machines layered on files in the directory of `id`, from exports made
+// there before snapshots went to the image repo.
+fn dependents(store: &Store, id: &str) -> Result<Vec<String>, Error> {
+    let dir = store.path_for_machine(id);
+    let mut found = Vec::new();
+    for m in store.list_machines()? {
+        if m.name == id {
+            continue;
+        }
+        let disk = match root_disk(store, &m.name) {
+            Ok(disk) if disk.exists() && !storage::is_block(&disk) => disk,
+            _ => continue,
+        };
+        let backing = qemu::Image { path: disk }
+            .backing_file()
+            .unwrap_or_else(|e| {
+                warn!("can't read the backing file of '{}': {}", m.name, e);
+                None
+            });
+        if backing.is_some_and(|b| b.starts_with(&dir)) {
+            found.push(m.name);
+        }
+    }
+    Ok(found)
+}
+
+fn check_dependents(store: &Store, id: &str, action: &str) -> Result<(), Error> {
+    let found = dependents(store, id)?;
+    if found.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "can't {} '{}', {} are layered on a snapshot exported into its directory",
+        action,
+        id,
+        found.join(", ")
+    )
+    .into())
+}
+
+pub fn get_machine_by_id(id: &str) -> Result<Option<models::Machine>, Error> {
+    let store = Store::new()?;
+    store.get_machine(id)
+}
+
+// graphical console address for machines with a graphics device
+pub fn get_machine_display(id: &str) -> Result<Option<String>, Error> {
+    let store = Store::new()?;
+    match store.get_machine(id)? {
+        Some(m) if m.spec.graphics.is_some() => libvirt::display(&m.name),
+        _ => Ok(None),
+    }
+}
+
+// tcp serial console endpoint, for machines that asked for one
+pub fn get_machine_sol(id: &str) -> Result<Option<String>, Error> {
+    let store = Store::new()?;
+    let sol = match store.get_machine(id)? {
+        Some(m) => match m.spec.sol {
+            Some(sol) => sol,
+            None => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+    let port = ports::Registry::default().lookup(id, sol::SERVICE)?;
+    Ok(port.map(|p| format!("{}://{}:{}", sol.protocol.as_str(), sol.listen_addr(), p)))
+}
+
+// oops.log of a machine with a crash console, once anything was logged
+pub fn get_machine_crash_log(id: &str) -> Result<Option<PathBuf>, Error> {
+    let store = Store::new()?;
+    match store.get_machine(id)? {
+        Some(m) if m.spec.crash_console == Some(true) => {
+            let log = crashlog::log_path(&store.path_for_machine(id));
+            Ok(log.metadata().is_ok_and(|m| m.len() > 0).then_some(log))
+        }
+        _ => Ok(None),
+    }
+}
+
+pub fn serve_crash_console(id: &str) -> Result<(), Error> {
+    let store = Store::new()?;
+    if store.get_machine(id)?.is_none() {
+        return Err(error::not_found(format!("No machine with id='{}'", id)));
+    }
+    crashlog::serve(id, &store.path_for_machine(id))
+}
+
+// Lifecycle events and qemu output of a machine, oldest first. Qemu's log is
+// kept by libvirt on the host running the machine, so only machines on this
+// host get it copied in.
+pub fn machine_logs(id: &str) -> Result<Vec<machinelog::Entry>, Error> {
+    let dir = sync_machine_log(id)?;
+    machinelog::read(&dir)
+}
+
+// Like machine_logs, then keep handing new entries to `out` until an error.
+pub fn follow_machine_logs<F: FnMut(&machinelog::Entry)>(id: &str, out: F) -> Result<(), Error> {
+    let dir = sync_machine_log(id)?;
+    machinelog::follow(&dir, || sync_machine_log(id).map(|_| ()), out)
+}
+
+fn sync_machine_log(id: &str) -> Result<PathBuf, Error> {
+    let store = Store::new()?;
+    if store.get_machine(id)?.is_none() {
+        return Err(error::not_found(format!("No machine with id='{}'", id)));
+    }
+    let dir = store.path_for_machine(id);
+    if placement::Assignments::default().host_of(id)?.is_none() {
+        machinelog::sync(&dir, "qemu", &libvirt::log_path(id))?;
+    }
+    Ok(dir)
+}
+
+// Run the serial console proxy of a machine in the foreground, this is what
+// start_domain spawns in the background.
+pub fn serve_sol(id: &str) -> Result<(), Error> {
+    let store = Store::new()?;
+    let machine = store
+        .get_machine(id)?
+        .ok_or_else(|| error::not_found(format!("No machine with id='{}'", id)))?;
+    let sol = machine
+        .spec
+        .sol
+        .ok_or_else(|| format!("machine '{}' has no sol console", id))?;
+    let port = ports::Registry::default().reserve(id, sol::SERVICE, sol.port)?;
+    sol::serve(&sol, port, &sol::socket_path(&store.path_for_machine(id)))
+}
+
+// What the guest agent reports, None if the machine doesn't exist. Errors
+// mostly mean the guest has no agent running.
+pub fn get_machine_guest_info(id: &str) -> Result<Option<agent::GuestInfo>, Error> {
+    let store = Store::new()?;
+    match store.get_machine(id)? {
+        Some(m) => Ok(Some(libvirt::guest_info(&m.name)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn delete_machine(id: &str, override_freeze: bool) -> Result<(), Error> {
+    let store = Store::new()?;
+    let machine = store.get_machine(id)?;
+    if let Some(m) = &machine {
+        freeze::check("delete", m.project.as_deref(), override_freeze)?;
+        check_dependents(&store, id, "delete")?;
+        if let Err(e) = libvirt::destroy(id) {
+            return Err(format!("Error while shutting down libvirt domain='{}': {}", id, e).into());
+        }
+    }
+    // gathered while the machine still has its reservation
+    let hook_env = machine.as_ref().map(hooks::env);
+    let reservation = network::reservation(id).unwrap_or_else(|err| {
+        error!("error while looking up network reservation: {}", err);
+        None
+    });
+    if let Err(err) = network::remove_reservation(&id) {
+        error!("error while removing network reservation: {}", err);
+    }
+    if let Some(r) = reservation {
+        if let Err(err) = dhcp::registrar().and_then(|reg| reg.remove_host(&r)) {
+            error!("error while removing dhcp host record: {}", err);
+        }
+    }
+    sol::stop(&store.path_for_machine(id));
+    crashlog::stop(&store.path_for_machine(id));
+    tpm::stop(&store.path_for_machine(id));
+    if let Err(err) = host::vlan::release(id) {
+        error!("error while releasing vlans: {}", err);
+    }
+    if let Err(err) = host::nat::remove(id) {
+        error!("error while removing port forwards: {}", err);
+    }
+    if let Err(err) = ports::Registry::default().release(id) {
+        error!("error while releasing ports: {}", err);
+    }
+    if let Err(err) = placement::Assignments::default().assign(id, None) {
+        error!("error while releasing host placement: {}", err);
+    }
+    release_cached_image(id);
+    if let Some(dir) = machine.as_ref().and_then(|m| m.spec.mirror.as_ref()) {
+        let target = mirror::target(dir, id);
+        if let Err(err) = std::fs::remove_file(&target) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                error!("error while removing mirror {}: {}", target.display(), err);
+            }
+        }
+    }
+    if let Some(m) = &machine {
+        delete_volumes(m);
+    }
+    store.remove_machine(id)?;
+    if let Some(env) = hook_env {
+        hooks::run_env(Hook::PostDelete, &env);
+    }
+    // leftovers of a half created machine are cleaned up all the same
+    match machine {
+        Some(_) => Ok(()),
+        None => Err(error::not_found(format!(
+            "No machine found with id='{}'",
+            id
+        ))),
+    }
+}
+
+// Volumes in block pools outlive the machine's directory, the dir pool's
+// files go along with it.
+fn delete_volumes(machine: &models::Machine) {
+    let pool = match storage::pool(&machine.spec) {
+        Ok(pool) => pool,
+        Err(err) => {
+            error!("error while opening storage pool: {}", err);
+            return;
+        }
+    };
+    for name in storage::volumes(&machine.spec) {
+        if !storage::is_block(&pool.path(&machine.name, &name)) {
+            continue;
+        }
+        if let Err(err) = pool.delete(&machine.name, &name) {
+            error!("error while deleting volume {}: {}", name, err);
+        }
+    }
+}
 
-        if let Some(bf) = backing_file {
-            cmd.arg("-b");
-            cmd.arg(bf.as_ref());
-        }
+// Rename a stopped machine, along with its directory, reservation, dhcp
+// host record and everything else kept by name. Bridges, port forwards and
+// service ports are dropped and set up under the new name on the next start,
+// like the libvirt domain, which only exists while the machine runs.
+pub fn rename_machine(id: &str, new: &str, override_freeze: bool) -> Result<(), Error> {
+    let store = Store::new()?;
+    let machine = store
+        .get_machine(id)?
+        .ok_or_else(|| error::not_found(format!("No machine with id='{}'", id)))?;
+    freeze::check("rename", machine.project.as_deref(), override_freeze)?;
+    models::check_name(new)?;
+    if store.get_machine(new)?.is_some() {
+        return Err(format!("a machine named '{}' already exists", new).into());
+    }
+    if libvirt::is_active(id)? {
+        return Err(format!("machine '{}' is running, stop it before renaming", id).into());
+    }
+    check_dependents(&store, id, "rename")?;
 
-        cmd.arg("-f");
-        cmd.arg("qcow2");
-        cmd.arg(filepath.as_ref());
+    sol::stop(&store.path_for_machine(id));
+    crashlog::stop(&store.path_for_machine(id));
+    tpm::stop(&store.path_for_machine(id));
+    host::vlan::release(id)?;
+    host::nat::remove(id)?;
+    ports::Registry::default().release(id)?;
 
-        if let Some(size) = resize {
-            cmd.arg(size.to_string());
+    let reservation = network::rename_reservation(id, new)?;
+    if let Err(e) = store.rename_machine(id, new) {
+        if reservation.is_some() {
+            network::rename_reservation(new, id)?;
         }
+        return Err(e);
+    }
+    if let Some(r) = reservation.filter(|r| !r.ip.is_empty() && !store::unprivileged()) {
+        let registrar = dhcp::registrar()?;
+        let mut old = r.clone();
+        old.hostname = id.to_string();
+        registrar.remove_host(&old)?;
+        registrar.add_host(&r)?;
+    }
 
-        debug!("Running: {:?}", cmd);
-        let r = cmd.status()?;
-        if r.success() {
-            return Ok(());
-        } else {
-            return Err("failed to create new image".into());
+    if let Some(cache) = imagecache::Cache::open()? {
+        cache.rename_user(id, new)?;
+    }
+    placement::Assignments::default().rename(id, new)?;
+    let pool = storage::pool(&machine.spec)?;
+    for name in storage::volumes(&machine.spec) {
+        pool.rename(id, new, &name)?;
+    }
+    if storage::is_block(&root_disk(&store, new)?) {
+        std::fs::write(
+            store.path_for_machine(new).join("rootdisk"),
+            pool.path(new, storage::ROOT_VOLUME).display().to_string(),
+        )?;
+    }
+    if let Some(dir) = &machine.spec.mirror {
+        let (from, to) = (mirror::target(dir, id), mirror::target(dir, new));
+        if from.exists() {
+            std::fs::rename(&from, &to)?;
+        }
+        if root_disk(&store, new)? == from {
+            std::fs::write(
+                store.path_for_machine(new).join("rootdisk"),
+                to.display().to_string(),
+            )?;
         }
     }
+    machinelog::record(
+        &store.path_for_machine(new),
+        &format!("renamed from {}", id),
+    );
+    audit::record("rename", &format!("machine={} name={}", id, new));
+    Ok(())
 }
 
-pub fn apply_specfile<P: AsRef<Path>>(path: P) -> Result<(), Error> {
-    let store = Store::new();
+// Create `new` as a copy of a machine. Its root disk is a qcow2 layer on an
+// export of a snapshot of the source's, so cloning doesn't copy the disk
+// for every clone. Without `snapshot` one is taken now, which needs the
+// source stopped. The clone gets an address and mac of its own and empty
+// extra disks.
+pub fn clone_machine(
+    source: &str,
+    new: &str,
+    snapshot: Option<&str>,
+    override_freeze: bool,
+) -> Result<(), Error> {
+    let store = Store::new()?;
+    let src = store
+        .get_machine(source)?
+        .ok_or_else(|| error::not_found(format!("No machine with id='{}'", source)))?;
+    freeze::check("clone", src.project.as_deref(), override_freeze)?;
+    models::check_name(new)?;
+    if store.get_machine(new)?.is_some() {
+        return Err(format!("a machine named '{}' already exists", new).into());
+    }
+    let root = clonable_disk(&store, source)?;
 
-    let buf = std::fs::read_to_string(path.as_ref())?;
+    let (snapshot, taken) = match snapshot {
+        Some(s) => (s.to_string(), false),
+        None => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let name = format!("clone-{}-{}", new, now);
+            snapshot_disk(source, storage::ROOT_VOLUME, &name)?;
+            (name, true)
+        }
+    };
 
-    let docs: Vec<&str> = buf.split("---").collect();
+    let (mut machine, dropped) = clone_spec(&src, new, &snapshot);
+    if !dropped.is_empty() {
+        warn!(
+            "'{}' doesn't take over the {} of '{}', only one machine can have them",
+            new,
+            dropped.join(", "),
+            source
+        );
+    }
 
-    for (i, doc) in docs.iter().enumerate() {
-        if doc.len() > 0 {
-            let r = match serde_yaml::from_str::<models::Resource>(&doc) {
-                Ok(r) => r,
-                Err(e) => {
-                    return Err(format!("Error reading document at index {}: {}", i, e).into())
+    let created = store
+        .add_machine(&machine)
+        .and_then(|_| match create_machine(&mut machine) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                delete_volumes(&machine);
+                if let Err(err) = network::remove_reservation(new) {
+                    warn!("error while removing network reservation: {}", err);
                 }
+                store.remove_machine(new)?;
+                Err(e)
+            }
+        });
+    if let Err(e) = created {
+        // the snapshot was only taken for this clone
+        if taken {
+            if let Err(err) = (qemu::Image { path: root }).delete_snapshot(&snapshot) {
+                warn!("error while deleting snapshot '{}': {}", snapshot, err);
+            }
+        }
+        return Err(e);
+    }
+    machinelog::record(
+        &store.path_for_machine(new),
+        &format!("cloned from {} snapshot {}", source, snapshot),
+    );
+    audit::record(
+        "clone",
+        &format!("machine={} from={} snapshot={}", new, source, snapshot),
+    );
+    hooks::run(Hook::PostCreate, &machine);
+    Ok(())
+}
+
+// A copy of `src` named `new` and layered on its `snapshot`, without the
+// settings only one machine can have, which are returned by name.
+fn clone_spec(
+    src: &models::Machine,
+    new: &str,
+    snapshot: &str,
+) -> (models::Machine, Vec<&'static str>) {
+    let mut machine = src.clone();
+    machine.name = new.to_string();
+    machine.status = None;
+    machine.spec.image = models::Image {
+        resize: src.spec.image.resize,
+        arch: src.spec.image.arch.clone(),
+        from_machine: Some(src.name.clone()),
+        snapshot: Some(snapshot.to_string()),
+        ..Default::default()
+    };
+    machine.spec.ip = None;
+    machine.spec.mac = None;
+
+    let mut dropped = Vec::new();
+    if machine.spec.port_forwards.take().is_some() {
+        dropped.push("port forwards");
+    }
+    if let Some(port) = machine.spec.graphics.as_mut().map(|g| &mut g.port) {
+        if port.take().is_some() {
+            dropped.push("graphics port");
+        }
+    }
+    if let Some(port) = machine.spec.sol.as_mut().map(|s| &mut s.port) {
+        if port.take().is_some() {
+            dropped.push("sol port");
+        }
+    }
+    // passed through host devices
+    if machine.spec.devices.take().is_some() {
+        dropped.push("devices");
+    }
+    (machine, dropped)
+}
+
+// A machine bundle is a tar file of the machine, its network reservation,
+// its domain xml if it was running and optionally compressed standalone
+// copies of its disks, for moving machines between hosts and keeping
+// backups of them.
+const BUNDLE_MACHINE: &str = "machine.yaml";
+const BUNDLE_RESERVATION: &str = "reservation.yaml";
+const BUNDLE_DOMAIN: &str = "domain.xml";
+const BUNDLE_DISKS: &str = "disks";
+
+pub fn export_machine(id: &str, out: &Path, disks: bool) -> Result<(), Error> {
+    let store = Store::new()?;
+    let machine = store
+        .get_machine(id)?
+        .ok_or_else(|| error::not_found(format!("No machine with id='{}'", id)))?;
+    let running = libvirt::is_active(&machine.name)?;
+    if disks && running {
+        return Err(format!("machine '{}' is running, stop it to export its disks", id).into());
+    }
+    // the copies are flattened, without internal snapshots to revert to
+    if disks && machine.spec.revert_on_boot.is_some() {
+        return Err("the disks of machines with revertOnBoot can't be exported".into());
+    }
+
+    let staging = store.path_for_machine(id).join(".export");
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::create_dir_all(&staging)?;
+    let r = write_bundle(&machine, running, disks, &staging, out);
+    std::fs::remove_dir_all(&staging)?;
+    r?;
+    audit::record("export", &format!("machine={} file={}", id, out.display()));
+    Ok(())
+}
+
+fn write_bundle(
+    machine: &models::Machine,
+    running: bool,
+    disks: bool,
+    staging: &Path,
+    out: &Path,
+) -> Result<(), Error> {
+    std::fs::write(staging.join(BUNDLE_MACHINE), machine.to_yaml()?)?;
+    if let Some(r) = network::reservation(&machine.name)? {
+        std::fs::write(staging.join(BUNDLE_RESERVATION), serde_yaml::to_string(&r)?)?;
+    }
+    // only for reference, imports define the domain from the spec
+    if running {
+        std::fs::write(
+            staging.join(BUNDLE_DOMAIN),
+            libvirt::domain_xml(&machine.name)?,
+        )?;
+    }
+    if disks {
+        let dir = staging.join(BUNDLE_DISKS);
+        std::fs::create_dir_all(&dir)?;
+        let pool = storage::pool(&machine.spec)?;
+        for name in storage::volumes(&machine.spec) {
+            let path = pool.path(&machine.name, &name);
+            let format = match storage::is_block(&path) {
+                true => "raw",
+                false => "qcow2",
             };
+            imgutil::compress(&path, format, dir.join(&name))?;
+        }
+    }
 
-            match r {
-                models::Resource::Machine(mut m) => {
-                    if store.get_machine(&m.name).is_none() {
-                        store.add_machine(&m)?;
-                        if create_machine(&mut m).is_err() {
-                            store.remove_machine(&m.name)?;
-                            eprintln!("Failed to create VM: {}", &m.name);
-                        }
-                    }
-                }
+    // tar runs in the staging directory
+    let out = std::env::current_dir()?.join(out);
+    let mut cmd = std::process::Command::new("tar");
+    cmd.arg("-cf").arg(&out).arg("-C").arg(staging).arg(".");
+    match cmd.status()?.success() {
+        true => Ok(()),
+        false => Err(format!("failed to write {}", out.display()).into()),
+    }
+}
+
+// Recreate a machine from a bundle, with the address it had if the network
+// here has it free. Bundles without disks get new ones from the image, like
+// on apply. Returns the machine's name.
+pub fn import_machine(bundle: &Path, override_freeze: bool) -> Result<String, Error> {
+    let store = Store::new()?;
+    let staging = store::data_dir().join(format!(".import-{}", std::process::id()));
+    std::fs::create_dir_all(&staging)?;
+    let r = import_bundle(&store, bundle, &staging, override_freeze);
+    std::fs::remove_dir_all(&staging)?;
+    r
+}
+
+fn import_bundle(
+    store: &Store,
+    bundle: &Path,
+    staging: &Path,
+    override_freeze: bool,
+) -> Result<String, Error> {
+    let mut cmd = std::process::Command::new("tar");
+    cmd.arg("-xf").arg(bundle).arg("-C").arg(staging);
+    if !cmd.status()?.success() {
+        return Err(format!("failed to read {}", bundle.display()).into());
+    }
+    let buf = std::fs::read_to_string(staging.join(BUNDLE_MACHINE))
+        .map_err(|_| format!("{} is not a machine bundle", bundle.display()))?;
+    let mut machine: models::Machine = serde_yaml::from_str(&buf)?;
+    freeze::check("import", machine.project.as_deref(), override_freeze)?;
+    if store.get_machine(&machine.name)?.is_some() {
+        return Err(format!("a machine named '{}' already exists", machine.name).into());
+    }
+    let reservation: Option<network::NetInfo> =
+        match std::fs::read_to_string(staging.join(BUNDLE_RESERVATION)) {
+            Ok(buf) => Some(serde_yaml::from_str(&buf)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+    machine.status = None;
+    store.add_machine(&machine)?;
+    if let Err(e) = restore_machine(store, &mut machine, staging, reservation.as_ref()) {
+        delete_volumes(&machine);
+        if let Err(err) = network::remove_reservation(&machine.name) {
+            error!("error while removing network reservation: {}", err);
+        }
+        store.remove_machine(&machine.name)?;
+        return Err(e);
+    }
+    machinelog::record(
+        &store.path_for_machine(&machine.name),
+        &format!("imported from {}", bundle.display()),
+    );
+    audit::record(
+        "import",
+        &format!("machine={} file={}", machine.name, bundle.display()),
+    );
+    hooks::run(Hook::PostCreate, &machine);
+    Ok(machine.name)
+}
+
+fn restore_machine(
+    store: &Store,
+    machine: &mut models::Machine,
+    staging: &Path,
+    reservation: Option<&network::NetInfo>,
+) -> Result<(), Error> {
+    // reserved ahead of start_domain, which then picks it up
+    if let Some(r) = reservation {
+        let ip = Some(r.ip.as_str()).filter(|ip| !ip.is_empty());
+        if let Err(e) = network::new_reservation(&machine.name, ip, Some(&r.mac)) {
+            warn!(
+                "'{}' can't keep address {}, it gets a new one: {}",
+                machine.name, r.ip, e
+            );
+            network::new_reservation(&machine.name, None, Some(&r.mac))?;
+        }
+    }
+
+    let disks = staging.join(BUNDLE_DISKS);
+    if !disks.exists() {
+        create_machine(machine)?;
+        return Ok(());
+    }
+    check_host(machine)?;
+    let pool = pin_storage_pool(store, machine)?;
+    for name in storage::volumes(&machine.spec) {
+        let path = pool.import(&machine.name, &name, &disks.join(&name))?;
+        if name == storage::ROOT_VOLUME && storage::is_block(&path) {
+            std::fs::write(
+                store.path_for_machine(&machine.name).join("rootdisk"),
+                path.display().to_string(),
+            )?;
+        }
+    }
+    start_domain(store, machine)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerAction {
+    On,
+    // pull the plug
+    Off,
+    // ask the guest to shut down via ACPI
+    Soft,
+    Cycle,
+}
+
+// Virtual power control, idempotent like a BMC: turning on a running
+// machine or off a stopped one does nothing.
+pub fn power(id: &str, action: PowerAction, override_freeze: bool) -> Result<(), Error> {
+    let store = Store::new()?;
+    let machine = store
+        .get_machine(id)?
+        .ok_or_else(|| error::not_found(format!("No machine with id='{}'", id)))?;
+    freeze::check("power", machine.project.as_deref(), override_freeze)?;
+
+    let on = libvirt::is_active(&machine.name)?;
+    let event = match action {
+        PowerAction::On if on => None,
+        PowerAction::Off | PowerAction::Soft if !on => None,
+        PowerAction::On => {
+            check_host(&machine)?;
+            start_domain(&store, &machine)?;
+            Some("powered on")
+        }
+        PowerAction::Off => {
+            libvirt::destroy(&machine.name)?;
+            bus::publish_action(bus::Kind::Machine, &machine.name, bus::Action::Stopped);
+            hooks::run(Hook::PostStop, &machine);
+            Some("powered off")
+        }
+        PowerAction::Soft => {
+            // the always policy would restart it on power off
+            if machine.spec.restart_policy == Some(models::RestartPolicy::Always) {
+                libvirt::stay_off(&machine.name)?;
+            }
+            libvirt::shutdown(&machine.name)?;
+            Some("shutdown requested")
+        }
+        PowerAction::Cycle => {
+            if on {
+                libvirt::destroy(&machine.name)?;
             }
+            check_host(&machine)?;
+            start_domain(&store, &machine)?;
+            Some("power cycled")
+        }
+    };
+    if let Some(event) = event {
+        machinelog::record(&store.path_for_machine(&machine.name), event);
+    }
+    audit::record("power", &format!("machine={} action={:?}", id, action));
+    Ok(())
+}
+
+// Power on the stopped machines whose restart policy is always, for running
+// once the host has booted: domains are transient and don't survive it.
+// Returns the machines started.
+pub fn autostart() -> Result<Vec<String>, Error> {
+    let store = Store::new()?;
+    let mut started = Vec::new();
+    for machine in store.list_machines()? {
+        if machine.spec.restart_policy != Some(models::RestartPolicy::Always)
+            || libvirt::is_active(&machine.name)?
+        {
+            continue;
         }
+        // one machine that can't start doesn't keep the others down
+        if let Err(e) = check_host(&machine).and_then(|_| start_domain(&store, &machine)) {
+            error!("error starting {}: {}", machine.name, e);
+            continue;
+        }
+        machinelog::record(&store.path_for_machine(&machine.name), "autostarted");
+        audit::record("autostart", &format!("machine={}", machine.name));
+        started.push(machine.name);
     }
+    Ok(started)
+}
 
+// Virtual media, like a BMC's: an image in the machine's cdrom drive that
+// survives power cycles until ejected. Only local images are supported.
+pub fn insert_media(id: &str, image: &str) -> Result<(), Error> {
+    let store = Store::new()?;
+    let machine = store
+        .get_machine(id)?
+        .ok_or_else(|| error::not_found(format!("No machine with id='{}'", id)))?;
+
+    let path = media_path(image)?;
+    if libvirt::is_active(&machine.name)? {
+        libvirt::change_media(&machine, Some(&path))?;
+    }
+    std::fs::write(media_state(&store, id), path.display().to_string())?;
+    machinelog::record(
+        &store.path_for_machine(id),
+        &format!("media {} inserted", path.display()),
+    );
+    audit::record(
+        "insert-media",
+        &format!("machine={} image={}", id, path.display()),
+    );
     Ok(())
 }
 
-fn create_machine(machine: &mut models::Machine) -> Result<(), Error> {
-    // resolve image
-    let images = ImageRepo::new();
+pub fn eject_media(id: &str) -> Result<(), Error> {
+    let store = Store::new()?;
+    let machine = store
+        .get_machine(id)?
+        .ok_or_else(|| error::not_found(format!("No machine with id='{}'", id)))?;
 
-    let image_url = Url::parse(&machine.spec.image.url)?;
-    let image = images.add_from_url(image_url).unwrap();
+    if libvirt::is_active(&machine.name)? {
+        libvirt::change_media(&machine, None)?;
+    }
+    let state = media_state(&store, id);
+    if state.exists() {
+        std::fs::remove_file(state)?;
+        machinelog::record(&store.path_for_machine(id), "media ejected");
+    }
+    audit::record("eject-media", &format!("machine={}", id));
+    Ok(())
+}
 
-    let s = Store::new();
+// an existing local image, given as a path or file:// url
+fn media_path(image: &str) -> Result<PathBuf, Error> {
+    let path = match Url::parse(image) {
+        Ok(url) if url.scheme() == "file" => url
+            .to_file_path()
+            .map_err(|_| format!("invalid file url {}", image))?,
+        Ok(url) => return Err(format!("media url scheme not supported: {}", url.scheme()).into()),
+        Err(_) => PathBuf::from(image),
+    };
+    if !path.is_file() {
+        return Err(format!("media image {} does not exist", path.display()).into());
+    }
+    Ok(path.canonicalize()?)
+}
 
-    // create derived image file in data dir
-    let imgpath = s.path_for_machine(&machine.name).join("image.qcow2");
-    imgutil::create(
-        &imgpath,
-        machine
-            .spec
-            .image
-            .resize
-            .as_ref()
-            .map(|s| to_size(s).expect("error parsing size value")),
-        Some(image.path),
-    )?;
+// image in the machine's cdrom drive, if any
+pub fn inserted_media(store: &Store, id: &str) -> Result<Option<PathBuf>, Error> {
+    match std::fs::read_to_string(media_state(store, id)) {
+        Ok(p) => Ok(Some(PathBuf::from(p.trim()))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
 
-    // create additional storage drives in data dir
-    // FIXME(mrodden): implement me
+fn media_state(store: &Store, id: &str) -> PathBuf {
+    store.path_for_machine(id).join("vmedia")
+}
 
-    // ensure bridged management network
-    // FIXME(mrodden): implement me
-    let bridge_name = "br0";
+// The image a machine boots from: its own, or the mirror it was switched
+// over to after losing the device its own was on.
+fn root_disk(store: &Store, id: &str) -> Result<PathBuf, Error> {
+    storage::root_disk(&store.path_for_machine(id))
+}
 
-    // generate MAC and IP
-    let netinfo = network::new_reservation(&machine.name);
-    let dnsmasq = Dnsmasq::new();
-    dnsmasq.add_host(&netinfo.mac, &netinfo.ip, &netinfo.hostname);
+pub struct MirrorStatus {
+    pub target: PathBuf,
+    // image the machine runs from, the target once switched over
+    pub root_disk: PathBuf,
+    // the mirror job while running
+    pub job: Option<mirror::Job>,
+    pub running: bool,
+    // whether both images hold the same data, only checked while stopped
+    pub identical: Option<bool>,
+}
 
-    // create libvirt XML definition
-    // create domain from XML definition
-    // start VM
-    libvirt::define(&machine, &imgpath, &bridge_name, &netinfo.mac)?;
+pub fn mirror_status(id: &str) -> Result<MirrorStatus, Error> {
+    let store = Store::new()?;
+    let machine = store
+        .get_machine(id)?
+        .ok_or_else(|| error::not_found(format!("No machine with id='{}'", id)))?;
+    let dir = machine
+        .spec
+        .mirror
+        .as_ref()
+        .ok_or_else(|| format!("machine '{}' is not mirrored", id))?;
+
+    let target = mirror::target(dir, &machine.name);
+    let root_disk = root_disk(&store, &machine.name)?;
+    let running = libvirt::is_active(&machine.name)?;
+    let job = match running {
+        true => mirror::job(&machine.name)?,
+        false => None,
+    };
+    let identical = match running || root_disk == target || !target.exists() {
+        true => None,
+        false => Some(
+            qemu::Image {
+                path: root_disk.clone(),
+            }
+            .same_content(&target)?,
+        ),
+    };
+    Ok(MirrorStatus {
+        target,
+        root_disk,
+        job,
+        running,
+        identical,
+    })
+}
+
+// Make a machine run from its mirror from now on. A running machine is
+// pivoted live when the mirror is in sync; with `force` it is powered off
+// instead, for when the primary failed and took the mirror job with it.
+pub fn switch_to_mirror(id: &str, force: bool) -> Result<(), Error> {
+    let store = Store::new()?;
+    let machine = store
+        .get_machine(id)?
+        .ok_or_else(|| error::not_found(format!("No machine with id='{}'", id)))?;
+    let dir = machine
+        .spec
+        .mirror
+        .as_ref()
+        .ok_or_else(|| format!("machine '{}' is not mirrored", id))?;
+    let target = mirror::target(dir, &machine.name);
+    if root_disk(&store, &machine.name)? == target {
+        return Err(format!("machine '{}' already runs from its mirror", id).into());
+    }
+    if !target.exists() {
+        return Err(format!("mirror {} does not exist", target.display()).into());
+    }
 
+    if libvirt::is_active(&machine.name)? {
+        match mirror::complete(&machine.name) {
+            Ok(()) => {}
+            Err(e) if force => {
+                warn!("{}, powering off {} to switch over", e, machine.name);
+                libvirt::destroy(&machine.name)?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    std::fs::write(
+        store.path_for_machine(&machine.name).join("rootdisk"),
+        target.display().to_string(),
+    )?;
+    machinelog::record(
+        &store.path_for_machine(&machine.name),
+        &format!("switched to mirror {}", target.display()),
+    );
+    audit::record(
+        "switch-mirror",
+        &format!("machine={} disk={}", id, target.display()),
+    );
     Ok(())
 }
 
-pub fn get_machine_by_id(id: &str) -> Option<models::Machine> {
-    let store = Store::new();
-    store.get_machine(id)
+// Effective virtual hardware of a machine: what the running domain has, or
+// for a stopped machine what starting it would give it, with disk sizes
+// read from the images.
+pub fn machine_hardware(
+    id: &str,
+) -> Result<(models::Machine, libvirt::DomainHardware, bool), Error> {
+    let store = Store::new()?;
+    let machine = store
+        .get_machine(id)?
+        .ok_or_else(|| error::not_found(format!("No machine with id='{}'", id)))?;
+    let running = libvirt::is_active(&machine.name)?;
+    let mut hw = match running {
+        true => libvirt::hardware(&machine.name)?,
+        false => offline_hardware(&store, &machine)?,
+    };
+    for disk in hw.disks.iter_mut() {
+        if let Some(path) = &disk.source {
+            let image = qemu::Image { path: path.clone() };
+            disk.size = match image.virtual_size() {
+                Ok(size) => Some(size),
+                Err(e) => {
+                    warn!("{}", e);
+                    None
+                }
+            };
+        }
+    }
+    Ok((machine, hw, running))
 }
 
-pub fn delete_machine(id: &str) -> Result<(), Error> {
-    let store = Store::new();
-    if store.get_machine(id).is_some() {
-        if let Err(e) = libvirt::destroy(id) {
-            return Err(format!("Error while shutting down libvirt domain='{}': {}", id, e).into());
+// the same devices start_domain gives a machine, without allocating any
+fn offline_hardware(
+    store: &Store,
+    machine: &models::Machine,
+) -> Result<libvirt::DomainHardware, Error> {
+    let pool = storage::pool(&machine.spec)?;
+    let disk = |target: String, source: PathBuf| libvirt::DiskInfo {
+        device: "disk".to_string(),
+        target,
+        bus: "virtio".to_string(),
+        format: Some(match storage::is_block(&source) {
+            true => "raw".to_string(),
+            false => "qcow2".to_string(),
+        }),
+        source: Some(source),
+        size: None,
+    };
+    let mut disks = vec![disk(libvirt::disk_dev(0), root_disk(store, &machine.name)?)];
+    for (i, storage) in machine.spec.storage.iter().flatten().enumerate() {
+        match storage {
+            models::StorageKind::DiskFile(d) => disks.push(disk(
+                libvirt::disk_dev(i + 1),
+                pool.path(&machine.name, &d.local.display().to_string()),
+            )),
         }
     }
-    if let Err(err) = network::remove_reservation(&id) {
-        error!("error while removing network reservation: {}", err);
+    let (target, bus) = libvirt::cdrom_target(&machine.spec)?;
+    disks.push(libvirt::DiskInfo {
+        device: "cdrom".to_string(),
+        target: target.to_string(),
+        bus: bus.to_string(),
+        source: inserted_media(store, &machine.name)?,
+        format: Some("raw".to_string()),
+        size: None,
+    });
+
+    let mac = network::reservation(&machine.name)?
+        .map(|r| r.mac)
+        .or_else(|| machine.spec.mac.clone());
+    let mut nics = vec![libvirt::NicInfo {
+        mac,
+        bridge: Some(network::MANAGEMENT_BRIDGE.to_string()),
+        model: None,
+        tap: None,
+    }];
+    for n in machine.spec.network.iter().flatten() {
+        match n {
+            models::NetKind::Vlan(v) => nics.push(libvirt::NicInfo {
+                mac: None,
+                bridge: Some(host::vlan::bridge(host::vlan::check_id(v.vlan)?)?),
+                model: Some("virtio".to_string()),
+                tap: None,
+            }),
+        }
     }
-    let dnsmasq = Dnsmasq::new();
-    dnsmasq.rm_host(&id);
-    store.remove_machine(id)?;
+
+    // pool virtual functions are only picked at start
+    let mut hostdevs = Vec::new();
+    for d in machine.spec.devices.iter().flatten() {
+        if let models::Device::Pci(p) = d {
+            hostdevs.push(PciAddress::parse(&p.pci)?);
+        }
+    }
+
+    Ok(libvirt::DomainHardware {
+        machine_type: Some(libvirt::machine_type(&machine.spec)?),
+        loader: None,
+        disks,
+        nics,
+        hostdevs,
+    })
+}
+
+pub struct NicTraffic {
+    pub nic: libvirt::NicInfo,
+    pub total: host::net::Counters,
+    // bytes per second received and sent, when sampled
+    pub rates: Option<(f64, f64)>,
+}
+
+// Traffic of each nic of a running machine, with rates over `interval` if
+// given. Empty when it isn't running.
+pub fn machine_traffic(id: &str, interval: Option<Duration>) -> Result<Vec<NicTraffic>, Error> {
+    if !libvirt::is_active(id)? {
+        return Ok(Vec::new());
+    }
+    let nics: Vec<libvirt::NicInfo> = libvirt::hardware(id)?
+        .nics
+        .into_iter()
+        .filter(|n| n.tap.is_some())
+        .collect();
+    let sample = || {
+        nics.iter()
+            .map(|n| host::net::tap_counters(n.tap.as_deref().unwrap_or_default()))
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let before = sample()?;
+    let interval = match interval {
+        Some(i) if !nics.is_empty() => i,
+        _ => {
+            return Ok(nics
+                .into_iter()
+                .zip(before)
+                .map(|(nic, total)| NicTraffic {
+                    nic,
+                    total,
+                    rates: None,
+                })
+                .collect())
+        }
+    };
+    let start = Instant::now();
+    std::thread::sleep(interval);
+    let after = sample()?;
+    let secs = start.elapsed().as_secs_f64();
+
+    Ok(nics
+        .into_iter()
+        .zip(before.iter().zip(after))
+        .map(|(nic, (b, a))| NicTraffic {
+            rates: Some(a.rates(b, secs)),
+            nic,
+            total: a,
+        })
+        .collect())
+}
+
+// guest memory usage from the balloon driver, None when the machine isn't
+// running or the guest hasn't reported it yet
+pub fn machine_memory(id: &str) -> Result<Option<libvirt::MemoryStats>, Error> {
+    if !libvirt::is_active(id)? {
+        return Ok(None);
+    }
+    libvirt::memory_stats(id)
+}
+
+#[derive(Debug)]
+pub enum DeleteOutcome {
+    Deleted,
+    NotFound,
+    // left in place, with the reason
+    Kept(String),
+    Failed(String),
+}
+
+// what `delete -f` did with one document of the spec file
+#[derive(Debug)]
+pub struct DeleteResult {
+    pub kind: &'static str,
+    pub name: String,
+    pub outcome: DeleteOutcome,
+}
+
+// Delete every resource declared in a spec file, going through the
+// documents backwards so machines go before the images they were made
+// from. Images are only untagged, their data stays until `image delete`.
+pub fn delete_specfile<P: AsRef<Path>>(
+    path: P,
+    values: &specfile::Values,
+    override_freeze: bool,
+) -> Result<Vec<DeleteResult>, Error> {
+    let store = Store::new()?;
+    let mut resources = Vec::new();
+    for (i, doc) in specfile::load(path.as_ref(), values)?
+        .into_iter()
+        .enumerate()
+    {
+        let r = serde_yaml::from_value::<models::Resource>(doc)
+            .map_err(|e| format!("Error reading document at index {}: {}", i, e))?;
+        resources.push(r);
+    }
+
+    let delete = |name: &str| -> Result<DeleteOutcome, Error> {
+        Ok(match store.get_machine(name)? {
+            None => DeleteOutcome::NotFound,
+            Some(_) => match delete_machine(name, override_freeze) {
+                Ok(()) => DeleteOutcome::Deleted,
+                Err(e) => DeleteOutcome::Failed(e.to_string()),
+            },
+        })
+    };
+
+    let mut results = Vec::new();
+    for r in resources.into_iter().rev() {
+        let (kind, name, outcome) = match r {
+            models::Resource::Machine(m) => {
+                let outcome = delete(&m.name)?;
+                ("Machine", m.name, outcome)
+            }
+            // only the machines the template has now, ones left over from
+            // higher replicas stay until they are deleted by name
+            models::Resource::MachineTemplate(t) => {
+                for m in t.machines()?.into_iter().rev() {
+                    results.push(DeleteResult {
+                        kind: "Machine",
+                        outcome: delete(&m.name)?,
+                        name: m.name,
+                    });
+                }
+                continue;
+            }
+            models::Resource::Network(n) => (
+                "Network",
+                n.name,
+                DeleteOutcome::Kept("networks are not deleted".into()),
+            ),
+            models::Resource::BareMetal(b) => {
+                let known = baremetal::list()?.iter().any(|h| h.host.name == b.name);
+                let outcome = match known {
+                    false => DeleteOutcome::NotFound,
+                    true => match freeze::check("delete", None, override_freeze)
+                        .and_then(|_| baremetal::remove(&b.name))
+                    {
+                        Ok(()) => DeleteOutcome::Deleted,
+                        Err(e) => DeleteOutcome::Failed(e.to_string()),
+                    },
+                };
+                ("BareMetal", b.name, outcome)
+            }
+            models::Resource::Image(img) => {
+                let repo = ImageRepo::new()?;
+                let outcome = match repo.catalog()?.contains_key(&img.name) {
+                    false => DeleteOutcome::NotFound,
+                    true => match repo.untag(&img.name) {
+                        Ok(()) => DeleteOutcome::Deleted,
+                        Err(e) => DeleteOutcome::Failed(e.to_string()),
+                    },
+                };
+                ("Image", img.name, outcome)
+            }
+        };
+        results.push(DeleteResult {
+            kind,
+            name,
+            outcome,
+        });
+    }
+    Ok(results)
+}
+
+pub struct HostSummary {
+    pub name: String,
+    pub cpus: u32,
+    pub memory: u64,
+    // memory of the machines placed on the host, running or not
+    pub used_memory: u64,
+    pub machines: Vec<String>,
+    // powered down to save energy
+    pub asleep: bool,
+}
+
+// the local and configured remote hosts with the machines placed on each
+pub fn list_hosts() -> Result<Vec<HostSummary>, Error> {
+    let machines = Store::new()?.list_machines()?;
+    let assigned = placement::Assignments::default().load()?;
+    let hosts = placement::hosts(&machines)?;
+    let local = hosts[0].name.clone();
+    let cfg = config::load()?;
+    let sleeping = hostpower::Sleeping::default().load()?;
+    let asleep = cfg.hosts.iter().filter(|h| sleeping.contains_key(&h.name));
+    Ok(hosts
+        .into_iter()
+        .map(|h| HostSummary {
+            machines: machines
+                .iter()
+                .filter(|m| assigned.get(&m.name).unwrap_or(&local) == &h.name)
+                .map(|m| m.name.clone())
+                .collect(),
+            name: h.name,
+            cpus: h.cpus,
+            memory: h.memory,
+            used_memory: h.used_memory,
+            asleep: false,
+        })
+        .chain(asleep.map(|h| HostSummary {
+            name: h.name.clone(),
+            cpus: h.cpus,
+            memory: h.memory.bytes(),
+            used_memory: 0,
+            machines: Vec::new(),
+            asleep: true,
+        }))
+        .collect())
+}
+
+pub struct DhcpPurge {
+    pub host_records: Vec<String>,
+    pub leases: Vec<network::NetInfo>,
+}
+
+// Drop dnsmasq host records and leases that belong to no machine or bare
+// metal host, meant to run while dnsmasq is stopped. Reservations made with
+// `network reserve` are kept along with their records.
+pub fn purge_dhcp_state() -> Result<DhcpPurge, Error> {
+    let mut known: Vec<String> = Store::new()?
+        .list_machines()?
+        .into_iter()
+        .map(|m| m.name)
+        .collect();
+    known.extend(baremetal::list()?.into_iter().map(|h| h.host.name));
+    let reservations = network::reservations()?;
+    known.extend(
+        reservations
+            .iter()
+            .filter(|r| r.is_allocated())
+            .map(|r| r.hostname.clone()),
+    );
+
+    let host_records = Dnsmasq::new().purge_hosts(|h| known.iter().any(|k| k == h))?;
+    let mut leases = Vec::new();
+    for r in reservations {
+        if r.is_leased() && !r.is_allocated() && !known.contains(&r.hostname) {
+            network::del_lease(&r.mac, &r.ip, None)?;
+            leases.push(r);
+        }
+    }
+    audit::record(
+        "purge-dhcp",
+        &format!(
+            "host_records={} leases={}",
+            host_records.len(),
+            leases.len()
+        ),
+    );
+    Ok(DhcpPurge {
+        host_records,
+        leases,
+    })
+}
+
+// Delete a repo image. Machines with disks layered on it block that, unless
+// `flatten` is given: their disks then get a copy of everything they read
+// from the image, which needs them stopped.
+pub fn delete_image(id: &str, flatten: bool) -> Result<Vec<String>, Error> {
+    let store = Store::new()?;
+    let images = ImageRepo::new()?;
+    images.get(id)?;
+
+    let users = store.image_users(id)?;
+    if !users.is_empty() && !flatten {
+        return Err(format!(
+            "image {} is the base of {}, use --flatten to copy it into their disks",
+            id,
+            users.join(", ")
+        )
+        .into());
+    }
+    for m in &users {
+        if libvirt::is_active(m)? {
+            return Err(format!("machine '{}' must be stopped to flatten its disk", m).into());
+        }
+    }
+    for m in &users {
+        qemu::Image {
+            path: root_disk(&store, m)?,
+        }
+        .flatten()?;
+        store.set_backing(m, None)?;
+        // the cached copy is no longer read either
+        if let Some(cache) = imagecache::Cache::open()? {
+            cache.release(m)?;
+        }
+        audit::record("flatten-disk", &format!("machine={} image={}", m, id));
+    }
+
+    images.remove(id)?;
+    audit::record("delete-image", &format!("image={}", id));
+    Ok(users)
+}
+
+// true if the machine's domain is running
+pub fn power_status(id: &str) -> Result<bool, Error> {
+    let store = Store::new()?;
+    match store.get_machine(id)? {
+        Some(m) => libvirt::is_active(&m.name),
+        None => Err(error::not_found(format!("No machine with id='{}'", id))),
+    }
+}
+
+// Resize a machine's cpu and/or memory. Changes within the maximums the domain
+// was defined with are applied live; anything else is only recorded in the
+// Store and takes effect the next time the machine is started.
+//
+// Returns true if the change was applied to the running domain.
+pub fn scale_machine(
+    id: &str,
+    cpu: Option<u32>,
+    memory: Option<models::Size>,
+    override_freeze: bool,
+) -> Result<bool, Error> {
+    let store = Store::new()?;
+    let mut machine = match store.get_machine(id)? {
+        Some(m) => m,
+        None => return Err(error::not_found(format!("No machine with id='{}'", id))),
+    };
+    freeze::check("resize", machine.project.as_deref(), override_freeze)?;
+
+    let max_cpu = machine
+        .spec
+        .max_cpu
+        .unwrap_or(machine.spec.cpu)
+        .max(machine.spec.cpu);
+    let cur_memory = machine.spec.memory.bytes();
+    let max_memory = match &machine.spec.max_memory {
+        Some(max) => max.bytes().max(cur_memory),
+        None => cur_memory,
+    };
+
+    let memory_bytes = memory.map(|m| m.bytes());
+
+    let mut live = cpu.unwrap_or(0) <= max_cpu && memory_bytes.unwrap_or(0) <= max_memory;
+    if live {
+        if let Err(e) = libvirt::scale(&machine.name, cpu, memory_bytes) {
+            warn!(
+                "live resize of '{}' failed, restart required: {}",
+                machine.name, e
+            );
+            live = false;
+        }
+    }
+
+    if let Some(cpu) = cpu {
+        machine.spec.cpu = cpu;
+    }
+    if let Some(memory) = memory {
+        machine.spec.memory = memory;
+    }
+    store.update_machine(&machine)?;
+    machinelog::record(
+        &store.path_for_machine(&machine.name),
+        &format!(
+            "resized to cpu={} memory={}{}",
+            machine.spec.cpu,
+            machine.spec.memory,
+            if live { "" } else { ", restart required" }
+        ),
+    );
+
+    Ok(live)
+}
+
+// the pool and volume name of a disk of a stopped machine, root or the
+// local file name of one of its storage disks
+fn stopped_volume(
+    store: &Store,
+    id: &str,
+    disk: &str,
+) -> Result<(models::Machine, Box<dyn storage::Pool>), Error> {
+    let machine = store
+        .get_machine(id)?
+        .ok_or_else(|| error::not_found(format!("No machine with id='{}'", id)))?;
+    if !storage::volumes(&machine.spec).iter().any(|v| v == disk) {
+        return Err(format!("machine '{}' has no disk '{}'", id, disk).into());
+    }
+    if libvirt::is_active(&machine.name)? {
+        return Err(format!("machine '{}' is running, stop it first", id).into());
+    }
+    let pool = storage::pool(&machine.spec)?;
+    Ok((machine, pool))
+}
+
+// Grow a disk of a stopped machine. Its partitions and filesystems are left
+// for the guest to grow.
+pub fn resize_disk(
+    id: &str,
+    disk: &str,
+    size: models::Size,
+    override_freeze: bool,
+) -> Result<(), Error> {
+    let store = Store::new()?;
+    let (machine, pool) = stopped_volume(&store, id, disk)?;
+    freeze::check("resize", machine.project.as_deref(), override_freeze)?;
+    pool.resize(&machine.name, disk, size.bytes())?;
+    machinelog::record(
+        &store.path_for_machine(&machine.name),
+        &format!("disk {} resized to {}", disk, size),
+    );
+    audit::record(
+        "disk-resize",
+        &format!("machine={} disk={} size={}", id, disk, size),
+    );
     Ok(())
 }
 
-fn get_unique_id(name: &str) -> String {
-    let mut h = Sha256::new();
-    h.update(name.as_bytes());
-    let r = h.finalize();
-    let mut h = hex::encode(r);
+// Snapshot a disk of a stopped machine in its pool: an internal qcow2
+// snapshot in the dir pool, a volume snapshot in lvm and zfs.
+pub fn snapshot_disk(id: &str, disk: &str, snapshot: &str) -> Result<(), Error> {
+    let store = Store::new()?;
+    let (machine, pool) = stopped_volume(&store, id, disk)?;
+    pool.snapshot(&machine.name, disk, snapshot)?;
+    machinelog::record(
+        &store.path_for_machine(&machine.name),
+        &format!("disk {} snapshot {} taken", disk, snapshot),
+    );
+    Ok(())
+}
 
-    // truncate to 128 bits (32 chars) so we can interoperate with UUIDs.
-    // this does not affect the uniqueness enough to be an issue here.
-    // obviously, do not do this if used for crypto
-    h.truncate(32);
-    h
+// A change to a machine, with the machine as it is when the event is read
+// rather than as it was at the change. Deleted machines have none.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MachineEvent {
+    pub action: bus::Action,
+    pub resource_version: u64,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub machine: Option<models::Machine>,
 }
 
-pub struct Store {
-    path: PathBuf,
+// Machine changes for controllers that would otherwise poll `list`. Start
+// from a list and its resource_version to see every change after it.
+pub struct MachineWatch {
+    store: Store,
+    sub: bus::Subscriber,
+    backlog: Vec<bus::Notification>,
+    // version of the last event handed out
+    seen: u64,
+}
+
+// the current resource version, for pairing with a list
+pub fn resource_version() -> Result<u64, Error> {
+    bus::Bus::default().version()
+}
+
+pub fn watch_machines(since: Option<u64>) -> Result<MachineWatch, Error> {
+    let bus = bus::Bus::default();
+    // subscribe first, anything published meanwhile is in both and skipped
+    let sub = bus.subscribe()?;
+    let since = match since {
+        Some(v) => v,
+        None => bus.version()?,
+    };
+    Ok(MachineWatch {
+        store: Store::new()?,
+        sub,
+        backlog: bus.since(since)?,
+        seen: since,
+    })
 }
 
-fn machine_from_file<P: AsRef<Path>>(path: P) -> models::Machine {
-    let buf = std::fs::read_to_string(path.as_ref()).expect("error reading spec file");
-    let m =
-        serde_yaml::from_str::<models::Machine>(&buf).expect("error parsing spec file contents");
-    m
+impl MachineWatch {
+    // Wait for machine events, an empty list means the timeout passed first.
+    pub fn next(&mut self, timeout: Option<Duration>) -> Result<Vec<MachineEvent>, Error> {
+        let start = Instant::now();
+        loop {
+            let notes = match self.backlog.is_empty() {
+                true => {
+                    let left = timeout.map(|t| t.saturating_sub(start.elapsed()));
+                    self.sub.next(left)?
+                }
+                false => std::mem::take(&mut self.backlog),
+            };
+            let mut events = Vec::new();
+            for n in notes {
+                if n.kind != bus::Kind::Machine || n.version <= self.seen {
+                    continue;
+                }
+                self.seen = n.version;
+                let machine = self.store.get_machine(&n.name)?;
+                let action = match (n.action, &machine) {
+                    // power changes are modifications to watchers
+                    (Some(bus::Action::Started | bus::Action::Stopped), _) => bus::Action::Modified,
+                    (Some(a), _) => a,
+                    (None, Some(_)) => bus::Action::Modified,
+                    (None, None) => bus::Action::Deleted,
+                };
+                events.push(MachineEvent {
+                    action,
+                    resource_version: n.version,
+                    name: n.name,
+                    machine,
+                });
+            }
+            let timed_out = timeout.is_some_and(|t| start.elapsed() >= t);
+            if !events.is_empty() || timed_out {
+                return Ok(events);
+            }
+        }
+    }
+}
+
+pub struct Store {
+    path: PathBuf,
+    backend: Box<dyn StoreBackend>,
 }
 
 impl Store {
-    pub fn new() -> Self {
-        let path = Path::new("/var/lib/bigiron/libvirt");
+    pub fn new() -> Result<Self, Error> {
+        let path = store::data_dir().join("libvirt");
+        std::fs::create_dir_all(&path)?;
+        let s = Self {
+            path,
+            backend: store::open()?,
+        };
+        s.backfill_backing()?;
+        Ok(s)
+    }
 
-        if !path.exists() {
-            std::fs::create_dir_all(path).expect("error creating datastore directory");
+    // Machines created before backing images were recorded only have them in
+    // their disk's backing chain. Read those once, so image deletion sees
+    // every machine layered on an image.
+    fn backfill_backing(&self) -> Result<(), Error> {
+        let done = store::data_dir().join("backing.backfilled");
+        if done.exists() {
+            return Ok(());
+        }
+        let images = self.backend.list_images()?;
+        let cache = imagecache::Cache::open()?;
+        let mut complete = true;
+        for m in self.list_machines()? {
+            if self.get_backing(&m.name)?.is_some() {
+                continue;
+            }
+            match self.backing_image(&m.name, &images, cache.as_ref()) {
+                Ok(Some(id)) => {
+                    info!("machine {} is layered on image {}", m.name, id);
+                    self.set_backing(&m.name, Some(&id))?;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("error reading the backing chain of {}: {}", m.name, e);
+                    complete = false;
+                }
+            }
         }
-        Self {
-            path: path.to_path_buf(),
+        // retried on the next open if a disk could not be read
+        if complete {
+            std::fs::write(done, "")?;
         }
+        Ok(())
     }
 
-    pub fn get_machine(&self, id: &str) -> Option<models::Machine> {
-        let mp = self.path.join(get_unique_id(id));
-
-        if !mp.exists() {
-            return None;
+    // the repo image in the backing chain of a machine's root disk
+    fn backing_image(
+        &self,
+        id: &str,
+        images: &[imagerepo::Image],
+        cache: Option<&imagecache::Cache>,
+    ) -> Result<Option<String>, Error> {
+        let mut path = root_disk(self, id)?;
+        if storage::is_block(&path) || !path.exists() {
+            return Ok(None);
+        }
+        while let Some(b) = (qemu::Image { path }).backing_file()? {
+            if let Some(img) = images.iter().find(|i| i.path == b) {
+                return Ok(Some(img.id.clone()));
+            }
+            // copies in the image cache are named after the image id
+            if cache.is_some_and(|c| c.holds(&b)) {
+                let name = b.file_name().map(|n| n.to_string_lossy().to_string());
+                if let Some(img) = images.iter().find(|i| Some(&i.id) == name.as_ref()) {
+                    return Ok(Some(img.id.clone()));
+                }
+            }
+            if !b.exists() {
+                break;
+            }
+            path = b;
         }
+        Ok(None)
+    }
 
-        let sp = mp.join("spec.yaml");
-        let m = machine_from_file(&sp);
-        Some(m)
+    pub fn get_machine(&self, id: &str) -> Result<Option<models::Machine>, Error> {
+        self.backend.get_machine(id)
     }
 
+    // directory holding a machine's disks and other files
     pub fn path_for_machine(&self, id: &str) -> PathBuf {
         self.path.join(get_unique_id(id))
     }
 
-    pub fn list_machines(&self) -> Vec<models::Machine> {
-        let mut r = Vec::new();
-        for e in self
-            .path
-            .read_dir()
-            .expect("error reading data store directories")
-        {
-            if let Ok(entry) = e {
-                let m = machine_from_file(&entry.path().join("spec.yaml"));
-                r.push(m);
-            }
-        }
-        r
+    pub fn list_machines(&self) -> Result<Vec<models::Machine>, Error> {
+        self.backend.list_machines()
+    }
+
+    // the machines whose labels match the selector
+    pub fn select_machines(
+        &self,
+        selector: &models::Selector,
+    ) -> Result<Vec<models::Machine>, Error> {
+        let mut machines = self.list_machines()?;
+        machines.retain(|m| selector.matches(&m.labels));
+        Ok(machines)
     }
 
     pub fn add_machine(&self, machine: &models::Machine) -> Result<(), Error> {
-        if self.get_machine(&machine.name).is_some() {
-            return Err("Machine with name already exists".into());
-        }
+        models::check_name(&machine.name)?;
+        models::check_labels(&machine.labels)?;
+        self.backend.insert_machine(machine)?;
+        std::fs::create_dir_all(self.path_for_machine(&machine.name))?;
+        bus::publish_action(bus::Kind::Machine, &machine.name, bus::Action::Added);
+        Ok(())
+    }
+
+    pub fn update_machine(&self, machine: &models::Machine) -> Result<(), Error> {
+        models::check_labels(&machine.labels)?;
+        self.backend.update_machine(machine)?;
+        bus::publish_action(bus::Kind::Machine, &machine.name, bus::Action::Modified);
+        Ok(())
+    }
+
+    pub fn get_backing(&self, id: &str) -> Result<Option<String>, Error> {
+        self.backend.get_backing(id)
+    }
 
-        let mp = self.path.join(get_unique_id(&machine.name));
-        std::fs::create_dir_all(&mp).expect("error creating machine diretory");
+    pub fn set_backing(&self, id: &str, image: Option<&str>) -> Result<(), Error> {
+        self.backend.set_backing(id, image)
+    }
 
-        let sp = mp.join("spec.yaml");
-        let buf = serde_yaml::to_string(machine).expect("error serializing machine spec");
-        std::fs::write(sp, buf.as_bytes()).expect("error writing spec file");
+    pub fn image_users(&self, image: &str) -> Result<Vec<String>, Error> {
+        self.backend.image_users(image)
+    }
 
+    // re-key a machine and move its directory along
+    pub fn rename_machine(&self, id: &str, new: &str) -> Result<(), Error> {
+        let mut machine = self
+            .get_machine(id)?
+            .ok_or_else(|| error::not_found(format!("No machine with id='{}'", id)))?;
+        machine.name = new.to_string();
+        let (from, to) = (self.path_for_machine(id), self.path_for_machine(new));
+        if to.exists() {
+            return Err(format!("{} is in the way", to.display()).into());
+        }
+        self.backend.rename_machine(id, &machine)?;
+        // the file backend keeps its records in these directories and has
+        // already moved it
+        if from.exists() {
+            std::fs::rename(&from, &to)?;
+        }
+        bus::publish_action(bus::Kind::Machine, id, bus::Action::Deleted);
+        bus::publish_action(bus::Kind::Machine, new, bus::Action::Added);
         Ok(())
     }
 
     pub fn remove_machine(&self, id: &str) -> Result<(), Error> {
-        if self.get_machine(id).is_none() {
-            return Err(format!("No machine with id='{}'", id).into());
-        }
+        self.backend.remove_machine(id)?;
 
-        let mp = self.path.join(get_unique_id(id));
-        std::fs::remove_dir_all(mp)?;
+        let mp = self.path_for_machine(id);
+        if mp.exists() {
+            std::fs::remove_dir_all(mp)?;
+        }
+        bus::publish_action(bus::Kind::Machine, id, bus::Action::Deleted);
 
         Ok(())
     }
@@ -266,13 +2648,48 @@ mod test {
     use super::*;
 
     #[test]
-    fn test_get_unique_id() {
-        let name = "test1234";
-        let id = get_unique_id(name);
-        assert_eq!(id, "937e8d5fbb48bd4949536cd65b8d35c4");
-
-        let name = "test1324";
-        let id = get_unique_id(name);
-        assert_eq!(id, "9884aab1d7385f53a0e96bac13b6d7b5");
+    fn test_clone_spec() {
+        let yaml = "
+          name: web-1
+          spec:
+            cpu: 2
+            memory: 2G
+            ip: 10.0.0.5
+            mac: 52:54:00:12:34:56
+            image:
+              name: ubuntu-22.04
+              resize: 20G
+            portForwards:
+            - hostPort: 8080
+              port: 80
+            graphics:
+              type: vnc
+              port: 5905
+            sol:
+              port: 2300
+            devices:
+            - pci: 0000:3b:00.0
+        ";
+        let src: models::Machine = serde_yaml::from_str(yaml).unwrap();
+        let (clone, dropped) = clone_spec(&src, "web-2", "clean");
+
+        assert_eq!(clone.name, "web-2");
+        assert_eq!(clone.spec.image.from_machine.as_deref(), Some("web-1"));
+        assert_eq!(clone.spec.image.snapshot.as_deref(), Some("clean"));
+        assert!(clone.spec.image.name.is_none());
+        assert!(clone.spec.image.resize.is_some());
+        assert!(clone.spec.ip.is_none() && clone.spec.mac.is_none());
+        assert!(clone.spec.port_forwards.is_none());
+        assert!(clone.spec.devices.is_none());
+        // the consoles stay, on ports of their own
+        assert!(clone.spec.graphics.as_ref().unwrap().port.is_none());
+        assert!(clone.spec.sol.as_ref().unwrap().port.is_none());
+        assert_eq!(
+            dropped,
+            vec!["port forwards", "graphics port", "sol port", "devices"]
+        );
+
+        let (_, dropped) = clone_spec(&clone, "web-3", "clean");
+        assert!(dropped.is_empty());
     }
 }
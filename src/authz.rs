@@ -0,0 +1,117 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Authorization of gRPC api calls. The api is served on a unix socket that
+// only root and members of the api group can open. Root may do anything
+// there, group members what the config's groupAllow says, read only
+// operations unless set. Over tcp every call needs a bearer token from the
+// config, and gets that token's allow list. So monitoring users and
+// dashboards can look without being able to delete machines.
+
+use sha2::{Digest, Sha256};
+
+use crate::config::ApiConfig;
+use crate::error::Error;
+
+// operations that change nothing, what "read" allows
+pub const READ_OPERATIONS: &[&str] = &[
+    "ListMachines",
+    "GetMachine",
+    "ListImages",
+    "GetNetwork",
+    "ListReservations",
+    "ListHosts",
+];
+
+pub enum Caller<'a> {
+    // over the unix socket, by the peer's uid
+    Local { uid: u32 },
+    // over tcp, with the bearer token if one was sent
+    Remote { token: Option<&'a str> },
+}
+
+// Check that the caller may run `op`, returning who it is for the logs.
+pub fn check(cfg: &ApiConfig, caller: &Caller, op: &str) -> Result<String, Error> {
+    let (who, allow) = match caller {
+        Caller::Local { uid: 0 } => return Ok("root".to_string()),
+        Caller::Local { uid } => (format!("uid {}", uid), &cfg.group_allow),
+        Caller::Remote { token: None } => return Err("no api token given".into()),
+        Caller::Remote { token: Some(token) } => {
+            let digest = hex::encode(Sha256::digest(token.as_bytes()));
+            match cfg
+                .tokens
+                .iter()
+                .find(|t| t.sha256.trim().eq_ignore_ascii_case(&digest))
+            {
+                Some(t) => (format!("token {}", t.name), &t.allow),
+                None => return Err("unknown api token".into()),
+            }
+        }
+    };
+    match allowed(allow, op) {
+        true => Ok(who),
+        false => Err(format!("{} may not call {}", who, op).into()),
+    }
+}
+
+fn allowed(allow: &[String], op: &str) -> bool {
+    allow.iter().any(|a| match a.as_str() {
+        "*" => true,
+        "read" => READ_OPERATIONS.contains(&op),
+        a => a == op,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ApiToken;
+
+    #[test]
+    fn test_check() {
+        let mut cfg = ApiConfig::default();
+        cfg.tokens.push(ApiToken {
+            name: "grafana".to_string(),
+            // sha256 of "secret"
+            sha256: "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b".to_string(),
+            allow: vec!["read".to_string(), "Power".to_string()],
+        });
+
+        assert!(check(&cfg, &Caller::Local { uid: 0 }, "DeleteMachine").is_ok());
+        assert!(check(&cfg, &Caller::Local { uid: 1000 }, "ListMachines").is_ok());
+        assert!(check(&cfg, &Caller::Local { uid: 1000 }, "DeleteMachine").is_err());
+
+        let token = Caller::Remote {
+            token: Some("secret"),
+        };
+        assert_eq!(check(&cfg, &token, "Power").unwrap(), "token grafana");
+        assert!(check(&cfg, &token, "GetMachine").is_ok());
+        assert!(check(&cfg, &token, "Apply").is_err());
+        assert!(check(
+            &cfg,
+            &Caller::Remote {
+                token: Some("guess")
+            },
+            "ListHosts"
+        )
+        .is_err());
+        assert!(check(&cfg, &Caller::Remote { token: None }, "ListHosts").is_err());
+
+        cfg.group_allow = vec!["*".to_string()];
+        assert!(check(&cfg, &Caller::Local { uid: 1000 }, "Apply").is_ok());
+    }
+}
@@ -0,0 +1,461 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Backups of bigiron's own state: the Store's machine and image records,
+// the image catalog, the network reservations and the dhcp host records,
+// captured together under their locks into a timestamped tar file. Disks
+// and images are not included, a restore only rolls the records back.
+
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::config::{self, StoreKind};
+use crate::dnsmasq::Dnsmasq;
+use crate::error::Error;
+use crate::imagerepo::ImageRepo;
+use crate::lockfile::LockFile;
+use crate::network;
+use crate::store;
+
+const MANIFEST: &str = "manifest.yaml";
+// the sqlite Store's database in an archive, wherever it is configured
+const STORE_DB: &str = "store.db";
+// files in a machine's directory that are records rather than disks
+const MACHINE_FILES: [&str; 4] = ["spec.yaml", "backing", "rootdisk", "vmedia"];
+const LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    // unix time the backup was taken
+    created: u64,
+    store: StoreKind,
+}
+
+pub fn default_dir() -> PathBuf {
+    store::data_dir().join("backups")
+}
+
+// Take a backup into `dir`, returning the archive's path.
+pub fn create(dir: &Path) -> Result<PathBuf, Error> {
+    let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let staging = scratch_dir("backup")?;
+    let r = capture(&staging, created).and_then(|_| {
+        std::fs::create_dir_all(dir)?;
+        let out = dir.join(format!("bigiron-{}.tar", created));
+        let mut cmd = Command::new("tar");
+        cmd.arg("-cf").arg(&out).arg("-C").arg(&staging).arg(".");
+        match cmd.status()?.success() {
+            true => Ok(out),
+            false => Err(format!("failed to write {}", out.display()).into()),
+        }
+    });
+    std::fs::remove_dir_all(&staging)?;
+    r
+}
+
+// Roll the records back to those in `archive`. The state being replaced is
+// backed up first, its archive is returned.
+pub fn restore(archive: &Path) -> Result<PathBuf, Error> {
+    let staging = scratch_dir("restore")?;
+    let r = (|| {
+        let mut cmd = Command::new("tar");
+        cmd.arg("-xf").arg(archive).arg("-C").arg(&staging);
+        if !cmd.status()?.success() {
+            return Err(format!("failed to read {}", archive.display()).into());
+        }
+        let manifest: Manifest = serde_yaml::from_str(
+            &std::fs::read_to_string(staging.join(MANIFEST))
+                .map_err(|_| format!("{} is not a bigiron backup", archive.display()))?,
+        )?;
+        if manifest.store != config::load()?.store.backend {
+            return Err(format!(
+                "{} is a backup of a {:?} store, migrate the store first",
+                archive.display(),
+                manifest.store
+            )
+            .into());
+        }
+        let before = create(&default_dir())?;
+        put_back(&staging)?;
+        Ok(before)
+    })();
+    std::fs::remove_dir_all(&staging)?;
+    r
+}
+
+// Take a backup every `interval`, keeping the `keep` newest archives in
+// `dir`. Runs until killed, failed backups are logged and retried.
+pub fn run(dir: &Path, interval: Duration, keep: usize) -> Result<(), Error> {
+    loop {
+        match create(dir) {
+            Ok(path) => info!("backed up to {}", path.display()),
+            Err(e) => error!("backup failed: {}", e),
+        }
+        if let Err(e) = prune(dir, keep) {
+            error!("error while removing old backups: {}", e);
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+// remove all but the `keep` newest archives in `dir`
+pub fn prune(dir: &Path, keep: usize) -> Result<(), Error> {
+    let mut archives: Vec<(u64, PathBuf)> = Vec::new();
+    for entry in dir.read_dir()? {
+        let path = entry?.path();
+        let created = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix("bigiron-"))
+            .and_then(|n| n.strip_suffix(".tar"))
+            .and_then(|n| n.parse().ok());
+        if let Some(created) = created {
+            archives.push((created, path));
+        }
+    }
+    archives.sort();
+    let remove = archives.len().saturating_sub(keep);
+    for (_, path) in archives.into_iter().take(remove) {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+fn scratch_dir(what: &str) -> Result<PathBuf, Error> {
+    let dir = store::data_dir().join(format!(".{}-{}", what, std::process::id()));
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+// The locks of everything backed up, in one order so a backup and a restore
+// can't deadlock. Writers of the sqlite databases aren't held off by these,
+// those are copied in a transaction of their own.
+struct Locks {
+    store: LockFile,
+    network: LockFile,
+    images: LockFile,
+}
+
+impl Locks {
+    fn new() -> Result<Self, Error> {
+        Ok(Self {
+            store: LockFile::new(store::data_dir().join(store::LOCK_FILE)),
+            network: LockFile::new(network::netstate_lock()),
+            images: ImageRepo::new()?.lockfile(),
+        })
+    }
+}
+
+// the files to back up, by where they are kept under the data directory
+struct Layout {
+    root: PathBuf,
+    machines: PathBuf,
+    images: PathBuf,
+    sources: PathBuf,
+    catalog: PathBuf,
+    netstate: PathBuf,
+    netstate_db: PathBuf,
+    hosts: PathBuf,
+}
+
+impl Layout {
+    fn new() -> Result<Self, Error> {
+        let root = store::data_dir();
+        let repo = ImageRepo::new()?;
+        Ok(Self {
+            machines: root.join("libvirt"),
+            images: root.join("images"),
+            sources: repo.sources_path(),
+            catalog: repo.catalog_path(),
+            netstate: network::netstate_path(),
+            netstate_db: network::netstate_db(),
+            hosts: Dnsmasq::new().hostsdir(),
+            root,
+        })
+    }
+
+    // where a file goes in the archive
+    fn archived(&self, staging: &Path, path: &Path) -> PathBuf {
+        staging.join(path.strip_prefix(&self.root).unwrap_or(path))
+    }
+
+    // every record file, in both the data directory and a staging directory
+    fn files(&self, staging: &Path) -> Result<Vec<PathBuf>, Error> {
+        let mut files = vec![
+            self.sources.clone(),
+            self.catalog.clone(),
+            self.netstate.clone(),
+            self.netstate_db.clone(),
+        ];
+        let both = |dir: &Path| -> Result<BTreeSet<OsString>, Error> {
+            let mut names = entries(dir)?;
+            names.extend(entries(&self.archived(staging, dir))?);
+            Ok(names)
+        };
+        if config::load()?.store.backend == StoreKind::File {
+            for dir in both(&self.machines)? {
+                for f in MACHINE_FILES {
+                    files.push(self.machines.join(&dir).join(f));
+                }
+            }
+            for name in both(&self.images)? {
+                if Path::new(&name).extension().is_some_and(|e| e == "json") {
+                    files.push(self.images.join(name));
+                }
+            }
+        }
+        for name in both(&self.hosts)? {
+            files.push(self.hosts.join(name));
+        }
+        Ok(files)
+    }
+}
+
+// names in a directory, skipping hidden ones, none if it doesn't exist
+fn entries(dir: &Path) -> Result<BTreeSet<OsString>, Error> {
+    let mut names = BTreeSet::new();
+    if !dir.exists() {
+        return Ok(names);
+    }
+    for entry in dir.read_dir()? {
+        let name = entry?.file_name();
+        if !name.to_string_lossy().starts_with('.') {
+            names.insert(name);
+        }
+    }
+    Ok(names)
+}
+
+fn capture(staging: &Path, created: u64) -> Result<(), Error> {
+    let cfg = config::load()?;
+    let layout = Layout::new()?;
+    let locks = Locks::new()?;
+    let _store = locks.store.acquire_timeout(LOCK_TIMEOUT)?;
+    let _network = locks.network.acquire_timeout(LOCK_TIMEOUT)?;
+    let _images = locks.images.acquire_timeout(LOCK_TIMEOUT)?;
+
+    for path in layout.files(staging)? {
+        if !path.is_file() {
+            continue;
+        }
+        let dest = layout.archived(staging, &path);
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        match path == layout.netstate_db {
+            true => copy_db(&path, &dest)?,
+            false => {
+                std::fs::copy(&path, &dest)?;
+            }
+        }
+    }
+    if cfg.store.backend == StoreKind::Sqlite {
+        let db = cfg.store.path.unwrap_or_else(store::default_db);
+        copy_db(&db, &staging.join(STORE_DB))?;
+    }
+
+    let manifest = Manifest {
+        created,
+        store: cfg.store.backend,
+    };
+    std::fs::write(staging.join(MANIFEST), serde_yaml::to_string(&manifest)?)?;
+    Ok(())
+}
+
+// a consistent copy of a database that may be written to meanwhile
+fn copy_db(db: &Path, dest: &Path) -> Result<(), Error> {
+    let conn = Connection::open(db)?;
+    conn.busy_timeout(LOCK_TIMEOUT)?;
+    conn.execute(
+        "VACUUM INTO ?1",
+        params![dest.to_string_lossy().to_string()],
+    )?;
+    Ok(())
+}
+
+// Records that weren't there at the time of the backup are removed. Machine
+// directories are left in place with whatever disks they hold.
+fn put_back(staging: &Path) -> Result<(), Error> {
+    let cfg = config::load()?;
+    let layout = Layout::new()?;
+    let locks = Locks::new()?;
+    let _store = locks.store.acquire_timeout(LOCK_TIMEOUT)?;
+    let _network = locks.network.acquire_timeout(LOCK_TIMEOUT)?;
+    let _images = locks.images.acquire_timeout(LOCK_TIMEOUT)?;
+
+    for path in layout.files(staging)? {
+        match path == layout.netstate_db {
+            true => restore_db(&layout.archived(staging, &path), &path)?,
+            false => replace(&layout.archived(staging, &path), &path)?,
+        }
+    }
+    if cfg.store.backend == StoreKind::Sqlite {
+        let db = cfg.store.path.unwrap_or_else(store::default_db);
+        restore_db(&staging.join(STORE_DB), &db)?;
+    }
+    Ok(())
+}
+
+// Load the rows of the database `from` into the one at `to`, emptying its
+// tables if there is no `from`. Done in a transaction of the live database
+// rather than by copying the file over it, which would race its writers and
+// leave its -wal and -journal files behind.
+fn restore_db(from: &Path, to: &Path) -> Result<(), Error> {
+    let mut conn = Connection::open(to)?;
+    conn.busy_timeout(LOCK_TIMEOUT)?;
+    let source = match from.exists() {
+        true => {
+            conn.execute(
+                "ATTACH DATABASE ?1 AS backup",
+                params![from.to_string_lossy().to_string()],
+            )?;
+            "backup"
+        }
+        false => "main",
+    };
+    let tables = {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT name, sql FROM {}.sqlite_master \
+             WHERE type = 'table' AND name NOT LIKE 'sqlite_%'",
+            source
+        ))?;
+        let rows = stmt.query_map(params![], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        rows.collect::<Result<Vec<_>, _>>()?
+    };
+
+    let tx = conn.transaction()?;
+    for (name, sql) in tables {
+        let exists = tx
+            .prepare("SELECT name FROM main.sqlite_master WHERE type = 'table' AND name = ?1")?
+            .query_map(params![name], |row| row.get::<_, String>(0))?
+            .next()
+            .is_some();
+        if !exists {
+            tx.execute_batch(&sql)?;
+        }
+        tx.execute_batch(&format!("DELETE FROM main.\"{}\"", name))?;
+        if source == "backup" {
+            tx.execute_batch(&format!(
+                "INSERT INTO main.\"{0}\" SELECT * FROM backup.\"{0}\"",
+                name
+            ))?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// put `from` in place of `to` atomically, or remove `to` if there is no `from`
+fn replace(from: &Path, to: &Path) -> Result<(), Error> {
+    if !from.exists() {
+        return match std::fs::remove_file(to) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        };
+    }
+    let parent = to.parent().ok_or("no parent directory")?;
+    std::fs::create_dir_all(parent)?;
+    let name = to.file_name().ok_or("no file name")?.to_string_lossy();
+    let tmp = parent.join(format!(".{}.restore", name));
+    std::fs::copy(from, &tmp)?;
+    std::fs::rename(&tmp, to)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_prune() {
+        let dir = TestDir::new("backup");
+        for created in [1700000300, 1700000100, 1700000200] {
+            std::fs::write(dir.join(format!("bigiron-{}.tar", created)), "").unwrap();
+        }
+        std::fs::write(dir.join("notes.txt"), "").unwrap();
+
+        prune(&dir, 2).unwrap();
+        let left: Vec<_> = entries(&dir).unwrap().into_iter().collect();
+        assert_eq!(
+            left,
+            vec![
+                "bigiron-1700000200.tar",
+                "bigiron-1700000300.tar",
+                "notes.txt"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_restore_db() {
+        let dir = TestDir::new("restore");
+        let live = dir.join("live.db");
+        let saved = dir.join("saved.db");
+        let rows = |db: &Path| -> Vec<String> {
+            let conn = Connection::open(db).unwrap();
+            let mut stmt = conn
+                .prepare("SELECT name FROM machines ORDER BY name")
+                .unwrap();
+            let r = stmt
+                .query_map(params![], |row| row.get::<_, String>(0))
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect();
+            r
+        };
+
+        let conn = Connection::open(&saved).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE machines (name TEXT PRIMARY KEY);
+             INSERT INTO machines VALUES ('web1');",
+        )
+        .unwrap();
+        drop(conn);
+        let conn = Connection::open(&live).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE machines (name TEXT PRIMARY KEY);
+             INSERT INTO machines VALUES ('web2');
+             INSERT INTO machines VALUES ('web3');",
+        )
+        .unwrap();
+
+        // with the live database still open
+        restore_db(&saved, &live).unwrap();
+        assert_eq!(rows(&live), vec!["web1"]);
+        restore_db(&dir.join("missing.db"), &live).unwrap();
+        assert!(rows(&live).is_empty());
+
+        // a database that wasn't there yet gets the saved tables
+        let new = dir.join("new.db");
+        restore_db(&saved, &new).unwrap();
+        assert_eq!(rows(&new), vec!["web1"]);
+        drop(conn);
+    }
+}
@@ -0,0 +1,178 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Physical hosts provisioned over the network. A BareMetal resource
+// registers a mac, the boot server hands it its boot config and the state
+// follows the install as the host fetches its files.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::audit;
+use crate::dhcp;
+use crate::error::Error;
+use crate::lockfile::LockFile;
+use crate::models::BareMetal;
+use crate::network;
+use crate::store;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    // known, waiting for the host to netboot
+    #[default]
+    Registered,
+    // fetched its iPXE script
+    Booting,
+    // fetched its kernel
+    Installing,
+    // the installer called back, the host boots from disk from now on
+    Provisioned,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Host {
+    pub host: BareMetal,
+    pub ip: String,
+    pub state: State,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Hosts {
+    hosts: BTreeMap<String, Host>,
+}
+
+impl Hosts {
+    fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        if !path.as_ref().exists() {
+            return Ok(Self::default());
+        }
+        let buf = std::fs::read_to_string(path.as_ref())?;
+        Ok(serde_yaml::from_str(&buf)?)
+    }
+
+    fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let buf = serde_yaml::to_string(self)?;
+        std::fs::write(path.as_ref(), buf.as_bytes())?;
+        Ok(())
+    }
+
+    fn by_mac(&mut self, mac: &str) -> Option<&mut Host> {
+        self.hosts
+            .values_mut()
+            .find(|h| h.host.spec.mac.eq_ignore_ascii_case(mac))
+    }
+}
+
+fn state_path() -> PathBuf {
+    store::data_dir().join("baremetal.yaml")
+}
+
+fn lock_path() -> PathBuf {
+    store::data_dir().join("baremetal.lock")
+}
+
+// Register a host, or update the boot config of one already registered.
+// Its mac gets a reservation and a dhcp host record like a machine's.
+pub fn register(bm: &BareMetal) -> Result<(), Error> {
+    let lf = LockFile::new(lock_path());
+    let _lock = lf.acquire();
+    let mut hosts = Hosts::load(state_path())?;
+
+    let r = network::new_reservation(&bm.name, bm.spec.ip.as_deref(), Some(&bm.spec.mac))?;
+    if r.ip.is_empty() {
+        warn!(
+            "{} is on a relay network, its address and boot options come from the upstream dhcp server",
+            bm.name
+        );
+    } else {
+        dhcp::registrar()?.add_host(&r)?;
+    }
+
+    let state = hosts
+        .hosts
+        .get(&bm.name)
+        .map(|h| h.state)
+        .unwrap_or_default();
+    hosts.hosts.insert(
+        bm.name.clone(),
+        Host {
+            host: bm.clone(),
+            ip: r.ip,
+            state,
+        },
+    );
+    audit::record(
+        "register-baremetal",
+        &format!("name={} mac={}", bm.name, r.mac),
+    );
+    hosts.save(state_path())
+}
+
+pub fn list() -> Result<Vec<Host>, Error> {
+    Ok(Hosts::load(state_path())?.hosts.into_values().collect())
+}
+
+pub fn find_by_mac(mac: &str) -> Result<Option<Host>, Error> {
+    Ok(Hosts::load(state_path())?.by_mac(mac).cloned())
+}
+
+// Move a host forward to a state, never back, so a retried download
+// doesn't undo progress. Unknown macs are ignored.
+pub fn advance(mac: &str, state: State) -> Result<(), Error> {
+    let lf = LockFile::new(lock_path());
+    let _lock = lf.acquire();
+    let mut hosts = Hosts::load(state_path())?;
+    match hosts.by_mac(mac) {
+        Some(h) if h.state < state => h.state = state,
+        _ => return Ok(()),
+    }
+    hosts.save(state_path())
+}
+
+// start over, the host reinstalls the next time it netboots
+pub fn reprovision(name: &str) -> Result<(), Error> {
+    let lf = LockFile::new(lock_path());
+    let _lock = lf.acquire();
+    let mut hosts = Hosts::load(state_path())?;
+    let h = hosts
+        .hosts
+        .get_mut(name)
+        .ok_or_else(|| format!("No bare metal host named '{}'", name))?;
+    h.state = State::Registered;
+    audit::record("reprovision-baremetal", &format!("name={}", name));
+    hosts.save(state_path())
+}
+
+pub fn remove(name: &str) -> Result<(), Error> {
+    let lf = LockFile::new(lock_path());
+    let _lock = lf.acquire();
+    let mut hosts = Hosts::load(state_path())?;
+    if hosts.hosts.remove(name).is_none() {
+        return Err(format!("No bare metal host named '{}'", name).into());
+    }
+    let reservation = network::reservation(name)?;
+    network::remove_reservation(name)?;
+    if let Some(r) = reservation {
+        dhcp::registrar()?.remove_host(&r)?;
+    }
+    audit::record("remove-baremetal", &format!("name={}", name));
+    hosts.save(state_path())
+}
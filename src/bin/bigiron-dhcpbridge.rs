@@ -15,9 +15,13 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use clap::{Parser, Subcommand};
+use tracing::{error, info, warn};
 use tracing_subscriber;
 
+use bigiron::leasespool::{LeaseAction, LeaseEvent, Spool};
 use bigiron::network;
 
 #[derive(Parser, Debug)]
@@ -56,28 +60,61 @@ fn main() {
     let cli = Cli::parse();
     eprintln!("{:?}", cli);
 
-    match cli.command {
-        Commands::Init => {}
+    let mut event = match cli.command {
+        Commands::Init => None,
         Commands::Add {
             mac,
             addr,
             hostname,
-        } => {
-            network::add_lease(&mac, &addr, hostname);
-        }
+        } => Some(LeaseEvent::new(LeaseAction::Add, &mac, &addr, hostname)),
         Commands::Old {
             mac,
             addr,
             hostname,
-        } => {
-            network::add_lease(&mac, &addr, hostname);
-        }
+        } => Some(LeaseEvent::new(LeaseAction::Old, &mac, &addr, hostname)),
         Commands::Del {
             mac,
             addr,
             hostname,
-        } => {
-            network::del_lease(&mac, &addr, hostname);
+        } => Some(LeaseEvent::new(LeaseAction::Del, &mac, &addr, hostname)),
+    };
+
+    if let Some(e) = event.as_mut() {
+        e.expires = lease_expiry();
+    }
+
+    // events that can't be applied now are spooled and retried next time
+    let spool = Spool::default();
+    let r = match event {
+        Some(e) => spool.submit(e),
+        None => spool.replay(),
+    };
+    match r {
+        Ok(0) => {}
+        Ok(n) => warn!("{} lease events waiting in spool", n),
+        Err(e) => error!("error processing lease events: {}", e),
+    }
+
+    match network::reap_leases() {
+        Ok(reaped) => {
+            for r in reaped {
+                info!("lease of {} for {} expired", r.ip, r.mac);
+            }
         }
+        Err(e) => error!("error reaping expired leases: {}", e),
+    }
+}
+
+// dnsmasq passes the lease expiry in the environment, or only the lease
+// length when built without a real time clock
+fn lease_expiry() -> Option<u64> {
+    if let Some(t) = std::env::var("DNSMASQ_LEASE_EXPIRES")
+        .ok()
+        .and_then(|t| t.parse::<u64>().ok())
+    {
+        return Some(t).filter(|t| *t != 0);
     }
+    let length: u64 = std::env::var("DNSMASQ_LEASE_LENGTH").ok()?.parse().ok()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(now + length)
 }
@@ -0,0 +1,351 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Network boot artifacts over http, keyed by mac: an iPXE script per
+// machine or bare metal host, the kernel and initrd it loads and install
+// files such as kickstarts or preseeds, templated from its spec.
+//
+//   /boot.ipxe                  chains to the script of the booting nic
+//   /boot/<mac>/ipxe            kernel, initrd and cmdline of the host
+//   /boot/<mac>/kernel
+//   /boot/<mac>/initrd
+//   /boot/<mac>/files/<name>    a templated file from netboot.files
+//   /boot/<mac>/done            for the installer to report success
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use tracing::{debug, info, warn};
+
+use crate::api;
+use crate::baremetal::{self, State};
+use crate::error::Error;
+use crate::models::Netboot;
+use crate::network;
+
+// longest request line and headers read from a client
+const MAX_HEAD: usize = 8192;
+
+// what a request for a mac is answered from
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub hostname: String,
+    pub mac: String,
+    pub ip: String,
+    pub netboot: Netboot,
+    // installed bare metal boots from its disk
+    pub provisioned: bool,
+}
+
+impl Target {
+    fn vars(&self, server: &str) -> BTreeMap<&'static str, String> {
+        let mut vars = BTreeMap::new();
+        vars.insert("hostname", self.hostname.clone());
+        vars.insert("mac", self.mac.clone());
+        vars.insert("ip", self.ip.clone());
+        vars.insert("server", server.to_string());
+        vars.insert("base", format!("{}/boot/{}", server, self.mac));
+        vars
+    }
+}
+
+// Replace `{{ name }}` with its variable, unknown names are left as they are
+// so a typo shows up in the rendered file.
+pub fn render(template: &str, vars: &BTreeMap<&str, String>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find("}}") {
+            Some(end) => {
+                let name = after[..end].trim();
+                match vars.get(name) {
+                    Some(v) => out.push_str(v),
+                    None => out.push_str(&rest[start..start + 2 + end + 2]),
+                }
+                rest = &after[end + 2..];
+            }
+            None => {
+                out.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+pub fn ipxe_script(target: &Target, server: &str) -> String {
+    let vars = target.vars(server);
+    let base = &vars["base"];
+    let cmdline = render(target.netboot.cmdline.as_deref().unwrap_or(""), &vars);
+
+    let mut script = String::from("#!ipxe\n");
+    script.push_str(&format!("kernel {}/kernel {}\n", base, cmdline).replace(" \n", "\n"));
+    if target.netboot.initrd.is_some() {
+        script.push_str(&format!("initrd {}/initrd\n", base));
+    }
+    script.push_str("boot\n");
+    script
+}
+
+// the bare metal host or machine a mac belongs to, if it boots from the
+// network
+fn lookup(mac: &str) -> Result<Option<Target>, Error> {
+    if let Some(h) = baremetal::find_by_mac(mac)? {
+        return Ok(Some(Target {
+            hostname: h.host.name,
+            mac: h.host.spec.mac.to_lowercase(),
+            ip: h.ip,
+            netboot: h.host.spec.netboot,
+            provisioned: h.state == State::Provisioned,
+        }));
+    }
+
+    let r = match network::reservations()?
+        .into_iter()
+        .find(|r| r.mac.eq_ignore_ascii_case(mac))
+    {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    let netboot = match api::get_machine_by_id(&r.hostname)? {
+        Some(m) => m.spec.netboot,
+        None => None,
+    };
+    Ok(netboot.map(|netboot| Target {
+        hostname: r.hostname,
+        mac: r.mac,
+        ip: r.ip,
+        netboot,
+        provisioned: false,
+    }))
+}
+
+enum Response {
+    Text(String),
+    File(PathBuf),
+    NotFound,
+}
+
+fn route(path: &str, server: &str) -> Result<Response, Error> {
+    let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+    if parts == ["boot.ipxe"] {
+        return Ok(Response::Text(format!(
+            "#!ipxe\nchain {}/boot/${{net0/mac}}/ipxe\n",
+            server
+        )));
+    }
+    if parts.len() < 3 || parts[0] != "boot" {
+        return Ok(Response::NotFound);
+    }
+    let target = match lookup(parts[1])? {
+        Some(t) => t,
+        None => return Ok(Response::NotFound),
+    };
+
+    // progress of bare metal installs, nothing happens for machines
+    let mac = &target.mac;
+    let nb = &target.netboot;
+    Ok(match &parts[2..] {
+        // back to the bios, which goes on to the disk
+        ["ipxe"] if target.provisioned => Response::Text("#!ipxe\nexit\n".to_string()),
+        ["ipxe"] => {
+            baremetal::advance(mac, State::Booting)?;
+            Response::Text(ipxe_script(&target, server))
+        }
+        ["kernel"] => {
+            baremetal::advance(mac, State::Installing)?;
+            Response::File(nb.kernel.clone())
+        }
+        ["done"] => {
+            baremetal::advance(mac, State::Provisioned)?;
+            Response::Text("ok\n".to_string())
+        }
+        ["initrd"] => match &nb.initrd {
+            Some(p) => Response::File(p.clone()),
+            None => Response::NotFound,
+        },
+        ["files", name] => match nb.files.as_ref().and_then(|f| f.get(*name)) {
+            Some(p) => Response::Text(render(&std::fs::read_to_string(p)?, &target.vars(server))),
+            None => Response::NotFound,
+        },
+        _ => Response::NotFound,
+    })
+}
+
+// method, path and host header of a request
+fn parse_request(head: &str) -> Option<(&str, &str, Option<&str>)> {
+    let mut lines = head.lines();
+    let mut first = lines.next()?.split_whitespace();
+    let method = first.next()?;
+    let path = first.next()?;
+    let host = lines
+        .filter_map(|l| l.split_once(':'))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, v)| v.trim());
+    Some((method, path, host))
+}
+
+fn handle(stream: TcpStream, listen: &str) -> Result<(), Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    // a line without an end stops one byte past the limit
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_HEAD as u64 + 1));
+    let mut head = String::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+        head.push_str(&line);
+        if head.len() > MAX_HEAD {
+            return Err("request header too large".into());
+        }
+    }
+
+    let mut stream = stream;
+    let (method, path, host) = match parse_request(&head) {
+        Some(r) => r,
+        None => return respond(&mut stream, "400 Bad Request", &mut std::io::empty(), 0),
+    };
+    if method != "GET" && method != "HEAD" {
+        return respond(
+            &mut stream,
+            "405 Method Not Allowed",
+            &mut std::io::empty(),
+            0,
+        );
+    }
+    // urls in scripts point back at whatever address the client used
+    let server = format!("http://{}", host.unwrap_or(listen));
+    debug!("{} {}", method, path);
+
+    let (status, mut body, len): (&str, Box<dyn Read>, u64) = match route(path, &server) {
+        Ok(Response::Text(t)) => {
+            let len = t.len() as u64;
+            ("200 OK", Box::new(std::io::Cursor::new(t)), len)
+        }
+        Ok(Response::File(p)) => match File::open(&p) {
+            Ok(f) => {
+                let len = f.metadata()?.len();
+                ("200 OK", Box::new(f), len)
+            }
+            Err(e) => {
+                warn!("error opening {}: {}", p.display(), e);
+                ("404 Not Found", Box::new(std::io::empty()), 0)
+            }
+        },
+        Ok(Response::NotFound) => ("404 Not Found", Box::new(std::io::empty()), 0),
+        Err(e) => {
+            warn!("error serving {}: {}", path, e);
+            ("500 Internal Server Error", Box::new(std::io::empty()), 0)
+        }
+    };
+    if method == "HEAD" {
+        body = Box::new(std::io::empty());
+    }
+    respond(&mut stream, status, &mut body, len)
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    body: &mut dyn Read,
+    len: u64,
+) -> Result<(), Error> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, len
+    )?;
+    std::io::copy(body, stream)?;
+    Ok(())
+}
+
+// Serve boot artifacts until killed.
+pub fn serve(listen: &str) -> Result<(), Error> {
+    let listener = TcpListener::bind(listen)?;
+    info!("serving boot artifacts on {}", listen);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("error accepting connection: {}", e);
+                continue;
+            }
+        };
+        let listen = listen.to_string();
+        thread::spawn(move || {
+            if let Err(e) = handle(stream, &listen) {
+                debug!("error handling request: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ipxe_script() {
+        let target = Target {
+            hostname: "web1".into(),
+            mac: "00:16:3e:00:00:01".into(),
+            ip: "172.20.0.2".into(),
+            netboot: Netboot {
+                kernel: "/srv/boot/vmlinuz".into(),
+                initrd: Some("/srv/boot/initrd.img".into()),
+                cmdline: Some("inst.ks={{ base }}/files/ks.cfg ip={{ip}} {{nope}}".into()),
+                files: None,
+            },
+            provisioned: false,
+        };
+        let script = ipxe_script(&target, "http://172.20.0.1:8080");
+        assert_eq!(
+            script,
+            "#!ipxe\n\
+             kernel http://172.20.0.1:8080/boot/00:16:3e:00:00:01/kernel \
+             inst.ks=http://172.20.0.1:8080/boot/00:16:3e:00:00:01/files/ks.cfg ip=172.20.0.2 {{nope}}\n\
+             initrd http://172.20.0.1:8080/boot/00:16:3e:00:00:01/initrd\n\
+             boot\n"
+        );
+
+        let vars = target.vars("http://h");
+        assert_eq!(
+            render("network --hostname={{hostname}} {{", &vars),
+            "network --hostname=web1 {{"
+        );
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let head = "GET /boot.ipxe HTTP/1.1\r\nUser-Agent: iPXE/1.21\r\nHost: 172.20.0.1:8080\r\n";
+        assert_eq!(
+            parse_request(head),
+            Some(("GET", "/boot.ipxe", Some("172.20.0.1:8080")))
+        );
+        assert_eq!(parse_request(""), None);
+    }
+}
@@ -0,0 +1,411 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Change notifications between bigiron processes.
+//
+// Every change to the store or netstate appends a line to a journal file.
+// Subscribers keep the journal open and use inotify to wake up when it
+// grows, so they learn what changed right away instead of re-reading state
+// on a timer. Notifications only name the object, subscribers read the
+// current state themselves. The journal is rotated once it gets large.
+//
+// Every notification has a version, counting up across the whole bus, so a
+// subscriber that went away can pick up where it left off as long as the
+// journal still goes back that far.
+//
+// Publishers that know what happened say so in the action, such as a
+// machine starting or a lease running out, which is what `bigiron events`
+// shows. The journal and its rotated predecessor hold the recent history.
+
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::error::Error;
+use crate::lockfile::LockFile;
+use crate::store;
+
+const BUS_DIR: &str = "bus";
+const JOURNAL: &str = "journal";
+const VERSION: &str = "version";
+
+// size at which the journal is rotated
+const MAX_JOURNAL: u64 = 256 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Machine,
+    Image,
+    Netstate,
+    // a guest kernel crashed, see the machine's oops.log
+    Crash,
+    // a dhcp lease of the management network, named by its address
+    Lease,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Added,
+    Modified,
+    Deleted,
+    // machines powering on and off
+    Started,
+    Stopped,
+    // an image brought into the repo
+    Imported,
+    // leases handed out, and released or run out
+    Acquired,
+    Expired,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notification {
+    pub kind: Kind,
+    pub name: String,
+    // unix timestamp of the change
+    pub time: u64,
+    // 0 for notifications from before versions were kept
+    #[serde(default)]
+    pub version: u64,
+    // what happened to the object, when the publisher knows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<Action>,
+}
+
+impl std::fmt::Display for Notification {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let action = match self.action {
+            Some(a) => format!("{:?}", a),
+            None => "changed".to_string(),
+        };
+        write!(
+            f,
+            "{} {:>6} {:<8} {:<9} {}",
+            self.time,
+            self.version,
+            format!("{:?}", self.kind).to_lowercase(),
+            action.to_lowercase(),
+            self.name
+        )
+    }
+}
+
+pub struct Bus {
+    dir: PathBuf,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new(store::data_dir().join(BUS_DIR))
+    }
+}
+
+// Publish on the default bus. Notifications are best effort, a failure is
+// logged but never fails the change itself.
+pub fn publish(kind: Kind, name: &str) {
+    if let Err(e) = Bus::default().publish(kind, name, None) {
+        warn!("error publishing {:?} change for '{}': {}", kind, name, e);
+    }
+}
+
+pub fn publish_action(kind: Kind, name: &str, action: Action) {
+    if let Err(e) = Bus::default().publish(kind, name, Some(action)) {
+        warn!("error publishing {:?} change for '{}': {}", kind, name, e);
+    }
+}
+
+impl Bus {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    fn journal(&self) -> PathBuf {
+        self.dir.join(JOURNAL)
+    }
+
+    pub fn publish(&self, kind: Kind, name: &str, action: Option<Action>) -> Result<(), Error> {
+        // nothing to notify before bigiron has set up its data dir
+        match self.dir.parent() {
+            Some(p) if !p.exists() => return Ok(()),
+            _ => std::fs::create_dir_all(&self.dir)?,
+        }
+
+        let lf = LockFile::new(self.dir.join("journal.lock"));
+        let _lock = lf.acquire_timeout(Duration::from_secs(5))?;
+
+        let version = self.version()? + 1;
+        std::fs::write(self.dir.join(VERSION), version.to_string())?;
+        let n = Notification {
+            kind,
+            name: name.to_string(),
+            time: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            version,
+            action,
+        };
+        let mut line = serde_json::to_string(&n)?;
+        line.push('\n');
+
+        let path = self.journal();
+        if std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0) > MAX_JOURNAL {
+            std::fs::rename(&path, self.dir.join(format!("{}.1", JOURNAL)))?;
+        }
+        let mut f = OpenOptions::new().create(true).append(true).open(&path)?;
+        f.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    // version of the latest notification, 0 before the first
+    pub fn version(&self) -> Result<u64, Error> {
+        match std::fs::read_to_string(self.dir.join(VERSION)) {
+            Ok(v) => Ok(v.trim().parse()?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // everything still in the journal, oldest first
+    pub fn history(&self) -> Result<Vec<Notification>, Error> {
+        let mut all = Vec::new();
+        for path in [self.dir.join(format!("{}.1", JOURNAL)), self.journal()] {
+            let buf = match std::fs::read_to_string(&path) {
+                Ok(buf) => buf,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            all.extend(
+                buf.lines()
+                    .filter_map(|l| serde_json::from_str::<Notification>(l).ok()),
+            );
+        }
+        Ok(all)
+    }
+
+    // Notifications after version `since` still in the journal, failing if
+    // some of them were already rotated away.
+    pub fn since(&self, since: u64) -> Result<Vec<Notification>, Error> {
+        let mut all = self.history()?;
+        let oldest = all.iter().map(|n| n.version).find(|v| *v > 0);
+        let gap = match oldest {
+            Some(o) => o > since + 1,
+            None => true,
+        };
+        if since < self.version()? && gap {
+            return Err(format!("version {} is too old, start from a fresh list", since).into());
+        }
+        all.retain(|n| n.version > since);
+        Ok(all)
+    }
+
+    // notifications published from now on
+    pub fn subscribe(&self) -> Result<Subscriber, Error> {
+        std::fs::create_dir_all(&self.dir)?;
+        let mask = libc::IN_MODIFY | libc::IN_CREATE | libc::IN_MOVED_TO;
+        let inotify = Inotify::watch(&self.dir, mask)?;
+        let mut sub = Subscriber {
+            path: self.journal(),
+            file: None,
+            ino: 0,
+            partial: String::new(),
+            inotify,
+        };
+        if let Some((mut f, ino)) = sub.open()? {
+            f.seek(SeekFrom::End(0))?;
+            sub.file = Some(f);
+            sub.ino = ino;
+        }
+        Ok(sub)
+    }
+}
+
+pub struct Subscriber {
+    path: PathBuf,
+    file: Option<File>,
+    ino: u64,
+    // an incomplete last line, finished by a later read
+    partial: String,
+    inotify: Inotify,
+}
+
+impl Subscriber {
+    // Wait for notifications, returning an empty list if none came in before
+    // the timeout. With no timeout this blocks until something changes.
+    pub fn next(&mut self, timeout: Option<Duration>) -> Result<Vec<Notification>, Error> {
+        let start = Instant::now();
+        loop {
+            let r = self.read()?;
+            if !r.is_empty() {
+                return Ok(r);
+            }
+
+            let remaining = match timeout {
+                Some(t) => match t.checked_sub(start.elapsed()) {
+                    Some(r) => Some(r),
+                    None => return Ok(r),
+                },
+                None => None,
+            };
+            self.inotify.wait(remaining)?;
+        }
+    }
+
+    fn open(&self) -> Result<Option<(File, u64)>, Error> {
+        match File::open(&self.path) {
+            Ok(f) => {
+                let ino = f.metadata()?.ino();
+                Ok(Some((f, ino)))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn read(&mut self) -> Result<Vec<Notification>, Error> {
+        let mut buf = std::mem::take(&mut self.partial);
+        if let Some(f) = self.file.as_mut() {
+            f.read_to_string(&mut buf)?;
+        }
+
+        // once the old journal is drained, switch to the one replacing it
+        if let Some((mut f, ino)) = self.open()? {
+            if self.file.is_none() || ino != self.ino {
+                f.read_to_string(&mut buf)?;
+                self.file = Some(f);
+                self.ino = ino;
+            }
+        }
+
+        let mut r = Vec::new();
+        let complete = buf.ends_with('\n');
+        let mut lines: Vec<&str> = buf.lines().collect();
+        if !complete {
+            self.partial = lines.pop().unwrap_or_default().to_string();
+        }
+        for line in lines {
+            match serde_json::from_str(line) {
+                Ok(n) => r.push(n),
+                Err(e) => warn!("skipping bad notification '{}': {}", line, e),
+            }
+        }
+        Ok(r)
+    }
+}
+
+pub(crate) struct Inotify {
+    fd: RawFd,
+}
+
+impl Inotify {
+    // events of `mask`, the libc::IN_* flags, on the entries of dir
+    pub(crate) fn watch(dir: &Path, mask: u32) -> Result<Self, Error> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let inotify = Self { fd };
+
+        let path = CString::new(dir.as_os_str().as_bytes())?;
+        if unsafe { libc::inotify_add_watch(fd, path.as_ptr(), mask) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(inotify)
+    }
+
+    // block until an event arrives or the timeout passes, then drain events
+    pub(crate) fn wait(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        let mut pfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ms = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+        if unsafe { libc::poll(&mut pfd, 1, ms) } < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() != std::io::ErrorKind::Interrupted {
+                return Err(e.into());
+            }
+        }
+
+        let mut buf = [0u8; 4096];
+        while unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } > 0 {
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_publish_subscribe() {
+        let dir = TestDir::new("bus");
+        let bus = Bus::new(&dir);
+
+        bus.publish(Kind::Image, "old", None).unwrap();
+        let mut sub = bus.subscribe().unwrap();
+        assert!(sub
+            .next(Some(Duration::from_millis(10)))
+            .unwrap()
+            .is_empty());
+
+        let t = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            Bus::new(&dir)
+                .publish(Kind::Machine, "web1", Some(Action::Added))
+                .unwrap();
+            dir
+        });
+        let r = sub.next(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(r.len(), 1);
+        assert_eq!((r[0].kind, r[0].name.as_str()), (Kind::Machine, "web1"));
+        assert_eq!((r[0].version, r[0].action), (2, Some(Action::Added)));
+
+        // keeps following the journal across a rotation
+        let dir = t.join().unwrap();
+        std::fs::rename(dir.join(JOURNAL), dir.join("journal.1")).unwrap();
+        bus.publish(Kind::Netstate, "default", None).unwrap();
+        let r = sub.next(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(r[0].kind, Kind::Netstate);
+
+        // resuming works from anything still in either journal file
+        let missed: Vec<u64> = bus.since(1).unwrap().iter().map(|n| n.version).collect();
+        assert_eq!(missed, vec![2, 3]);
+        assert_eq!(bus.history().unwrap().len(), 3);
+        assert!(bus.since(3).unwrap().is_empty());
+        std::fs::remove_file(dir.join("journal.1")).unwrap();
+        assert!(bus.since(1).is_err());
+    }
+}
@@ -0,0 +1,72 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Subcommands shared by the bigiron binaries, for packaging to generate
+// shell completions and man pages from each binary's own definition.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Command, Subcommand};
+use clap_complete::Shell;
+use clap_mangen::Man;
+
+use crate::error::Error;
+
+#[derive(Subcommand)]
+pub enum DocCommands {
+    /// Print a completion script for the shell
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Print the man page, or write one per subcommand into a directory
+    Man {
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+}
+
+impl DocCommands {
+    // `cmd` is the binary's whole command, from CommandFactory::command
+    pub fn run(&self, mut cmd: Command) -> Result<(), Error> {
+        match self {
+            DocCommands::Completions { shell } => {
+                let name = cmd.get_name().to_string();
+                clap_complete::generate(*shell, &mut cmd, name, &mut std::io::stdout());
+            }
+            DocCommands::Man { dir: None } => Man::new(cmd).render(&mut std::io::stdout())?,
+            DocCommands::Man { dir: Some(dir) } => {
+                std::fs::create_dir_all(dir)?;
+                let name = cmd.get_name().to_string();
+                for sub in cmd.get_subcommands().filter(|s| !s.is_hide_set()) {
+                    let page = format!("{}-{}", name, sub.get_name());
+                    write_page(sub.clone().name(&page), &dir.join(format!("{}.1", page)))?;
+                }
+                write_page(cmd.clone(), &dir.join(format!("{}.1", name)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_page(cmd: Command, path: &Path) -> Result<(), Error> {
+    let mut buf = Vec::new();
+    Man::new(cmd).render(&mut buf)?;
+    std::fs::File::create(path)?.write_all(&buf)?;
+    Ok(())
+}
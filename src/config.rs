@@ -0,0 +1,502 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::models::{Dns, Size};
+
+const CONFIG_PATH: &str = "/etc/bigiron/config.yaml";
+
+// host wide settings, everything is optional and defaults to the
+// behaviour of an unconfigured host
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    #[serde(default)]
+    pub store: StoreConfig,
+    #[serde(default)]
+    pub network: NetworkDefaults,
+    #[serde(default)]
+    pub vlan: VlanConfig,
+    #[serde(default)]
+    pub netboot: NetbootConfig,
+    #[serde(default)]
+    pub scheduling: Scheduling,
+    #[serde(default)]
+    pub image_cache: ImageCacheConfig,
+    #[serde(default)]
+    pub dhcp: DhcpConfig,
+    // more hypervisors to place machines on, next to this host
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<HostConfig>,
+    // pools for machine disks besides the built in dir pool
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub storage_pools: Vec<PoolConfig>,
+    // pool for machines that don't name one, dir if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_storage_pool: Option<String>,
+    #[serde(default)]
+    pub api: ApiConfig,
+    // holds the <hook>.d directories of lifecycle hook scripts, see hooks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hooks_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub qemu: QemuConfig,
+}
+
+// Overrides for what probing the host finds, for guests of the host's
+// architecture. Probing looks for kvm, qemu-kvm and qemu-system-<arch>.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QemuConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emulator: Option<PathBuf>,
+    // for machines whose spec has none, e.g. q35 where there is no i440fx
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub machine_type: Option<String>,
+}
+
+// Who may call the gRPC api, see authz. Allow lists name operations of
+// proto/bigiron.proto, "read" for all the read only ones or "*" for all.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiConfig {
+    // group the api socket belongs to, its members may connect
+    #[serde(default = "default_api_group")]
+    pub group: String,
+    // what members of the group may do, root may do anything
+    #[serde(default = "default_group_allow")]
+    pub group_allow: Vec<String>,
+    // bearer tokens of tcp clients
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tokens: Vec<ApiToken>,
+    // certificate for tcp, which is only served on loopback without it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+}
+
+// pem files of the server's certificate chain and its key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            group: default_api_group(),
+            group_allow: default_group_allow(),
+            tokens: Vec::new(),
+            tls: None,
+        }
+    }
+}
+
+fn default_api_group() -> String {
+    "bigiron".to_string()
+}
+
+fn default_group_allow() -> Vec<String> {
+    vec!["read".to_string()]
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    // who the token belongs to, for the logs
+    pub name: String,
+    // sha256 of the token, so the config doesn't hold the token itself
+    pub sha256: String,
+    pub allow: Vec<String>,
+}
+
+// A remote libvirt host. Images and machine directories are not copied, it
+// has to see them at the same paths as this host does, e.g. over nfs, and
+// have the same bridges for the machines' networks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HostConfig {
+    pub name: String,
+    // libvirt uri such as qemu+ssh://root@hv2/system
+    pub uri: String,
+    pub cpus: u32,
+    pub memory: Size,
+    // how to power the host down when it is empty and up again, see hostpower
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub power: Option<HostPower>,
+}
+
+// Power control of a remote host: its BMC, or wake-on-lan along with a
+// command that suspends it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HostPower {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipmi: Option<IpmiConfig>,
+    // mac of the interface that wakes the host
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wake_mac: Option<String>,
+    // e.g. [ssh, root@hv2, systemctl, suspend]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sleep: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IpmiConfig {
+    pub address: String,
+    pub username: String,
+    pub password_file: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub driver: PoolDriver,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "driver", rename_all = "lowercase")]
+pub enum PoolDriver {
+    Dir,
+    // thin volumes, the thin pool has to exist in the volume group
+    #[serde(rename_all = "camelCase")]
+    Lvm {
+        volume_group: String,
+        thin_pool: String,
+    },
+    // zvols under an existing dataset
+    Zfs {
+        dataset: String,
+    },
+}
+
+impl Config {
+    pub fn host(&self, name: &str) -> Option<&HostConfig> {
+        self.hosts.iter().find(|h| h.name == name)
+    }
+
+    pub fn storage_pool(&self, name: &str) -> Option<&PoolConfig> {
+        self.storage_pools.iter().find(|p| p.name == name)
+    }
+
+    pub fn hooks_dir(&self) -> PathBuf {
+        self.hooks_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("/etc/bigiron/hooks"))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DhcpConfig {
+    // where host records for reservations are sent
+    #[serde(default)]
+    pub registrar: RegistrarKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kea: Option<KeaConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dhcpd: Option<DhcpdConfig>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistrarKind {
+    #[default]
+    Dnsmasq,
+    Kea,
+    Dhcpd,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeaConfig {
+    // control agent, e.g. http://127.0.0.1:8000/
+    pub url: String,
+    // id of the management network's subnet in the kea config
+    pub subnet_id: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DhcpdConfig {
+    // file included from dhcpd.conf
+    pub include: PathBuf,
+    // run after the file changes, e.g. [systemctl, restart, isc-dhcp-server]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reload: Option<Vec<String>>,
+}
+
+// fast local tier base images are mirrored onto before machines use them
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageCacheConfig {
+    // directory on the fast disk or tmpfs, no cache when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    // most space the copies may take, e.g. 64G, unlimited when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<Size>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Scheduling {
+    // replaces the built in ci-ephemeral, dev and infra classes when set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority_classes: Option<BTreeMap<String, PriorityClass>>,
+    // what apply does to lower priority machines when a new one doesn't
+    // fit, unset never preempts unless asked to with --preempt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preemption: Option<Preemption>,
+    // consolidate machines onto fewer hosts and power down the empty ones,
+    // off when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub power_saving: Option<PowerSaving>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PowerSaving {
+    // hosts with power control that stay awake, so new machines can start
+    // without waiting for one to wake up
+    #[serde(default)]
+    pub spare_hosts: u32,
+    // how long apply waits for a woken host's libvirt, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wake_timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PriorityClass {
+    pub value: i32,
+    // may be stopped or deleted for a higher priority machine
+    #[serde(default)]
+    pub preemptible: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preemption {
+    // power off, the machine keeps its disks and can be powered on again
+    Stop,
+    Delete,
+}
+
+impl std::str::FromStr for Preemption {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stop" => Ok(Preemption::Stop),
+            "delete" => Ok(Preemption::Delete),
+            _ => Err(format!("unknown preemption '{}', use stop or delete", s)),
+        }
+    }
+}
+
+// priority of machines without a class
+pub const DEFAULT_PRIORITY: i32 = 100;
+
+impl Scheduling {
+    pub fn priority_class(&self, name: &str) -> Result<PriorityClass, Error> {
+        let class = match &self.priority_classes {
+            Some(classes) => classes.get(name).copied(),
+            None => match name {
+                "ci-ephemeral" => Some(PriorityClass {
+                    value: 0,
+                    preemptible: true,
+                }),
+                "dev" => Some(PriorityClass {
+                    value: DEFAULT_PRIORITY,
+                    preemptible: false,
+                }),
+                "infra" => Some(PriorityClass {
+                    value: 1000,
+                    preemptible: false,
+                }),
+                _ => None,
+            },
+        };
+        class.ok_or_else(|| format!("unknown priority class '{}'", name).into())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NetbootConfig {
+    // serve pxe boot files over tftp and point iPXE at the boot server
+    #[serde(default)]
+    pub enabled: bool,
+    // boot server url, defaults to port 8080 on the network gateway
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VlanConfig {
+    // uplink carrying tagged traffic, bridges for machine vlans are created
+    // on it when set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trunk: Option<String>,
+    // linux bridges or open vswitch, ovs when ovsBridge is set or the trunk
+    // is already on an ovs bridge if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backend: Option<BridgeBackend>,
+    // the ovs bridge machine vlans are tagged on, brovs when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ovs_bridge: Option<String>,
+    // Port added to the ovs bridge as its uplink carrying every vlan. The
+    // trunk is never moved in on its own, as it often holds the host's
+    // address which it would lose there.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ovs_uplink: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BridgeBackend {
+    Linux,
+    Ovs,
+}
+
+// defaults for settings a Network resource leaves unset
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkDefaults {
+    // dns domain of the management network, cloud.local when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dns: Option<Dns>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ntp: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoreConfig {
+    #[serde(default)]
+    pub backend: StoreKind,
+    // database file for the sqlite backend
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    #[default]
+    File,
+    Sqlite,
+}
+
+// read the config file, BIGIRON_CONFIG overrides its location
+pub fn load() -> Result<Config, Error> {
+    let path = std::env::var_os("BIGIRON_CONFIG")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(CONFIG_PATH));
+
+    match std::fs::read_to_string(&path) {
+        Ok(buf) => serde_yaml::from_str(&buf)
+            .map_err(|e| format!("error parsing {}: {}", path.display(), e).into()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let c: Config = serde_yaml::from_str("store:\n  backend: sqlite\n").unwrap();
+        assert_eq!(c.store.backend, StoreKind::Sqlite);
+        assert!(c.hosts.is_empty());
+
+        let c: Config = serde_yaml::from_str(
+            "
+            hosts:
+            - name: hv2
+              uri: qemu+ssh://root@hv2/system
+              cpus: 32
+              memory: 128Gi
+            ",
+        )
+        .unwrap();
+        assert_eq!(c.host("hv2").unwrap().cpus, 32);
+        assert!(c.host("hv3").is_none());
+        assert_eq!(c.store.path, None);
+
+        let c: Config = serde_yaml::from_str("{}").unwrap();
+        assert_eq!(c.store.backend, StoreKind::File);
+        assert!(c.network.dns.is_none());
+        assert!(c.vlan.trunk.is_none());
+        assert!(c.image_cache.path.is_none());
+        assert_eq!(c.dhcp.registrar, RegistrarKind::Dnsmasq);
+        assert_eq!(c.api.group, "bigiron");
+        assert_eq!(c.hooks_dir(), PathBuf::from("/etc/bigiron/hooks"));
+        assert_eq!(c.api.group_allow, vec!["read"]);
+        assert_eq!(
+            c.scheduling.priority_class("ci-ephemeral").unwrap().value,
+            0
+        );
+        assert!(c.scheduling.priority_class("gold").is_err());
+
+        let c: Config = serde_yaml::from_str(
+            "scheduling:\n  preemption: stop\n  priorityClasses:\n    gold: {value: 10}\n",
+        )
+        .unwrap();
+        assert_eq!(c.scheduling.preemption, Some(Preemption::Stop));
+        assert!(!c.scheduling.priority_class("gold").unwrap().preemptible);
+        assert!(c.scheduling.priority_class("dev").is_err());
+
+        let c: Config = serde_yaml::from_str("network:\n  dns:\n    servers: [1.1.1.1]\n").unwrap();
+        assert_eq!(c.network.dns.unwrap().servers, vec!["1.1.1.1"]);
+
+        let c: Config = serde_yaml::from_str(
+            "
+            defaultStoragePool: thin
+            storagePools:
+            - name: thin
+              driver: lvm
+              volumeGroup: vg0
+              thinPool: pool0
+            - name: tank
+              driver: zfs
+              dataset: tank/bigiron
+            ",
+        )
+        .unwrap();
+        assert_eq!(c.default_storage_pool.as_deref(), Some("thin"));
+        assert_eq!(
+            c.storage_pool("thin").unwrap().driver,
+            PoolDriver::Lvm {
+                volume_group: "vg0".into(),
+                thin_pool: "pool0".into()
+            }
+        );
+        assert!(c.storage_pool("dir").is_none());
+    }
+}
@@ -0,0 +1,187 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Kernel crash capture: a second serial port on the machine, meant for the
+// guest kernel's console (console=ttyS1), read by a small process per
+// machine into oops.log with a timestamp on every line. Unlike the guest's
+// ring buffer the log survives the panic, and each crash is announced on the
+// bus for whoever watches for them.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tracing::warn;
+
+use crate::bus;
+use crate::error::Error;
+use crate::machinelog;
+
+// how long after a crash further crash lines count as the same crash
+const CRASH_WINDOW: Duration = Duration::from_secs(60);
+
+const MARKERS: &[&str] = &[
+    "Kernel panic",
+    "Oops:",
+    "BUG:",
+    "general protection fault",
+    "watchdog: BUG: soft lockup",
+];
+
+pub fn socket_path(machine_dir: &Path) -> PathBuf {
+    machine_dir.join("crash.sock")
+}
+
+pub fn log_path(machine_dir: &Path) -> PathBuf {
+    machine_dir.join("oops.log")
+}
+
+fn pid_path(machine_dir: &Path) -> PathBuf {
+    machine_dir.join("crash.pid")
+}
+
+// Start the reader for a machine unless it is already running. Like the
+// sol proxy it outlives the cli and reconnects across machine restarts.
+pub fn spawn(id: &str, machine_dir: &Path) -> Result<(), Error> {
+    if running(machine_dir).is_some() {
+        return Ok(());
+    }
+    let log = File::options()
+        .append(true)
+        .create(true)
+        .open(machine_dir.join("crash-serve.log"))?;
+    let child = Command::new(std::env::current_exe()?)
+        .arg("crash-serve")
+        .arg(id)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .spawn()?;
+    std::fs::write(pid_path(machine_dir), child.id().to_string())?;
+    Ok(())
+}
+
+pub fn stop(machine_dir: &Path) {
+    if let Some(pid) = running(machine_dir) {
+        unsafe { libc::kill(pid, libc::SIGTERM) };
+    }
+    let _ = std::fs::remove_file(pid_path(machine_dir));
+}
+
+fn running(machine_dir: &Path) -> Option<i32> {
+    let pid = std::fs::read_to_string(pid_path(machine_dir))
+        .ok()?
+        .trim()
+        .parse::<i32>()
+        .ok()?;
+    match unsafe { libc::kill(pid, 0) } {
+        0 => Some(pid),
+        _ => None,
+    }
+}
+
+// Copy the crash console of machine `id` into its oops.log until killed.
+pub fn serve(id: &str, machine_dir: &Path) -> Result<(), Error> {
+    let mut log = File::options()
+        .append(true)
+        .create(true)
+        .open(log_path(machine_dir))?;
+    let mut last_crash: Option<Instant> = None;
+    loop {
+        if let Ok(mut s) = UnixStream::connect(socket_path(machine_dir)) {
+            let mut lines = Lines::default();
+            let mut buf = [0u8; 4096];
+            while let Ok(n) = s.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                for line in lines.push(&buf[..n]) {
+                    writeln!(log, "{} {}", timestamp(), line)?;
+                    let repeat = last_crash.is_some_and(|t| t.elapsed() <= CRASH_WINDOW);
+                    if is_crash(&line) && !repeat {
+                        warn!("kernel crash on {}: {}", id, line);
+                        bus::publish(bus::Kind::Crash, id);
+                        machinelog::record(machine_dir, &format!("kernel crash: {}", line));
+                        last_crash = Some(Instant::now());
+                    }
+                }
+            }
+        }
+        // machine is off or restarting
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+fn is_crash(line: &str) -> bool {
+    MARKERS.iter().any(|m| line.contains(m))
+}
+
+// unix time with milliseconds, kernel lines carry their own uptime stamps
+fn timestamp() -> String {
+    let t = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{}.{:03}", t.as_secs(), t.subsec_millis())
+}
+
+// complete lines out of the console byte stream
+#[derive(Default)]
+struct Lines {
+    partial: Vec<u8>,
+}
+
+impl Lines {
+    fn push(&mut self, data: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for &b in data {
+            match b {
+                b'\n' => {
+                    lines.push(String::from_utf8_lossy(&self.partial).into_owned());
+                    self.partial.clear();
+                }
+                b'\r' => {}
+                _ => self.partial.push(b),
+            }
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lines() {
+        let mut lines = Lines::default();
+        assert!(lines.push(b"[  12.3] Kernel pa").is_empty());
+        let out = lines.push(b"nic - not syncing: Fatal exception\r\n[  12.4] ---[ end\r\n");
+        assert_eq!(
+            out,
+            vec![
+                "[  12.3] Kernel panic - not syncing: Fatal exception",
+                "[  12.4] ---[ end"
+            ]
+        );
+        assert!(is_crash(&out[0]));
+        assert!(!is_crash(&out[1]));
+    }
+}
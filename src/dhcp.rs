@@ -0,0 +1,274 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Where host records for reservations go. bigiron runs dnsmasq itself by
+// default, sites with their own dhcp server get the records pushed to Kea
+// or written to an include file for ISC dhcpd instead.
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tracing::debug;
+use url::Url;
+
+use crate::config::{self, DhcpdConfig, KeaConfig, RegistrarKind};
+use crate::dnsmasq::Dnsmasq;
+use crate::error::Error;
+use crate::network::{self, NetInfo};
+
+pub trait DhcpRegistrar {
+    // add or update the record for a reservation
+    fn add_host(&self, host: &NetInfo) -> Result<(), Error>;
+    fn remove_host(&self, host: &NetInfo) -> Result<(), Error>;
+}
+
+// the registrar selected in the config file
+pub fn registrar() -> Result<Box<dyn DhcpRegistrar>, Error> {
+    let cfg = config::load()?.dhcp;
+    Ok(match cfg.registrar {
+        RegistrarKind::Dnsmasq => Box::new(Dnsmasq::new()),
+        RegistrarKind::Kea => Box::new(Kea::new(
+            cfg.kea
+                .ok_or("dhcp.kea must be set for the kea registrar")?,
+        )?),
+        RegistrarKind::Dhcpd => Box::new(Dhcpd::new(
+            cfg.dhcpd
+                .ok_or("dhcp.dhcpd must be set for the dhcpd registrar")?,
+        )),
+    })
+}
+
+impl DhcpRegistrar for Dnsmasq {
+    fn add_host(&self, host: &NetInfo) -> Result<(), Error> {
+        Dnsmasq::add_host(self, &host.mac, &host.ip, &host.hostname)
+    }
+
+    fn remove_host(&self, host: &NetInfo) -> Result<(), Error> {
+        self.rm_host(&host.hostname)
+    }
+}
+
+// Reservations through the host_cmds hook of a Kea control agent.
+pub struct Kea {
+    url: Url,
+    subnet_id: u32,
+}
+
+impl Kea {
+    pub fn new(cfg: KeaConfig) -> Result<Self, Error> {
+        let url = Url::parse(&cfg.url)?;
+        if url.scheme() != "http" {
+            return Err(format!("kea url must be http, not {}", url.scheme()).into());
+        }
+        Ok(Self {
+            url,
+            subnet_id: cfg.subnet_id,
+        })
+    }
+
+    fn command(&self, command: &Value) -> Result<(), Error> {
+        let host = self.url.host_str().ok_or("kea url has no host")?;
+        let port = self.url.port().unwrap_or(8000);
+        let body = command.to_string();
+
+        let mut stream = TcpStream::connect((host, port))?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.url.path(),
+            host,
+            port,
+            body.len(),
+            body
+        )?;
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf)?;
+        let (head, body) = buf
+            .split_once("\r\n\r\n")
+            .ok_or("malformed response from kea")?;
+        if !head.starts_with("HTTP/1.1 200") && !head.starts_with("HTTP/1.0 200") {
+            let status = head.lines().next().unwrap_or_default();
+            return Err(format!("kea returned {}", status).into());
+        }
+        debug!("kea: {}", body);
+        kea_result(body)
+    }
+}
+
+fn kea_add(subnet_id: u32, host: &NetInfo) -> Value {
+    json!({
+        "command": "reservation-add",
+        "service": ["dhcp4"],
+        "arguments": {
+            "reservation": {
+                "subnet-id": subnet_id,
+                "hw-address": host.mac,
+                "ip-address": host.ip,
+                "hostname": host.hostname,
+            }
+        }
+    })
+}
+
+fn kea_del(subnet_id: u32, host: &NetInfo) -> Value {
+    json!({
+        "command": "reservation-del",
+        "service": ["dhcp4"],
+        "arguments": {
+            "subnet-id": subnet_id,
+            "identifier-type": "hw-address",
+            "identifier": host.mac,
+        }
+    })
+}
+
+// Kea answers with a result per service, 0 for success and 3 when there
+// was nothing to do
+fn kea_result(body: &str) -> Result<(), Error> {
+    let v: Value = serde_json::from_str(body)?;
+    let answers = match v {
+        Value::Array(a) => a,
+        v => vec![v],
+    };
+    for a in answers {
+        match a.get("result").and_then(|r| r.as_i64()) {
+            Some(0) | Some(3) => {}
+            _ => {
+                let text = a.get("text").and_then(|t| t.as_str()).unwrap_or("");
+                return Err(format!("kea command failed: {}", text).into());
+            }
+        }
+    }
+    Ok(())
+}
+
+impl DhcpRegistrar for Kea {
+    fn add_host(&self, host: &NetInfo) -> Result<(), Error> {
+        // reservation-add refuses to replace an existing one
+        self.command(&kea_del(self.subnet_id, host))?;
+        self.command(&kea_add(self.subnet_id, host))
+    }
+
+    fn remove_host(&self, host: &NetInfo) -> Result<(), Error> {
+        self.command(&kea_del(self.subnet_id, host))
+    }
+}
+
+// An include file for ISC dhcpd with a host block per reservation,
+// rewritten from the netstate on every change.
+pub struct Dhcpd {
+    path: PathBuf,
+    reload: Option<Vec<String>>,
+}
+
+impl Dhcpd {
+    pub fn new(cfg: DhcpdConfig) -> Self {
+        Self {
+            path: cfg.include,
+            reload: cfg.reload,
+        }
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        let hosts: Vec<NetInfo> = network::reservations()?
+            .into_iter()
+            .filter(|r| r.is_allocated() && !r.ip.is_empty())
+            .collect();
+        let tmp = self.path.with_extension("part");
+        std::fs::write(&tmp, render_dhcpd(&hosts))?;
+        std::fs::rename(&tmp, &self.path)?;
+
+        if let Some((cmd, args)) = self.reload.as_ref().and_then(|r| r.split_first()) {
+            if !Command::new(cmd).args(args).status()?.success() {
+                return Err(format!("dhcpd reload command {} failed", cmd).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn render_dhcpd(hosts: &[NetInfo]) -> String {
+    let mut buf = String::from("# generated by bigiron, changes are overwritten\n");
+    for h in hosts {
+        buf.push_str(&format!(
+            "host {} {{\n  hardware ethernet {};\n  fixed-address {};\n  option host-name \"{}\";\n}}\n",
+            h.hostname, h.mac, h.ip, h.hostname
+        ));
+    }
+    buf
+}
+
+impl DhcpRegistrar for Dhcpd {
+    fn add_host(&self, _host: &NetInfo) -> Result<(), Error> {
+        self.sync()
+    }
+
+    // the reservation is gone by now, so syncing drops it
+    fn remove_host(&self, _host: &NetInfo) -> Result<(), Error> {
+        self.sync()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn host() -> NetInfo {
+        serde_yaml::from_str(
+            "mac: 52:54:00:00:00:01\nip: 172.20.0.10\nhostname: web1\nallocated: true\nleased: false\n",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_kea() {
+        let add = kea_add(1, &host());
+        assert_eq!(
+            add["arguments"]["reservation"]["hw-address"],
+            "52:54:00:00:00:01"
+        );
+        assert_eq!(add["arguments"]["reservation"]["subnet-id"], 1);
+        assert_eq!(
+            kea_del(1, &host())["arguments"]["identifier"],
+            "52:54:00:00:00:01"
+        );
+
+        assert!(kea_result(r#"[{"result": 0, "text": "Host added."}]"#).is_ok());
+        assert!(kea_result(r#"[{"result": 3, "text": "Host not deleted (not found)."}]"#).is_ok());
+        let err = kea_result(r#"[{"result": 1, "text": "'reservation-add' unsupported"}]"#);
+        assert!(err.unwrap_err().to_string().contains("unsupported"));
+    }
+
+    #[test]
+    fn test_render_dhcpd() {
+        assert_eq!(
+            render_dhcpd(&[host()]),
+            "# generated by bigiron, changes are overwritten\n\
+             host web1 {\n  \
+               hardware ethernet 52:54:00:00:00:01;\n  \
+               fixed-address 172.20.0.10;\n  \
+               option host-name \"web1\";\n\
+             }\n"
+        );
+    }
+}
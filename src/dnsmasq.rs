@@ -15,12 +15,23 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
-use std::io::Read;
+use std::ffi::CString;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::{Duration, Instant};
 
+use ipnet::Ipv4Net;
 use libc;
-use tracing::debug;
+use rand::Rng;
+use tracing::{debug, error, warn};
+
+use crate::config;
+use crate::error::Error;
+use crate::models::{DhcpMode, Dns, Relay};
+use crate::network;
+use crate::store;
 
 pub struct Dnsmasq {
     path: PathBuf,
@@ -28,11 +39,9 @@ pub struct Dnsmasq {
 
 impl Dnsmasq {
     pub fn new() -> Self {
-        let path = Path::new("/var/lib/bigiron/dnsmasq");
+        let path = store::data_dir().join("dnsmasq");
 
-        let s = Self {
-            path: path.to_path_buf(),
-        };
+        let s = Self { path: path.clone() };
 
         if !s.hostsdir().exists() {
             std::fs::create_dir_all(path).expect("error creating dnsmasq state directories");
@@ -53,76 +62,598 @@ impl Dnsmasq {
         self.path.join("dnsmasq.pid")
     }
 
-    pub fn start(&self) {
+    // undionly.kpxe and ipxe.efi are expected here
+    pub fn tftp_root(&self) -> PathBuf {
+        self.path.join("tftp")
+    }
+
+    pub fn confpath(&self) -> PathBuf {
+        self.path.join("conf")
+    }
+
+    pub fn start(&self) -> Result<(), Error> {
+        if let Status {
+            pid: Some(pid),
+            alive: true,
+            ..
+        } = self.status()
+        {
+            warn!("dnsmasq is already running as pid {}", pid);
+            return Ok(());
+        }
+        let conf = match self.render()? {
+            Some(conf) => conf,
+            None => {
+                warn!("network uses an external dhcp relay, not starting dnsmasq");
+                return Ok(());
+            }
+        };
+        std::fs::write(self.confpath(), conf)?;
+        std::fs::create_dir_all(self.hostsdir())?;
+
+        // everything about the network is in the conf file, only our own
+        // paths are passed here
         let mut cmd = Command::new("/usr/sbin/dnsmasq");
-        let confpath = self.path.join("conf");
-
-        cmd.arg("--strict-order");
-        cmd.arg("--bind-interfaces");
-        cmd.arg(format!("--pid-file={}", self.pidfile().to_str().unwrap()));
-        cmd.arg(format!(
-            "--dhcp-hostsdir={}",
-            self.hostsdir().to_str().unwrap()
-        ));
-        //cmd.arg(format!("--dhcp-leasefile={}", self.leasefile().to_str().unwrap()));
-        cmd.arg(format!("--conf-file={}", confpath.to_str().unwrap()));
-        cmd.arg("--dhcp-range=set:mgmt,172.20.0.2,static,255.255.255.0,30m");
-        //cmd.arg("--dhcp-range=set:mgmt,172.20.0.2,172.20.0.254,255.255.255.0,30m");
-        cmd.arg("--interface=br0");
-        cmd.arg("--except-interface=lo");
-        //cmd.arg("--listen-address=172.20.0.1");
-        cmd.arg("--domain=cloud.local");
-        cmd.arg("--dhcp-authoritative");
-        cmd.arg("--dhcp-option=3");
-        cmd.arg("--port=0");
+        cmd.arg(format!("--conf-file={}", self.confpath().display()));
+        cmd.arg(format!("--pid-file={}", self.pidfile().display()));
+        cmd.arg(format!("--dhcp-hostsdir={}", self.hostsdir().display()));
         cmd.arg("--dhcp-script=/usr/local/sbin/bigiron-dhcpbridge");
         cmd.arg("--leasefile-ro");
 
-        std::fs::write(&confpath, b"").unwrap();
-        std::fs::create_dir_all(&self.hostsdir()).expect("error creating hostsdir");
-
         debug!("Running: {:?}", cmd);
 
-        let _ = cmd.spawn();
+        cmd.spawn()?;
+        Ok(())
+    }
+
+    // Start unless another dhcp server already answers on the management
+    // bridge, guests would get offers from both. With `force` that is only
+    // a warning.
+    pub fn start_exclusive(&self, force: bool) -> Result<(), Error> {
+        let status = self.status();
+        if !status.alive {
+            let mut others = Vec::new();
+            if status.listening {
+                others.push(format!("a local process on udp port {}", DHCP_PORT));
+            }
+            match probe_servers(network::MANAGEMENT_BRIDGE, PROBE_WAIT) {
+                Ok(servers) => others.extend(servers.iter().map(|s| s.to_string())),
+                Err(e) => warn!("could not probe for other dhcp servers: {}", e),
+            }
+            if !others.is_empty() {
+                let msg = format!(
+                    "another dhcp server is active on {}: {}",
+                    network::MANAGEMENT_BRIDGE,
+                    others.join(", ")
+                );
+                if !force {
+                    return Err(format!("{}, use --force to start anyway", msg).into());
+                }
+                warn!("{}", msg);
+            }
+        }
+        self.start()
     }
 
-    pub fn stop(&self) {
-        self.send_signal(libc::SIGTERM);
+    // Rewrite the conf file after a network change, restarting dnsmasq if it
+    // is running since it only reads the file on start.
+    pub fn reconfigure(&self) -> Result<(), Error> {
+        let conf = self.render()?;
+        let current = std::fs::read_to_string(self.confpath()).ok();
+        if conf.is_some() && conf == current {
+            return Ok(());
+        }
+        if self.status().alive {
+            self.restart()?;
+        } else if let Some(conf) = conf {
+            std::fs::write(self.confpath(), conf)?;
+        }
+        Ok(())
     }
 
-    fn send_signal(&self, signal: i32) {
-        if self.pidfile().exists() {
-            let mut buf = String::new();
-            let mut f =
-                std::fs::File::open(&self.pidfile()).expect("error opening dnsmasq pidfile");
-            f.read_to_string(&mut buf).unwrap();
-            let pid = buf.trim().parse::<i32>().unwrap();
+    // the conf file for the current netstate and config, None when
+    // dhcp is left to an external relay agent
+    fn render(&self) -> Result<Option<String>, Error> {
+        let (mode, relay) = network::dhcp_mode()?;
+        if mode == DhcpMode::Relay
+            && relay
+                .as_ref()
+                .and_then(|r| r.local_address.as_ref())
+                .is_none()
+        {
+            return Ok(None);
+        }
+        let net: Ipv4Net = network::summary()?.cidr.parse()?;
+        let (dns, ntp) = network::guest_settings()?;
+        let config = config::load()?;
 
-            let r = unsafe { libc::kill(pid, signal) };
-            if r != 0 {
-                panic!("failed to send signal to dnsmasq daemon");
+        let netboot = match self.netboot_url() {
+            Ok(Some(url)) => {
+                std::fs::create_dir_all(self.tftp_root())?;
+                for f in [BIOS_LOADER, EFI_LOADER] {
+                    if !self.tftp_root().join(f).exists() {
+                        warn!("{} is missing from {}", f, self.tftp_root().display());
+                    }
+                }
+                Some((self.tftp_root(), url))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                warn!("error reading netboot config: {}", e);
+                None
             }
-        } else {
-            panic!("no pid file found");
+        };
+
+        Ok(Some(render_conf(&Conf {
+            interface: network::MANAGEMENT_BRIDGE,
+            domain: config.network.domain.as_deref().unwrap_or(DEFAULT_DOMAIN),
+            net,
+            relay: relay.as_ref().filter(|_| mode == DhcpMode::Relay),
+            dns: dns.as_ref(),
+            ntp: ntp.as_deref(),
+            netboot: netboot
+                .as_ref()
+                .map(|(root, url)| (root.as_path(), url.as_str())),
+        })))
+    }
+
+    // boot server url for iPXE, None unless netboot is enabled
+    fn netboot_url(&self) -> Result<Option<String>, Error> {
+        let conf = config::load()?.netboot;
+        if !conf.enabled {
+            return Ok(None);
+        }
+        match conf.url {
+            Some(url) => Ok(Some(url)),
+            None => match network::summary()?.gateway {
+                Some(gw) => Ok(Some(format!("http://{}:8080", gw))),
+                None => Err("no gateway address to serve netboot from, set netboot.url".into()),
+            },
         }
     }
 
-    pub fn add_host(&self, mac: &str, ip: &str, hostname: &str) {
+    fn pid(&self) -> Option<i32> {
+        std::fs::read_to_string(self.pidfile())
+            .ok()
+            .and_then(|p| p.trim().parse::<i32>().ok())
+    }
+
+    pub fn status(&self) -> Status {
+        let pid = self.pid();
+        Status {
+            pid,
+            alive: pid.is_some_and(|pid| unsafe { libc::kill(pid, 0) } == 0),
+            listening: std::fs::read_to_string("/proc/net/udp")
+                .map(|buf| udp_listening(&buf, DHCP_PORT))
+                .unwrap_or(false),
+        }
+    }
+
+    // Stop dnsmasq and wait for it to exit. Not running, or a pidfile left
+    // behind by a crash, is not an error.
+    pub fn stop(&self) -> Result<(), Error> {
+        let status = self.status();
+        let pid = match status.pid {
+            Some(pid) if status.alive => pid,
+            Some(_) => {
+                warn!("removing stale dnsmasq pidfile");
+                std::fs::remove_file(self.pidfile())?;
+                return Ok(());
+            }
+            None => {
+                warn!("dnsmasq is not running");
+                return Ok(());
+            }
+        };
+
+        if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+            return Err(format!("failed to signal dnsmasq pid {}", pid).into());
+        }
+        for _ in 0..50 {
+            if unsafe { libc::kill(pid, 0) } != 0 {
+                let _ = std::fs::remove_file(self.pidfile());
+                return Ok(());
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        Err(format!("dnsmasq pid {} did not exit", pid).into())
+    }
+
+    pub fn restart(&self) -> Result<(), Error> {
+        self.stop()?;
+        self.start()
+    }
+
+    // Host records only take effect with dnsmasq running, so bring it back
+    // if it died. Relay networks without a local relay never run it.
+    fn ensure_running(&self) {
+        if self.status().alive {
+            return;
+        }
+        match self.render() {
+            Ok(Some(_)) => {
+                warn!("dnsmasq is not running, starting it");
+                if let Err(e) = self.start() {
+                    error!("error starting dnsmasq: {}", e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => error!("error checking dnsmasq config: {}", e),
+        }
+    }
+
+    pub fn add_host(&self, mac: &str, ip: &str, hostname: &str) -> Result<(), Error> {
         // <macaddr>,<ipaddr>,<hostname>,<leasetime>
 
         let leasetime = 1 * 60 * 60;
 
         let buf = format!("{},{},{},{}", mac, ip, hostname, leasetime);
         let fp = self.hostsdir().join(hostname);
-        std::fs::write(&fp, &buf).expect("error creating host record file");
+        std::fs::write(&fp, &buf)?;
+        // picked up from the hostsdir without a signal, if it is running
+        self.ensure_running();
+        Ok(())
+    }
+
+    // hostnames with a host record
+    pub fn host_records(&self) -> Result<Vec<String>, Error> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(self.hostsdir())? {
+            names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    // Remove the host records of every hostname `keep` rejects, without
+    // signalling dnsmasq, for use while it is stopped.
+    pub fn purge_hosts<F: Fn(&str) -> bool>(&self, keep: F) -> Result<Vec<String>, Error> {
+        let mut purged = self.host_records()?;
+        purged.retain(|h| !keep(h));
+        for h in &purged {
+            std::fs::remove_file(self.hostsdir().join(h))?;
+        }
+        Ok(purged)
     }
 
-    pub fn rm_host(&self, hostname: &str) {
+    pub fn rm_host(&self, hostname: &str) -> Result<(), Error> {
         let fp = self.hostsdir().join(hostname);
         if fp.exists() {
-            std::fs::remove_file(&fp).expect("error removing host record file");
+            std::fs::remove_file(&fp)?;
             // dnsmasq needs notification to re-read hostsdir when removing files
-            self.send_signal(libc::SIGHUP);
+            match self.status() {
+                Status {
+                    pid: Some(pid),
+                    alive: true,
+                    ..
+                } => {
+                    unsafe { libc::kill(pid, libc::SIGHUP) };
+                }
+                _ => self.ensure_running(),
+            }
+        }
+        Ok(())
+    }
+}
+
+const DHCP_PORT: u16 = 67;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub pid: Option<i32>,
+    // the pid from the pidfile is a live process
+    pub alive: bool,
+    // something is bound to the dhcp port
+    pub listening: bool,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.pid, self.alive) {
+            (Some(pid), true) => write!(f, "running, pid {}", pid)?,
+            (Some(pid), false) => write!(f, "not running, stale pidfile for pid {}", pid)?,
+            (None, _) => write!(f, "not running")?,
+        }
+        match self.listening {
+            true => write!(f, ", listening on udp port {}", DHCP_PORT),
+            false => write!(f, ", nothing listening on udp port {}", DHCP_PORT),
+        }
+    }
+}
+
+// whether /proc/net/udp has a socket bound to a local port
+fn udp_listening(buf: &str, port: u16) -> bool {
+    let port = format!("{:04X}", port);
+    buf.lines().skip(1).any(|l| {
+        l.split_whitespace()
+            .nth(1)
+            .and_then(|local| local.split(':').nth(1))
+            .is_some_and(|p| p == port)
+    })
+}
+
+const PROBE_WAIT: Duration = Duration::from_secs(3);
+
+// Find other dhcp servers on `interface` by broadcasting a discover and
+// collecting who makes an offer. Needs udp port 68, so it fails where a
+// dhcp client runs on the host.
+pub fn probe_servers(interface: &str, wait: Duration) -> Result<Vec<Ipv4Addr>, Error> {
+    let sock = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 68))?;
+    sock.set_broadcast(true)?;
+    let dev = CString::new(interface)?;
+    let r = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            dev.as_ptr() as *const libc::c_void,
+            dev.as_bytes_with_nul().len() as libc::socklen_t,
+        )
+    };
+    if r != 0 {
+        return Err(format!(
+            "failed to bind to {}: {}",
+            interface,
+            std::io::Error::last_os_error()
+        )
+        .into());
+    }
+
+    let mut rng = rand::thread_rng();
+    let xid: u32 = rng.gen();
+    // locally administered, so it can't be anybody's reservation
+    let mut mac = [0u8; 6];
+    rng.fill(&mut mac);
+    mac[0] = (mac[0] | 0x02) & 0xfe;
+    sock.send_to(
+        &discover_packet(xid, &mac),
+        (Ipv4Addr::BROADCAST, DHCP_PORT),
+    )?;
+
+    let mut servers = Vec::new();
+    let mut buf = [0u8; 1500];
+    let deadline = Instant::now() + wait;
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        if left.is_zero() {
+            break;
+        }
+        sock.set_read_timeout(Some(left))?;
+        match sock.recv_from(&mut buf) {
+            Ok((n, _)) => {
+                if let Some(server) = parse_offer(&buf[..n], xid) {
+                    if !servers.contains(&server) {
+                        servers.push(server);
+                    }
+                }
+            }
+            Err(e)
+                if e.kind() == std::io::ErrorKind::WouldBlock
+                    || e.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                break
+            }
+            Err(e) => return Err(e.into()),
         }
     }
+    Ok(servers)
+}
+
+const DHCP_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+// a DHCPDISCOVER asking for a broadcast reply
+fn discover_packet(xid: u32, mac: &[u8; 6]) -> Vec<u8> {
+    let mut p = vec![0u8; 236];
+    p[0] = 1; // BOOTREQUEST
+    p[1] = 1; // ethernet
+    p[2] = 6;
+    p[4..8].copy_from_slice(&xid.to_be_bytes());
+    p[10] = 0x80;
+    p[28..34].copy_from_slice(mac);
+    p.extend_from_slice(&DHCP_COOKIE);
+    // message type discover, end
+    p.extend_from_slice(&[53, 1, 1, 255]);
+    p
+}
+
+// the server identifier of a DHCPOFFER for our discover
+fn parse_offer(p: &[u8], xid: u32) -> Option<Ipv4Addr> {
+    if p.len() < 240 || p[0] != 2 || p[4..8] != xid.to_be_bytes() || p[236..240] != DHCP_COOKIE {
+        return None;
+    }
+    let mut offer = false;
+    let mut server = None;
+    let mut opts = &p[240..];
+    while let Some((&code, rest)) = opts.split_first() {
+        match code {
+            0 => opts = rest,
+            255 => break,
+            _ => {
+                let (&len, rest) = rest.split_first()?;
+                let val = rest.get(..len as usize)?;
+                match (code, val) {
+                    (53, [2]) => offer = true,
+                    (54, [a, b, c, d]) => server = Some(Ipv4Addr::new(*a, *b, *c, *d)),
+                    _ => {}
+                }
+                opts = &rest[len as usize..];
+            }
+        }
+    }
+    // siaddr for servers that leave out the identifier
+    offer.then(|| server.unwrap_or(Ipv4Addr::new(p[20], p[21], p[22], p[23])))
+}
+
+const BIOS_LOADER: &str = "undionly.kpxe";
+const EFI_LOADER: &str = "ipxe.efi";
+
+const DEFAULT_DOMAIN: &str = "cloud.local";
+
+// what the conf file is rendered from
+pub struct Conf<'a> {
+    pub interface: &'a str,
+    pub domain: &'a str,
+    pub net: Ipv4Net,
+    // forward to this server instead of serving leases ourselves
+    pub relay: Option<&'a Relay>,
+    pub dns: Option<&'a Dns>,
+    pub ntp: Option<&'a [String]>,
+    // tftp root and boot server url
+    pub netboot: Option<(&'a Path, &'a str)>,
+}
+
+pub fn render_conf(conf: &Conf) -> String {
+    let mut lines = vec![
+        "# generated by bigiron, changes are overwritten".to_string(),
+        "strict-order".to_string(),
+        "bind-interfaces".to_string(),
+        format!("interface={}", conf.interface),
+        "except-interface=lo".to_string(),
+        format!("domain={}", conf.domain),
+        // no dns, only dhcp
+        "port=0".to_string(),
+    ];
+    match conf.relay {
+        Some(relay) => {
+            // the upstream server is authoritative, we only forward
+            lines.push(format!(
+                "dhcp-relay={},{}",
+                relay.local_address.as_deref().unwrap_or_default(),
+                relay.server
+            ));
+        }
+        None => {
+            // addresses only come from the host records bigiron writes
+            lines.push(format!(
+                "dhcp-range=set:mgmt,{},static,{},30m",
+                conf.net.network(),
+                conf.net.netmask()
+            ));
+            lines.push("dhcp-authoritative".to_string());
+            lines.push("dhcp-option=3".to_string());
+            lines.extend(dhcp_options(conf.dns, conf.ntp));
+            if let Some((root, url)) = conf.netboot {
+                lines.extend(netboot_options(root, url));
+            }
+        }
+    }
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+// Chainload iPXE over tftp, then have iPXE, which identifies itself with
+// option 175, fetch its script from the boot server.
+fn netboot_options(tftp_root: &Path, url: &str) -> Vec<String> {
+    vec![
+        "enable-tftp".to_string(),
+        format!("tftp-root={}", tftp_root.display()),
+        "dhcp-match=set:ipxe,175".to_string(),
+        "dhcp-match=set:efi64,option:client-arch,7".to_string(),
+        "dhcp-match=set:efi64,option:client-arch,9".to_string(),
+        format!("dhcp-boot=tag:ipxe,{}/boot.ipxe", url.trim_end_matches('/')),
+        format!("dhcp-boot=tag:efi64,tag:!ipxe,{}", EFI_LOADER),
+        format!("dhcp-boot=tag:!efi64,tag:!ipxe,{}", BIOS_LOADER),
+    ]
+}
+
+// options pushing dns and ntp servers and search domains to guests
+fn dhcp_options(dns: Option<&Dns>, ntp: Option<&[String]>) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(dns) = dns {
+        if !dns.servers.is_empty() {
+            args.push(format!(
+                "dhcp-option=option:dns-server,{}",
+                dns.servers.join(",")
+            ));
+        }
+        if !dns.search.is_empty() {
+            args.push(format!(
+                "dhcp-option=option:domain-search,{}",
+                dns.search.join(",")
+            ));
+        }
+    }
+    if let Some(ntp) = ntp.filter(|n| !n.is_empty()) {
+        args.push(format!("dhcp-option=option:ntp-server,{}", ntp.join(",")));
+    }
+    args
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_conf() {
+        let mut conf = Conf {
+            interface: "br0",
+            domain: "cloud.local",
+            net: "10.1.0.0/16".parse().unwrap(),
+            relay: None,
+            dns: None,
+            ntp: None,
+            netboot: Some((
+                Path::new("/var/lib/bigiron/dnsmasq/tftp"),
+                "http://10.1.0.1:8080/",
+            )),
+        };
+        let out = render_conf(&conf);
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines.contains(&"interface=br0"));
+        assert!(lines.contains(&"dhcp-range=set:mgmt,10.1.0.0,static,255.255.0.0,30m"));
+        assert!(lines.contains(&"tftp-root=/var/lib/bigiron/dnsmasq/tftp"));
+        assert!(lines.contains(&"dhcp-boot=tag:ipxe,http://10.1.0.1:8080/boot.ipxe"));
+        assert!(lines.contains(&"dhcp-boot=tag:!efi64,tag:!ipxe,undionly.kpxe"));
+
+        let relay = Relay {
+            server: "10.0.0.5".into(),
+            local_address: Some("10.1.0.1".into()),
+        };
+        conf.relay = Some(&relay);
+        let out = render_conf(&conf);
+        assert!(out.lines().any(|l| l == "dhcp-relay=10.1.0.1,10.0.0.5"));
+        assert!(!out.contains("dhcp-range") && !out.contains("tftp"));
+    }
+
+    #[test]
+    fn test_udp_listening() {
+        let buf = "   sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops\n\
+                   \x20 12: 00000000:0043 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 31337 2 0000000000000000 0\n\
+                   \x20 40: 0100007F:0035 00000000:0000 07 00000000:00000000 00:00000000 00000000   101        0 20871 2 0000000000000000 0\n";
+        assert!(udp_listening(buf, 67));
+        assert!(udp_listening(buf, 53));
+        assert!(!udp_listening(buf, 69));
+    }
+
+    #[test]
+    fn test_parse_offer() {
+        let mac = [0x02, 0, 0, 0, 0, 1];
+        let mut p = discover_packet(42, &mac);
+        assert_eq!(p.len(), 244);
+        assert_eq!(parse_offer(&p, 42), None);
+
+        // turn it into an offer from 10.0.0.2 the way a server would
+        p[0] = 2;
+        p.truncate(240);
+        p.extend_from_slice(&[53, 1, 2, 0, 54, 4, 10, 0, 0, 2, 255]);
+        assert_eq!(parse_offer(&p, 42), Some(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(parse_offer(&p, 43), None);
+    }
+
+    #[test]
+    fn test_dhcp_options() {
+        assert!(dhcp_options(None, None).is_empty());
+
+        let dns = Dns {
+            servers: vec!["10.0.0.53".into(), "10.0.0.54".into()],
+            search: vec!["lab.example.com".into()],
+        };
+        assert_eq!(
+            dhcp_options(Some(&dns), Some(&["10.0.0.123".to_string()])),
+            vec![
+                "dhcp-option=option:dns-server,10.0.0.53,10.0.0.54",
+                "dhcp-option=option:domain-search,lab.example.com",
+                "dhcp-option=option:ntp-server,10.0.0.123",
+            ]
+        );
+    }
 }
@@ -16,3 +16,86 @@
 //  USA
 
 pub type Error = Box<dyn std::error::Error>;
+
+// Kinds of failure scripts driving the cli want to tell apart, each exits
+// with its own code. Anything else exits 1, and clap exits 2 on bad usage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    // 3, a checked service or job isn't running
+    NotRunning,
+    // 4, the machine or other resource asked for doesn't exist
+    NotFound,
+    // 5, libvirt or another service bigiron drives can't be reached
+    Unavailable,
+    // 6, a freeze blocks the change
+    Frozen,
+    // 7, the network is out of addresses or one is already taken
+    Network,
+}
+
+impl Category {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Category::NotRunning => 3,
+            Category::NotFound => 4,
+            Category::Unavailable => 5,
+            Category::Frozen => 6,
+            Category::Network => 7,
+        }
+    }
+
+    pub fn of(err: &Error) -> Option<Category> {
+        if let Some(e) = err.downcast_ref::<Categorized>() {
+            return Some(e.category);
+        }
+        if err.is::<crate::network::NetworkError>() {
+            return Some(Category::Network);
+        }
+        None
+    }
+}
+
+// exit code for an error returned to main
+pub fn exit_code(err: &Error) -> i32 {
+    Category::of(err).map_or(1, |c| c.exit_code())
+}
+
+#[derive(Debug)]
+pub struct Categorized {
+    pub category: Category,
+    pub message: String,
+}
+
+impl std::fmt::Display for Categorized {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Categorized {}
+
+pub fn categorized<S: Into<String>>(category: Category, message: S) -> Error {
+    Box::new(Categorized {
+        category,
+        message: message.into(),
+    })
+}
+
+pub fn not_found<S: Into<String>>(message: S) -> Error {
+    categorized(Category::NotFound, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::network::NetworkError;
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(&"plain".into()), 1);
+        assert_eq!(exit_code(&not_found("no machine web1")), 4);
+        let e: Error = Box::new(NetworkError::NoState);
+        assert_eq!(exit_code(&e), 7);
+        assert_eq!(not_found("no machine web1").to_string(), "no machine web1");
+    }
+}
@@ -0,0 +1,247 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::audit;
+use crate::error::{self, Category, Error};
+use crate::lockfile::LockFile;
+use crate::store;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Freeze {
+    // unix timestamp after which the freeze lapses, None freezes indefinitely
+    pub until: Option<u64>,
+    pub reason: Option<String>,
+}
+
+impl Freeze {
+    fn active(&self, now: u64) -> bool {
+        match self.until {
+            Some(until) => now < until,
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct FreezeState {
+    host: Option<Freeze>,
+    projects: BTreeMap<String, Freeze>,
+}
+
+impl FreezeState {
+    fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        if !path.as_ref().exists() {
+            return Ok(Self::default());
+        }
+        let buf = std::fs::read_to_string(path.as_ref())?;
+        Ok(serde_yaml::from_str(&buf)?)
+    }
+
+    fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let buf = serde_yaml::to_string(self)?;
+        std::fs::write(path.as_ref(), buf.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn state_path() -> PathBuf {
+    store::data_dir().join("freeze.yaml")
+}
+
+fn lock_path() -> PathBuf {
+    store::data_dir().join("freeze.lock")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Parse an --until value, either a relative duration (30m, 2h, 7d) or an
+// absolute unix timestamp, into a unix timestamp.
+pub fn parse_until(s: &str) -> Result<u64, Error> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err("empty freeze expiry".into());
+    }
+
+    match s.ends_with(|c: char| c.is_ascii_digit()) {
+        true => Ok(s.parse::<u64>()?),
+        false => Ok(now() + parse_duration(s)?),
+    }
+}
+
+// parse a duration such as 90s, 30m, 2h or 7d into seconds, plain numbers
+// are seconds
+pub fn parse_duration(s: &str) -> Result<u64, Error> {
+    let s = s.trim();
+    let (num, mult) = match s.chars().last() {
+        Some('s') => (&s[..s.len() - 1], 1),
+        Some('m') => (&s[..s.len() - 1], 60),
+        Some('h') => (&s[..s.len() - 1], 60 * 60),
+        Some('d') => (&s[..s.len() - 1], 24 * 60 * 60),
+        Some(_) => (s, 1),
+        None => return Err("empty duration".into()),
+    };
+    num.parse::<u64>()
+        .map(|n| n * mult)
+        .map_err(|_| format!("invalid duration '{}'", s).into())
+}
+
+pub fn freeze(
+    project: Option<&str>,
+    until: Option<u64>,
+    reason: Option<String>,
+) -> Result<(), Error> {
+    let lf = LockFile::new(lock_path());
+    let _lock = lf.acquire();
+
+    let mut state = FreezeState::load(state_path())?;
+    let f = Freeze { until, reason };
+    audit::record("freeze", &format!("scope={} {:?}", scope_name(project), f));
+    match project {
+        Some(p) => {
+            state.projects.insert(p.to_string(), f);
+        }
+        None => state.host = Some(f),
+    }
+    state.save(state_path())
+}
+
+pub fn unfreeze(project: Option<&str>) -> Result<(), Error> {
+    let lf = LockFile::new(lock_path());
+    let _lock = lf.acquire();
+
+    let mut state = FreezeState::load(state_path())?;
+    let removed = match project {
+        Some(p) => state.projects.remove(p).is_some(),
+        None => state.host.take().is_some(),
+    };
+    if !removed {
+        return Err(format!("No freeze set for {}", scope_name(project)).into());
+    }
+    audit::record("unfreeze", &format!("scope={}", scope_name(project)));
+    state.save(state_path())
+}
+
+// currently active freezes as (scope, freeze) pairs, host scope first
+pub fn list() -> Result<Vec<(String, Freeze)>, Error> {
+    let state = FreezeState::load(state_path())?;
+    let now = now();
+
+    let mut r = Vec::new();
+    if let Some(f) = state.host.filter(|f| f.active(now)) {
+        r.push((scope_name(None), f));
+    }
+    for (p, f) in state.projects {
+        if f.active(now) {
+            r.push((scope_name(Some(&p)), f));
+        }
+    }
+    Ok(r)
+}
+
+// Fail a mutating operation if the host or the machine's project is frozen.
+// An override lets the operation through but leaves a record of it.
+pub fn check(action: &str, project: Option<&str>, override_freeze: bool) -> Result<(), Error> {
+    let state = FreezeState::load(state_path())?;
+    let now = now();
+
+    let mut frozen = Vec::new();
+    if let Some(f) = state.host.as_ref().filter(|f| f.active(now)) {
+        frozen.push((None, f));
+    }
+    if let Some(p) = project {
+        if let Some(f) = state.projects.get(p).filter(|f| f.active(now)) {
+            frozen.push((project, f));
+        }
+    }
+
+    for (scope, f) in frozen {
+        if override_freeze {
+            warn!("overriding freeze on {} for {}", scope_name(scope), action);
+            audit::record(
+                "override-freeze",
+                &format!("scope={} operation={}", scope_name(scope), action),
+            );
+            continue;
+        }
+
+        let mut msg = format!("{} is frozen", scope_name(scope));
+        if let Some(until) = f.until {
+            msg.push_str(&format!(" until {}", until));
+        }
+        if let Some(reason) = &f.reason {
+            msg.push_str(&format!(" ({})", reason));
+        }
+        msg.push_str(&format!(", refusing to {} without an override", action));
+        return Err(error::categorized(Category::Frozen, msg));
+    }
+
+    Ok(())
+}
+
+fn scope_name(project: Option<&str>) -> String {
+    match project {
+        Some(p) => format!("project '{}'", p),
+        None => "host".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_until() {
+        assert_eq!(parse_until("1700000000").unwrap(), 1700000000);
+
+        let t = parse_until("2h").unwrap();
+        assert!(t >= now() + 2 * 60 * 60 - 1 && t <= now() + 2 * 60 * 60);
+
+        assert!(parse_until("").is_err());
+        assert!(parse_until("soon").is_err());
+        assert_eq!(parse_duration("90s").unwrap(), 90);
+        assert_eq!(parse_duration("10m").unwrap(), 600);
+        assert_eq!(parse_duration("45").unwrap(), 45);
+    }
+
+    #[test]
+    fn test_freeze_active() {
+        let f = Freeze {
+            until: Some(100),
+            reason: None,
+        };
+        assert!(f.active(99));
+        assert!(!f.active(100));
+
+        let f = Freeze {
+            until: None,
+            reason: None,
+        };
+        assert!(f.active(u64::MAX));
+    }
+}
@@ -0,0 +1,215 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Admin hook scripts run on machine lifecycle transitions, e.g. to update
+// a firewall, register dns names or send notifications. The executables in
+// <hooks dir>/<hook>.d, /etc/bigiron/hooks/post-create.d and so on, run in
+// name order with the machine in BIGIRON_* variables. Hooks run after the
+// fact and can't stop anything, failures are only logged. Each gets
+// TIMEOUT before it is killed.
+
+use std::collections::BTreeMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use tracing::{debug, error, warn};
+
+use crate::config;
+use crate::error::Error;
+use crate::models::Machine;
+use crate::network;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    PostCreate,
+    PostStart,
+    PostStop,
+    PostDelete,
+}
+
+impl Hook {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Hook::PostCreate => "post-create",
+            Hook::PostStart => "post-start",
+            Hook::PostStop => "post-stop",
+            Hook::PostDelete => "post-delete",
+        }
+    }
+}
+
+// what hooks are told about a machine
+pub fn env(machine: &Machine) -> BTreeMap<String, String> {
+    let mut env = BTreeMap::new();
+    env.insert("BIGIRON_MACHINE".to_string(), machine.name.clone());
+    if let Some(p) = &machine.project {
+        env.insert("BIGIRON_PROJECT".to_string(), p.clone());
+    }
+    env.insert("BIGIRON_CPU".to_string(), machine.spec.cpu.to_string());
+    env.insert(
+        "BIGIRON_MEMORY".to_string(),
+        machine.spec.memory.bytes().to_string(),
+    );
+    // labels as BIGIRON_LABEL_<KEY>, with bigiron/template as
+    // BIGIRON_LABEL_BIGIRON_TEMPLATE
+    for (k, v) in &machine.labels {
+        let key: String = k
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() {
+                true => c.to_ascii_uppercase(),
+                false => '_',
+            })
+            .collect();
+        env.insert(format!("BIGIRON_LABEL_{}", key), v.clone());
+    }
+    match network::reservation(&machine.name) {
+        Ok(Some(r)) => {
+            if !r.ip.is_empty() {
+                env.insert("BIGIRON_IP".to_string(), r.ip);
+            }
+            env.insert("BIGIRON_MAC".to_string(), r.mac);
+        }
+        Ok(None) => {}
+        Err(e) => warn!("no address for hooks of {}: {}", machine.name, e),
+    }
+    env
+}
+
+pub fn run(hook: Hook, machine: &Machine) {
+    run_env(hook, &env(machine));
+}
+
+// Run the hooks with an environment gathered earlier, for post-delete,
+// when the machine's reservation is already gone.
+pub fn run_env(hook: Hook, env: &BTreeMap<String, String>) {
+    let dir = match config::load() {
+        Ok(c) => c.hooks_dir().join(format!("{}.d", hook.as_str())),
+        Err(e) => {
+            error!("not running {} hooks: {}", hook.as_str(), e);
+            return;
+        }
+    };
+    run_dir(&dir, hook, env);
+}
+
+fn run_dir(dir: &Path, hook: Hook, env: &BTreeMap<String, String>) {
+    let scripts = match scripts(dir) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("error listing {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    for script in scripts {
+        debug!("running {} hook {}", hook.as_str(), script.display());
+        if let Err(e) = run_script(&script, hook, env) {
+            error!("{} hook {} failed: {}", hook.as_str(), script.display(), e);
+        }
+    }
+}
+
+fn run_script(script: &Path, hook: Hook, env: &BTreeMap<String, String>) -> Result<(), Error> {
+    let mut child = Command::new(script)
+        .env("BIGIRON_HOOK", hook.as_str())
+        .envs(env)
+        .stdin(Stdio::null())
+        // stdout may carry output meant for a program, e.g. apply --report -
+        .stdout(std::io::stderr())
+        .spawn()?;
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return match status.success() {
+                true => Ok(()),
+                false => Err(format!("exited with {}", status).into()),
+            };
+        }
+        if start.elapsed() > TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("killed after {}s", TIMEOUT.as_secs()).into());
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// executables in dir by name, skipping hidden files and editor backups
+fn scripts(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let entries = match dir.read_dir() {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut scripts = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if name.starts_with('.') || name.ends_with('~') {
+            continue;
+        }
+        let meta = std::fs::metadata(&path)?;
+        if meta.is_file() && meta.permissions().mode() & 0o111 != 0 {
+            scripts.push(path);
+        }
+    }
+    scripts.sort();
+    Ok(scripts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_run_dir() {
+        let dir = TestDir::new("hooks");
+        let out = dir.join("out");
+        let script = |name: &str, body: &str, mode: u32| {
+            let path = dir.join(name);
+            std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        };
+        let log = format!(">> {}", out.display());
+        script(
+            "10-name",
+            &format!("echo $BIGIRON_HOOK $BIGIRON_MACHINE {}", log),
+            0o755,
+        );
+        script("20-fails", "exit 3", 0o755);
+        script(
+            "30-label",
+            &format!("echo $BIGIRON_LABEL_TIER {}", log),
+            0o755,
+        );
+        script("40-not-executable", &format!("echo no {}", log), 0o644);
+        script("50-backup~", &format!("echo no {}", log), 0o755);
+
+        let mut env = BTreeMap::new();
+        env.insert("BIGIRON_MACHINE".to_string(), "web-1".to_string());
+        env.insert("BIGIRON_LABEL_TIER".to_string(), "frontend".to_string());
+        run_dir(&dir, Hook::PostCreate, &env);
+        assert_eq!(
+            std::fs::read_to_string(&out).unwrap(),
+            "post-create web-1\nfrontend\n"
+        );
+    }
+}
@@ -15,18 +15,43 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
+use std::collections::BTreeMap;
+use std::path::Path;
+
 use sysinfo::{System, SystemExt};
 
+use crate::models;
+
+pub mod cgroup;
+pub mod nat;
+pub mod net;
+pub mod ovs;
+pub mod pci;
+pub mod vlan;
 
 type Error = Box<dyn std::error::Error>;
 
+const SYS_NODE: &str = "/sys/devices/system/node";
+const SYS_HUGEPAGES: &str = "/sys/kernel/mm/hugepages";
+const SYS_MODULE: &str = "/sys/module";
+
+// numa nodes and hugepage pools of the host
+#[derive(Debug, Clone, Default)]
+pub struct Topology {
+    // host cpus of each numa node
+    pub nodes: BTreeMap<u32, Vec<u32>>,
+    // free hugepages by page size in bytes, host wide
+    pub hugepages: BTreeMap<u64, u64>,
+    // free hugepages by node and page size in bytes
+    pub node_hugepages: BTreeMap<u32, BTreeMap<u64, u64>>,
+}
+
 pub struct HostAgent {
-    sys: System
+    sys: System,
 }
 
 pub struct Job {}
 
-
 impl HostAgent {
     pub fn new() -> Self {
         Self {
@@ -38,12 +63,265 @@ impl HostAgent {
         vec![]
     }
 
-    pub fn get_usage(&self) -> Result<String, Error>{
+    pub fn get_usage(&self) -> Result<String, Error> {
         Ok("".to_string())
     }
 
+    pub fn topology(&self) -> Result<Topology, Error> {
+        let mut topo = Topology {
+            hugepages: read_hugepages(Path::new(SYS_HUGEPAGES))?,
+            ..Default::default()
+        };
+
+        // hosts without numa support still expose everything as node0
+        let nodes = match std::fs::read_dir(SYS_NODE) {
+            Ok(d) => d,
+            Err(_) => return Ok(topo),
+        };
+        for entry in nodes {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let id = match name.strip_prefix("node").map(|n| n.parse::<u32>()) {
+                Some(Ok(id)) => id,
+                _ => continue,
+            };
+            let cpulist = std::fs::read_to_string(entry.path().join("cpulist"))?;
+            topo.nodes.insert(id, parse_cpulist(&cpulist)?);
+            topo.node_hugepages
+                .insert(id, read_hugepages(&entry.path().join("hugepages"))?);
+        }
+        Ok(topo)
+    }
+
+    // cpu count and memory in bytes
+    pub fn capacity(&mut self) -> (u32, u64) {
+        self.sys.refresh_all();
+        (self.sys.cpus().len() as u32, self.sys.total_memory())
+    }
+
     pub fn report(&mut self) -> String {
         self.sys.refresh_all();
-        format!("Capacity: cpu={} memory_kb={}", self.sys.cpus().len(), self.sys.total_memory())
+        format!(
+            "Capacity: cpu={} memory_kb={}",
+            self.sys.cpus().len(),
+            self.sys.total_memory()
+        )
+    }
+}
+
+// free page count of each hugepage pool in a sysfs hugepages directory
+fn read_hugepages(dir: &Path) -> Result<BTreeMap<u64, u64>, Error> {
+    let mut pools = BTreeMap::new();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return Ok(pools),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // pools are named like hugepages-2048kB
+        let kb = match name
+            .strip_prefix("hugepages-")
+            .and_then(|n| n.strip_suffix("kB"))
+            .map(|n| n.parse::<u64>())
+        {
+            Some(Ok(kb)) => kb,
+            _ => continue,
+        };
+        let free = std::fs::read_to_string(entry.path().join("free_hugepages"))?;
+        pools.insert(kb * 1024, free.trim().parse()?);
+    }
+    Ok(pools)
+}
+
+// The cpu flag a nested machine needs on this host, None when the spec
+// doesn't ask for nesting.
+pub fn nested_cpu_feature(spec: &models::Spec) -> Result<Option<&'static str>, Error> {
+    if spec.nested != Some(true) {
+        return Ok(None);
+    }
+    let flag = nested_feature(Path::new(SYS_MODULE))?;
+    for f in spec.cpu_features.iter().flatten() {
+        if models::parse_cpu_feature(f) == (flag, false) {
+            return Err(format!("nested conflicts with cpu feature {}", f).into());
+        }
+    }
+    Ok(Some(flag))
+}
+
+// vmx on intel and svm on amd, as long as the loaded kvm module allows
+// nested guests
+fn nested_feature(modules: &Path) -> Result<&'static str, Error> {
+    for (module, flag) in [("kvm_intel", "vmx"), ("kvm_amd", "svm")] {
+        let param = match std::fs::read_to_string(modules.join(module).join("parameters/nested")) {
+            Ok(p) => p,
+            Err(_) => continue,
+        };
+        // kvm_intel says Y, older kvm_amd 1
+        return match param.trim() {
+            "Y" | "1" => Ok(flag),
+            _ => Err(format!(
+                "nested virtualization is off, reload {} with nested=1",
+                module
+            )
+            .into()),
+        };
+    }
+    Err("nested virtualization needs the kvm_intel or kvm_amd module".into())
+}
+
+// parse a kernel cpu list such as "0-3,8-11"
+pub fn parse_cpulist(s: &str) -> Result<Vec<u32>, Error> {
+    let mut cpus = Vec::new();
+    for part in s.trim().split(',').filter(|p| !p.is_empty()) {
+        match part.split_once('-') {
+            Some((a, b)) => cpus.extend(a.parse::<u32>()?..=b.parse::<u32>()?),
+            None => cpus.push(part.parse()?),
+        }
+    }
+    Ok(cpus)
+}
+
+impl Topology {
+    // check the hugepage and numa placement requested by a spec can be met
+    pub fn validate(&self, spec: &models::Spec) -> Result<(), Error> {
+        let numa = spec.numa.as_ref();
+        let node = numa.and_then(|n| n.node);
+
+        if let Some(node) = node {
+            if !self.nodes.contains_key(&node) {
+                return Err(format!(
+                    "numa node {} does not exist, host has nodes {:?}",
+                    node,
+                    self.nodes.keys().collect::<Vec<_>>()
+                )
+                .into());
+            }
+        }
+
+        if let Some(pinning) = numa.and_then(|n| n.cpu_pinning.as_ref()) {
+            let max_cpus = spec.max_cpu.unwrap_or(spec.cpu).max(spec.cpu) as usize;
+            if pinning.len() > max_cpus {
+                return Err(format!(
+                    "cpuPinning has {} entries but the machine only has {} vcpus",
+                    pinning.len(),
+                    max_cpus
+                )
+                .into());
+            }
+            let allowed: Vec<u32> = match node {
+                Some(node) => self.nodes[&node].clone(),
+                None => self.nodes.values().flatten().copied().collect(),
+            };
+            for cpu in pinning {
+                if !allowed.contains(cpu) {
+                    return Err(match node {
+                        Some(node) => format!("host cpu {} is not on numa node {}", cpu, node),
+                        None => format!("host cpu {} does not exist", cpu),
+                    }
+                    .into());
+                }
+            }
+        }
+
+        if let Some(size) = &spec.hugepages {
+            let page = size.bytes();
+            let pools = match node {
+                Some(node) => self.node_hugepages.get(&node).unwrap_or(&self.hugepages),
+                None => &self.hugepages,
+            };
+            let free = match pools.get(&page) {
+                Some(free) => *free,
+                None => {
+                    return Err(format!(
+                        "host has no hugepage pool of size {}, available sizes are: {:?}",
+                        size,
+                        pools.keys().collect::<Vec<_>>()
+                    )
+                    .into())
+                }
+            };
+            let memory = spec.memory.bytes();
+            if !memory.is_multiple_of(page) {
+                return Err(format!(
+                    "memory {} is not a multiple of hugepage size {}",
+                    spec.memory, size
+                )
+                .into());
+            }
+            if memory / page > free {
+                return Err(format!(
+                    "not enough free {} hugepages, need {} but {} are free",
+                    size,
+                    memory / page,
+                    free
+                )
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_nested_feature() {
+        let dir = TestDir::new("nested");
+        let params = dir.join("kvm_amd/parameters");
+        std::fs::create_dir_all(&params).unwrap();
+        assert!(nested_feature(&dir.join("missing")).is_err());
+
+        std::fs::write(params.join("nested"), "0\n").unwrap();
+        assert!(nested_feature(&dir).is_err());
+        std::fs::write(params.join("nested"), "1\n").unwrap();
+        assert_eq!(nested_feature(&dir).unwrap(), "svm");
+    }
+
+    #[test]
+    fn test_parse_cpulist() {
+        assert_eq!(
+            parse_cpulist("0-3,8,10-11\n").unwrap(),
+            vec![0, 1, 2, 3, 8, 10, 11]
+        );
+        assert_eq!(parse_cpulist("\n").unwrap(), Vec::<u32>::new());
+        assert!(parse_cpulist("0-x").is_err());
+    }
+
+    #[test]
+    fn test_topology_validate() {
+        let mut topo = Topology::default();
+        topo.nodes.insert(0, vec![0, 1, 2, 3]);
+        topo.nodes.insert(1, vec![4, 5, 6, 7]);
+        topo.hugepages.insert(2 * 1024 * 1024, 4096);
+        topo.node_hugepages
+            .insert(1, BTreeMap::from([(2 * 1024 * 1024, 1024)]));
+
+        let yaml = "
+            cpu: 2
+            memory: 4Gi
+            image:
+              url: http://example.com/image.qcow2
+            hugepages: 2Mi
+            numa:
+              node: 1
+              cpuPinning: [4, 5]
+        ";
+        let mut spec: models::Spec = serde_yaml::from_str(yaml).unwrap();
+        // 4Gi needs 2048 pages, only 1024 are free on node 1
+        assert!(topo.validate(&spec).is_err());
+
+        spec.memory = "2Gi".parse().unwrap();
+        topo.validate(&spec).unwrap();
+
+        spec.numa.as_mut().unwrap().cpu_pinning = Some(vec![0, 1]);
+        assert!(topo.validate(&spec).is_err());
+
+        spec.numa.as_mut().unwrap().node = Some(2);
+        assert!(topo.validate(&spec).is_err());
     }
 }
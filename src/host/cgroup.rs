@@ -0,0 +1,298 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Resource usage of libvirt domains read straight from their cgroups.
+//
+// libvirt puts each qemu domain in a systemd scope under machine.slice,
+// named like machine-qemu\x2d3\x2dweb1.scope, where 3 is the domain id.
+// Both the unified (v2) and legacy (v1) hierarchies are handled.
+//
+// VMs of the qemu driver have no libvirt to do this for them, each gets a
+// cgroup of its own under bigiron.slice with cpu and memory limits from its
+// spec. Those need the unified hierarchy.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const VM_SLICE: &str = "bigiron.slice";
+// qemu's own memory on top of the guest's, device emulation, vram, caches
+const QEMU_OVERHEAD: u64 = 256 * 1024 * 1024;
+// cpu.max period in microseconds, the kernel's default
+const CPU_PERIOD: u64 = 100_000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    // cumulative cpu time in microseconds
+    pub cpu_usec: u64,
+    // memory charged to the domain in bytes
+    pub memory: u64,
+}
+
+// usage of a running domain, None if it has no cgroup (i.e. isn't running)
+pub fn domain_usage(name: &str) -> Result<Option<Usage>, Error> {
+    usage_under(Path::new(CGROUP_ROOT), name)
+}
+
+// Cpu use (100.0 per busy cpu) and memory of each domain, with cpu averaged
+// over `interval`. Domains that aren't running get None.
+pub fn utilization(names: &[&str], interval: Duration) -> Result<Vec<Option<(f64, u64)>>, Error> {
+    let before = names
+        .iter()
+        .map(|n| domain_usage(n))
+        .collect::<Result<Vec<_>, _>>()?;
+    let start = Instant::now();
+    std::thread::sleep(interval);
+
+    let mut r = Vec::new();
+    for (name, before) in names.iter().zip(before) {
+        let after = domain_usage(name)?;
+        r.push(match (before, after) {
+            (Some(b), Some(a)) => {
+                let elapsed = start.elapsed().as_micros() as f64;
+                let cpu = a.cpu_usec.saturating_sub(b.cpu_usec) as f64 / elapsed * 100.0;
+                Some((cpu, a.memory))
+            }
+            _ => None,
+        });
+    }
+    Ok(r)
+}
+
+// whether the unified (v2) hierarchy is mounted
+pub fn unified() -> bool {
+    Path::new(CGROUP_ROOT).join("cgroup.controllers").exists()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    // cpus worth of time for all of qemu's threads together
+    pub cpus: u32,
+    // bytes, the guest's memory plus qemu's overhead
+    pub memory: u64,
+}
+
+impl Limits {
+    pub fn new(cpus: u32, guest_memory: u64) -> Self {
+        Limits {
+            cpus,
+            memory: guest_memory + QEMU_OVERHEAD,
+        }
+    }
+
+    fn cpu_max(&self) -> String {
+        format!("{} {}", self.cpus as u64 * CPU_PERIOD, CPU_PERIOD)
+    }
+}
+
+// cgroup of a qemu driver VM, named after its id
+#[derive(Debug, Clone)]
+pub struct VmCgroup {
+    root: PathBuf,
+    path: PathBuf,
+}
+
+impl VmCgroup {
+    pub fn new(id: &str) -> Self {
+        Self::under(Path::new(CGROUP_ROOT), id)
+    }
+
+    fn under(root: &Path, id: &str) -> Self {
+        VmCgroup {
+            root: root.to_path_buf(),
+            path: root
+                .join(VM_SLICE)
+                .join(format!("vm-{}.slice", systemd_escape(id))),
+        }
+    }
+
+    // Create the cgroup with `limits`, or update those of an existing one.
+    pub fn create(&self, limits: &Limits) -> Result<(), Error> {
+        // each level has to hand the controllers down to the next
+        let slice = self.root.join(VM_SLICE);
+        std::fs::write(self.root.join("cgroup.subtree_control"), "+cpu +memory")?;
+        std::fs::create_dir_all(&slice)?;
+        std::fs::write(slice.join("cgroup.subtree_control"), "+cpu +memory")?;
+        std::fs::create_dir_all(&self.path)?;
+
+        std::fs::write(self.path.join("cpu.max"), limits.cpu_max())?;
+        std::fs::write(self.path.join("memory.max"), limits.memory.to_string())?;
+        Ok(())
+    }
+
+    // Move a process into the cgroup, children it starts after stay in it.
+    pub fn attach(&self, pid: u32) -> Result<(), Error> {
+        std::fs::write(self.path.join("cgroup.procs"), pid.to_string())?;
+        Ok(())
+    }
+
+    // Move the calling process back to the root cgroup and remove this one,
+    // once whatever else ran in it has exited.
+    pub fn leave(&self) -> Result<(), Error> {
+        std::fs::write(
+            self.root.join("cgroup.procs"),
+            std::process::id().to_string(),
+        )?;
+        match std::fs::remove_dir(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    // usage of the VM, None if it has no cgroup (i.e. isn't running)
+    pub fn usage(&self) -> Result<Option<Usage>, Error> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let stat = std::fs::read_to_string(self.path.join("cpu.stat"))?;
+        Ok(Some(Usage {
+            cpu_usec: parse_cpu_stat(&stat).unwrap_or(0),
+            memory: read_u64(&self.path.join("memory.current"))?,
+        }))
+    }
+}
+
+fn usage_under(root: &Path, name: &str) -> Result<Option<Usage>, Error> {
+    // cgroup v2, everything in one tree
+    if let Some(scope) = find_scope(&root.join("machine.slice"), name)? {
+        let stat = std::fs::read_to_string(scope.join("cpu.stat"))?;
+        return Ok(Some(Usage {
+            cpu_usec: parse_cpu_stat(&stat).unwrap_or(0),
+            memory: read_u64(&scope.join("memory.current"))?,
+        }));
+    }
+
+    // cgroup v1, one tree per controller
+    let cpu = find_scope(&root.join("cpuacct/machine.slice"), name)?;
+    let mem = find_scope(&root.join("memory/machine.slice"), name)?;
+    match (cpu, mem) {
+        (Some(cpu), Some(mem)) => Ok(Some(Usage {
+            cpu_usec: read_u64(&cpu.join("cpuacct.usage"))? / 1000,
+            memory: read_u64(&mem.join("memory.usage_in_bytes"))?,
+        })),
+        _ => Ok(None),
+    }
+}
+
+fn find_scope(slice: &Path, name: &str) -> Result<Option<PathBuf>, Error> {
+    let entries = match std::fs::read_dir(slice) {
+        Ok(e) => e,
+        Err(_) => return Ok(None),
+    };
+    let suffix = format!("\\x2d{}.scope", systemd_escape(name));
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if file_name.starts_with("machine-qemu\\x2d") && file_name.ends_with(&suffix) {
+            // the domain id in between is only digits
+            let id = &file_name["machine-qemu\\x2d".len()..file_name.len() - suffix.len()];
+            if !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()) {
+                return Ok(Some(entry.path()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// escape a name the way systemd does for unit names
+fn systemd_escape(name: &str) -> String {
+    let mut r = String::new();
+    for (i, c) in name.chars().enumerate() {
+        match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | ':' | '_' => r.push(c),
+            '.' if i > 0 => r.push(c),
+            _ => {
+                let mut buf = [0u8; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    r.push_str(&format!("\\x{:02x}", b));
+                }
+            }
+        }
+    }
+    r
+}
+
+fn parse_cpu_stat(buf: &str) -> Option<u64> {
+    buf.lines()
+        .find_map(|l| l.strip_prefix("usage_usec "))
+        .and_then(|v| v.trim().parse().ok())
+}
+
+fn read_u64(path: &Path) -> Result<u64, Error> {
+    Ok(std::fs::read_to_string(path)?.trim().parse()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_domain_usage() {
+        assert_eq!(systemd_escape("web-1.lab"), "web\\x2d1.lab");
+
+        let root = TestDir::new("cgroup");
+        let scope = root.join("machine.slice/machine-qemu\\x2d12\\x2dweb\\x2d1.scope");
+        std::fs::create_dir_all(&scope).unwrap();
+        std::fs::write(
+            scope.join("cpu.stat"),
+            "usage_usec 2500000\nuser_usec 2000000\nsystem_usec 500000\n",
+        )
+        .unwrap();
+        std::fs::write(scope.join("memory.current"), "1073741824\n").unwrap();
+
+        assert_eq!(
+            usage_under(&root, "web-1").unwrap(),
+            Some(Usage {
+                cpu_usec: 2_500_000,
+                memory: 1 << 30
+            })
+        );
+        // web is a prefix of the other name, but not the same domain
+        assert_eq!(usage_under(&root, "1").unwrap(), None);
+        assert_eq!(usage_under(&root, "web").unwrap(), None);
+    }
+
+    #[test]
+    fn test_vm_cgroup() {
+        let root = TestDir::new("vmcgroup");
+        let cg = VmCgroup::under(&root, "2f1c-web");
+        assert_eq!(cg.usage().unwrap(), None);
+
+        cg.create(&Limits::new(2, 1 << 30)).unwrap();
+        let dir = root.join("bigiron.slice/vm-2f1c\\x2dweb.slice");
+        let read = |f: &str| std::fs::read_to_string(dir.join(f)).unwrap();
+        assert_eq!(read("cpu.max"), "200000 100000");
+        assert_eq!(read("memory.max"), "1342177280");
+        assert_eq!(
+            std::fs::read_to_string(root.join("bigiron.slice/cgroup.subtree_control")).unwrap(),
+            "+cpu +memory"
+        );
+
+        std::fs::write(dir.join("cpu.stat"), "usage_usec 1500000\n").unwrap();
+        std::fs::write(dir.join("memory.current"), "536870912\n").unwrap();
+        assert_eq!(
+            cg.usage().unwrap(),
+            Some(Usage {
+                cpu_usec: 1_500_000,
+                memory: 1 << 29
+            })
+        );
+    }
+}
@@ -0,0 +1,285 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Port forwards from the host to machine services, as dnat rules in an
+// nftables table of our own. The table is rendered from the saved forwards
+// and replaced as a whole, so it never drifts from them.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use serde::{Deserialize, Serialize};
+
+use super::Error;
+use crate::lockfile::LockFile;
+use crate::models::{PortForward, Protocol};
+use crate::ports;
+use crate::store;
+
+fn state_path() -> PathBuf {
+    store::data_dir().join("forwards.yaml")
+}
+
+fn lock_path() -> PathBuf {
+    store::data_dir().join("forwards.lock")
+}
+
+const TABLE: &str = "bigiron";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Forward {
+    pub machine: String,
+    pub ip: String,
+    pub forward: PortForward,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Forwards {
+    machines: BTreeMap<String, Vec<Forward>>,
+}
+
+impl Forwards {
+    fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        if !path.as_ref().exists() {
+            return Ok(Self::default());
+        }
+        let buf = std::fs::read_to_string(path.as_ref())?;
+        Ok(serde_yaml::from_str(&buf)?)
+    }
+
+    fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let buf = serde_yaml::to_string(self)?;
+        std::fs::write(path.as_ref(), buf.as_bytes())?;
+        Ok(())
+    }
+
+    fn all(&self) -> impl Iterator<Item = &Forward> {
+        self.machines.values().flatten()
+    }
+
+    // a host port can only be forwarded once per protocol
+    fn check(&self, machine: &str, forwards: &[PortForward]) -> Result<(), Error> {
+        for (i, f) in forwards.iter().enumerate() {
+            let taken = self
+                .all()
+                .filter(|o| o.machine != machine)
+                .map(|o| &o.forward)
+                .chain(&forwards[..i])
+                .any(|o| o.host_port == f.host_port && o.protocol == f.protocol);
+            if taken {
+                return Err(format!(
+                    "host port {}/{} is already forwarded",
+                    f.host_port,
+                    f.protocol.as_str()
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+// The whole table, deleted and created again in one transaction. Declaring
+// it first makes the delete work when it doesn't exist yet.
+fn render(forwards: &Forwards) -> String {
+    let mut prerouting = String::new();
+    let mut output = String::new();
+    for f in forwards.all() {
+        let rule = format!(
+            "{} dport {} dnat to {}:{} comment \"{}\"",
+            f.forward.protocol.as_str(),
+            f.forward.host_port,
+            f.ip,
+            f.forward.port,
+            f.machine
+        );
+        prerouting.push_str(&format!("        {}\n", rule));
+        // connections from the host itself to one of its own addresses
+        output.push_str(&format!("        fib daddr type local {}\n", rule));
+    }
+    format!(
+        "table ip {table}\n\
+         delete table ip {table}\n\
+         table ip {table} {{\n    \
+             chain prerouting {{\n        \
+                 type nat hook prerouting priority dstnat; policy accept;\n\
+         {prerouting}    }}\n    \
+             chain output {{\n        \
+                 type nat hook output priority -100; policy accept;\n\
+         {output}    }}\n\
+         }}\n",
+        table = TABLE,
+        prerouting = prerouting,
+        output = output,
+    )
+}
+
+fn program(forwards: &Forwards) -> Result<(), Error> {
+    if forwards.all().next().is_some() {
+        // dnat'd packets are routed on to the machine
+        std::fs::write("/proc/sys/net/ipv4/ip_forward", "1")?;
+    }
+    let mut child = Command::new("nft")
+        .arg("-f")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .ok_or("error opening nft stdin")?
+        .write_all(render(forwards).as_bytes())?;
+    let out = child.wait_with_output()?;
+    if !out.status.success() {
+        return Err(format!(
+            "nft failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// Forward host ports to a machine's address, replacing any forwards it had.
+pub fn add(machine: &str, ip: &str, forwards: &[PortForward]) -> Result<(), Error> {
+    if forwards.is_empty() {
+        return Ok(());
+    }
+    if ip.is_empty() {
+        return Err(format!(
+            "can't forward ports to {}, it has no address on this host",
+            machine
+        )
+        .into());
+    }
+
+    let lf = LockFile::new(lock_path());
+    let _lock = lf.acquire();
+    let mut state = Forwards::load(state_path())?;
+    state.check(machine, forwards)?;
+
+    // keeps automatically allocated ports of other services off them
+    let registry = ports::Registry::default();
+    for f in forwards.iter().filter(|f| f.protocol == Protocol::Tcp) {
+        registry.reserve(
+            machine,
+            &format!("forward-{}", f.host_port),
+            Some(f.host_port),
+        )?;
+    }
+
+    state.machines.insert(
+        machine.to_string(),
+        forwards
+            .iter()
+            .map(|f| Forward {
+                machine: machine.to_string(),
+                ip: ip.to_string(),
+                forward: f.clone(),
+            })
+            .collect(),
+    );
+    program(&state)?;
+    state.save(state_path())
+}
+
+pub fn remove(machine: &str) -> Result<(), Error> {
+    let lf = LockFile::new(lock_path());
+    let _lock = lf.acquire();
+    let mut state = Forwards::load(state_path())?;
+    if state.machines.remove(machine).is_none() {
+        return Ok(());
+    }
+    program(&state)?;
+    state.save(state_path())
+}
+
+pub fn list() -> Result<Vec<Forward>, Error> {
+    Ok(Forwards::load(state_path())?.all().cloned().collect())
+}
+
+// program the saved forwards again, e.g. after the host rebooted
+pub fn sync() -> Result<(), Error> {
+    let lf = LockFile::new(lock_path());
+    let _lock = lf.acquire();
+    program(&Forwards::load(state_path())?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn forward(host_port: u16, port: u16, protocol: Protocol) -> PortForward {
+        PortForward {
+            host_port,
+            port,
+            protocol,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let mut state = Forwards::default();
+        state.machines.insert(
+            "web1".into(),
+            vec![Forward {
+                machine: "web1".into(),
+                ip: "172.20.0.10".into(),
+                forward: forward(8080, 80, Protocol::Tcp),
+            }],
+        );
+        assert_eq!(
+            render(&state),
+            "table ip bigiron\n\
+             delete table ip bigiron\n\
+             table ip bigiron {\n    \
+                 chain prerouting {\n        \
+                     type nat hook prerouting priority dstnat; policy accept;\n        \
+                     tcp dport 8080 dnat to 172.20.0.10:80 comment \"web1\"\n    \
+                 }\n    \
+                 chain output {\n        \
+                     type nat hook output priority -100; policy accept;\n        \
+                     fib daddr type local tcp dport 8080 dnat to 172.20.0.10:80 comment \"web1\"\n    \
+                 }\n\
+             }\n"
+        );
+
+        // same port on the other protocol is fine, twice on one isn't
+        assert!(state
+            .check("web2", &[forward(8080, 80, Protocol::Udp)])
+            .is_ok());
+        assert!(state
+            .check("web2", &[forward(8080, 80, Protocol::Tcp)])
+            .is_err());
+        assert!(state
+            .check("web1", &[forward(8080, 8000, Protocol::Tcp)])
+            .is_ok());
+        assert!(state
+            .check(
+                "web2",
+                &[
+                    forward(53, 53, Protocol::Udp),
+                    forward(53, 53, Protocol::Udp)
+                ]
+            )
+            .is_err());
+    }
+}
@@ -0,0 +1,331 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+
+use serde::Serialize;
+
+use crate::error::Error;
+use crate::network::MANAGEMENT_BRIDGE;
+
+const SYS_CLASS_NET: &str = "/sys/class/net";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InterfaceKind {
+    Bridge,
+    Vlan,
+    Physical,
+    Virtual,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Interface {
+    pub name: String,
+    pub kind: InterfaceKind,
+    pub state: String,
+    // bridge (or bond) this interface is enslaved to
+    pub master: Option<String>,
+    // interfaces enslaved to this bridge
+    pub slaves: Vec<String>,
+    // parent interface and tag for vlan subinterfaces
+    pub vlan: Option<(String, u16)>,
+    pub addresses: Vec<String>,
+    pub managed: bool,
+}
+
+// Enumerate host network interfaces from sysfs, procfs and `ip addr`.
+pub fn interfaces() -> Result<Vec<Interface>, Error> {
+    let vlans = match std::fs::read_to_string("/proc/net/vlan/config") {
+        Ok(buf) => parse_vlan_config(&buf),
+        Err(_) => BTreeMap::new(),
+    };
+    let addrs = addresses()?;
+
+    let mut r = Vec::new();
+    for entry in Path::new(SYS_CLASS_NET).read_dir()? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = entry.path();
+
+        let vlan = vlans.get(&name).cloned();
+        let kind = if path.join("bridge").exists() {
+            InterfaceKind::Bridge
+        } else if vlan.is_some() {
+            InterfaceKind::Vlan
+        } else if path.join("device").exists() {
+            InterfaceKind::Physical
+        } else {
+            InterfaceKind::Virtual
+        };
+
+        let mut slaves = Vec::new();
+        if let Ok(dir) = path.join("brif").read_dir() {
+            for s in dir.flatten() {
+                slaves.push(s.file_name().to_string_lossy().to_string());
+            }
+        }
+        slaves.sort();
+
+        let master = std::fs::read_link(path.join("master"))
+            .ok()
+            .and_then(|p| p.file_name().map(|f| f.to_string_lossy().to_string()));
+
+        let state = std::fs::read_to_string(path.join("operstate"))
+            .map(|s| s.trim().to_string())
+            .unwrap_or_else(|_| "unknown".to_string());
+
+        r.push(Interface {
+            managed: name == MANAGEMENT_BRIDGE,
+            addresses: addrs.get(&name).cloned().unwrap_or_default(),
+            name,
+            kind,
+            state,
+            master,
+            slaves,
+            vlan,
+        });
+    }
+
+    r.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(r)
+}
+
+pub fn bridges() -> Result<Vec<String>, Error> {
+    Ok(interfaces()?
+        .into_iter()
+        .filter(|i| i.kind == InterfaceKind::Bridge)
+        .map(|i| i.name)
+        .collect())
+}
+
+// Fail with a helpful message if a bridge machines will be attached to is missing.
+pub fn ensure_bridge(name: &str) -> Result<(), Error> {
+    let bridges = bridges()?;
+    if bridges.iter().any(|b| b == name) {
+        return Ok(());
+    }
+
+    let available = match bridges.is_empty() {
+        true => "none".to_string(),
+        false => bridges.join(", "),
+    };
+    Err(format!(
+        "bridge {} does not exist, available bridges are: {}",
+        name, available
+    )
+    .into())
+}
+
+pub fn vlan_bridge_name(vlan: u16) -> String {
+    format!("brv{}", vlan)
+}
+
+// Create `<trunk>.<vlan>` and enslave it to the vlan's bridge, skipping
+// whichever of the two already exists.
+pub fn create_vlan_bridge(trunk: &str, vlan: u16) -> Result<(), Error> {
+    let subif = format!("{}.{}", trunk, vlan);
+    // IFNAMSIZ less the terminating nul
+    if subif.len() > 15 {
+        return Err(format!("vlan interface name {} is too long", subif).into());
+    }
+    let bridge = vlan_bridge_name(vlan);
+    let sys = Path::new(SYS_CLASS_NET);
+
+    if !sys.join(&subif).exists() {
+        ip(&[
+            "link",
+            "add",
+            "link",
+            trunk,
+            "name",
+            &subif,
+            "type",
+            "vlan",
+            "id",
+            &vlan.to_string(),
+        ])?;
+    }
+    if !sys.join(&bridge).exists() {
+        ip(&["link", "add", "name", &bridge, "type", "bridge"])?;
+    }
+    ip(&["link", "set", &subif, "master", &bridge])?;
+    ip(&["link", "set", &subif, "up"])?;
+    ip(&["link", "set", &bridge, "up"])
+}
+
+pub fn delete_vlan_bridge(trunk: &str, vlan: u16) -> Result<(), Error> {
+    ip(&["link", "del", &vlan_bridge_name(vlan)])?;
+    ip(&["link", "del", &format!("{}.{}", trunk, vlan)])
+}
+
+// put a link on a bridge and bring it up
+pub fn attach(dev: &str, bridge: &str) -> Result<(), Error> {
+    ip(&["link", "set", dev, "master", bridge])?;
+    ip(&["link", "set", dev, "up"])
+}
+
+pub fn delete_link(dev: &str) -> Result<(), Error> {
+    ip(&["link", "del", dev])
+}
+
+// traffic of a machine nic, seen from the guest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+}
+
+impl Counters {
+    // bytes per second received and sent since an earlier sample
+    pub fn rates(&self, before: &Counters, secs: f64) -> (f64, f64) {
+        let rate = |a: u64, b: u64| a.saturating_sub(b) as f64 / secs;
+        (
+            rate(self.rx_bytes, before.rx_bytes),
+            rate(self.tx_bytes, before.tx_bytes),
+        )
+    }
+}
+
+// Counters of the tap device behind a machine nic. What the tap receives
+// the guest sent, so the directions are swapped.
+pub fn tap_counters(dev: &str) -> Result<Counters, Error> {
+    read_counters(&Path::new(SYS_CLASS_NET).join(dev).join("statistics"))
+}
+
+fn read_counters(dir: &Path) -> Result<Counters, Error> {
+    let read = |name: &str| -> Result<u64, Error> {
+        Ok(std::fs::read_to_string(dir.join(name))?.trim().parse()?)
+    };
+    Ok(Counters {
+        rx_bytes: read("tx_bytes")?,
+        tx_bytes: read("rx_bytes")?,
+        rx_packets: read("tx_packets")?,
+        tx_packets: read("rx_packets")?,
+    })
+}
+
+fn ip(args: &[&str]) -> Result<(), Error> {
+    let out = Command::new("ip").args(args).output()?;
+    if !out.status.success() {
+        return Err(format!(
+            "ip {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn addresses() -> Result<BTreeMap<String, Vec<String>>, Error> {
+    let out = Command::new("ip")
+        .arg("-o")
+        .arg("addr")
+        .arg("show")
+        .output()?;
+    if !out.status.success() {
+        return Err("failed to list interface addresses".into());
+    }
+    Ok(parse_ip_addr(&String::from_utf8_lossy(&out.stdout)))
+}
+
+// parse `ip -o addr show` output into interface -> CIDR addresses
+fn parse_ip_addr(buf: &str) -> BTreeMap<String, Vec<String>> {
+    let mut r: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for line in buf.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 || !(fields[2] == "inet" || fields[2] == "inet6") {
+            continue;
+        }
+        // vlan subinterfaces are shown as name@parent
+        let name = fields[1].split('@').next().unwrap_or(fields[1]);
+        r.entry(name.to_string())
+            .or_default()
+            .push(fields[3].to_string());
+    }
+    r
+}
+
+// parse /proc/net/vlan/config into interface -> (parent, vlan id)
+fn parse_vlan_config(buf: &str) -> BTreeMap<String, (String, u16)> {
+    let mut r = BTreeMap::new();
+    for line in buf.lines() {
+        let fields: Vec<&str> = line.split('|').map(|f| f.trim()).collect();
+        if fields.len() != 3 {
+            continue;
+        }
+        if let Ok(id) = fields[1].parse::<u16>() {
+            r.insert(fields[0].to_string(), (fields[2].to_string(), id));
+        }
+    }
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_parse_vlan_config() {
+        let buf = "VLAN Dev name    | VLAN ID\n\
+                   Name-Type: VLAN_NAME_TYPE_RAW_PLUS_VID_NO_PAD\n\
+                   eno1.208       | 208  | eno1\n\
+                   eno1.209       | 209  | eno1\n";
+        let vlans = parse_vlan_config(buf);
+        assert_eq!(vlans.len(), 2);
+        assert_eq!(vlans["eno1.208"], ("eno1".to_string(), 208));
+    }
+
+    #[test]
+    fn test_tap_counters() {
+        let dir = TestDir::new("stats");
+        for (name, v) in [
+            ("rx_bytes", "1000\n"),
+            ("tx_bytes", "5000\n"),
+            ("rx_packets", "10\n"),
+            ("tx_packets", "50\n"),
+        ] {
+            std::fs::write(dir.join(name), v).unwrap();
+        }
+        let c = read_counters(&dir).unwrap();
+        assert_eq!(c.rx_bytes, 5000);
+        assert_eq!(c.tx_packets, 10);
+
+        let before = Counters {
+            rx_bytes: 4000,
+            tx_bytes: 1000,
+            ..Default::default()
+        };
+        assert_eq!(c.rates(&before, 2.0), (500.0, 0.0));
+    }
+
+    #[test]
+    fn test_parse_ip_addr() {
+        let buf = "1: lo    inet 127.0.0.1/8 scope host lo\\       valid_lft forever preferred_lft forever\n\
+                   3: br0    inet 172.20.0.1/24 brd 172.20.0.255 scope global br0\\       valid_lft forever\n\
+                   4: eno1.208@eno1    inet6 fe80::1/64 scope link \\       valid_lft forever\n";
+        let addrs = parse_ip_addr(buf);
+        assert_eq!(addrs["br0"], vec!["172.20.0.1/24".to_string()]);
+        assert_eq!(addrs["eno1.208"], vec!["fe80::1/64".to_string()]);
+    }
+}
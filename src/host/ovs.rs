@@ -0,0 +1,70 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Open vSwitch, for labs whose vlans run over an ovs bridge rather than a
+// linux bridge per vlan. libvirt adds each machine nic as a port of the
+// bridge tagged with its vlan, from the domain's <virtualport
+// type='openvswitch'/>, and removes it again when the domain goes away.
+// Only the bridge and its uplink are set up here.
+
+use std::process::Command;
+
+use super::Error;
+
+const VSCTL: &str = "ovs-vsctl";
+
+fn vsctl(args: &[&str]) -> Result<String, Error> {
+    let out = Command::new(VSCTL)
+        .args(args)
+        .output()
+        .map_err(|e| format!("error running {}: {}", VSCTL, e))?;
+    if !out.status.success() {
+        return Err(format!(
+            "{} {} failed: {}",
+            VSCTL,
+            args.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        )
+        .into());
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+// the ovs bridge a port is on, None if it isn't on one or ovs isn't there
+pub fn port_bridge(port: &str) -> Option<String> {
+    vsctl(&["port-to-br", port]).ok().filter(|b| !b.is_empty())
+}
+
+// Create the bridge when missing, with the configured uplink carrying every
+// vlan. An uplink already on another ovs bridge is not moved.
+pub fn ensure_bridge(bridge: &str, uplink: Option<&str>) -> Result<(), Error> {
+    vsctl(&["--may-exist", "add-br", bridge])?;
+    if let Some(uplink) = uplink {
+        match port_bridge(uplink) {
+            Some(b) if b == bridge => {}
+            Some(b) => {
+                return Err(
+                    format!("ovs uplink {} is on bridge {}, not {}", uplink, b, bridge).into(),
+                )
+            }
+            None => {
+                vsctl(&["add-port", bridge, uplink])?;
+            }
+        }
+    }
+    Ok(())
+}
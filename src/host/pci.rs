@@ -0,0 +1,168 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+
+const SYS_PCI_DEVICES: &str = "/sys/bus/pci/devices";
+const SYS_CLASS_NET: &str = "/sys/class/net";
+const VFIO_DRIVER: &str = "/sys/bus/pci/drivers/vfio-pci";
+
+// drivers that may share an iommu group with a passed through device
+const GROUP_SAFE_DRIVERS: [&str; 2] = ["vfio-pci", "pcieport"];
+
+// domain, bus, slot and function of a pci address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub domain: u16,
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    // parse 0000:03:00.0, the domain may be left off
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let err = || format!("invalid pci address '{}'", s);
+        let (rest, function) = s.rsplit_once('.').ok_or_else(err)?;
+        let parts: Vec<&str> = rest.split(':').collect();
+        let (domain, bus, slot) = match parts[..] {
+            [d, b, s] => (d, b, s),
+            [b, s] => ("0000", b, s),
+            _ => return Err(err().into()),
+        };
+        Ok(Self {
+            domain: u16::from_str_radix(domain, 16).map_err(|_| err())?,
+            bus: u8::from_str_radix(bus, 16).map_err(|_| err())?,
+            slot: u8::from_str_radix(slot, 16).map_err(|_| err())?,
+            function: u8::from_str_radix(function, 16).map_err(|_| err())?,
+        })
+    }
+
+    fn sysfs_path(&self) -> PathBuf {
+        Path::new(SYS_PCI_DEVICES).join(self.to_string())
+    }
+}
+
+impl std::fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{:x}",
+            self.domain, self.bus, self.slot, self.function
+        )
+    }
+}
+
+// name of the last path component of a sysfs symlink, e.g. a driver
+fn link_name(path: &Path) -> Option<String> {
+    let target = std::fs::read_link(path).ok()?;
+    Some(target.file_name()?.to_string_lossy().to_string())
+}
+
+// Make sure a device can be handed to a guest with vfio. libvirt rebinds
+// the device itself (managed='yes') but fails with an obscure error when
+// the iommu is off or the device shares its group with host devices.
+pub fn check_passthrough(addr: &PciAddress) -> Result<(), Error> {
+    let path = addr.sysfs_path();
+    if !path.exists() {
+        return Err(format!("pci device {} does not exist", addr).into());
+    }
+
+    let group = path.join("iommu_group");
+    if !group.exists() {
+        return Err(format!(
+            "pci device {} has no iommu group, is the iommu enabled (intel_iommu=on / amd_iommu=on)?",
+            addr
+        )
+        .into());
+    }
+
+    if !Path::new(VFIO_DRIVER).exists() {
+        return Err("vfio-pci driver is not loaded, run `modprobe vfio-pci`".into());
+    }
+
+    for entry in std::fs::read_dir(group.join("devices"))? {
+        let member = entry?.file_name().to_string_lossy().to_string();
+        if member == addr.to_string() {
+            continue;
+        }
+        let driver = link_name(&Path::new(SYS_PCI_DEVICES).join(&member).join("driver"));
+        if let Some(d) = driver {
+            if !GROUP_SAFE_DRIVERS.contains(&d.as_str()) {
+                return Err(format!(
+                    "pci device {} shares its iommu group with {} which is in use by {}",
+                    addr, member, d
+                )
+                .into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// addresses of the SR-IOV virtual functions of a physical function netdev
+pub fn virtual_functions(pf: &str) -> Result<Vec<PciAddress>, Error> {
+    let dev = Path::new(SYS_CLASS_NET).join(pf).join("device");
+    if !dev.exists() {
+        return Err(format!("network interface {} does not exist", pf).into());
+    }
+
+    let mut vfs = Vec::new();
+    for entry in std::fs::read_dir(&dev)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let index = match name.strip_prefix("virtfn").map(|i| i.parse::<u32>()) {
+            Some(Ok(i)) => i,
+            _ => continue,
+        };
+        if let Some(target) = link_name(&entry.path()) {
+            vfs.push((index, PciAddress::parse(&target)?));
+        }
+    }
+
+    if vfs.is_empty() {
+        return Err(format!("{} has no SR-IOV virtual functions enabled", pf).into());
+    }
+    vfs.sort_by_key(|(i, _)| *i);
+    Ok(vfs.into_iter().map(|(_, a)| a).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_pci_address() {
+        let a = PciAddress::parse("0000:3b:02.1").unwrap();
+        assert_eq!(
+            a,
+            PciAddress {
+                domain: 0,
+                bus: 0x3b,
+                slot: 2,
+                function: 1
+            }
+        );
+        assert_eq!(a.to_string(), "0000:3b:02.1");
+        assert_eq!(PciAddress::parse("3b:02.1").unwrap(), a);
+        assert!(PciAddress::parse("3b:02").is_err());
+        assert!(PciAddress::parse("zz:02.1").is_err());
+    }
+}
@@ -0,0 +1,247 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Host side of machine vlans: a subinterface of the trunk uplink and a
+// bridge per vlan, created on first use and removed with the last machine
+// using them. With open vswitch all vlans share one bridge instead, see
+// ovs.rs.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use super::Error;
+use super::{net, ovs};
+use crate::config::{self, BridgeBackend, VlanConfig};
+use crate::lockfile::LockFile;
+use crate::store;
+
+const DEFAULT_OVS_BRIDGE: &str = "brovs";
+
+// where a machine nic on a vlan is plugged in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Attachment {
+    // the vlan's own linux bridge, untagged
+    Bridge(String),
+    // a port of the ovs bridge, tagged with the vlan
+    Ovs { bridge: String, vlan: u16 },
+}
+
+impl Attachment {
+    pub fn bridge(&self) -> &str {
+        match self {
+            Attachment::Bridge(b) => b,
+            Attachment::Ovs { bridge, .. } => bridge,
+        }
+    }
+}
+
+// the ovs bridge if vlans go over open vswitch, None for linux bridges
+fn ovs_bridge(conf: &VlanConfig) -> Option<String> {
+    let trunk_bridge = || conf.trunk.as_deref().and_then(ovs::port_bridge);
+    match conf.backend {
+        Some(BridgeBackend::Linux) => None,
+        Some(BridgeBackend::Ovs) => Some(
+            conf.ovs_bridge
+                .clone()
+                .or_else(trunk_bridge)
+                .unwrap_or_else(|| DEFAULT_OVS_BRIDGE.to_string()),
+        ),
+        None => conf.ovs_bridge.clone().or_else(trunk_bridge),
+    }
+}
+
+// the bridge a vlan's nics are on
+pub fn bridge(vlan: u16) -> Result<String, Error> {
+    match ovs_bridge(&config::load()?.vlan) {
+        Some(bridge) => Ok(bridge),
+        None => Ok(net::vlan_bridge_name(vlan)),
+    }
+}
+
+fn state_path() -> PathBuf {
+    store::data_dir().join("vlans.yaml")
+}
+
+fn lock_path() -> PathBuf {
+    store::data_dir().join("vlans.lock")
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct VlanUse {
+    machines: BTreeSet<String>,
+    // whether bigiron created the bridge, pre-existing ones are left alone
+    created: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct VlanState {
+    vlans: BTreeMap<u16, VlanUse>,
+}
+
+impl VlanState {
+    fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        if !path.as_ref().exists() {
+            return Ok(Self::default());
+        }
+        let buf = std::fs::read_to_string(path.as_ref())?;
+        Ok(serde_yaml::from_str(&buf)?)
+    }
+
+    fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let buf = serde_yaml::to_string(self)?;
+        std::fs::write(path.as_ref(), buf.as_bytes())?;
+        Ok(())
+    }
+
+    // record a machine using a vlan, true if it is the first user
+    fn add(&mut self, machine: &str, vlan: u16) -> bool {
+        let u = self.vlans.entry(vlan).or_default();
+        let first = u.machines.is_empty();
+        u.machines.insert(machine.to_string());
+        first
+    }
+
+    // forget a machine, returning the vlans no one uses any more and
+    // whether their bridges were created by us
+    fn remove(&mut self, machine: &str) -> Vec<(u16, bool)> {
+        let mut unused = Vec::new();
+        for (vlan, u) in self.vlans.iter_mut() {
+            if u.machines.remove(machine) && u.machines.is_empty() {
+                unused.push((*vlan, u.created));
+            }
+        }
+        self.vlans.retain(|_, u| !u.machines.is_empty());
+        unused
+    }
+}
+
+pub fn check_id(vlan: u32) -> Result<u16, Error> {
+    match vlan {
+        1..=4094 => Ok(vlan as u16),
+        _ => Err(format!("vlan {} is out of range, must be 1-4094", vlan).into()),
+    }
+}
+
+// Make sure the bridges of a machine's vlans exist, creating them on the
+// configured trunk when missing. Returns where to plug in each vlan's nic.
+pub fn attach(machine: &str, vlans: &[u32]) -> Result<Vec<Attachment>, Error> {
+    if vlans.is_empty() {
+        return Ok(Vec::new());
+    }
+    let conf = config::load()?.vlan;
+    let trunk = conf.trunk.clone();
+    let ovs_bridge = ovs_bridge(&conf);
+    if let Some(bridge) = &ovs_bridge {
+        ovs::ensure_bridge(bridge, conf.ovs_uplink.as_deref())?;
+    }
+
+    let lf = LockFile::new(lock_path());
+    let _lock = lf.acquire();
+    let mut state = VlanState::load(state_path())?;
+
+    let mut attachments = Vec::new();
+    for vlan in vlans {
+        let vlan = check_id(*vlan)?;
+        if let Some(bridge) = &ovs_bridge {
+            state.add(machine, vlan);
+            attachments.push(Attachment::Ovs {
+                bridge: bridge.clone(),
+                vlan,
+            });
+            continue;
+        }
+        let bridge = net::vlan_bridge_name(vlan);
+        let exists = net::bridges()?.contains(&bridge);
+        match (&trunk, exists) {
+            (_, true) => {}
+            (Some(trunk), false) => {
+                net::create_vlan_bridge(trunk, vlan)?;
+                state.vlans.entry(vlan).or_default().created = true;
+            }
+            (None, false) => {
+                warn!(
+                    "not attaching {} to vlan {}, bridge {} doesn't exist and no trunk is configured",
+                    machine, vlan, bridge
+                );
+                continue;
+            }
+        }
+        state.add(machine, vlan);
+        attachments.push(Attachment::Bridge(bridge));
+    }
+
+    state.save(state_path())?;
+    Ok(attachments)
+}
+
+// drop a deleted machine's vlans, removing bridges we created once unused
+pub fn release(machine: &str) -> Result<(), Error> {
+    let lf = LockFile::new(lock_path());
+    let _lock = lf.acquire();
+    let mut state = VlanState::load(state_path())?;
+
+    let unused = state.remove(machine);
+    if unused.is_empty() {
+        return Ok(());
+    }
+    let trunk = config::load()?.vlan.trunk;
+    for (vlan, created) in unused {
+        if let (true, Some(trunk)) = (created, &trunk) {
+            if let Err(e) = net::delete_vlan_bridge(trunk, vlan) {
+                error!("error removing bridge for vlan {}: {}", vlan, e);
+            }
+        }
+    }
+    state.save(state_path())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_vlan_refcount() {
+        let mut s = VlanState::default();
+        assert!(s.add("web1", 208));
+        assert!(!s.add("web2", 208));
+        assert!(s.add("web2", 209));
+        s.vlans.get_mut(&209).unwrap().created = true;
+
+        assert!(s.remove("web1").is_empty());
+        assert_eq!(s.remove("web2"), vec![(208, false), (209, true)]);
+        assert!(s.vlans.is_empty());
+
+        assert!(check_id(4095).is_err());
+        assert_eq!(check_id(208).unwrap(), 208);
+    }
+
+    #[test]
+    fn test_ovs_bridge() {
+        let mut conf = VlanConfig::default();
+        assert_eq!(ovs_bridge(&conf), None);
+        conf.ovs_bridge = Some("br-lab".into());
+        assert_eq!(ovs_bridge(&conf).as_deref(), Some("br-lab"));
+        conf.backend = Some(BridgeBackend::Linux);
+        assert_eq!(ovs_bridge(&conf), None);
+        conf.backend = Some(BridgeBackend::Ovs);
+        conf.ovs_bridge = None;
+        assert_eq!(ovs_bridge(&conf).as_deref(), Some("brovs"));
+    }
+}
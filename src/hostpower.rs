@@ -0,0 +1,455 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Power management of the remote hosts, with scheduling.powerSaving set.
+// `host consolidate` live migrates the machines off the least used hosts
+// that have power control onto the others and powers down the hosts it
+// emptied. Apply wakes one up again when no awake host has room for a new
+// machine.
+
+use std::collections::BTreeMap;
+use std::net::UdpSocket;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tracing::{debug, info};
+
+use crate::audit;
+use crate::config::{self, HostConfig, IpmiConfig};
+use crate::error::{self, Error};
+use crate::libvirt;
+use crate::lockfile::LockFile;
+use crate::models::Machine;
+use crate::placement::{self, HostState};
+use crate::store;
+
+const STATE_FILE: &str = "hostpower.yaml";
+
+// how long apply waits for a woken host by default
+const WAKE_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Hosts bigiron powered down, with when in seconds since the epoch.
+// Placement leaves them out until they are woken again.
+pub struct Sleeping {
+    path: PathBuf,
+}
+
+impl Default for Sleeping {
+    fn default() -> Self {
+        Self::new(store::data_dir().join(STATE_FILE))
+    }
+}
+
+impl Sleeping {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    fn lock(&self) -> LockFile {
+        LockFile::new(self.path.with_extension("lock"))
+    }
+
+    pub fn load(&self) -> Result<BTreeMap<String, u64>, Error> {
+        if !self.path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        let buf = std::fs::read_to_string(&self.path)?;
+        Ok(serde_yaml::from_str(&buf)?)
+    }
+
+    fn set(&self, host: &str, asleep: bool) -> Result<(), Error> {
+        let lf = self.lock();
+        let _lock = lf.acquire();
+
+        let mut hosts = self.load()?;
+        match asleep {
+            true => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                hosts.insert(host.to_string(), now);
+            }
+            false => {
+                hosts.remove(host);
+            }
+        }
+        std::fs::write(&self.path, serde_yaml::to_string(&hosts)?)?;
+        Ok(())
+    }
+}
+
+// a host to power down and the host each of its machines moves to first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Drain {
+    pub host: String,
+    pub moves: Vec<(String, String)>,
+}
+
+// Empty the least used of the `managed` hosts as long as their machines fit
+// on the hosts that stay awake, keeping `spare` of them. `on` holds the
+// machines of each host.
+pub fn plan(
+    hosts: &[HostState],
+    on: &BTreeMap<String, Vec<Machine>>,
+    managed: &[String],
+    spare: u32,
+) -> Vec<Drain> {
+    let mut hosts = hosts.to_vec();
+    let mut on = on.clone();
+    let mut candidates: Vec<(String, u64)> = hosts
+        .iter()
+        .filter(|h| managed.contains(&h.name))
+        .map(|h| (h.name.clone(), h.used_memory))
+        .collect();
+    candidates.sort_by_key(|(_, used)| *used);
+    let most = candidates.len().saturating_sub(spare as usize);
+
+    let mut drains: Vec<Drain> = Vec::new();
+    for (name, _) in candidates {
+        if drains.len() >= most {
+            break;
+        }
+        let mut targets: Vec<HostState> = hosts
+            .iter()
+            .filter(|h| h.name != name && !drains.iter().any(|d| d.host == h.name))
+            .cloned()
+            .collect();
+        let machines = on.get(&name).cloned().unwrap_or_default();
+        let mut moves = Vec::new();
+        for m in &machines {
+            match placement::place(m, &mut targets).host {
+                Some(to) => moves.push((m.name.clone(), to)),
+                None => break,
+            }
+        }
+        if moves.len() < machines.len() {
+            continue;
+        }
+        // later hosts see the machines moved here
+        for t in targets {
+            if let Some(h) = hosts.iter_mut().find(|h| h.name == t.name) {
+                *h = t;
+            }
+        }
+        for (m, (_, to)) in machines.into_iter().zip(&moves) {
+            on.entry(to.clone()).or_default().push(m);
+        }
+        drains.push(Drain { host: name, moves });
+    }
+    drains
+}
+
+// Plan and, unless `dry_run`, carry out a consolidation of the running
+// machines.
+pub fn consolidate(dry_run: bool) -> Result<Vec<Drain>, Error> {
+    let cfg = config::load()?;
+    let policy = cfg
+        .scheduling
+        .power_saving
+        .clone()
+        .ok_or("scheduling.powerSaving is not set, hosts are never powered down")?;
+
+    // a host that can't be asked would look empty and get powered down
+    let mut running = Vec::new();
+    for m in store::open()?.list_machines()? {
+        if libvirt::is_active(&m.name)? {
+            running.push(m);
+        }
+    }
+    let hosts = placement::hosts(&running)?;
+    let local = hosts[0].name.clone();
+    let assigned = placement::Assignments::default().load()?;
+    let mut on: BTreeMap<String, Vec<Machine>> = BTreeMap::new();
+    for m in running {
+        let host = assigned.get(&m.name).unwrap_or(&local).clone();
+        on.entry(host).or_default().push(m);
+    }
+    // asleep already when they aren't among the hosts
+    let managed: Vec<String> = cfg
+        .hosts
+        .iter()
+        .filter(|h| h.power.is_some() && hosts.iter().any(|s| s.name == h.name))
+        .map(|h| h.name.clone())
+        .collect();
+
+    let drains = plan(&hosts, &on, &managed, policy.spare_hosts);
+    if dry_run {
+        return Ok(drains);
+    }
+    for d in &drains {
+        for (machine, to) in &d.moves {
+            let remote = cfg.host(to);
+            let uri = match remote {
+                Some(h) => h.uri.as_str(),
+                None => placement::local_uri(),
+            };
+            info!("migrating {} from {} to {}", machine, d.host, to);
+            libvirt::migrate(machine, uri)?;
+            placement::Assignments::default().assign(machine, remote.map(|h| h.name.as_str()))?;
+            audit::record("migrate", &format!("machine={} host={}", machine, to));
+        }
+        // listed in managed, so configured with power control
+        power_down(cfg.host(&d.host).unwrap())?;
+    }
+    Ok(drains)
+}
+
+// Power a host down, it has to be able to come back up.
+pub fn power_down(host: &HostConfig) -> Result<(), Error> {
+    let power = host
+        .power
+        .as_ref()
+        .ok_or_else(|| format!("host {} has no power control", host.name))?;
+    if power.ipmi.is_none() && power.wake_mac.is_none() {
+        return Err(format!("host {} has no ipmi or wakeMac to wake it with", host.name).into());
+    }
+    match (&power.ipmi, &power.sleep) {
+        (Some(ipmi), _) => ipmitool(ipmi, "soft")?,
+        (None, Some(argv)) => run(argv)?,
+        (None, None) => {
+            return Err(format!("host {} has no ipmi or sleep command", host.name).into())
+        }
+    }
+    Sleeping::default().set(&host.name, true)?;
+    info!("powered down host {}", host.name);
+    audit::record("host-power-down", &format!("host={}", host.name));
+    Ok(())
+}
+
+// Power a host up and wait for its libvirt to answer.
+pub fn wake(host: &HostConfig, timeout: Duration) -> Result<(), Error> {
+    let power = host
+        .power
+        .as_ref()
+        .ok_or_else(|| format!("host {} has no power control", host.name))?;
+    match (&power.ipmi, &power.wake_mac) {
+        (Some(ipmi), _) => ipmitool(ipmi, "on")?,
+        (None, Some(mac)) => {
+            let sock = UdpSocket::bind("0.0.0.0:0")?;
+            sock.set_broadcast(true)?;
+            sock.send_to(&magic_packet(mac)?, "255.255.255.255:9")?;
+        }
+        (None, None) => {
+            return Err(format!("host {} has no ipmi or wakeMac to wake it with", host.name).into())
+        }
+    }
+    info!("waking host {}", host.name);
+    let start = Instant::now();
+    while !libvirt::reachable(&host.uri) {
+        if start.elapsed() >= timeout {
+            return Err(format!(
+                "host {} didn't come up within {}s",
+                host.name,
+                timeout.as_secs()
+            )
+            .into());
+        }
+        std::thread::sleep(Duration::from_secs(5));
+    }
+    Sleeping::default().set(&host.name, false)?;
+    audit::record("host-wake", &format!("host={}", host.name));
+    Ok(())
+}
+
+fn wake_timeout(cfg: &config::Config) -> Duration {
+    cfg.scheduling
+        .power_saving
+        .as_ref()
+        .and_then(|p| p.wake_timeout)
+        .map(Duration::from_secs)
+        .unwrap_or(WAKE_TIMEOUT)
+}
+
+pub fn wake_host(name: &str) -> Result<(), Error> {
+    let cfg = config::load()?;
+    let host = cfg
+        .host(name)
+        .ok_or_else(|| error::not_found(format!("No host named '{}'", name)))?;
+    wake(host, wake_timeout(&cfg))
+}
+
+// Wake the first sleeping host with room for `machine`, returning its name.
+// None without power saving or a host that would fit.
+pub fn wake_for(machine: &Machine) -> Result<Option<String>, Error> {
+    let cfg = config::load()?;
+    if cfg.scheduling.power_saving.is_none() {
+        return Ok(None);
+    }
+    let timeout = wake_timeout(&cfg);
+    for name in Sleeping::default().load()?.keys() {
+        let host = match cfg.host(name) {
+            Some(h) => h,
+            None => continue,
+        };
+        let mut state = [HostState::remote(host, &[])?];
+        if placement::place(machine, &mut state).host.is_none() {
+            debug!("{} wouldn't fit on sleeping host {}", machine.name, name);
+            continue;
+        }
+        wake(host, timeout)?;
+        return Ok(Some(name.clone()));
+    }
+    Ok(None)
+}
+
+fn ipmitool(ipmi: &IpmiConfig, power: &str) -> Result<(), Error> {
+    let mut cmd = Command::new("ipmitool");
+    cmd.args(["-I", "lanplus", "-H", &ipmi.address, "-U", &ipmi.username])
+        .arg("-f")
+        .arg(&ipmi.password_file)
+        .args(["chassis", "power", power]);
+    debug!("Running: {:?}", cmd);
+    let out = cmd.output()?;
+    if !out.status.success() {
+        return Err(format!(
+            "ipmitool chassis power {} on {} failed: {}",
+            power,
+            ipmi.address,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn run(argv: &[String]) -> Result<(), Error> {
+    let (prog, args) = argv.split_first().ok_or("empty sleep command")?;
+    let mut cmd = Command::new(prog);
+    cmd.args(args);
+    debug!("Running: {:?}", cmd);
+    if !cmd.status()?.success() {
+        return Err(format!("{} failed", argv.join(" ")).into());
+    }
+    Ok(())
+}
+
+// six 0xff bytes followed by the mac 16 times
+fn magic_packet(mac: &str) -> Result<Vec<u8>, Error> {
+    let bytes: Vec<u8> = mac
+        .split([':', '-'])
+        .map(|b| u8::from_str_radix(b, 16))
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("invalid mac '{}'", mac))?;
+    if bytes.len() != 6 {
+        return Err(format!("invalid mac '{}'", mac).into());
+    }
+    let mut packet = vec![0xff; 6];
+    for _ in 0..16 {
+        packet.extend(&bytes);
+    }
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::host::Topology;
+
+    const GI: u64 = 1024 * 1024 * 1024;
+
+    fn machine(name: &str, memory: &str) -> Machine {
+        serde_yaml::from_str(&format!(
+            "
+            name: {}
+            status: null
+            spec:
+              cpu: 1
+              memory: {}
+              image:
+                url: file:///images/jammy.qcow2
+            ",
+            name, memory
+        ))
+        .unwrap()
+    }
+
+    fn host(name: &str, machines: &[&Machine]) -> HostState {
+        HostState {
+            name: name.into(),
+            local: false,
+            cpus: 8,
+            memory: 16 * GI,
+            used_memory: machines.iter().map(|m| m.spec.memory.bytes()).sum(),
+            topology: Topology::default(),
+            machines: machines
+                .iter()
+                .map(|m| (m.name.clone(), m.labels.clone()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_plan() {
+        let (web1, web2, db) = (
+            machine("web1", "2Gi"),
+            machine("web2", "2Gi"),
+            machine("db", "13Gi"),
+        );
+        let hosts = vec![
+            host("local", &[&db]),
+            host("hv2", &[&web1]),
+            host("hv3", &[&web2]),
+            host("hv4", &[]),
+        ];
+        let on = BTreeMap::from([
+            ("local".to_string(), vec![db.clone()]),
+            ("hv2".to_string(), vec![web1.clone()]),
+            ("hv3".to_string(), vec![web2.clone()]),
+        ]);
+        let managed: Vec<String> = ["hv2", "hv3", "hv4"]
+            .iter()
+            .map(|h| h.to_string())
+            .collect();
+
+        // the empty host goes first, then web1 joins db locally and web2
+        // has to stay as the local host is full
+        let drains = plan(&hosts, &on, &managed, 0);
+        assert_eq!(
+            drains,
+            vec![
+                Drain {
+                    host: "hv4".into(),
+                    moves: vec![]
+                },
+                Drain {
+                    host: "hv2".into(),
+                    moves: vec![("web1".into(), "local".into())]
+                },
+            ]
+        );
+
+        // spare hosts stay awake, hv3 already does
+        assert_eq!(plan(&hosts, &on, &managed, 1), drains);
+        let drains = plan(&hosts, &on, &managed, 2);
+        assert_eq!(drains.len(), 1);
+        assert_eq!(drains[0].host, "hv4");
+        // hosts without power control are never drained
+        assert!(plan(&hosts, &on, &[], 0).is_empty());
+    }
+
+    #[test]
+    fn test_magic_packet() {
+        let p = magic_packet("52:54:00:ab:cd:ef").unwrap();
+        assert_eq!(p.len(), 102);
+        assert_eq!(&p[..6], &[0xff; 6]);
+        assert_eq!(&p[96..], &[0x52, 0x54, 0x00, 0xab, 0xcd, 0xef]);
+        assert!(magic_packet("52:54:00:ab:cd").is_err());
+        assert!(magic_packet("not a mac").is_err());
+    }
+}
@@ -0,0 +1,278 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Fast local tier for base images. Images in use by machines are mirrored
+// onto a fast path (NVMe, tmpfs) and overlays are created against the copy,
+// so first boots don't read the base image from the slow repo. Copies no
+// machine uses are evicted least recently used first when space runs out.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::config::{self, ImageCacheConfig};
+use crate::error::Error;
+use crate::imagerepo::Image;
+use crate::lockfile::LockFile;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    size: u64,
+    // seconds since the epoch
+    last_used: u64,
+    // machines with overlays on the copy, it can't be evicted while any
+    users: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Index {
+    images: BTreeMap<String, Entry>,
+}
+
+impl Index {
+    fn used(&self) -> u64 {
+        self.images.values().map(|e| e.size).sum()
+    }
+
+    // Unused copies to evict, oldest first, to make room for `need` bytes.
+    // None if evicting everything that can go still isn't enough.
+    fn evictions(&self, need: u64, capacity: u64) -> Option<Vec<String>> {
+        let mut free = capacity.saturating_sub(self.used());
+        let mut idle: Vec<(&String, &Entry)> = self
+            .images
+            .iter()
+            .filter(|(_, e)| e.users.is_empty())
+            .collect();
+        idle.sort_by_key(|(_, e)| e.last_used);
+
+        let mut evict = Vec::new();
+        for (id, e) in idle {
+            if free >= need {
+                break;
+            }
+            free += e.size;
+            evict.push(id.clone());
+        }
+        match free >= need {
+            true => Some(evict),
+            false => None,
+        }
+    }
+}
+
+pub struct Cache {
+    path: PathBuf,
+    // bytes, None is bounded only by the filesystem
+    capacity: Option<u64>,
+}
+
+impl Cache {
+    // the configured cache, None when there is no fast tier
+    pub fn open() -> Result<Option<Self>, Error> {
+        Self::from_config(&config::load()?.image_cache)
+    }
+
+    fn from_config(c: &ImageCacheConfig) -> Result<Option<Self>, Error> {
+        let path = match &c.path {
+            Some(p) => p.clone(),
+            None => return Ok(None),
+        };
+        let capacity = c.size.map(|s| s.bytes());
+        std::fs::create_dir_all(&path)?;
+        Ok(Some(Self { path, capacity }))
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.path.join("index.yaml")
+    }
+
+    fn lockfile(&self) -> LockFile {
+        LockFile::new(self.path.join(".lock"))
+    }
+
+    fn load(&self) -> Result<Index, Error> {
+        let path = self.index_path();
+        if !path.exists() {
+            return Ok(Index::default());
+        }
+        let buf = std::fs::read_to_string(&path)?;
+        Ok(serde_yaml::from_str(&buf)?)
+    }
+
+    fn save(&self, index: &Index) -> Result<(), Error> {
+        std::fs::write(self.index_path(), serde_yaml::to_string(index)?)?;
+        Ok(())
+    }
+
+    // Path a machine's overlay should use as its backing file: the fast copy
+    // of the image, made now if needed, or the repo copy when it won't fit.
+    pub fn warm(&self, image: &Image, machine: &str) -> Result<PathBuf, Error> {
+        let lf = self.lockfile();
+        let _lock = lf.acquire();
+        let mut index = self.load()?;
+        let copy = self.path.join(&image.id);
+
+        if !(index.images.contains_key(&image.id) && copy.exists()) {
+            let size = std::fs::metadata(&image.path)?.len();
+            let evict = match self.capacity {
+                Some(capacity) => index.evictions(size, capacity),
+                None => Some(Vec::new()),
+            };
+            let evict = match evict {
+                Some(e) => e,
+                None => {
+                    warn!(
+                        "image {} doesn't fit in the image cache, using the repo copy",
+                        image.id
+                    );
+                    return Ok(image.path.clone());
+                }
+            };
+            for id in evict {
+                info!("evicting image {} from the image cache", id);
+                remove_copy(&self.path.join(&id))?;
+                index.images.remove(&id);
+            }
+
+            // copy next to the final name so a partial copy is never used
+            info!("copying image {} to the image cache", image.id);
+            let tmp = copy.with_extension("part");
+            if let Err(e) = std::fs::copy(&image.path, &tmp) {
+                let _ = std::fs::remove_file(&tmp);
+                warn!(
+                    "error copying image {} to the image cache, using the repo copy: {}",
+                    image.id, e
+                );
+                return Ok(image.path.clone());
+            }
+            std::fs::rename(&tmp, &copy)?;
+            index.images.insert(
+                image.id.clone(),
+                Entry {
+                    size,
+                    ..Default::default()
+                },
+            );
+        }
+
+        let e = index.images.get_mut(&image.id).unwrap();
+        e.last_used = now();
+        e.users.insert(machine.to_string());
+        self.save(&index)?;
+        Ok(copy)
+    }
+
+    // whether `path` is a copy in the cache
+    pub fn holds(&self, path: &Path) -> bool {
+        path.parent() == Some(self.path.as_path())
+    }
+
+    pub fn rename_user(&self, machine: &str, new: &str) -> Result<(), Error> {
+        let lf = self.lockfile();
+        let _lock = lf.acquire();
+        let mut index = self.load()?;
+        let mut changed = false;
+        for e in index.images.values_mut() {
+            if e.users.remove(machine) {
+                e.users.insert(new.to_string());
+                changed = true;
+            }
+        }
+        if changed {
+            self.save(&index)?;
+        }
+        Ok(())
+    }
+
+    // A machine no longer needs its copy, which stays cached until evicted.
+    pub fn release(&self, machine: &str) -> Result<(), Error> {
+        let lf = self.lockfile();
+        let _lock = lf.acquire();
+        let mut index = self.load()?;
+        let mut changed = false;
+        for e in index.images.values_mut() {
+            if e.users.remove(machine) {
+                e.last_used = now();
+                changed = true;
+            }
+        }
+        if changed {
+            self.save(&index)?;
+        }
+        Ok(())
+    }
+}
+
+fn remove_copy(path: &Path) -> Result<(), Error> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(size: u64, last_used: u64, users: &[&str]) -> Entry {
+        Entry {
+            size,
+            last_used,
+            users: users.iter().map(|u| u.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_evictions() {
+        let mut index = Index::default();
+        index.images.insert("a".into(), entry(40, 300, &[]));
+        index.images.insert("b".into(), entry(30, 100, &[]));
+        index.images.insert("c".into(), entry(20, 50, &["web1"]));
+
+        // 10 free already
+        assert_eq!(index.evictions(10, 100), Some(vec![]));
+        // least recently used first, in use copies stay
+        assert_eq!(index.evictions(30, 100), Some(vec!["b".to_string()]));
+        assert_eq!(
+            index.evictions(80, 100),
+            Some(vec!["b".to_string(), "a".to_string()])
+        );
+        assert_eq!(index.evictions(81, 100), None);
+    }
+
+    #[test]
+    fn test_holds() {
+        let cache = Cache {
+            path: PathBuf::from("/dev/shm/bigiron"),
+            capacity: None,
+        };
+        assert!(cache.holds(Path::new("/dev/shm/bigiron/abc123")));
+        assert!(!cache.holds(Path::new("/var/lib/bigiron/images/abc123")));
+        assert!(!cache.holds(Path::new("/dev/shm/bigiron")));
+    }
+}
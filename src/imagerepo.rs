@@ -15,18 +15,30 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use hex;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use tracing::{debug, info};
 use url::Url;
 
+use crate::audit;
+use crate::bus;
 use crate::error::Error;
 use crate::lockfile::LockFile;
+use crate::qemu;
+use crate::store::{self, StoreBackend};
 
 pub struct ImageRepo {
     path: PathBuf,
+    store: Box<dyn StoreBackend>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -35,25 +47,445 @@ pub struct Image {
     pub path: PathBuf,
     pub origin: String,
     pub format: String,
+    // guest architecture, None if it couldn't be determined
+    #[serde(default)]
+    pub arch: Option<String>,
 }
 
-impl ImageRepo {
-    pub fn new() -> Self {
-        let path = Path::new("/var/lib/bigiron/images");
-        if !path.exists() {
-            std::fs::create_dir_all(path).expect("error creating image repo directory");
+// What a catalog name refers to: a fixed image, or a url that is imported
+// whenever the name is used, so it follows changes to the file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CatalogEntry {
+    Id(String),
+    Url(String),
+}
+
+impl std::fmt::Display for CatalogEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CatalogEntry::Id(id) => write!(f, "{}", id),
+            CatalogEntry::Url(url) => write!(f, "{}", url),
         }
+    }
+}
+
+// names like ubuntu-22.04 or debian:12
+fn check_name(name: &str) -> Result<(), Error> {
+    let ok = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._:-".contains(c));
+    match ok {
+        true => Ok(()),
+        false => Err(format!("invalid image name '{}'", name).into()),
+    }
+}
+
+// canonical architecture name for common aliases, as used by qemu and libvirt
+pub fn normalize_arch(arch: &str) -> String {
+    match arch {
+        "amd64" | "x86-64" | "x64" => "x86_64".to_string(),
+        "arm64" => "aarch64".to_string(),
+        "powerpc64le" | "ppc64el" => "ppc64le".to_string(),
+        a => a.to_string(),
+    }
+}
+
+pub fn host_arch() -> String {
+    normalize_arch(match std::env::consts::ARCH {
+        "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+        a => a,
+    })
+}
+
+// architecture of a guest given its spec.arch, the host's when unset
+pub fn guest_arch(arch: Option<&str>) -> String {
+    arch.map(normalize_arch).unwrap_or_else(host_arch)
+}
+
+// Cloud images usually carry the architecture in their file name, e.g.
+// jammy-server-cloudimg-s390x.img, use that when nothing else is known.
+pub fn guess_arch(name: &str) -> Option<String> {
+    let name = name.to_lowercase();
+    let known = [
+        "x86_64", "amd64", "aarch64", "arm64", "s390x", "ppc64le", "ppc64el", "riscv64",
+    ];
+    name.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .find(|t| known.contains(t))
+        .map(normalize_arch)
+}
+
+// What a new image is kept as: qcow2 and raw are used as they are, other
+// formats qemu-img knows are converted to qcow2.
+fn stored_format(detected: &str) -> Result<Option<&'static str>, Error> {
+    match detected {
+        "qcow2" => Ok(Some("qcow2")),
+        "raw" => Ok(Some("raw")),
+        "vmdk" | "vdi" | "vhdx" | "vpc" | "qed" => Ok(None),
+        f => Err(format!("unsupported image format {}", f).into()),
+    }
+}
+
+// FICLONE from linux/fs.h
+const FICLONE: libc::c_ulong = 0x40049409;
+
+// A source file as last imported. A file with the same size and mtime is
+// taken to be unchanged, so it is neither hashed nor copied again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Source {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    id: String,
+}
+
+impl Source {
+    fn matches(&self, md: &std::fs::Metadata) -> bool {
+        self.size == md.len() && self.mtime == md.mtime() && self.mtime_nsec == md.mtime_nsec()
+    }
+}
+
+// Put a copy of `from` at `to` if the filesystem can do it without copying
+// the data. A reflink shares blocks until either side is written. A
+// hardlink shares the file itself, so it is only used for read-only sources
+// that can't change under the overlays layered on them.
+fn clone_cheap(from: &Path, to: &Path) -> Result<Option<&'static str>, Error> {
+    let src = File::open(from)?;
+    let dst = File::create(to)?;
+    if unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE, src.as_raw_fd()) } == 0 {
+        return Ok(Some("reflinked"));
+    }
+    drop(dst);
+    std::fs::remove_file(to)?;
+    let readonly = src.metadata()?.mode() & 0o222 == 0;
+    if readonly && std::fs::hard_link(from, to).is_ok() {
+        return Ok(Some("hardlinked"));
+    }
+    Ok(None)
+}
+
+// Hash everything read from `src`, writing it on to `dst` if given.
+fn stream(
+    src: &mut dyn Read,
+    mut dst: Option<&mut dyn Write>,
+    h: &mut Sha256,
+    progress: &mut Progress,
+) -> Result<(), Error> {
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        let n = src.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        h.update(&buf[..n]);
+        if let Some(dst) = dst.as_mut() {
+            dst.write_all(&buf[..n])?;
+        }
+        progress.update(n as u64);
+    }
+}
+
+// Import progress, as a status line on a terminal or log lines otherwise.
+struct Progress {
+    what: String,
+    total: u64,
+    done: u64,
+    start: Instant,
+    last: Instant,
+    tty: bool,
+}
 
+impl Progress {
+    fn new(what: &str, total: u64) -> Self {
+        let now = Instant::now();
         Self {
-            path: path.to_path_buf(),
+            what: what.to_string(),
+            total,
+            done: 0,
+            start: now,
+            last: now,
+            tty: unsafe { libc::isatty(2) } == 1,
+        }
+    }
+
+    fn update(&mut self, n: u64) {
+        self.done += n;
+        let every = match self.tty {
+            true => Duration::from_millis(200),
+            false => Duration::from_secs(5),
+        };
+        if self.last.elapsed() < every {
+            return;
+        }
+        self.last = Instant::now();
+        let line = self.line();
+        match self.tty {
+            true => eprint!("\r{}\x1b[K", line),
+            false => info!("{}", line),
         }
     }
 
-    fn lockfile(&self) -> LockFile {
+    fn line(&self) -> String {
+        let eta = match eta(self.done, self.total, self.start.elapsed()) {
+            Some(eta) => format!("{}s", eta.as_secs()),
+            None => "-".to_string(),
+        };
+        format!(
+            "importing {}: {}Mi of {}Mi ({}%), eta {}",
+            self.what,
+            self.done >> 20,
+            self.total >> 20,
+            (self.done * 100).checked_div(self.total).unwrap_or(100),
+            eta
+        )
+    }
+
+    fn finish(&self) {
+        if self.tty && self.start.elapsed() >= Duration::from_millis(200) {
+            eprintln!("\r{}\x1b[K", self.line());
+        }
+    }
+}
+
+// time left at the rate so far, None until something was read
+fn eta(done: u64, total: u64, elapsed: Duration) -> Option<Duration> {
+    if done == 0 {
+        return None;
+    }
+    let left = total.saturating_sub(done) as f64;
+    Some(Duration::from_secs_f64(
+        left * elapsed.as_secs_f64() / done as f64,
+    ))
+}
+
+impl ImageRepo {
+    pub fn new() -> Result<Self, Error> {
+        let path = store::data_dir().join("images");
+        if !path.exists() {
+            std::fs::create_dir_all(&path)?;
+        }
+
+        Ok(Self {
+            path,
+            store: store::open()?,
+        })
+    }
+
+    pub fn lockfile(&self) -> LockFile {
         LockFile::new(self.path.join(".lock"))
     }
 
-    pub fn add_from_url(&self, url: Url) -> Result<Image, Error> {
+    pub fn sources_path(&self) -> PathBuf {
+        self.path.join("sources.yaml")
+    }
+
+    fn load_sources(&self) -> Result<BTreeMap<PathBuf, Source>, Error> {
+        let path = self.sources_path();
+        if !path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        Ok(serde_yaml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    fn save_sources(&self, sources: &BTreeMap<PathBuf, Source>) -> Result<(), Error> {
+        std::fs::write(self.sources_path(), serde_yaml::to_string(sources)?)?;
+        Ok(())
+    }
+
+    pub fn catalog_path(&self) -> PathBuf {
+        self.path.join("catalog.yaml")
+    }
+
+    pub fn catalog(&self) -> Result<BTreeMap<String, CatalogEntry>, Error> {
+        let path = self.catalog_path();
+        if !path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        Ok(serde_yaml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    fn save_catalog(&self, catalog: &BTreeMap<String, CatalogEntry>) -> Result<(), Error> {
+        std::fs::write(self.catalog_path(), serde_yaml::to_string(catalog)?)?;
+        Ok(())
+    }
+
+    // Give an image id or url a name in the catalog, replacing whatever the
+    // name pointed at before.
+    pub fn tag(&self, name: &str, target: &str) -> Result<CatalogEntry, Error> {
+        check_name(name)?;
+        let lf = self.lockfile();
+        let _lock = lf.acquire();
+
+        let entry = match Url::parse(target) {
+            Ok(url) => CatalogEntry::Url(url.to_string()),
+            Err(_) => match self.store.get_image(target)? {
+                Some(img) => CatalogEntry::Id(img.id),
+                None => return Err(format!("No image with id='{}'", target).into()),
+            },
+        };
+        let mut catalog = self.catalog()?;
+        catalog.insert(name.to_string(), entry.clone());
+        self.save_catalog(&catalog)?;
+        bus::publish(bus::Kind::Image, name);
+        audit::record("tag-image", &format!("name={} image={}", name, entry));
+        Ok(entry)
+    }
+
+    pub fn untag(&self, name: &str) -> Result<(), Error> {
+        let lf = self.lockfile();
+        let _lock = lf.acquire();
+
+        let mut catalog = self.catalog()?;
+        if catalog.remove(name).is_none() {
+            return Err(format!("No image named '{}'", name).into());
+        }
+        self.save_catalog(&catalog)?;
+        bus::publish(bus::Kind::Image, name);
+        audit::record("untag-image", &format!("name={}", name));
+        Ok(())
+    }
+
+    // the image a catalog name refers to, imported first for urls
+    pub fn lookup(&self, name: &str, arch: Option<&str>) -> Result<Image, Error> {
+        let entry = {
+            let lf = self.lockfile();
+            let _lock = lf.acquire();
+            self.catalog()?.remove(name)
+        };
+        match entry {
+            Some(CatalogEntry::Id(id)) => self.get(&id),
+            Some(CatalogEntry::Url(url)) => self.add_from_url(Url::parse(&url)?, arch),
+            None => Err(format!("No image named '{}'", name).into()),
+        }
+    }
+
+    // Bring a file into the repo under its hash, reading it once. A cheap
+    // clone is hashed where it lands, anything else while it is copied.
+    // Formats other than qcow2 and raw are converted, still under the hash
+    // of the file they came from.
+    fn import_file(&self, from: &Path) -> Result<String, Error> {
+        let tmp = self
+            .path
+            .join(format!(".import-{}.part", std::process::id()));
+        let _ = std::fs::remove_file(&tmp);
+
+        let mut progress =
+            Progress::new(&from.display().to_string(), std::fs::metadata(from)?.len());
+        let mut h = Sha256::new();
+        let how = match clone_cheap(from, &tmp)? {
+            Some(how) => {
+                stream(&mut File::open(&tmp)?, None, &mut h, &mut progress)?;
+                how
+            }
+            None => {
+                let mut dst = File::create(&tmp)?;
+                stream(
+                    &mut File::open(from)?,
+                    Some(&mut dst),
+                    &mut h,
+                    &mut progress,
+                )?;
+                dst.sync_all()?;
+                "copied"
+            }
+        };
+        progress.finish();
+
+        let tmp_image = qemu::Image { path: tmp.clone() };
+        let detected = tmp_image.format()?;
+        if stored_format(&detected)?.is_none() {
+            info!("converting {} image {:?} to qcow2", detected, from);
+            let converted = tmp.with_extension("qcow2");
+            if let Err(e) = tmp_image.convert_to_qcow2(&detected, &converted) {
+                let _ = std::fs::remove_file(&converted);
+                let _ = std::fs::remove_file(&tmp);
+                return Err(e);
+            }
+            std::fs::rename(&converted, &tmp)?;
+        }
+
+        self.keep(&tmp, h, how, &from.display().to_string())
+    }
+
+    // move a file hashed into `h` to its place in the repo, returning its id
+    fn keep(&self, tmp: &Path, h: Sha256, how: &str, from: &str) -> Result<String, Error> {
+        let id = hex::encode(h.finalize());
+        let to = self.path.join(&id);
+        if to.exists() {
+            std::fs::remove_file(tmp)?;
+        } else {
+            std::fs::rename(tmp, &to)?;
+            info!("{} new image {} to {:?}", how, from, to);
+        }
+        Ok(id)
+    }
+
+    // Export an internal snapshot of a machine's disk into the repo, so the
+    // machines layered on it don't depend on the source machine's files and
+    // are counted as users of the image. A snapshot is exported once, later
+    // clones find it by its origin.
+    pub fn add_snapshot(
+        &self,
+        machine: &str,
+        disk: &Path,
+        snapshot: &str,
+        arch: Option<String>,
+    ) -> Result<Image, Error> {
+        let lf = self.lockfile();
+        let _lock = lf.acquire();
+
+        let disk = qemu::Image {
+            path: disk.to_path_buf(),
+        };
+        let taken = disk
+            .snapshot_taken(snapshot)?
+            .ok_or_else(|| format!("'{}' has no snapshot '{}'", machine, snapshot))?;
+        let origin = format!("machine:{}@{}?taken={}", machine, snapshot, taken);
+        if let Some(img) = self
+            .store
+            .list_images()?
+            .into_iter()
+            .find(|i| i.origin == origin && i.path.exists())
+        {
+            debug!("{}@{} was exported before as {}", machine, snapshot, img.id);
+            return Ok(img);
+        }
+
+        let tmp = self
+            .path
+            .join(format!(".export-{}.part", std::process::id()));
+        let _ = std::fs::remove_file(&tmp);
+        let what = format!("{}@{}", machine, snapshot);
+        let exported = disk.export_snapshot(snapshot, &tmp).and_then(|_| {
+            let mut progress = Progress::new(&what, std::fs::metadata(&tmp)?.len());
+            let mut h = Sha256::new();
+            stream(&mut File::open(&tmp)?, None, &mut h, &mut progress)?;
+            progress.finish();
+            self.keep(&tmp, h, "exported", &what)
+        });
+        let id = match exported {
+            Ok(id) => id,
+            Err(e) => {
+                let _ = std::fs::remove_file(&tmp);
+                return Err(e);
+            }
+        };
+
+        let img = Image {
+            path: self.path.join(&id),
+            id,
+            origin,
+            format: "qcow2".to_string(),
+            arch,
+        };
+        self.store.put_image(&img)?;
+        bus::publish_action(bus::Kind::Image, &img.id, bus::Action::Imported);
+        Ok(img)
+    }
+
+    pub fn add_from_url(&self, url: Url, arch: Option<&str>) -> Result<Image, Error> {
         match url.scheme() {
             "file" => {}
             //"http" | "https" | "file" => {},
@@ -67,28 +499,50 @@ impl ImageRepo {
                 .to_file_path()
                 .expect("error converting URL to filepath");
 
-            let mut h = Sha256::new();
-            let mut f = std::fs::File::open(&from_path)?;
-            let _ = std::io::copy(&mut f, &mut h)?;
-            let r = h.finalize();
-            let hx = hex::encode(r);
-
+            let from_path = from_path.canonicalize()?;
+            let md = std::fs::metadata(&from_path)?;
+            let mut sources = self.load_sources()?;
+            let hx = match sources
+                .get(&from_path)
+                .filter(|s| s.matches(&md) && self.path.join(&s.id).exists())
+            {
+                Some(s) => {
+                    debug!("{} is unchanged since it was imported", from_path.display());
+                    s.id.clone()
+                }
+                None => {
+                    let id = self.import_file(&from_path)?;
+                    sources.insert(
+                        from_path.clone(),
+                        Source {
+                            size: md.len(),
+                            mtime: md.mtime(),
+                            mtime_nsec: md.mtime_nsec(),
+                            id: id.clone(),
+                        },
+                    );
+                    self.save_sources(&sources)?;
+                    id
+                }
+            };
             let to_path = self.path.join(&hx);
-            if !to_path.exists() {
-                eprintln!("copying new image from {:?} to {:?}", from_path, to_path);
-                std::fs::copy(&from_path, &to_path).expect("error copying image file to repo");
+            let detected = qemu::Image {
+                path: to_path.clone(),
             }
+            .format()?;
+            let format = stored_format(&detected)?
+                .ok_or_else(|| format!("image {} was stored as {}", hx, detected))?;
 
             let img = Image {
                 id: hx.clone(),
                 path: to_path,
                 origin: url.to_string(),
-                format: "qcow2".to_string(),
+                format: format.to_string(),
+                arch: arch.map(normalize_arch).or_else(|| guess_arch(url.path())),
             };
 
-            let imf = self.path.join(format!("{}.json", hx));
-            let buf = serde_yaml::to_string(&img).expect("error serializing image json file");
-            std::fs::write(&imf, buf.as_bytes()).expect("error writing image json file");
+            self.store.put_image(&img)?;
+            bus::publish_action(bus::Kind::Image, &img.id, bus::Action::Imported);
 
             return Ok(img);
         }
@@ -96,13 +550,129 @@ impl ImageRepo {
         unimplemented!();
     }
 
+    // Drop an image along with catalog names and sources that lead to it.
+    // Disks still layered on it must be flattened first.
+    pub fn remove(&self, id: &str) -> Result<(), Error> {
+        let lf = self.lockfile();
+        let _lock = lf.acquire();
+
+        let img = self
+            .store
+            .get_image(id)?
+            .ok_or_else(|| format!("No image with id='{}'", id))?;
+        let mut catalog = self.catalog()?;
+        catalog.retain(|_, e| *e != CatalogEntry::Id(id.to_string()));
+        self.save_catalog(&catalog)?;
+        let mut sources = self.load_sources()?;
+        sources.retain(|_, s| s.id != id);
+        self.save_sources(&sources)?;
+
+        self.store.remove_image(id)?;
+        if let Err(e) = std::fs::remove_file(&img.path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+        bus::publish_action(bus::Kind::Image, id, bus::Action::Deleted);
+        Ok(())
+    }
+
     pub fn get(&self, id: &str) -> Result<Image, Error> {
         let lf = self.lockfile();
         let _lock = lf.acquire();
 
-        let imf = self.path.join(format!("{}.json", id));
-        let f = std::fs::File::open(&imf)?;
-        let img: Image = serde_yaml::from_reader(&f)?;
-        Ok(img)
+        match self.store.get_image(id)? {
+            Some(img) => Ok(img),
+            None => Err(format!("No image with id='{}'", id).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_import() {
+        let dir = TestDir::new("clone");
+        let from = dir.join("src.img");
+        let to = dir.join("dst.img");
+        std::fs::write(&from, b"qcow2 image").unwrap();
+
+        // whichever way it gets there, the copy and its hash match the source
+        if clone_cheap(&from, &to).unwrap().is_none() {
+            assert!(!to.exists());
+        }
+        let mut h = Sha256::new();
+        let mut out = Vec::new();
+        let mut progress = Progress::new("src.img", 11);
+        stream(
+            &mut File::open(&from).unwrap(),
+            Some(&mut out),
+            &mut h,
+            &mut progress,
+        )
+        .unwrap();
+        assert_eq!(out, b"qcow2 image");
+        assert_eq!(progress.done, 11);
+        assert_eq!(
+            hex::encode(h.finalize()),
+            hex::encode(Sha256::digest(b"qcow2 image"))
+        );
+
+        let md = std::fs::metadata(&from).unwrap();
+        let s = Source {
+            size: md.len(),
+            mtime: md.mtime(),
+            mtime_nsec: md.mtime_nsec(),
+            id: "abc".into(),
+        };
+        assert!(s.matches(&md));
+        std::fs::write(&from, b"changed").unwrap();
+        assert!(!s.matches(&std::fs::metadata(&from).unwrap()));
+    }
+
+    #[test]
+    fn test_check_name() {
+        assert!(check_name("ubuntu-22.04").is_ok());
+        assert!(check_name("debian:12").is_ok());
+        assert!(check_name("").is_err());
+        assert!(check_name("../etc").is_err());
+        assert!(check_name("my image").is_err());
+    }
+
+    #[test]
+    fn test_stored_format() {
+        assert_eq!(stored_format("raw").unwrap(), Some("raw"));
+        assert_eq!(stored_format("vmdk").unwrap(), None);
+        assert!(stored_format("dmg").is_err());
+    }
+
+    #[test]
+    fn test_eta() {
+        assert_eq!(eta(0, 100, Duration::from_secs(1)), None);
+        assert_eq!(
+            eta(25, 100, Duration::from_secs(10)),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(eta(100, 100, Duration::from_secs(10)), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_guess_arch() {
+        assert_eq!(
+            guess_arch("/images/jammy-server-cloudimg-s390x.img"),
+            Some("s390x".to_string())
+        );
+        assert_eq!(
+            guess_arch("/images/debian-12-generic-amd64.qcow2"),
+            Some("x86_64".to_string())
+        );
+        assert_eq!(
+            guess_arch("/images/Fedora-Cloud-Base-38-1.6.x86_64.qcow2"),
+            Some("x86_64".to_string())
+        );
+        assert_eq!(guess_arch("/images/my-image.qcow2"), None);
     }
 }
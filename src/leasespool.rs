@@ -0,0 +1,299 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// On-disk spool for dnsmasq lease events.
+//
+// dnsmasq runs bigiron-dhcpbridge once per lease change and never retries,
+// so an event that can't be applied to the netstate (lock held, file
+// corrupt) would otherwise be lost. Failed events are written to the spool
+// and retried, oldest first, on the next invocation. Events that keep
+// failing are moved to a dead-letter log for manual inspection.
+
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::error::Error;
+use crate::lockfile::LockFile;
+use crate::network;
+use crate::store;
+
+const SPOOL_DIR: &str = "lease-spool";
+const DEAD_LETTER: &str = "lease-deadletter.log";
+
+// attempts before an event is given up on
+const MAX_ATTEMPTS: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LeaseAction {
+    Add,
+    Old,
+    Del,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeaseEvent {
+    pub action: LeaseAction,
+    pub mac: String,
+    pub addr: String,
+    pub hostname: Option<String>,
+    // unix time the lease runs out, if dnsmasq told us
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+impl LeaseEvent {
+    pub fn new(action: LeaseAction, mac: &str, addr: &str, hostname: Option<String>) -> Self {
+        Self {
+            action,
+            mac: mac.to_string(),
+            addr: addr.to_string(),
+            hostname,
+            expires: None,
+            attempts: 0,
+            last_error: None,
+        }
+    }
+
+    // apply the event to the netstate
+    pub fn apply(&self) -> Result<(), Error> {
+        match self.action {
+            LeaseAction::Add | LeaseAction::Old => {
+                network::add_lease(&self.mac, &self.addr, self.hostname.clone(), self.expires)
+            }
+            LeaseAction::Del => network::del_lease(&self.mac, &self.addr, self.hostname.clone()),
+        }
+    }
+}
+
+pub struct Spool {
+    dir: PathBuf,
+    dead_letter: PathBuf,
+}
+
+impl Default for Spool {
+    fn default() -> Self {
+        let dir = store::data_dir();
+        Self::new(dir.join(SPOOL_DIR), dir.join(DEAD_LETTER))
+    }
+}
+
+impl Spool {
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(dir: P, dead_letter: Q) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            dead_letter: dead_letter.as_ref().to_path_buf(),
+        }
+    }
+
+    // Retry spooled events, then apply `event`. Returns the number of
+    // events left in the spool.
+    pub fn submit(&self, event: LeaseEvent) -> Result<usize, Error> {
+        self.process(Some(event), LeaseEvent::apply)
+    }
+
+    // retry spooled events, returning the number still queued
+    pub fn replay(&self) -> Result<usize, Error> {
+        self.process(None, LeaseEvent::apply)
+    }
+
+    // spooled events, oldest first
+    pub fn pending(&self) -> Result<Vec<LeaseEvent>, Error> {
+        Ok(self.entries()?.into_iter().map(|(_, e)| e).collect())
+    }
+
+    fn process<F>(&self, new: Option<LeaseEvent>, apply: F) -> Result<usize, Error>
+    where
+        F: Fn(&LeaseEvent) -> Result<(), Error>,
+    {
+        std::fs::create_dir_all(&self.dir)?;
+        let lf = LockFile::new(self.dir.with_extension("lock"));
+        let _lock = lf.acquire();
+
+        // events for an address are applied in order, so once one fails the
+        // later ones for the same address wait behind it
+        let mut blocked = HashSet::new();
+        let mut queued = 0;
+
+        let mut events: Vec<(Option<PathBuf>, LeaseEvent)> = self
+            .entries()?
+            .into_iter()
+            .map(|(p, e)| (Some(p), e))
+            .collect();
+        if let Some(e) = new {
+            events.push((None, e));
+        }
+
+        for (path, mut event) in events {
+            let result = if blocked.contains(&event.addr) {
+                Err("waiting on an earlier event for the same address".into())
+            } else {
+                apply(&event)
+            };
+
+            let path = match (result, path) {
+                (Ok(()), Some(path)) => {
+                    std::fs::remove_file(path)?;
+                    continue;
+                }
+                (Ok(()), None) => continue,
+                (Err(e), path) => {
+                    event.attempts += 1;
+                    event.last_error = Some(e.to_string());
+                    path.unwrap_or_else(|| self.new_entry_path())
+                }
+            };
+
+            if event.attempts >= MAX_ATTEMPTS {
+                error!(
+                    "giving up on lease event {:?} {} {} after {} attempts: {}",
+                    event.action,
+                    event.mac,
+                    event.addr,
+                    event.attempts,
+                    event.last_error.as_deref().unwrap_or_default()
+                );
+                self.bury(&event)?;
+                if path.exists() {
+                    std::fs::remove_file(&path)?;
+                }
+                continue;
+            }
+
+            warn!(
+                "spooling lease event {:?} {} {} (attempt {}): {}",
+                event.action,
+                event.mac,
+                event.addr,
+                event.attempts,
+                event.last_error.as_deref().unwrap_or_default()
+            );
+            std::fs::write(&path, serde_yaml::to_string(&event)?)?;
+            blocked.insert(event.addr.clone());
+            queued += 1;
+        }
+
+        Ok(queued)
+    }
+
+    fn entries(&self) -> Result<Vec<(PathBuf, LeaseEvent)>, Error> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(&self.dir)?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension() == Some(OsStr::new("yaml")))
+            .collect();
+        // names start with a zero padded timestamp so they sort by age
+        paths.sort();
+
+        let mut r = Vec::new();
+        for path in paths {
+            let buf = std::fs::read_to_string(&path)?;
+            match serde_yaml::from_str(&buf) {
+                Ok(event) => r.push((path, event)),
+                Err(e) => {
+                    // keep the unreadable file around but out of the way
+                    error!("unreadable lease event {:?}: {}", path, e);
+                    std::fs::rename(&path, path.with_extension("bad"))?;
+                }
+            }
+        }
+        Ok(r)
+    }
+
+    fn new_entry_path(&self) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        self.dir
+            .join(format!("{:020}-{}.yaml", nanos, std::process::id()))
+    }
+
+    // append an event to the dead-letter log, one json object per line
+    fn bury(&self, event: &LeaseEvent) -> Result<(), Error> {
+        let mut f = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.dead_letter)?;
+        writeln!(f, "{}", serde_json::to_string(event)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_spool_retry_and_dead_letter() {
+        let base = TestDir::new("spool");
+        let spool = Spool::new(base.join("spool"), base.join("dead.log"));
+
+        let fail = RefCell::new(true);
+        let applied = RefCell::new(Vec::new());
+        let apply = |e: &LeaseEvent| -> Result<(), Error> {
+            if *fail.borrow() {
+                return Err("netstate locked".into());
+            }
+            applied.borrow_mut().push((e.action, e.addr.clone()));
+            Ok(())
+        };
+
+        let add = LeaseEvent::new(LeaseAction::Add, "00:16:3e:00:00:01", "172.20.0.2", None);
+        let del = LeaseEvent::new(LeaseAction::Del, "00:16:3e:00:00:01", "172.20.0.2", None);
+        assert_eq!(spool.process(Some(add), apply).unwrap(), 1);
+        assert_eq!(spool.process(Some(del), apply).unwrap(), 2);
+        assert_eq!(spool.pending().unwrap()[0].attempts, 2);
+
+        // once the netstate is usable again events apply in their original order
+        *fail.borrow_mut() = false;
+        assert_eq!(spool.process(None, apply).unwrap(), 0);
+        assert_eq!(
+            *applied.borrow(),
+            vec![
+                (LeaseAction::Add, "172.20.0.2".to_string()),
+                (LeaseAction::Del, "172.20.0.2".to_string())
+            ]
+        );
+
+        *fail.borrow_mut() = true;
+        let old = LeaseEvent::new(LeaseAction::Old, "00:16:3e:00:00:02", "172.20.0.3", None);
+        spool.process(Some(old), apply).unwrap();
+        for _ in 1..MAX_ATTEMPTS {
+            spool.process(None, apply).unwrap();
+        }
+        assert!(spool.pending().unwrap().is_empty());
+        let dead = std::fs::read_to_string(base.join("dead.log")).unwrap();
+        assert!(dead.contains("172.20.0.3"));
+    }
+}
@@ -20,11 +20,41 @@ pub mod qemu;
 pub mod vm;
 
 pub mod api;
+pub mod audit;
+pub mod authz;
+pub mod backup;
+pub mod baremetal;
+pub mod bootserver;
+pub mod bus;
+pub mod cli;
+pub mod config;
+pub mod crashlog;
+pub mod dhcp;
+pub mod freeze;
+pub mod hooks;
+pub mod host;
+pub mod hostpower;
+pub mod mirror;
 pub mod models;
+pub mod placement;
+pub mod ports;
+pub mod reconcile;
+#[cfg(feature = "grpc")]
+pub mod server;
+pub mod sol;
+pub mod specfile;
+pub mod storage;
+pub mod store;
+#[cfg(test)]
+mod testdir;
+pub mod tpm;
 
+pub mod imagecache;
 pub mod imagerepo;
 pub mod lockfile;
+pub mod machinelog;
 
 pub mod dnsmasq;
+pub mod leasespool;
 pub mod libvirt;
 pub mod network;
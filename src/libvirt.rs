@@ -15,75 +15,730 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
 
-use crate::error::Error;
-use crate::models;
+use tracing::{debug, warn};
+use virt::connect::Connect;
 
+use crate::config;
+use crate::error::{self, Category, Error};
+use crate::host::pci::PciAddress;
+use crate::host::vlan;
+use crate::imagerepo;
+use crate::models::{self, RestartPolicy};
+use crate::placement;
+use crate::qemu::agent;
+use crate::storage;
+use crate::store;
+
+// everything a domain needs that isn't in the machine spec
+#[allow(clippy::too_many_arguments)]
 pub fn define<P: AsRef<Path>>(
     machine: &models::Machine,
     image_file: P,
-    bridge_name: &str,
+    disks: &[PathBuf],
+    hostdevs: &[PciAddress],
+    // None for user-mode networking
+    bridge_name: Option<&str>,
     macaddr: &str,
+    cdrom: Option<&Path>,
+    serial: Option<&Path>,
+    crash_console: Option<&Path>,
+    tpm: Option<&Path>,
+    vlans: &[vlan::Attachment],
 ) -> Result<(), Error> {
+    let memory_bytes = machine.spec.memory.bytes();
+    let max_memory_bytes = match &machine.spec.max_memory {
+        Some(max) => max.bytes().max(memory_bytes),
+        None => memory_bytes,
+    };
+    let max_cpus = machine
+        .spec
+        .max_cpu
+        .unwrap_or(machine.spec.cpu)
+        .max(machine.spec.cpu);
+
+    let arch = imagerepo::guest_arch(machine.spec.arch.as_deref());
+    let native = arch == imagerepo::host_arch();
+    let s390x = arch == "s390x";
+    if s390x && tpm.is_some() {
+        return Err("tpm is not available on s390x".into());
+    }
+    let machine_type = machine_type(&machine.spec)?;
+    // libvirt finds the emulator in its capabilities unless one is configured
+    let emulator = match config::load()?.qemu.emulator {
+        Some(p) if native => format!("    <emulator>{}</emulator>", p.display()),
+        _ => String::new(),
+    };
+
+    let iotune = iotune_xml(&machine.spec);
+    let mut extra_disks = String::new();
+    for (i, disk) in disks.iter().enumerate() {
+        // vda is the boot image, additional disks follow from vdb
+        let dev = disk_dev(i + 1);
+        extra_disks.push_str(&disk_xml(disk, &dev, &iotune));
+    }
+
+    let mut hostdev_xml = String::new();
+    for addr in hostdevs {
+        // managed lets libvirt bind the device to vfio-pci and back
+        hostdev_xml.push_str(&format!(
+            r#"    <hostdev mode='subsystem' type='pci' managed='yes'>
+      <source>
+        <address domain='0x{:04x}' bus='0x{:02x}' slot='0x{:02x}' function='0x{:x}'/>
+      </source>
+    </hostdev>
+"#,
+            addr.domain, addr.bus, addr.slot, addr.function
+        ));
+    }
+
+    let graphics = match &machine.spec.graphics {
+        Some(g) => {
+            let port = match g.port {
+                Some(p) => format!("port='{}' autoport='no'", p),
+                None => "port='-1' autoport='yes'".to_string(),
+            };
+            let keymap = match &g.keymap {
+                Some(k) => {
+                    check_name("keymap", k)?;
+                    format!(" keymap='{}'", k)
+                }
+                None => String::new(),
+            };
+            format!(
+                "    <graphics type='{}' {} listen='{}'{}/>",
+                g.kind.as_str(),
+                port,
+                g.listen_addr(),
+                keymap
+            )
+        }
+        None => String::new(),
+    };
+
+    // vcpus without an explicit pin are kept on the requested numa node
+    let vcpu_cpuset = match machine.spec.numa.as_ref().and_then(|n| n.node) {
+        Some(node) => {
+            let topo = crate::host::HostAgent::new().topology()?;
+            let cpus = topo
+                .nodes
+                .get(&node)
+                .ok_or_else(|| format!("numa node {} does not exist", node))?;
+            let list: Vec<String> = cpus.iter().map(|c| c.to_string()).collect();
+            format!(" cpuset='{}'", list.join(","))
+        }
+        None => String::new(),
+    };
+
     let xml = format!(
         r#"
-<domain type='kvm'>
+<domain type='{domain_type}'>
   <name>{name}</name>
-  <memory unit="bytes">{memory_bytes}</memory>
+  <memory unit="bytes">{max_memory_bytes}</memory>
   <currentMemory unit="bytes">{memory_bytes}</currentMemory>
-  <vcpu>{cpus}</vcpu>
+  <vcpu placement='static' current='{cpus}'{vcpu_cpuset}>{max_cpus}</vcpu>
+{tuning}
   <os>
-    <type arch='x86_64' machine='pc'>hvm</type>
-    <boot dev='hd'/>
+    <type arch='{arch}' machine='{machine_type}'>hvm</type>
+{boot}
   </os>
-  <features>
-    <acpi/>
-    <apic/>
-  </features>
-  <clock offset='utc'/>
-  <pm>
-    <suspend-to-mem enabled='no'/>
-    <suspend-to-disk enabled='no'/>
-  </pm>
+{features}
+{cpu}
+{clock}
+{lifecycle}
+{pm}
   <devices>
-    <emulator>/usr/bin/kvm</emulator>
-    <disk type='file' device='disk'>
-      <driver name='qemu' type='qcow2' cache='writeback'/>
-      <source file='{image_file}'/>
-      <target dev='vda' bus='virtio'/>
-    </disk>
-    <serial type='pty'>
-      <source path='/dev/pts/0'/>
-      <target type='isa-serial' port='0'/>
-    </serial>
-    <input type='keyboard' bus='ps2'/>
-    <input type='mouse' bus='ps2'/>
-    <interface type="bridge">
-      <source bridge="{management_bridge}"/>
-      <mac address="{macaddr}"/>
-    </interface>
-    <memballoon model='virtio'/>
+{emulator}
+{root_disk}{extra_disks}
+{cdrom}
+{hostdevs}
+{shares}
+    <channel type='unix'>
+      <target type='virtio' name='{agent_channel}'/>
+    </channel>
+{serial}
+{panic}
+{tpm}
+{inputs}
+{nic}
+{vlans}
+{graphics}
+{rng}
+    <memballoon model='virtio'>
+      <stats period='{balloon_period}'/>
+    </memballoon>
   </devices>
 </domain>
     "#,
         name = &machine.name,
-        memory_bytes = crate::models::to_size(&machine.spec.memory)?,
+        agent_channel = agent::CHANNEL,
+        memory_bytes = memory_bytes,
+        max_memory_bytes = max_memory_bytes,
         cpus = machine.spec.cpu,
-        image_file = image_file.as_ref().to_str().unwrap(),
-        management_bridge = bridge_name,
-        macaddr = macaddr
+        max_cpus = max_cpus,
+        vcpu_cpuset = vcpu_cpuset,
+        tuning = tuning_xml(&machine.spec)?,
+        root_disk = disk_xml(image_file.as_ref(), "vda", &iotune),
+        extra_disks = extra_disks.trim_end(),
+        cdrom = cdrom_xml(&machine.spec, cdrom)?,
+        hostdevs = hostdev_xml.trim_end(),
+        serial = serial_xml(serial, crash_console, s390x),
+        boot = boot_xml(&machine.spec),
+        vlans = vlan_xml(vlans),
+        shares = shares_xml(&machine.spec),
+        graphics = graphics,
+        domain_type = if native { "kvm" } else { "qemu" },
+        arch = arch,
+        machine_type = machine_type,
+        features = if s390x { "" } else { X86_FEATURES },
+        pm = if s390x { "" } else { X86_PM },
+        inputs = if s390x { "" } else { X86_INPUTS },
+        emulator = emulator,
+        cpu = cpu_xml(
+            &machine.spec,
+            crate::host::nested_cpu_feature(&machine.spec)?
+        ),
+        clock = clock_xml(&machine.spec)?,
+        lifecycle = lifecycle_xml(&machine.spec),
+        panic = panic_xml(&machine.spec),
+        rng = rng_xml(&machine.spec),
+        tpm = tpm_xml(tpm),
+        balloon_period = BALLOON_STATS_PERIOD,
+        nic = nic_xml(bridge_name, macaddr, &machine.spec),
     );
 
-    use virt::{connect::Connect, domain::Domain};
-    let c = Connect::open("")?;
+    use virt::domain::Domain;
+    let c = connect(&machine.name)?;
+    check_capabilities(&c, &machine.spec, &machine_type)?;
     let _dom = Domain::create_xml(&c, &xml.to_string(), 0)?;
     Ok(())
 }
 
+// platform devices of x86 machines, which s390x has no equivalent of
+const X86_FEATURES: &str = "  <features>\n    <acpi/>\n    <apic/>\n  </features>";
+const X86_PM: &str =
+    "  <pm>\n    <suspend-to-mem enabled='no'/>\n    <suspend-to-disk enabled='no'/>\n  </pm>";
+const X86_INPUTS: &str =
+    "    <input type='keyboard' bus='ps2'/>\n    <input type='mouse' bus='ps2'/>";
+
+fn is_s390x(spec: &models::Spec) -> bool {
+    imagerepo::guest_arch(spec.arch.as_deref()) == "s390x"
+}
+
+// The spec's machine type, else the configured one for guests of the host's
+// architecture, else the usual one of the architecture.
+pub fn machine_type(spec: &models::Spec) -> Result<String, Error> {
+    if let Some(mt) = &spec.machine_type {
+        return Ok(mt.clone());
+    }
+    let arch = imagerepo::guest_arch(spec.arch.as_deref());
+    if arch == imagerepo::host_arch() {
+        if let Some(mt) = config::load()?.qemu.machine_type {
+            return Ok(mt);
+        }
+    }
+    Ok(match arch.as_str() {
+        "s390x" => "s390-ccw-virtio",
+        _ => "pc",
+    }
+    .to_string())
+}
+
+// The serial console is a pty unless something else serves it from a
+// socket. A crash console is always a socket, as the second port. On s390x
+// these are the sclp console and its line mode variant.
+fn serial_xml(socket: Option<&Path>, crash_console: Option<&Path>, s390x: bool) -> String {
+    let port = |source: String, port: u32| {
+        let target = match (s390x, port) {
+            (false, _) => format!("<target type='isa-serial' port='{}'/>", port),
+            (true, 0) => "<target type='sclp-serial' port='0'>\n        <model name='sclpconsole'/>\n      </target>".to_string(),
+            (true, _) => format!(
+                "<target type='sclp-serial' port='{}'>\n        <model name='sclplmconsole'/>\n      </target>",
+                port
+            ),
+        };
+        format!("    <serial {}\n      {}\n    </serial>", source, target)
+    };
+    let unix = |p: &Path| {
+        format!(
+            "type='unix'>\n      <source mode='bind' path='{}'/>",
+            p.display()
+        )
+    };
+    let mut xml = port(
+        match socket {
+            Some(p) => unix(p),
+            None => "type='pty'>\n      <source path='/dev/pts/0'/>".to_string(),
+        },
+        0,
+    );
+    if let Some(p) = crash_console {
+        xml.push('\n');
+        xml.push_str(&port(unix(p), 1));
+    }
+    xml
+}
+
+// An empty disk or cdrom drive falls through to the next device, so netboot
+// and installer media only take over until something is installed or the
+// media is ejected.
+fn boot_xml(spec: &models::Spec) -> String {
+    spec.boot_devices()
+        .iter()
+        .map(|d| {
+            let dev = match d {
+                models::BootDevice::Disk => "hd",
+                models::BootDevice::Cdrom => "cdrom",
+                models::BootDevice::Network => "network",
+            };
+            format!("    <boot dev='{}'/>", dev)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// A cdrom drive is always there, empty unless media is inserted, so media
+// can be changed at runtime without hotplugging a drive.
+fn cdrom_xml(spec: &models::Spec, media: Option<&Path>) -> Result<String, Error> {
+    let (dev, bus) = cdrom_target(spec)?;
+    let source = match media {
+        Some(p) => format!("\n      <source file='{}'/>", p.display()),
+        None => String::new(),
+    };
+    Ok(format!(
+        r#"    <disk type='file' device='cdrom'>
+      <driver name='qemu' type='raw'/>{}
+      <target dev='{}' bus='{}'/>
+      <readonly/>
+    </disk>"#,
+        source, dev, bus
+    ))
+}
+
+// target device and bus of the cdrom drive
+pub fn cdrom_target(spec: &models::Spec) -> Result<(&'static str, &'static str), Error> {
+    // s390x has no ide at all, libvirt adds a virtio-scsi controller
+    if is_s390x(spec) {
+        return Ok(("sda", "scsi"));
+    }
+    // q35 has no ide controller
+    let mt = machine_type(spec)?;
+    Ok(match mt == "q35" || mt.starts_with("pc-q35") {
+        true => ("sda", "sata"),
+        false => ("hdc", "ide"),
+    })
+}
+
+// insert media into the cdrom drive of a running domain, or eject it
+pub fn change_media(machine: &models::Machine, media: Option<&Path>) -> Result<(), Error> {
+    use virt::domain::Domain;
+    let c = connect(&machine.name)?;
+    let dom = Domain::lookup_by_name(&c, &machine.name)?;
+    // VIR_DOMAIN_AFFECT_LIVE, and VIR_DOMAIN_DEVICE_MODIFY_FORCE to eject
+    // even when an installer has locked the tray
+    let flags = match media {
+        Some(_) => 1,
+        None => 1 | 2,
+    };
+    dom.update_device_flags(&cdrom_xml(&machine.spec, media)?, flags)?;
+    Ok(())
+}
+
+// names end up in attributes, so only allow what zones and keymaps use
+fn check_name(what: &str, name: &str) -> Result<(), Error> {
+    let ok = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_+/".contains(c));
+    match ok {
+        true => Ok(()),
+        false => Err(format!("invalid {} '{}'", what, name).into()),
+    }
+}
+
+// Target name of the disk at `index`, vda being 0. Past vdz the names go
+// on with two letters, vdaa, vdab and so on, like the guest kernel does.
+pub fn disk_dev(index: usize) -> String {
+    let letter = |n: usize| (b'a' + n as u8) as char;
+    match index {
+        0..=25 => format!("vd{}", letter(index)),
+        _ => format!("vd{}{}", letter(index / 26 - 1), letter(index % 26)),
+    }
+}
+
+// for values from the spec that go into an attribute as they are
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&apos;")
+        .replace('"', "&quot;")
+}
+
+fn clock_xml(spec: &models::Spec) -> Result<String, Error> {
+    let offset = match spec.timezone.as_deref() {
+        None | Some("utc" | "UTC") => "offset='utc'".to_string(),
+        Some("localtime") => "offset='localtime'".to_string(),
+        Some(zone) => {
+            check_name("timezone", zone)?;
+            format!("offset='timezone' timezone='{}'", zone)
+        }
+    };
+    Ok(format!("  <clock {}/>", offset))
+}
+
+// What libvirt does when the guest powers off or crashes. Its defaults,
+// destroying the domain on both, are the never policy.
+fn lifecycle_xml(spec: &models::Spec) -> String {
+    match spec.restart_policy.unwrap_or_default() {
+        RestartPolicy::Never => String::new(),
+        RestartPolicy::OnFailure => "  <on_crash>restart</on_crash>".to_string(),
+        RestartPolicy::Always => {
+            "  <on_poweroff>restart</on_poweroff>\n  <on_crash>restart</on_crash>".to_string()
+        }
+    }
+}
+
+// a pvpanic device, for the guest kernel to tell qemu it crashed
+fn panic_xml(spec: &models::Spec) -> &'static str {
+    match spec.restart_policy.unwrap_or_default() {
+        RestartPolicy::Never => "",
+        _ if is_s390x(spec) => "    <panic model='s390'/>",
+        _ => "    <panic model='isa'/>",
+    }
+}
+
+// a tpm device backed by the machine's swtpm
+fn tpm_xml(socket: Option<&Path>) -> String {
+    match socket {
+        Some(path) => format!(
+            r#"    <tpm model='tpm-crb'>
+      <backend type='external'>
+        <source type='unix' mode='connect' path='{}'/>
+      </backend>
+    </tpm>"#,
+            path.display()
+        ),
+        None => String::new(),
+    }
+}
+
+// guest entropy from the host, so early boot doesn't stall waiting for it
+fn rng_xml(spec: &models::Spec) -> &'static str {
+    match spec.rng.unwrap_or(true) {
+        true => "    <rng model='virtio'>\n      <backend model='random'>/dev/urandom</backend>\n    </rng>",
+        false => "",
+    }
+}
+
+// `nested` is the virtualization flag a nested machine needs required
+fn cpu_xml(spec: &models::Spec, nested: Option<&str>) -> String {
+    let features = spec.cpu_features.as_deref().unwrap_or_default();
+    let mut inner = String::new();
+    for flag in features.iter().map(|f| f.as_str()).chain(nested) {
+        let (name, enabled) = models::parse_cpu_feature(flag);
+        let policy = if enabled { "require" } else { "disable" };
+        inner.push_str(&format!(
+            "    <feature policy='{}' name='{}'/>\n",
+            policy, name
+        ));
+    }
+
+    let open = match spec.cpu_model.as_deref() {
+        Some(mode @ ("host-passthrough" | "host-model")) => format!("  <cpu mode='{}'>\n", mode),
+        Some(model) => format!(
+            "  <cpu mode='custom' match='exact'>\n    <model fallback='forbid'>{}</model>\n",
+            model
+        ),
+        // nested guests see the host cpu as it is
+        None if nested.is_some() => "  <cpu mode='host-passthrough'>\n".to_string(),
+        // features alone are layered over the host cpu model
+        None if !features.is_empty() => "  <cpu mode='host-model'>\n".to_string(),
+        None => return String::new(),
+    };
+
+    format!("{}{}  </cpu>", open, inner)
+}
+
+// The management nic, on the bridge or, without one, on qemu's user-mode
+// network stack, which needs no privileges and hands out its own address.
+fn nic_xml(bridge: Option<&str>, macaddr: &str, spec: &models::Spec) -> String {
+    match bridge {
+        Some(b) => format!(
+            "    <interface type=\"bridge\">\n      <source bridge=\"{}\"/>\n      <mac address=\"{}\"/>\n{}    </interface>",
+            b,
+            macaddr,
+            bandwidth_xml(spec)
+        ),
+        None => format!(
+            "    <interface type=\"user\">\n      <mac address=\"{}\"/>\n      <model type='virtio'/>\n    </interface>",
+            macaddr
+        ),
+    }
+}
+
+// Volumes from block storage pools are raw devices, read and written
+// directly without the host's page cache.
+fn disk_xml(path: &Path, dev: &str, iotune: &str) -> String {
+    let (kind, driver, source) = match storage::is_block(path) {
+        true => ("block", "type='raw' cache='none' io='native'", "dev"),
+        false => ("file", "type='qcow2' cache='writeback'", "file"),
+    };
+    format!(
+        r#"    <disk type='{kind}' device='disk'>
+      <driver name='qemu' {driver}/>
+      <source {source}='{path}'/>
+      <target dev='{dev}' bus='virtio'/>
+{iotune}    </disk>
+"#,
+        path = path.display(),
+    )
+}
+
+// throttling for each of the machine's disks, empty without disk limits
+fn iotune_xml(spec: &models::Spec) -> String {
+    let qos = spec.qos.clone().unwrap_or_default();
+    let mut inner = String::new();
+    if let Some(iops) = qos.disk_iops {
+        inner.push_str(&format!(
+            "        <total_iops_sec>{}</total_iops_sec>\n",
+            iops
+        ));
+    }
+    if let Some(bw) = qos.disk_bandwidth {
+        inner.push_str(&format!(
+            "        <total_bytes_sec>{}</total_bytes_sec>\n",
+            bw.bytes()
+        ));
+    }
+    match inner.is_empty() {
+        true => inner,
+        false => format!("      <iotune>\n{}      </iotune>\n", inner),
+    }
+}
+
+// rate limit of the management nic, which libvirt takes in KiB/s
+fn bandwidth_xml(spec: &models::Spec) -> String {
+    let bw = match spec.qos.as_ref().and_then(|q| q.net_bandwidth) {
+        Some(bw) => bw,
+        None => return String::new(),
+    };
+    let kib = (bw.bytes() / 1024).max(1);
+    format!(
+        "      <bandwidth>\n        <inbound average='{0}'/>\n        <outbound average='{0}'/>\n      </bandwidth>\n",
+        kib
+    )
+}
+
+// an extra nic on the bridge of each vlan the machine is on
+fn vlan_xml(vlans: &[vlan::Attachment]) -> String {
+    let mut xml = String::new();
+    for v in vlans {
+        let port = match v {
+            vlan::Attachment::Bridge(_) => String::new(),
+            vlan::Attachment::Ovs { vlan, .. } => format!(
+                "      <virtualport type='openvswitch'/>\n      <vlan>\n        <tag id='{}'/>\n      </vlan>\n",
+                vlan
+            ),
+        };
+        xml.push_str(&format!(
+            r#"    <interface type="bridge">
+      <source bridge="{}"/>
+{}      <model type='virtio'/>
+    </interface>
+"#,
+            v.bridge(),
+            port
+        ));
+    }
+    xml.trim_end().to_string()
+}
+
+// filesystem devices for shared host directories, libvirt starts and stops
+// virtiofsd for us
+fn shares_xml(spec: &models::Spec) -> String {
+    let mut xml = String::new();
+    for share in spec.shares.iter().flatten() {
+        let (accessmode, driver) = match share.driver {
+            models::ShareDriver::Virtiofs => ("passthrough", "virtiofs"),
+            models::ShareDriver::NineP => ("mapped", "path"),
+        };
+        xml.push_str(&format!(
+            r#"    <filesystem type='mount' accessmode='{}'>
+      <driver type='{}'/>
+      <source dir='{}'/>
+      <target dir='{}'/>
+{}    </filesystem>
+"#,
+            accessmode,
+            driver,
+            escape(&share.source.display().to_string()),
+            escape(&share.tag),
+            if share.readonly {
+                "      <readonly/>\n"
+            } else {
+                ""
+            }
+        ));
+    }
+    xml.trim_end().to_string()
+}
+
+// hugepage backing, vcpu pinning and numa memory placement
+fn tuning_xml(spec: &models::Spec) -> Result<String, Error> {
+    let mut xml = String::new();
+    let node = spec.numa.as_ref().and_then(|n| n.node);
+
+    // virtiofsd maps guest memory, so it has to be shared with it
+    let shared = spec
+        .shares
+        .iter()
+        .flatten()
+        .any(|s| s.driver == models::ShareDriver::Virtiofs);
+
+    if spec.hugepages.is_some() || shared {
+        xml.push_str("  <memoryBacking>\n");
+        if let Some(size) = &spec.hugepages {
+            let kib = size.bytes() / 1024;
+            let nodeset = match node {
+                Some(n) => format!(" nodeset='{}'", n),
+                None => String::new(),
+            };
+            xml.push_str(&format!(
+                "    <hugepages>\n      <page size='{}' unit='KiB'{}/>\n    </hugepages>\n",
+                kib, nodeset
+            ));
+        } else {
+            xml.push_str("    <source type='memfd'/>\n");
+        }
+        if shared {
+            xml.push_str("    <access mode='shared'/>\n");
+        }
+        xml.push_str("  </memoryBacking>\n");
+    }
+
+    if let Some(pinning) = spec.numa.as_ref().and_then(|n| n.cpu_pinning.as_ref()) {
+        xml.push_str("  <cputune>\n");
+        for (vcpu, cpu) in pinning.iter().enumerate() {
+            xml.push_str(&format!(
+                "    <vcpupin vcpu='{}' cpuset='{}'/>\n",
+                vcpu, cpu
+            ));
+        }
+        xml.push_str("  </cputune>\n");
+    }
+
+    if let Some(n) = node {
+        xml.push_str(&format!(
+            "  <numatune>\n    <memory mode='strict' nodeset='{}'/>\n  </numatune>\n",
+            n
+        ));
+    }
+
+    Ok(xml.trim_end().to_string())
+}
+
+// make sure the hypervisor supports the requested machine type and cpu model
+fn check_capabilities(c: &Connect, spec: &models::Spec, machine_type: &str) -> Result<(), Error> {
+    let caps = c.get_capabilities()?;
+    let machines = element_texts(&caps, "machine");
+    if !machines.contains(&machine_type) {
+        return Err(format!(
+            "machine type '{}' not supported by hypervisor, set qemu.machineType in the host config or one of: {}",
+            machine_type,
+            machines.join(", ")
+        )
+        .into());
+    }
+
+    match spec.cpu_model.as_deref() {
+        None | Some("host-passthrough") | Some("host-model") => {}
+        Some(model) => {
+            let arch = imagerepo::guest_arch(spec.arch.as_deref());
+            let models = c.get_cpu_models_names(&arch, 0)?;
+            if !models.iter().any(|m| m == model) {
+                return Err(format!("cpu model '{}' not supported by hypervisor", model).into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Where libvirt sends the qemu output of a domain on the local host, under
+// the user's cache directory for a session instance.
+pub fn log_path(name: &str) -> PathBuf {
+    let dir = match (store::unprivileged(), std::env::var_os("HOME")) {
+        (true, Some(home)) => std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| Path::new(&home).join(".cache"))
+            .join("libvirt/qemu/log"),
+        _ => PathBuf::from("/var/log/libvirt/qemu"),
+    };
+    dir.join(format!("{}.log", name))
+}
+
+// Open libvirt connections, one per uri, shared by everything in the
+// process. A connection is closed once dropped from here and no longer
+// in use.
+static CONNECTIONS: Mutex<BTreeMap<String, Arc<Connection>>> = Mutex::new(BTreeMap::new());
+
+struct Connection(Connect);
+
+// libvirt connections are safe to use from several threads at once
+unsafe impl Send for Connection {}
+unsafe impl Sync for Connection {}
+
+impl Deref for Connection {
+    type Target = Connect;
+
+    fn deref(&self) -> &Connect {
+        &self.0
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.close() {
+            warn!("error closing libvirt connection: {}", e);
+        }
+    }
+}
+
+// the shared connection to `uri`, reopened if libvirtd dropped it
+fn open(uri: &str) -> Result<Arc<Connection>, Error> {
+    let mut conns = CONNECTIONS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(c) = conns.get(uri) {
+        if c.is_alive().unwrap_or(false) {
+            return Ok(c.clone());
+        }
+        conns.remove(uri);
+    }
+    let c = Connect::open(uri).map_err(|e| {
+        error::categorized(
+            Category::Unavailable,
+            format!("can't connect to libvirt at {}: {}", uri, e),
+        )
+    })?;
+    let c = Arc::new(Connection(c));
+    conns.insert(uri.to_string(), c.clone());
+    Ok(c)
+}
+
+// connection to the host a machine was placed on
+fn connect(name: &str) -> Result<Arc<Connection>, Error> {
+    open(&placement::uri(name)?)
+}
+
 pub fn destroy(name: &str) -> Result<(), Error> {
-    use virt::{connect::Connect, domain::Domain};
-    let c = Connect::open("")?;
+    use virt::domain::Domain;
+    let c = connect(name)?;
     let dom = Domain::lookup_by_name(&c, name);
     if let Err(ref e) = dom {
         if e.to_string().contains("Domain not found") {
@@ -95,3 +750,757 @@ pub fn destroy(name: &str) -> Result<(), Error> {
     }
     Ok(())
 }
+
+// whether libvirt at `uri` answers, e.g. on a host that was just woken
+pub fn reachable(uri: &str) -> bool {
+    open(uri).is_ok()
+}
+
+// VIR_MIGRATE_LIVE
+const MIGRATE_LIVE: u32 = 1;
+
+// Live migrate a running domain to the libvirt at `uri`. The host there has
+// to see the domain's disks at the same paths.
+pub fn migrate(name: &str, uri: &str) -> Result<(), Error> {
+    use virt::domain::Domain;
+    let c = connect(name)?;
+    let dest = open(uri)?;
+    Domain::lookup_by_name(&c, name)?.migrate(&dest, MIGRATE_LIVE, "", 0)?;
+    Ok(())
+}
+
+pub fn is_active(name: &str) -> Result<bool, Error> {
+    use virt::domain::Domain;
+    let c = connect(name)?;
+    match Domain::lookup_by_name(&c, name) {
+        Ok(dom) => Ok(dom.is_active()?),
+        // transient domains are gone once stopped
+        Err(e) if e.to_string().contains("Domain not found") => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// ask the guest to shut down, returns without waiting for it
+pub fn shutdown(name: &str) -> Result<(), Error> {
+    use virt::domain::Domain;
+    let c = connect(name)?;
+    Domain::lookup_by_name(&c, name)?.shutdown()?;
+    Ok(())
+}
+
+// Have a guest powering off stay off, for a shutdown asked for by bigiron of
+// a machine whose restart policy would bring it back. Only the running
+// domain changes, the next start gets the policy's action again. The virt
+// crate has no binding for this.
+pub fn stay_off(name: &str) -> Result<(), Error> {
+    let uri = placement::uri(name)?;
+    let mut cmd = Command::new("virsh");
+    cmd.args([
+        "-c",
+        &uri,
+        "set-lifecycle-action",
+        name,
+        "poweroff",
+        "destroy",
+    ]);
+    debug!("Running: {:?}", cmd);
+    let out = cmd.output()?;
+    if !out.status.success() {
+        return Err(format!(
+            "error changing the power off action of {}: {}",
+            name,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// live resize a running domain, bounded by the maximums it was defined with
+pub fn scale(name: &str, cpus: Option<u32>, memory_bytes: Option<u64>) -> Result<(), Error> {
+    use virt::domain::Domain;
+    let c = connect(name)?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    if let Some(cpus) = cpus {
+        dom.set_vcpus(cpus)?;
+    }
+    if let Some(bytes) = memory_bytes {
+        // libvirt takes memory sizes in KiB
+        dom.set_memory(bytes / 1024)?;
+    }
+    Ok(())
+}
+
+fn agent_command(name: &str, execute: &str) -> Result<serde_json::Value, Error> {
+    use virt::domain::Domain;
+    let c = connect(name)?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    let reply = dom.qemu_agent_command(&agent::command(execute, None), 5, 0)?;
+    agent::parse_reply(&reply)
+}
+
+// Run a QMP command on the domain's monitor, for the block jobs there is
+// no libvirt api for here. libvirt marks the domain tainted for it.
+pub fn monitor_command(
+    name: &str,
+    execute: &str,
+    arguments: Option<serde_json::Value>,
+) -> Result<serde_json::Value, Error> {
+    use virt::domain::Domain;
+    let c = connect(name)?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    let reply = dom.qemu_monitor_command(&agent::command(execute, arguments), 0)?;
+    let mut v: serde_json::Value = serde_json::from_str(&reply)?;
+    if let Some(e) = v.get("error") {
+        let desc = e.get("desc").and_then(|d| d.as_str()).unwrap_or("unknown");
+        return Err(format!("Error from qemu monitor: {}", desc).into());
+    }
+    Ok(v["return"].take())
+}
+
+// seconds between the guest balloon driver's memory stats updates
+const BALLOON_STATS_PERIOD: u32 = 10;
+
+// guest memory in bytes as reported by the balloon driver
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryStats {
+    pub total: u64,
+    pub free: u64,
+    // what applications could get without swapping, reported by newer
+    // guest kernels only
+    pub available: Option<u64>,
+}
+
+impl MemoryStats {
+    pub fn used(&self) -> u64 {
+        self.total
+            .saturating_sub(self.available.unwrap_or(self.free))
+    }
+}
+
+// Stats out of the balloon's guest-stats property, None until the guest
+// has reported once. Unreported stats are -1.
+fn parse_guest_stats(v: &serde_json::Value) -> Option<MemoryStats> {
+    if v.get("last-update").and_then(|u| u.as_u64()).unwrap_or(0) == 0 {
+        return None;
+    }
+    let stat = |name: &str| v["stats"].get(name).and_then(|s| s.as_u64());
+    Some(MemoryStats {
+        total: stat("stat-total-memory")?,
+        free: stat("stat-free-memory")?,
+        available: stat("stat-available-memory"),
+    })
+}
+
+// Guest memory usage of a running domain. The virt crate's memory_stats
+// only hands back the first stat, so this asks qemu for all of them.
+pub fn memory_stats(name: &str) -> Result<Option<MemoryStats>, Error> {
+    let v = monitor_command(
+        name,
+        "qom-get",
+        Some(serde_json::json!({
+            "path": "/machine/peripheral/balloon0",
+            "property": "guest-stats",
+        })),
+    )?;
+    Ok(parse_guest_stats(&v))
+}
+
+pub fn agent_ping(name: &str) -> Result<(), Error> {
+    agent_command(name, "guest-ping")?;
+    Ok(())
+}
+
+// hostname and interfaces as reported by the guest agent
+pub fn guest_info(name: &str) -> Result<agent::GuestInfo, Error> {
+    Ok(agent::GuestInfo {
+        hostname: agent::parse_hostname(&agent_command(name, "guest-get-host-name")?),
+        interfaces: agent::parse_interfaces(agent_command(name, "guest-network-get-interfaces")?)?,
+    })
+}
+
+// freeze or thaw guest filesystems, returning how many were affected
+pub fn fsfreeze(name: &str, freeze: bool) -> Result<u64, Error> {
+    let cmd = match freeze {
+        true => "guest-fsfreeze-freeze",
+        false => "guest-fsfreeze-thaw",
+    };
+    Ok(agent_command(name, cmd)?.as_u64().unwrap_or(0))
+}
+
+// Address of the graphical console of a running domain, e.g. vnc://127.0.0.1:5900
+pub fn display(name: &str) -> Result<Option<String>, Error> {
+    use virt::domain::Domain;
+    let c = connect(name)?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    let xml = dom.get_xml_desc(0)?;
+
+    for elem in find_elements(&xml, "graphics") {
+        let port = match attr(elem, "port") {
+            Some(p) if p != "-1" => p,
+            _ => continue,
+        };
+        let kind = attr(elem, "type").unwrap_or("vnc");
+        let listen = attr(elem, "listen").unwrap_or("127.0.0.1");
+        return Ok(Some(format!("{}://{}:{}", kind, listen, port)));
+    }
+    Ok(None)
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiskInfo {
+    // disk or cdrom
+    pub device: String,
+    pub target: String,
+    pub bus: String,
+    // None for an empty cdrom drive
+    pub source: Option<PathBuf>,
+    pub format: Option<String>,
+    // virtual size in bytes, filled in by callers that look at the image
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NicInfo {
+    pub mac: Option<String>,
+    pub bridge: Option<String>,
+    // None is the hypervisor's default model
+    pub model: Option<String>,
+    // host tap device, only known while running
+    pub tap: Option<String>,
+}
+
+// virtual hardware of a domain as libvirt sees it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DomainHardware {
+    pub machine_type: Option<String>,
+    // uefi firmware image, None boots the default bios
+    pub loader: Option<PathBuf>,
+    pub disks: Vec<DiskInfo>,
+    pub nics: Vec<NicInfo>,
+    pub hostdevs: Vec<PciAddress>,
+}
+
+// definition of a running domain, with everything libvirt filled in
+pub fn domain_xml(name: &str) -> Result<String, Error> {
+    use virt::domain::Domain;
+    let c = connect(name)?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    Ok(dom.get_xml_desc(0)?)
+}
+
+pub fn hardware(name: &str) -> Result<DomainHardware, Error> {
+    use virt::domain::Domain;
+    let c = connect(name)?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    Ok(parse_hardware(&dom.get_xml_desc(0)?))
+}
+
+// attribute of the first `tag` element in a block
+fn first_attr(block: &str, tag: &str, name: &str) -> Option<String> {
+    find_elements(block, tag)
+        .first()
+        .and_then(|e| attr(e, name))
+        .map(|v| v.to_string())
+}
+
+fn parse_hardware(xml: &str) -> DomainHardware {
+    let mut hw = DomainHardware {
+        machine_type: first_attr(xml, "type", "machine"),
+        loader: element_texts(xml, "loader").first().map(PathBuf::from),
+        hostdevs: hostdev_addresses(xml),
+        ..Default::default()
+    };
+
+    for block in element_blocks(xml, "disk") {
+        hw.disks.push(DiskInfo {
+            device: attr(block, "device").unwrap_or("disk").to_string(),
+            target: first_attr(block, "target", "dev").unwrap_or_default(),
+            bus: first_attr(block, "target", "bus").unwrap_or_default(),
+            source: first_attr(block, "source", "file").map(PathBuf::from),
+            format: first_attr(block, "driver", "type"),
+            size: None,
+        });
+    }
+
+    for block in element_blocks(xml, "interface") {
+        hw.nics.push(NicInfo {
+            mac: first_attr(block, "mac", "address"),
+            bridge: first_attr(block, "source", "bridge"),
+            model: first_attr(block, "model", "type"),
+            tap: first_attr(block, "target", "dev"),
+        });
+    }
+    hw
+}
+
+// pci devices passed through to any domain on the host
+pub fn hostdevs_in_use() -> Result<Vec<PciAddress>, Error> {
+    let c = open(placement::local_uri())?;
+    let mut r = Vec::new();
+    for dom in c.list_all_domains(0)? {
+        r.extend(hostdev_addresses(&dom.get_xml_desc(0)?));
+    }
+    Ok(r)
+}
+
+// source addresses of the pci hostdev elements in a domain definition
+fn hostdev_addresses(xml: &str) -> Vec<PciAddress> {
+    let mut r = Vec::new();
+    for block in xml.split("<hostdev").skip(1) {
+        let block = block.split("</hostdev>").next().unwrap_or_default();
+        // the guest side <address type='pci'> can appear on any hostdev,
+        // so check the type on the hostdev start tag itself
+        let start = block.split('>').next().unwrap_or_default();
+        if attr(start, "type") != Some("pci") {
+            continue;
+        }
+        if let Some(elem) = find_elements(block, "address").first() {
+            let field = |name| {
+                attr(elem, name)
+                    .and_then(|v| u16::from_str_radix(v.trim_start_matches("0x"), 16).ok())
+            };
+            if let (Some(domain), Some(bus), Some(slot), Some(function)) = (
+                field("domain"),
+                field("bus"),
+                field("slot"),
+                field("function"),
+            ) {
+                r.push(PciAddress {
+                    domain,
+                    bus: bus as u8,
+                    slot: slot as u8,
+                    function: function as u8,
+                });
+            }
+        }
+    }
+    r
+}
+
+// start tags (including attributes) of every `tag` element in a document
+fn find_elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{}", tag);
+    let mut r = Vec::new();
+    let mut rest = xml;
+    while let Some(i) = rest.find(&open) {
+        let after = &rest[i + open.len()..];
+        // make sure we matched the whole tag name and not a prefix
+        if after.starts_with(|c: char| c.is_whitespace() || c == '>' || c == '/') {
+            let end = after.find('>').map_or(after.len(), |e| e + 1);
+            r.push(&rest[i..i + open.len() + end]);
+        }
+        rest = after;
+    }
+    r
+}
+
+// every `tag` element from its start tag to its end, or just the start tag
+// of an empty one
+fn element_blocks<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let close = format!("</{}>", tag);
+    let mut r = Vec::new();
+    let mut rest = xml;
+    for start in find_elements(xml, tag) {
+        let i = match rest.find(start) {
+            Some(i) => i,
+            None => continue,
+        };
+        let block = &rest[i..];
+        let len = match start.ends_with("/>") {
+            true => start.len(),
+            false => block.find(&close).map_or(block.len(), |e| e + close.len()),
+        };
+        r.push(&block[..len]);
+        rest = &block[len..];
+    }
+    r
+}
+
+// text content of every `tag` element, e.g. the machine names in capabilities
+fn element_texts<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let close = format!("</{}>", tag);
+    let mut r = Vec::new();
+    let mut rest = xml;
+    for start in find_elements(xml, tag) {
+        if start.ends_with("/>") {
+            continue;
+        }
+        if let Some(i) = rest.find(start) {
+            let body = &rest[i + start.len()..];
+            if let Some(end) = body.find(&close) {
+                r.push(body[..end].trim());
+                rest = &body[end..];
+            }
+        }
+    }
+    r
+}
+
+fn attr<'a>(elem: &'a str, name: &str) -> Option<&'a str> {
+    for quote in ['\'', '"'] {
+        let pat = format!(" {}={}", name, quote);
+        if let Some(i) = elem.find(&pat) {
+            let val = &elem[i + pat.len()..];
+            return val.find(quote).map(|e| &val[..e]);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_elements() {
+        let xml = "<devices><graphics type='vnc' port='5901' listen=\"0.0.0.0\"><listen/></graphics><graphicsx/></devices>";
+        let elems = find_elements(xml, "graphics");
+        assert_eq!(elems.len(), 1);
+        assert_eq!(attr(elems[0], "type"), Some("vnc"));
+        assert_eq!(attr(elems[0], "port"), Some("5901"));
+        assert_eq!(attr(elems[0], "listen"), Some("0.0.0.0"));
+        assert_eq!(attr(elems[0], "autoport"), None);
+    }
+
+    #[test]
+    fn test_parse_hardware() {
+        let xml = "<domain type='kvm'><name>web1</name>\
+                   <os><type arch='x86_64' machine='pc-q35-7.2'>hvm</type>\
+                   <loader readonly='yes' type='pflash'>/usr/share/OVMF/OVMF_CODE.fd</loader></os>\
+                   <devices><disk type='file' device='disk'><driver name='qemu' type='qcow2'/>\
+                   <source file='/var/lib/bigiron/machines/web1/image.qcow2'/>\
+                   <target dev='vda' bus='virtio'/></disk>\
+                   <disk type='file' device='cdrom'><driver name='qemu' type='raw'/>\
+                   <target dev='sda' bus='sata'/><readonly/></disk>\
+                   <interface type='bridge'><mac address='52:54:00:00:00:01'/>\
+                   <source bridge='br0'/><target dev='vnet3'/><model type='virtio'/></interface></devices></domain>";
+        let hw = parse_hardware(xml);
+        assert_eq!(hw.machine_type.as_deref(), Some("pc-q35-7.2"));
+        assert_eq!(
+            hw.loader,
+            Some(PathBuf::from("/usr/share/OVMF/OVMF_CODE.fd"))
+        );
+        assert_eq!(hw.disks.len(), 2);
+        assert_eq!(hw.disks[0].target, "vda");
+        assert_eq!(hw.disks[0].format.as_deref(), Some("qcow2"));
+        assert_eq!(
+            hw.disks[0].source,
+            Some(PathBuf::from("/var/lib/bigiron/machines/web1/image.qcow2"))
+        );
+        assert_eq!(hw.disks[1].device, "cdrom");
+        assert_eq!(hw.disks[1].source, None);
+        assert_eq!(
+            hw.nics,
+            vec![NicInfo {
+                mac: Some("52:54:00:00:00:01".into()),
+                bridge: Some("br0".into()),
+                model: Some("virtio".into()),
+                tap: Some("vnet3".into()),
+            }]
+        );
+        assert!(hw.hostdevs.is_empty());
+    }
+
+    #[test]
+    fn test_hostdev_addresses() {
+        let xml = "<devices><hostdev mode='subsystem' type='pci' managed='yes'>\
+                   <driver name='vfio'/><source><address domain='0x0000' bus='0x3b' slot='0x02' function='0x1'/></source>\
+                   <address type='pci' domain='0x0000' bus='0x00' slot='0x07' function='0x0'/></hostdev>\
+                   <hostdev mode='subsystem' type='usb'><source><vendor id='0x1234'/></source></hostdev></devices>";
+        assert_eq!(
+            hostdev_addresses(xml),
+            vec![PciAddress::parse("0000:3b:02.1").unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_tuning_xml() {
+        let yaml = "
+            cpu: 2
+            memory: 2Gi
+            image:
+              url: http://example.com/image.qcow2
+            hugepages: 1Gi
+            numa:
+              node: 1
+              cpuPinning: [4, 5]
+        ";
+        let spec: models::Spec = serde_yaml::from_str(yaml).unwrap();
+        let xml = tuning_xml(&spec).unwrap();
+        assert_eq!(
+            attr(find_elements(&xml, "page")[0], "size"),
+            Some("1048576")
+        );
+        assert_eq!(find_elements(&xml, "vcpupin").len(), 2);
+        assert_eq!(attr(find_elements(&xml, "vcpupin")[1], "cpuset"), Some("5"));
+        assert_eq!(attr(find_elements(&xml, "memory")[0], "nodeset"), Some("1"));
+    }
+
+    #[test]
+    fn test_shares_xml() {
+        let yaml = "
+            cpu: 2
+            memory: 2Gi
+            image:
+              url: http://example.com/image.qcow2
+            shares:
+            - source: /home/dev/src
+              tag: src
+            - source: /srv/data
+              tag: data
+              driver: 9p
+              readonly: true
+            - source: '/srv/bob''s <files>'
+              tag: bob
+        ";
+        let spec: models::Spec = serde_yaml::from_str(yaml).unwrap();
+        let xml = shares_xml(&spec);
+        assert!(xml.contains("<source dir='/srv/bob&apos;s &lt;files&gt;'/>"));
+        let drivers = find_elements(&xml, "driver");
+        assert_eq!(attr(drivers[0], "type"), Some("virtiofs"));
+        assert_eq!(attr(drivers[1], "type"), Some("path"));
+        assert_eq!(xml.matches("<readonly/>").count(), 1);
+
+        // virtiofs needs shared memory even without hugepages
+        let tuning = tuning_xml(&spec).unwrap();
+        assert!(tuning.contains("<source type='memfd'/>"));
+        assert!(tuning.contains("<access mode='shared'/>"));
+    }
+
+    #[test]
+    fn test_disk_dev() {
+        assert_eq!(disk_dev(0), "vda");
+        assert_eq!(disk_dev(1), "vdb");
+        assert_eq!(disk_dev(25), "vdz");
+        assert_eq!(disk_dev(26), "vdaa");
+        assert_eq!(disk_dev(27), "vdab");
+        assert_eq!(disk_dev(52), "vdba");
+    }
+
+    #[test]
+    fn test_clock_xml() {
+        let yaml = "
+            cpu: 2
+            memory: 2Gi
+            image:
+              url: http://example.com/image.qcow2
+        ";
+        let mut spec: models::Spec = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(clock_xml(&spec).unwrap(), "  <clock offset='utc'/>");
+
+        spec.timezone = Some("America/Argentina/Buenos_Aires".into());
+        assert_eq!(
+            clock_xml(&spec).unwrap(),
+            "  <clock offset='timezone' timezone='America/Argentina/Buenos_Aires'/>"
+        );
+
+        spec.timezone = Some("Europe/Berlin' foo='".into());
+        assert!(clock_xml(&spec).is_err());
+    }
+
+    #[test]
+    fn test_lifecycle_xml() {
+        let yaml = "
+            cpu: 2
+            memory: 2Gi
+            image:
+              url: http://example.com/image.qcow2
+        ";
+        let mut spec: models::Spec = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(lifecycle_xml(&spec), "");
+        assert_eq!(panic_xml(&spec), "");
+
+        spec.restart_policy = Some(RestartPolicy::OnFailure);
+        assert_eq!(lifecycle_xml(&spec), "  <on_crash>restart</on_crash>");
+        assert!(panic_xml(&spec).contains("<panic"));
+
+        spec.restart_policy = Some(RestartPolicy::Always);
+        assert!(lifecycle_xml(&spec).contains("<on_poweroff>restart</on_poweroff>"));
+    }
+
+    #[test]
+    fn test_cpu_xml() {
+        let mut spec = models::Spec::default();
+        assert_eq!(cpu_xml(&spec, None), "");
+
+        let xml = cpu_xml(&spec, Some("vmx"));
+        assert!(xml.starts_with("  <cpu mode='host-passthrough'>"));
+        assert!(xml.contains("<feature policy='require' name='vmx'/>"));
+
+        spec.cpu_features = Some(vec!["-hle".into()]);
+        let xml = cpu_xml(&spec, None);
+        assert!(xml.starts_with("  <cpu mode='host-model'>"));
+        assert!(xml.contains("<feature policy='disable' name='hle'/>"));
+    }
+
+    #[test]
+    fn test_tpm_xml() {
+        assert_eq!(tpm_xml(None), "");
+        let xml = tpm_xml(Some(Path::new("/var/lib/bigiron/m/swtpm.sock")));
+        assert!(xml.contains("model='tpm-crb'"));
+        assert!(xml.contains("path='/var/lib/bigiron/m/swtpm.sock'"));
+    }
+
+    #[test]
+    fn test_vlan_xml() {
+        let xml = vlan_xml(&[
+            vlan::Attachment::Bridge("brv208".into()),
+            vlan::Attachment::Ovs {
+                bridge: "brovs".into(),
+                vlan: 209,
+            },
+        ]);
+        let (linux, ovs) = xml.split_once("</interface>").unwrap();
+        assert!(linux.contains("<source bridge=\"brv208\"/>"));
+        assert!(!linux.contains("virtualport"));
+        assert!(ovs.contains("<source bridge=\"brovs\"/>"));
+        assert!(ovs.contains("<virtualport type='openvswitch'/>"));
+        assert!(ovs.contains("<tag id='209'/>"));
+    }
+
+    #[test]
+    fn test_rng_xml() {
+        let mut spec = models::Spec::default();
+        assert!(rng_xml(&spec).contains("/dev/urandom"));
+        spec.rng = Some(false);
+        assert_eq!(rng_xml(&spec), "");
+    }
+
+    #[test]
+    fn test_parse_guest_stats() {
+        let v = serde_json::json!({"last-update": 0, "stats": {}});
+        assert_eq!(parse_guest_stats(&v), None);
+
+        let v = serde_json::json!({
+            "last-update": 1700000000,
+            "stats": {
+                "stat-total-memory": 2048,
+                "stat-free-memory": 512,
+                "stat-available-memory": -1,
+            },
+        });
+        let stats = parse_guest_stats(&v).unwrap();
+        assert_eq!(stats.available, None);
+        assert_eq!(stats.used(), 1536);
+    }
+
+    #[test]
+    fn test_qos_xml() {
+        let yaml = "
+            cpu: 2
+            memory: 2Gi
+            image:
+              url: http://example.com/image.qcow2
+        ";
+        let mut spec: models::Spec = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(iotune_xml(&spec), "");
+        assert_eq!(bandwidth_xml(&spec), "");
+
+        spec.qos = Some(models::Qos {
+            disk_iops: Some(500),
+            disk_bandwidth: Some("100Mi".parse().unwrap()),
+            net_bandwidth: Some("10Mi".parse().unwrap()),
+        });
+        let iotune = iotune_xml(&spec);
+        assert!(iotune.contains("<total_iops_sec>500</total_iops_sec>"));
+        assert!(iotune.contains("<total_bytes_sec>104857600</total_bytes_sec>"));
+        let nic = nic_xml(Some("br0"), "52:54:00:00:00:01", &spec);
+        assert_eq!(
+            attr(find_elements(&nic, "inbound")[0], "average"),
+            Some("10240")
+        );
+    }
+
+    #[test]
+    fn test_disk_xml() {
+        let xml = disk_xml(
+            Path::new("/var/lib/bigiron/libvirt/x/image.qcow2"),
+            "vda",
+            "",
+        );
+        let disk = find_elements(&xml, "disk")[0];
+        assert_eq!(attr(disk, "type"), Some("file"));
+        assert_eq!(
+            attr(find_elements(&xml, "driver")[0], "type"),
+            Some("qcow2")
+        );
+
+        let xml = disk_xml(Path::new("/dev/vg0/web1_root"), "vdb", "");
+        assert_eq!(attr(find_elements(&xml, "disk")[0], "type"), Some("block"));
+        assert_eq!(attr(find_elements(&xml, "driver")[0], "type"), Some("raw"));
+        assert_eq!(
+            attr(find_elements(&xml, "source")[0], "dev"),
+            Some("/dev/vg0/web1_root")
+        );
+    }
+
+    #[test]
+    fn test_boot_xml() {
+        let yaml = "
+            cpu: 2
+            memory: 2Gi
+            image:
+              url: http://example.com/image.qcow2
+        ";
+        let mut spec: models::Spec = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(boot_xml(&spec), "    <boot dev='hd'/>");
+
+        spec.boot_order = Some(vec![models::BootDevice::Cdrom, models::BootDevice::Disk]);
+        let xml = boot_xml(&spec);
+        let boot = find_elements(&xml, "boot");
+        assert_eq!(attr(boot[0], "dev"), Some("cdrom"));
+        assert_eq!(attr(boot[1], "dev"), Some("hd"));
+    }
+
+    #[test]
+    fn test_cdrom_xml() {
+        let yaml = "
+            cpu: 2
+            memory: 2Gi
+            image:
+              url: http://example.com/image.qcow2
+        ";
+        let mut spec: models::Spec = serde_yaml::from_str(yaml).unwrap();
+        let xml = cdrom_xml(&spec, None).unwrap();
+        assert_eq!(attr(find_elements(&xml, "target")[0], "bus"), Some("ide"));
+        assert!(find_elements(&xml, "source").is_empty());
+
+        spec.machine_type = Some("q35".into());
+        let xml = cdrom_xml(&spec, Some(Path::new("/srv/iso/install.iso"))).unwrap();
+        assert_eq!(attr(find_elements(&xml, "target")[0], "bus"), Some("sata"));
+        assert_eq!(
+            attr(find_elements(&xml, "source")[0], "file"),
+            Some("/srv/iso/install.iso")
+        );
+
+        spec.arch = Some("s390x".into());
+        spec.machine_type = None;
+        let xml = cdrom_xml(&spec, None).unwrap();
+        assert_eq!(attr(find_elements(&xml, "target")[0], "bus"), Some("scsi"));
+    }
+
+    #[test]
+    fn test_serial_xml() {
+        let xml = serial_xml(None, None, false);
+        assert_eq!(
+            attr(find_elements(&xml, "target")[0], "type"),
+            Some("isa-serial")
+        );
+
+        let xml = serial_xml(None, Some(Path::new("/run/m/oops.sock")), true);
+        let targets = find_elements(&xml, "target");
+        assert_eq!(attr(targets[1], "type"), Some("sclp-serial"));
+        assert_eq!(attr(targets[1], "port"), Some("1"));
+        let models = find_elements(&xml, "model");
+        assert_eq!(attr(models[0], "name"), Some("sclpconsole"));
+        assert_eq!(attr(models[1], "name"), Some("sclplmconsole"));
+    }
+
+    #[test]
+    fn test_element_texts() {
+        let xml = "<guest><machine maxCpus='255'>pc-i440fx-7.2</machine>\
+                   <machine canonical='pc-q35-7.2' maxCpus='288'>q35</machine><machines/></guest>";
+        assert_eq!(element_texts(xml, "machine"), vec!["pc-i440fx-7.2", "q35"]);
+    }
+}
@@ -15,22 +15,34 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use tracing::info;
+use tracing::{info, warn};
 
-// mkdir based mandatory locking for interprocess use
+use crate::error::Error;
+
+// flock(2) based mandatory locking for interprocess use. The kernel drops
+// the lock when the holder exits, so a crashed process can't wedge others.
 pub struct LockFile {
     path: PathBuf,
 }
 
+// the lock is held for as long as the file stays open
 pub struct LockFileGuard<'a> {
     lf: &'a LockFile,
+    file: File,
 }
 
 impl Drop for LockFileGuard<'_> {
     fn drop(&mut self) {
-        self.lf.release();
+        // clear our pid, the file itself stays for the next holder
+        if let Err(e) = self.file.set_len(0) {
+            warn!("error clearing lockfile {:?}: {}", self.lf.path, e);
+        }
     }
 }
 
@@ -41,34 +53,124 @@ impl LockFile {
         }
     }
 
-    pub fn acquire(&self) -> LockFileGuard {
+    pub fn acquire(&self) -> LockFileGuard<'_> {
+        self.lock(None).expect("error acquiring lockfile")
+    }
+
+    // like acquire, but give up once `timeout` has passed
+    pub fn acquire_timeout(&self, timeout: Duration) -> Result<LockFileGuard<'_>, Error> {
+        self.lock(Some(timeout))
+    }
+
+    fn lock(&self, timeout: Option<Duration>) -> Result<LockFileGuard<'_>, Error> {
+        let start = Instant::now();
+        let mut blocked = false;
         loop {
-            match std::fs::create_dir(&self.path) {
-                Ok(_) => break,
-                Err(_) => {
-                    info!("Blocked on acquiring lockfile {:?}", self.path)
+            if !self.legacy_held()? {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    // the pid belongs to the holder until we have the lock
+                    .truncate(false)
+                    .open(&self.path)?;
+                if try_flock(&file)? {
+                    // write PID to the file to indicate who has the lock
+                    file.set_len(0)?;
+                    file.write_all(std::process::id().to_string().as_bytes())?;
+                    return Ok(LockFileGuard { lf: self, file });
                 }
             }
 
-            let dur = std::time::Duration::from_millis(1);
-            std::thread::sleep(dur);
+            if !blocked {
+                info!("Blocked on acquiring lockfile {:?}", self.path);
+                blocked = true;
+            }
+            if let Some(timeout) = timeout {
+                if start.elapsed() > timeout {
+                    return Err(format!("timed out acquiring lockfile {:?}", self.path).into());
+                }
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Older versions locked by creating a directory holding a pid file. Wait
+    // on one whose owner is still running, remove one whose owner is gone.
+    fn legacy_held(&self) -> Result<bool, Error> {
+        if !self.path.is_dir() {
+            return Ok(false);
         }
 
-        // write PID to file inside directory to indicate who has the lock
-        let pid = std::process::id();
-        let buf = pid.to_string();
-        std::fs::write(self.path.join("pid"), buf.as_bytes())
-            .expect("error writing PID to lockfile");
+        let pid = std::fs::read_to_string(self.path.join("pid"))
+            .ok()
+            .and_then(|p| p.trim().parse::<i32>().ok());
+        match pid {
+            Some(pid) if pid_alive(pid) => return Ok(true),
+            // the owner may not have written its pid yet
+            None if modified_within(&self.path, Duration::from_secs(5)) => return Ok(true),
+            _ => {}
+        }
 
-        LockFileGuard { lf: self }
+        warn!(
+            "removing stale lockfile {:?} left behind by pid {:?}",
+            self.path, pid
+        );
+        match std::fs::remove_dir_all(&self.path) {
+            Ok(_) => Ok(false),
+            // someone else cleaned it up first
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// take an exclusive lock without blocking, false if someone else holds it
+fn try_flock(file: &File) -> Result<bool, Error> {
+    let r = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if r == 0 {
+        return Ok(true);
+    }
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EWOULDBLOCK) => Ok(false),
+        _ => Err(e.into()),
     }
+}
+
+fn pid_alive(pid: i32) -> bool {
+    // signal 0 only checks the process exists, EPERM means it does
+    let r = unsafe { libc::kill(pid, 0) };
+    r == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn modified_within(path: &Path, d: Duration) -> bool {
+    std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map(|t| t.elapsed().map_or(true, |e| e < d))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_lockfile() {
+        let dir = TestDir::new("lock");
+        let path = dir.join("lock");
+        let (a, b) = (LockFile::new(&path), LockFile::new(&path));
 
-    fn release(&self) {
-        // remove pid file
-        std::fs::remove_file(self.path.join("pid"))
-            .expect("error while unlinking pid file for lockfile");
+        let guard = a.acquire_timeout(Duration::from_millis(50)).unwrap();
+        assert!(b.acquire_timeout(Duration::from_millis(50)).is_err());
+        drop(guard);
+        drop(b.acquire_timeout(Duration::from_millis(50)).unwrap());
+        std::fs::remove_file(&path).unwrap();
 
-        // remove directory (i.e. the lock)
-        std::fs::remove_dir(&self.path).expect("error while removing lockfile");
+        // an old style lock directory whose owner is long gone
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join("pid"), i32::MAX.to_string()).unwrap();
+        drop(a.acquire_timeout(Duration::from_millis(50)).unwrap());
+        assert!(path.is_file());
     }
 }
@@ -0,0 +1,211 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Per-machine log in the machine's directory: lifecycle events recorded by
+// bigiron and the output of qemu, one json object per line. Qemu writes its
+// own log file, whose new lines are copied in by `sync`.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::error::Error;
+
+pub const FILE: &str = "machine.log";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    // unix time in milliseconds
+    pub ts: u64,
+    // bigiron for lifecycle events, or the log file copied in, e.g. qemu
+    pub source: String,
+    pub message: String,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:03} {:-7} {}",
+            self.ts / 1000,
+            self.ts % 1000,
+            self.source,
+            self.message
+        )
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn append(dir: &Path, entries: &[Entry]) -> Result<(), Error> {
+    let mut buf = String::new();
+    for e in entries {
+        buf.push_str(&serde_json::to_string(e)?);
+        buf.push('\n');
+    }
+    File::options()
+        .append(true)
+        .create(true)
+        .open(dir.join(FILE))?
+        .write_all(buf.as_bytes())?;
+    Ok(())
+}
+
+// Record a lifecycle event. Like the audit log, failing to write it is
+// logged and otherwise ignored.
+pub fn record(dir: &Path, message: &str) {
+    let entry = Entry {
+        ts: now(),
+        source: "bigiron".to_string(),
+        message: message.to_string(),
+    };
+    if let Err(e) = append(dir, &[entry]) {
+        error!("error writing machine log in {}: {}", dir.display(), e);
+    }
+}
+
+// Copy the lines added to `path` since the last sync, tagged with `source`.
+// They get the time of the sync, qemu and libvirt stamp their own lines.
+pub fn sync(dir: &Path, source: &str, path: &Path) -> Result<(), Error> {
+    let mut f = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let offset_path = dir.join(format!(".{}.offset", source));
+    let mut offset: u64 = std::fs::read_to_string(&offset_path)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(0);
+    // start over on a log that was rotated or truncated
+    if f.metadata()?.len() < offset {
+        offset = 0;
+    }
+    f.seek(SeekFrom::Start(offset))?;
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf)?;
+
+    // a line still being written is left for the next sync
+    let complete = match buf.iter().rposition(|b| *b == b'\n') {
+        Some(end) => end + 1,
+        None => return Ok(()),
+    };
+    let ts = now();
+    let entries: Vec<Entry> = String::from_utf8_lossy(&buf[..complete])
+        .lines()
+        .map(|l| Entry {
+            ts,
+            source: source.to_string(),
+            message: l.to_string(),
+        })
+        .collect();
+    append(dir, &entries)?;
+    std::fs::write(offset_path, (offset + complete as u64).to_string())?;
+    Ok(())
+}
+
+// entries from byte `pos` of the log on, with the position after the last
+// complete one
+fn read_from(dir: &Path, pos: u64) -> Result<(Vec<Entry>, u64), Error> {
+    let mut f = match File::open(dir.join(FILE)) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), pos)),
+        Err(e) => return Err(e.into()),
+    };
+    f.seek(SeekFrom::Start(pos))?;
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf)?;
+
+    let complete = buf.iter().rposition(|b| *b == b'\n').map_or(0, |e| e + 1);
+    let mut entries = Vec::new();
+    for line in buf[..complete].split(|b| *b == b'\n') {
+        if !line.is_empty() {
+            entries.push(serde_json::from_slice(line)?);
+        }
+    }
+    Ok((entries, pos + complete as u64))
+}
+
+pub fn read(dir: &Path) -> Result<Vec<Entry>, Error> {
+    Ok(read_from(dir, 0)?.0)
+}
+
+// Hand every entry to `out` and keep handing new ones as they are added,
+// calling `sync` to copy in the sources first, until an error.
+pub fn follow<S, O>(dir: &Path, mut sync: S, mut out: O) -> Result<(), Error>
+where
+    S: FnMut() -> Result<(), Error>,
+    O: FnMut(&Entry),
+{
+    let mut pos = 0;
+    loop {
+        sync()?;
+        let (entries, next) = read_from(dir, pos)?;
+        entries.iter().for_each(&mut out);
+        pos = next;
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_sync() {
+        let dir = TestDir::new("machinelog");
+        let qemu = dir.join("qemu.log");
+
+        record(&dir, "started");
+        std::fs::write(&qemu, "char device redirected\nwarning: host doesn't").unwrap();
+        sync(&dir, "qemu", &qemu).unwrap();
+        let messages = |dir: &Path| -> Vec<String> {
+            read(dir).unwrap().into_iter().map(|e| e.message).collect()
+        };
+        assert_eq!(messages(&dir), vec!["started", "char device redirected"]);
+
+        // the partial line comes in once finished, and only once
+        File::options()
+            .append(true)
+            .open(&qemu)
+            .unwrap()
+            .write_all(b" support feature\n")
+            .unwrap();
+        sync(&dir, "qemu", &qemu).unwrap();
+        sync(&dir, "qemu", &qemu).unwrap();
+        let entries = read(&dir).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2].source, "qemu");
+        assert_eq!(entries[2].message, "warning: host doesn't support feature");
+
+        // a rotated log is read from the start
+        std::fs::write(&qemu, "new\n").unwrap();
+        sync(&dir, "qemu", &qemu).unwrap();
+        assert_eq!(messages(&dir).last().unwrap(), "new");
+    }
+}
@@ -15,17 +15,37 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
 use tracing_subscriber;
 
 use bigiron::api;
+use bigiron::backup;
+use bigiron::baremetal;
+use bigiron::bootserver;
+use bigiron::bus;
+use bigiron::cli;
+use bigiron::config;
 use bigiron::dnsmasq;
+use bigiron::error::{self, Category};
+use bigiron::freeze;
+use bigiron::host;
+use bigiron::hostpower;
+use bigiron::imagerepo::ImageRepo;
+use bigiron::models;
+use bigiron::network;
+use bigiron::reconcile;
+use bigiron::specfile;
+use bigiron::storage;
+use bigiron::store;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
+#[command(after_help = EXIT_CODES)]
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
@@ -36,55 +56,1369 @@ enum Commands {
     Apply {
         #[arg(required(true))]
         specfile: PathBuf,
+        #[arg(long)]
+        override_freeze: bool,
+        /// Show where machines would be placed and why, without creating them
+        #[arg(long)]
+        plan_only: bool,
+        /// Wait until new machines have a dhcp lease or a responding guest agent
+        #[arg(long)]
+        wait: bool,
+        /// How long --wait waits (90s, 10m, ...)
+        #[arg(long, default_value = "5m")]
+        timeout: String,
+        /// Stop or delete lower priority machines when a new one doesn't fit
+        #[arg(long)]
+        preempt: Option<config::Preemption>,
+        /// Write a json report of what was done to a file, or - for stdout
+        #[arg(long)]
+        report: Option<PathBuf>,
+        /// Set a ${VAR} of the spec file, KEY=VALUE
+        #[arg(long)]
+        set: Vec<String>,
+        /// yaml file of values for the spec file's ${VAR}s
+        #[arg(long)]
+        values: Option<PathBuf>,
+    },
+    /// Check a spec file for what apply would reject, without root
+    Validate {
+        #[arg(required_unless_present("schema"))]
+        specfile: Option<PathBuf>,
+        /// Print the JSON Schema of spec file documents instead
+        #[arg(long, conflicts_with("specfile"))]
+        schema: bool,
+        /// Set a ${VAR} of the spec file, KEY=VALUE
+        #[arg(long)]
+        set: Vec<String>,
+        /// yaml file of values for the spec file's ${VAR}s
+        #[arg(long)]
+        values: Option<PathBuf>,
+    },
+    List {
+        /// Also show cpu and memory use of running machines
+        #[arg(long)]
+        wide: bool,
+        /// Only machines with matching labels, e.g. env=test,tier!=db
+        #[arg(short = 'l', long)]
+        selector: Option<String>,
     },
-    List,
     Get {
         #[arg(required(true))]
         id: String,
+        /// Sample nic traffic for half a second to show its rate
+        #[arg(long)]
+        rates: bool,
+    },
+    /// Show the effective virtual hardware of a machine
+    DescribeHw {
+        #[arg(required(true))]
+        id: String,
     },
     Delete {
+        #[arg(
+            required_unless_present_any(["selector", "file", "all"]),
+            conflicts_with_all(["selector", "file", "all"])
+        )]
+        id: Option<String>,
+        /// Delete every machine with matching labels, e.g. env=test
+        #[arg(short = 'l', long, conflicts_with_all(["file", "all"]))]
+        selector: Option<String>,
+        /// Delete every resource declared in a spec file
+        #[arg(short = 'f', long, conflicts_with("all"))]
+        file: Option<PathBuf>,
+        /// Set a ${VAR} of the spec file, KEY=VALUE
+        #[arg(long, requires("file"))]
+        set: Vec<String>,
+        /// yaml file of values for the spec file's ${VAR}s
+        #[arg(long, requires("file"))]
+        values: Option<PathBuf>,
+        /// Delete every machine
+        #[arg(long)]
+        all: bool,
+        /// Don't ask before deleting every machine
+        #[arg(short = 'y', long, requires("all"))]
+        yes: bool,
+        #[arg(long)]
+        override_freeze: bool,
+    },
+    /// Show a machine's lifecycle events and qemu output
+    Logs {
+        #[arg(required(true))]
+        id: String,
+        /// keep printing new entries
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// Bundle a machine into a tar file, to import on another host or keep as a backup
+    Export {
+        #[arg(required(true))]
+        id: String,
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Include compressed copies of the disks, the machine has to be stopped
+        #[arg(long)]
+        disks: bool,
+    },
+    /// Recreate and start a machine from an exported bundle
+    Import {
+        #[arg(required(true))]
+        bundle: PathBuf,
+        #[arg(long)]
+        override_freeze: bool,
+    },
+    /// Create a machine from a copy of another one's root disk
+    Clone {
+        #[arg(required(true))]
+        source: String,
+        #[arg(required(true))]
+        new: String,
+        /// Existing snapshot of the source's root disk to clone, instead of
+        /// taking one of the stopped source
+        #[arg(long)]
+        snapshot: Option<String>,
+        #[arg(long)]
+        override_freeze: bool,
+    },
+    /// Rename a stopped machine
+    Rename {
+        #[arg(required(true))]
+        id: String,
+        #[arg(required(true))]
+        new: String,
+        #[arg(long)]
+        override_freeze: bool,
+    },
+    Scale {
+        #[arg(required(true))]
+        id: String,
+        #[arg(long)]
+        cpu: Option<u32>,
+        #[arg(long)]
+        memory: Option<String>,
+        #[arg(long)]
+        override_freeze: bool,
+    },
+    /// Virtual power control, like a BMC for the machine
+    Power {
+        #[command(subcommand)]
+        command: PowerCommands,
+    },
+    /// Manage the disks of a stopped machine in its storage pool
+    Disk {
+        #[command(subcommand)]
+        command: DiskCommands,
+    },
+    /// Serve a machine's serial console over tcp, started along with the machine
+    #[command(hide = true)]
+    SolServe {
+        #[arg(required(true))]
+        id: String,
+    },
+    /// Copy a machine's crash console into its oops.log, started along with the machine
+    #[command(hide = true)]
+    CrashServe {
+        #[arg(required(true))]
+        id: String,
+    },
+    /// Names for images in the image repo
+    Image {
+        #[command(subcommand)]
+        command: ImageCommands,
+    },
+    /// Eject the media in a machine's cdrom drive, e.g. after an install
+    Eject {
         #[arg(required(true))]
         id: String,
     },
-    StartDhcp,
-    StopDhcp,
+    /// Virtual media in the machine's cdrom drive, like a BMC offers
+    Media {
+        #[command(subcommand)]
+        command: MediaCommands,
+    },
+    /// Stream machine changes as json lines, for external controllers
+    Watch {
+        /// Instead keep the machines in line with a directory of spec files,
+        /// applying new and changed files and deleting what files drop
+        #[arg(conflicts_with_all(["since", "initial"]))]
+        dir: Option<PathBuf>,
+        /// Resource version to resume after
+        #[arg(long, conflicts_with = "initial")]
+        since: Option<u64>,
+        /// Start with every current machine as an added event
+        #[arg(long)]
+        initial: bool,
+        #[arg(long, requires = "dir")]
+        override_freeze: bool,
+        /// Stop or delete lower priority machines when a new one doesn't fit
+        #[arg(long, requires = "dir")]
+        preempt: Option<config::Preemption>,
+        /// Set a ${VAR} of the spec files, KEY=VALUE
+        #[arg(long, requires = "dir")]
+        set: Vec<String>,
+        /// yaml file of values for the spec files' ${VAR}s
+        #[arg(long, requires = "dir")]
+        values: Option<PathBuf>,
+    },
+    /// Show recent events: machines created, started, stopped and deleted,
+    /// leases acquired and expired, images imported
+    Events {
+        /// keep printing new events
+        #[arg(short, long)]
+        follow: bool,
+        /// Only events of one kind: machine, image, lease, netstate or crash
+        #[arg(long)]
+        kind: Option<String>,
+        /// Print json lines
+        #[arg(long)]
+        json: bool,
+    },
+    /// Live mirrors of machine disks on a second device
+    Mirror {
+        #[command(subcommand)]
+        command: MirrorCommands,
+    },
+    /// Bare metal hosts provisioned over the network
+    Baremetal {
+        #[command(subcommand)]
+        command: BaremetalCommands,
+    },
+    /// Serve iPXE scripts, kernels and install files to netbooting machines
+    BootServer {
+        #[arg(long, default_value = "0.0.0.0:8080")]
+        listen: String,
+    },
+    /// Serve the gRPC control api, needs a build with the grpc feature
+    GrpcServer {
+        /// Unix socket for root and members of the api group
+        #[arg(long, default_value = "/run/bigiron/api.sock")]
+        socket: PathBuf,
+        /// Also serve on tcp for clients with a token, e.g. 127.0.0.1:50051,
+        /// or 0.0.0.0:50051 with api.tls set
+        #[arg(long)]
+        listen: Option<String>,
+    },
+    /// Block mutating operations on the host or a project
+    Freeze {
+        #[arg(long)]
+        project: Option<String>,
+        /// Duration (30m, 2h, 7d) or unix timestamp; indefinite if unset
+        #[arg(long)]
+        until: Option<String>,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    Unfreeze {
+        #[arg(long)]
+        project: Option<String>,
+    },
+    /// List active freezes
+    Freezes,
+    /// Manage the dnsmasq instance serving the management network
+    Dhcp {
+        #[command(subcommand)]
+        command: DhcpCommands,
+    },
+    #[command(hide = true)]
+    StartDhcp {
+        #[arg(long)]
+        force: bool,
+    },
+    #[command(hide = true)]
+    StopDhcp {
+        #[arg(long)]
+        purge: bool,
+    },
+    #[command(hide = true)]
     RestartDhcp,
+    /// Back up the machine and image records, reservations and dhcp hosts
+    Backup {
+        /// Directory for the archive, named after the time of the backup
+        #[arg(long)]
+        dir: Option<PathBuf>,
+        /// Keep running and back up at this interval, e.g. 6h
+        #[arg(long)]
+        every: Option<String>,
+        /// Archives to keep when backing up periodically
+        #[arg(long, default_value_t = 7, requires("every"))]
+        keep: usize,
+    },
+    /// Roll the records back to a backup, after backing up the current ones
+    Restore {
+        #[arg(required(true))]
+        archive: PathBuf,
+        /// Don't ask before replacing the current records
+        #[arg(short = 'y', long)]
+        yes: bool,
+    },
+    /// Import machines and images from the file store into a sqlite database
+    MigrateStore {
+        /// Database to create, defaults to the store path in the config
+        #[arg(long)]
+        db: Option<PathBuf>,
+    },
+    /// Move the netstate and dnsmasq leases into the sqlite backend
+    MigrateNetstate {
+        /// dnsmasq lease file to import, defaults to bigiron's own
+        #[arg(long)]
+        leasefile: Option<PathBuf>,
+        /// Only check the existing state for problems
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Inspect the management network
+    Network {
+        #[command(subcommand)]
+        command: NetworkCommands,
+    },
+    /// Inspect the host
+    Host {
+        #[command(subcommand)]
+        command: HostCommands,
+    },
+    #[command(flatten)]
+    Docs(cli::DocCommands),
+}
+
+#[derive(Subcommand)]
+enum PowerCommands {
+    On {
+        #[arg(required(true))]
+        id: String,
+        #[arg(long)]
+        override_freeze: bool,
+    },
+    Off {
+        #[arg(required(true))]
+        id: String,
+        /// Ask the guest to shut down instead of cutting power
+        #[arg(long)]
+        soft: bool,
+        #[arg(long)]
+        override_freeze: bool,
+    },
+    Cycle {
+        #[arg(required(true))]
+        id: String,
+        #[arg(long)]
+        override_freeze: bool,
+    },
+    Status {
+        #[arg(required(true))]
+        id: String,
+    },
+    /// Power on every machine with restartPolicy always, for running at host boot
+    Autostart,
+}
+
+#[derive(Subcommand)]
+enum DiskCommands {
+    /// Grow a disk, the guest grows its filesystems
+    Resize {
+        #[arg(required(true))]
+        id: String,
+        /// root, or the local file name of a storage disk
+        #[arg(long, default_value = storage::ROOT_VOLUME)]
+        disk: String,
+        #[arg(required(true))]
+        size: String,
+        #[arg(long)]
+        override_freeze: bool,
+    },
+    Snapshot {
+        #[arg(required(true))]
+        id: String,
+        #[arg(long, default_value = storage::ROOT_VOLUME)]
+        disk: String,
+        #[arg(required(true))]
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum BaremetalCommands {
+    /// Show registered hosts and how far their install got
+    List,
+    /// Reinstall a host the next time it netboots
+    Reprovision {
+        #[arg(required(true))]
+        name: String,
+    },
+    Delete {
+        #[arg(required(true))]
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum DhcpCommands {
+    Start {
+        /// Start even if another dhcp server answers on the bridge
+        #[arg(long)]
+        force: bool,
+    },
+    Stop {
+        /// Also drop host records and leases of hosts that no longer exist
+        #[arg(long)]
+        purge: bool,
+    },
+    Restart,
+    /// Show whether dnsmasq is running and listening
+    Status,
+}
+
+#[derive(Subcommand)]
+enum MediaCommands {
+    Insert {
+        #[arg(required(true))]
+        id: String,
+        /// Local path or file:// url of an ISO image
+        #[arg(required(true))]
+        image: String,
+    },
+    Eject {
+        #[arg(required(true))]
+        id: String,
+    },
+    Status {
+        #[arg(required(true))]
+        id: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ImageCommands {
+    /// Show the image catalog
+    List,
+    /// Name an image, for use as spec.image.name
+    Tag {
+        #[arg(required(true))]
+        name: String,
+        /// Image id, or a url that is imported whenever the name is used
+        #[arg(required(true))]
+        image: String,
+    },
+    Untag {
+        #[arg(required(true))]
+        name: String,
+    },
+    /// Delete an image no machine disk is layered on
+    Delete {
+        #[arg(required(true))]
+        id: String,
+        /// Copy the image into the disks of the (stopped) machines using it
+        #[arg(long)]
+        flatten: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum MirrorCommands {
+    /// Show whether the mirror is in sync with the machine's disk
+    Status {
+        #[arg(required(true))]
+        id: String,
+    },
+    /// Run the machine from its mirror, e.g. after its disk's device failed
+    Switch {
+        #[arg(required(true))]
+        id: String,
+        /// Power off a running machine whose mirror is not in sync
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum NetworkCommands {
+    /// Show the management network and how much of it is in use
+    Show,
+    /// List address reservations
+    Reservations,
+    /// Reserve an address for a hostname, e.g. a host outside of bigiron
+    Reserve {
+        #[arg(required(true))]
+        hostname: String,
+        /// Reserve this address instead of the next free one
+        #[arg(long)]
+        ip: Option<String>,
+        #[arg(long)]
+        mac: Option<String>,
+    },
+    /// Release the address reserved for a hostname
+    Release {
+        #[arg(required(true))]
+        hostname: String,
+    },
+    /// Show active leases with their age and expiry, clearing expired ones
+    Leases,
+    /// List host ports forwarded to machines
+    Forwards {
+        /// Program the forwarding rules again, e.g. after a host reboot
+        #[arg(long)]
+        sync: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum HostCommands {
+    /// Show bridges, their members, vlan subinterfaces and addresses
+    Net,
+    /// Show the hosts machines are placed on, with their capacity and use
+    List,
+    /// Migrate machines off lightly used hosts and power down the empty ones
+    Consolidate {
+        /// Only print what would move and which hosts would power down
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Power up a host powered down by consolidate
+    Wake { name: String },
+}
+
+// every machine, or those matching a label selector
+fn list_machines(
+    selector: Option<&str>,
+) -> Result<Vec<models::Machine>, Box<dyn std::error::Error>> {
+    let store = api::Store::new()?;
+    Ok(match selector {
+        Some(s) => store.select_machines(&models::Selector::parse(s)?)?,
+        None => store.list_machines()?,
+    })
+}
+
+#[cfg(feature = "grpc")]
+fn grpc_server(socket: &Path, listen: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    bigiron::server::serve(socket, listen)
+}
+
+#[cfg(not(feature = "grpc"))]
+fn grpc_server(_: &Path, _: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    Err("bigiron was built without the grpc feature".into())
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+const EXIT_CODES: &str = "Exit codes:
+  0  success
+  1  any other error
+  2  invalid usage
+  3  the checked service or job is not running
+  4  the machine or other resource does not exist
+  5  libvirt or another service can't be reached
+  6  a freeze blocks the change
+  7  the network is out of addresses or the address is taken";
+
+fn main() {
     tracing_subscriber::fmt::init();
 
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        std::process::exit(error::exit_code(&e));
+    }
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
     match &cli.command {
-        Commands::Apply { specfile } => {
-            let _ = api::apply_specfile(specfile)?;
+        Commands::Apply {
+            specfile,
+            override_freeze,
+            plan_only,
+            wait,
+            timeout,
+            preempt,
+            report,
+            set,
+            values,
+        } => {
+            let opts = api::ApplyOptions {
+                override_freeze: *override_freeze,
+                plan_only: *plan_only,
+                wait: match wait {
+                    true => Some(Duration::from_secs(freeze::parse_duration(timeout)?)),
+                    false => None,
+                },
+                preempt: *preempt,
+                report: report.clone(),
+                values: specfile::Values::new(set, values.as_deref())?,
+            };
+            api::apply_specfile(specfile, &opts)?;
         }
-        Commands::List => {
-            let v = api::Store::new().list_machines();
+        Commands::Validate {
+            specfile,
+            schema,
+            set,
+            values,
+        } => match specfile {
+            Some(path) if !*schema => {
+                let values = specfile::Values::new(set, values.as_deref())?;
+                let problems = specfile::validate(path, &values)?;
+                for p in &problems {
+                    println!("{}", p);
+                }
+                if !problems.is_empty() {
+                    return Err(format!("{} problems found", problems.len()).into());
+                }
+            }
+            _ => println!("{}", specfile::schema()?),
+        },
+        Commands::List {
+            wide: false,
+            selector,
+        } => {
+            let v = list_machines(selector.as_deref())?;
             println!("{:-20} {:-10}", "NAME", "STATUS");
             for m in v {
                 println!("{:-20} {:-10?}", m.name, m.status);
             }
         }
-        Commands::Get { id } => match api::get_machine_by_id(&id) {
-            Some(m) => println!("{}", m.to_yaml()?),
-            None => println!("No machine found with id='{}'", id),
+        Commands::List {
+            wide: true,
+            selector,
+        } => {
+            let v = list_machines(selector.as_deref())?;
+            let names: Vec<&str> = v.iter().map(|m| m.name.as_str()).collect();
+            let usage = host::cgroup::utilization(&names, Duration::from_millis(500))?;
+            println!(
+                "{:-20} {:-10} {:>4} {:>8} {:>6} {:>8}",
+                "NAME", "STATUS", "CPUS", "MEMORY", "CPU%", "MEM USED"
+            );
+            for (m, u) in v.iter().zip(usage) {
+                let (cpu, mem) = match u {
+                    Some((cpu, mem)) => (format!("{:.1}", cpu), format!("{}Mi", mem >> 20)),
+                    None => ("-".to_string(), "-".to_string()),
+                };
+                println!(
+                    "{:-20} {:-10} {:>4} {:>8} {:>6} {:>8}",
+                    m.name,
+                    format!("{:?}", m.status),
+                    m.spec.cpu,
+                    m.spec.memory,
+                    cpu,
+                    mem
+                );
+            }
+        }
+        Commands::Get { id, rates } => match api::get_machine_by_id(id)? {
+            Some(m) => {
+                println!("{}", m.to_yaml()?);
+                if let Some(display) = api::get_machine_display(id)? {
+                    println!("display: {}", display);
+                }
+                if let Some(sol) = api::get_machine_sol(id)? {
+                    println!("sol: {}", sol);
+                }
+                if let Some(log) = api::get_machine_crash_log(id)? {
+                    println!("crash log: {}", log.display());
+                }
+                let interval = rates.then(|| Duration::from_millis(500));
+                let traffic = api::machine_traffic(id, interval)?;
+                if !traffic.is_empty() {
+                    println!("traffic:");
+                }
+                for t in traffic {
+                    let c = t.total;
+                    let rate = |r: f64| format!(", {}/s", fmt_bytes(r as u64));
+                    let (rx_rate, tx_rate) = match t.rates {
+                        Some((rx, tx)) => (rate(rx), rate(tx)),
+                        None => (String::new(), String::new()),
+                    };
+                    println!(
+                        "  {} ({}): rx {} ({} pkts{}) tx {} ({} pkts{})",
+                        t.nic.tap.as_deref().unwrap_or("-"),
+                        t.nic.bridge.as_deref().unwrap_or("-"),
+                        fmt_bytes(c.rx_bytes),
+                        c.rx_packets,
+                        rx_rate,
+                        fmt_bytes(c.tx_bytes),
+                        c.tx_packets,
+                        tx_rate
+                    );
+                }
+                match api::machine_memory(id) {
+                    Ok(Some(mem)) => println!(
+                        "guest memory: {} used of {} ({} free)",
+                        fmt_bytes(mem.used()),
+                        fmt_bytes(mem.total),
+                        fmt_bytes(mem.free)
+                    ),
+                    Ok(None) => {}
+                    Err(e) => println!("guest memory: unavailable ({})", e),
+                }
+                match api::get_machine_guest_info(id) {
+                    Ok(Some(info)) => {
+                        if let Some(hostname) = info.hostname.as_deref() {
+                            println!("guest hostname: {}", hostname);
+                        }
+                        println!("guest addresses: {}", info.addresses().join(", "));
+                    }
+                    Ok(None) => {}
+                    Err(e) => println!("guest agent: unavailable ({})", e),
+                }
+            }
+            None => {
+                return Err(error::not_found(format!(
+                    "No machine found with id='{}'",
+                    id
+                )))
+            }
         },
-        Commands::Delete { id } => {
-            api::delete_machine(&id)?;
+        Commands::DescribeHw { id } => {
+            let (m, hw, running) = api::machine_hardware(id)?;
+            println!("name: {}", m.name);
+            println!("state: {}", if running { "running" } else { "stopped" });
+            println!("cpus: {}", m.spec.cpu);
+            println!("memory: {}", m.spec.memory);
+            println!(
+                "machine type: {}",
+                hw.machine_type.as_deref().unwrap_or("-")
+            );
+            match &hw.loader {
+                Some(l) => println!("firmware: uefi ({})", l.display()),
+                None => println!("firmware: bios"),
+            }
+            println!("disks:");
+            for d in &hw.disks {
+                println!(
+                    "  {:-5} {:-6} {:-6} {:>8} {}",
+                    d.target,
+                    d.bus,
+                    d.device,
+                    d.size.map(models::from_size).unwrap_or_else(|| "-".into()),
+                    d.source
+                        .as_ref()
+                        .map(|s| s.display().to_string())
+                        .unwrap_or_else(|| "(empty)".into())
+                );
+            }
+            println!("nics:");
+            for n in &hw.nics {
+                println!(
+                    "  {:-17} {:-10} {}",
+                    n.mac.as_deref().unwrap_or("-"),
+                    n.bridge.as_deref().unwrap_or("-"),
+                    n.model.as_deref().unwrap_or("default")
+                );
+            }
+            if !hw.hostdevs.is_empty() || m.spec.devices.is_some() {
+                println!("devices:");
+                for d in &hw.hostdevs {
+                    println!("  pci {}", d);
+                }
+                if !running {
+                    for d in m.spec.devices.iter().flatten() {
+                        if let models::Device::VfPool(p) = d {
+                            println!("  virtual function from {}", p.vf_pool);
+                        }
+                    }
+                }
+            }
+        }
+        Commands::Delete {
+            id,
+            selector,
+            file,
+            set,
+            values,
+            all,
+            yes,
+            override_freeze,
+        } => {
+            if let Some(id) = id {
+                api::delete_machine(id, *override_freeze)?;
+            } else if let Some(file) = file {
+                let mut failed = 0;
+                let values = specfile::Values::new(set, values.as_deref())?;
+                for r in api::delete_specfile(file, &values, *override_freeze)? {
+                    match r.outcome {
+                        api::DeleteOutcome::Deleted => println!("{} {}: deleted", r.kind, r.name),
+                        api::DeleteOutcome::NotFound => {
+                            println!("{} {}: not found", r.kind, r.name)
+                        }
+                        api::DeleteOutcome::Kept(why) => {
+                            println!("{} {}: kept, {}", r.kind, r.name, why)
+                        }
+                        api::DeleteOutcome::Failed(e) => {
+                            eprintln!("{} {}: {}", r.kind, r.name, e);
+                            failed += 1;
+                        }
+                    }
+                }
+                if failed > 0 {
+                    return Err(format!("{} resources could not be deleted", failed).into());
+                }
+            } else {
+                let machines = list_machines(selector.as_deref())?;
+                if *all && !*yes && !confirm(&format!("Delete all {} machines?", machines.len()))? {
+                    return Err("aborted".into());
+                }
+                let mut failed = 0;
+                for m in machines {
+                    match api::delete_machine(&m.name, *override_freeze) {
+                        Ok(()) => println!("{}: deleted", m.name),
+                        Err(e) => {
+                            eprintln!("{}: {}", m.name, e);
+                            failed += 1;
+                        }
+                    }
+                }
+                if failed > 0 {
+                    return Err(format!("{} machines could not be deleted", failed).into());
+                }
+            }
+        }
+        Commands::Logs { id, follow } => match follow {
+            true => api::follow_machine_logs(id, |e| println!("{}", e))?,
+            false => api::machine_logs(id)?
+                .iter()
+                .for_each(|e| println!("{}", e)),
+        },
+        Commands::Export { id, output, disks } => api::export_machine(id, output, *disks)?,
+        Commands::Import {
+            bundle,
+            override_freeze,
+        } => {
+            let name = api::import_machine(bundle, *override_freeze)?;
+            println!("Machine '{}' imported", name);
+        }
+        Commands::Clone {
+            source,
+            new,
+            snapshot,
+            override_freeze,
+        } => {
+            api::clone_machine(source, new, snapshot.as_deref(), *override_freeze)?;
+            println!("Machine '{}' cloned from '{}'", new, source);
         }
-        Commands::StartDhcp => {
-            dnsmasq::Dnsmasq::new().start();
+        Commands::Rename {
+            id,
+            new,
+            override_freeze,
+        } => api::rename_machine(id, new, *override_freeze)?,
+        Commands::Scale {
+            id,
+            cpu,
+            memory,
+            override_freeze,
+        } => {
+            if !api::scale_machine(
+                id,
+                *cpu,
+                memory.as_deref().map(str::parse).transpose()?,
+                *override_freeze,
+            )? {
+                println!(
+                    "Machine '{}' must be restarted for the change to take effect",
+                    id
+                );
+            }
+        }
+        Commands::Freeze {
+            project,
+            until,
+            reason,
+        } => {
+            let until = match until {
+                Some(u) => Some(freeze::parse_until(u)?),
+                None => None,
+            };
+            freeze::freeze(project.as_deref(), until, reason.clone())?;
         }
-        Commands::StopDhcp => {
-            dnsmasq::Dnsmasq::new().stop();
+        Commands::Unfreeze { project } => {
+            freeze::unfreeze(project.as_deref())?;
+        }
+        Commands::Freezes => {
+            println!("{:-20} {:-12} REASON", "SCOPE", "UNTIL");
+            for (scope, f) in freeze::list()? {
+                let until = f.until.map_or("-".to_string(), |u| u.to_string());
+                println!(
+                    "{:-20} {:-12} {}",
+                    scope,
+                    until,
+                    f.reason.unwrap_or_default()
+                );
+            }
+        }
+        Commands::Dhcp { command } => match command {
+            DhcpCommands::Start { force } => dnsmasq::Dnsmasq::new().start_exclusive(*force)?,
+            DhcpCommands::Stop { purge } => stop_dhcp(*purge)?,
+            DhcpCommands::Restart => dnsmasq::Dnsmasq::new().restart()?,
+            DhcpCommands::Status => {
+                let status = dnsmasq::Dnsmasq::new().status();
+                println!("dnsmasq: {}", status);
+                if !status.alive {
+                    std::process::exit(Category::NotRunning.exit_code());
+                }
+            }
+        },
+        Commands::StartDhcp { force } => {
+            dnsmasq::Dnsmasq::new().start_exclusive(*force)?;
+        }
+        Commands::StopDhcp { purge } => {
+            stop_dhcp(*purge)?;
         }
         Commands::RestartDhcp => {
-            dnsmasq::Dnsmasq::new().stop();
-            dnsmasq::Dnsmasq::new().start();
+            dnsmasq::Dnsmasq::new().restart()?;
+        }
+        Commands::Backup { dir, every, keep } => {
+            let dir = dir.clone().unwrap_or_else(backup::default_dir);
+            match every {
+                Some(every) => {
+                    let interval = Duration::from_secs(freeze::parse_duration(every)?);
+                    backup::run(&dir, interval, *keep)?;
+                }
+                None => println!("{}", backup::create(&dir)?.display()),
+            }
+        }
+        Commands::Restore { archive, yes } => {
+            if !*yes
+                && !confirm(&format!(
+                    "Replace bigiron's records with {}?",
+                    archive.display()
+                ))?
+            {
+                return Ok(());
+            }
+            let before = backup::restore(archive)?;
+            println!("restored, the previous records are in {}", before.display());
+        }
+        Commands::MigrateStore { db } => {
+            let db = match db {
+                Some(db) => db.clone(),
+                None => config::load()?.store.path.unwrap_or_else(store::default_db),
+            };
+            let files = store::FileBackend::new(store::data_dir());
+            let (machines, images) = store::migrate_to_sqlite(&files, &db)?;
+            println!(
+                "imported {} machines and {} images into {}",
+                machines,
+                images,
+                db.display()
+            );
+            println!("set `store: {{backend: sqlite}}` in the config file to switch over");
         }
+        Commands::MigrateNetstate { leasefile, dry_run } => {
+            let leasefile = leasefile
+                .clone()
+                .unwrap_or_else(|| dnsmasq::Dnsmasq::new().leasefile());
+            let report = network::migrate_netstate(&leasefile, *dry_run)?;
+            for p in &report.problems {
+                eprintln!("problem: {}", p);
+            }
+            println!(
+                "{} reservations, {} leases imported from {}",
+                report.reservations,
+                report.imported_leases,
+                leasefile.display()
+            );
+            if !report.problems.is_empty() {
+                return Err("netstate is inconsistent, fix the problems above and retry".into());
+            }
+            if !*dry_run {
+                println!("netstate migrated to sqlite");
+            }
+        }
+        Commands::Power { command } => match command {
+            PowerCommands::On {
+                id,
+                override_freeze,
+            } => api::power(id, api::PowerAction::On, *override_freeze)?,
+            PowerCommands::Off {
+                id,
+                soft,
+                override_freeze,
+            } => {
+                let action = match soft {
+                    true => api::PowerAction::Soft,
+                    false => api::PowerAction::Off,
+                };
+                api::power(id, action, *override_freeze)?;
+            }
+            PowerCommands::Cycle {
+                id,
+                override_freeze,
+            } => api::power(id, api::PowerAction::Cycle, *override_freeze)?,
+            PowerCommands::Status { id } => match api::power_status(id)? {
+                true => println!("Chassis Power is on"),
+                false => println!("Chassis Power is off"),
+            },
+            PowerCommands::Autostart => {
+                for name in api::autostart()? {
+                    println!("{}: powered on", name);
+                }
+            }
+        },
+        Commands::Disk { command } => match command {
+            DiskCommands::Resize {
+                id,
+                disk,
+                size,
+                override_freeze,
+            } => api::resize_disk(id, disk, size.parse()?, *override_freeze)?,
+            DiskCommands::Snapshot { id, disk, name } => api::snapshot_disk(id, disk, name)?,
+        },
+        Commands::SolServe { id } => api::serve_sol(id)?,
+        Commands::CrashServe { id } => api::serve_crash_console(id)?,
+        Commands::Baremetal { command } => match command {
+            BaremetalCommands::List => {
+                println!("{:-20} {:-17} {:-15} STATE", "NAME", "MAC", "IP");
+                for h in baremetal::list()? {
+                    println!(
+                        "{:-20} {:-17} {:-15} {:?}",
+                        h.host.name,
+                        h.host.spec.mac,
+                        if h.ip.is_empty() { "-" } else { &h.ip },
+                        h.state
+                    );
+                }
+            }
+            BaremetalCommands::Reprovision { name } => baremetal::reprovision(name)?,
+            BaremetalCommands::Delete { name } => baremetal::remove(name)?,
+        },
+        Commands::BootServer { listen } => bootserver::serve(listen)?,
+        Commands::GrpcServer { socket, listen } => grpc_server(socket, listen.as_deref())?,
+        Commands::Eject { id } => api::eject_media(id)?,
+        Commands::Media { command } => match command {
+            MediaCommands::Insert { id, image } => api::insert_media(id, image)?,
+            MediaCommands::Eject { id } => api::eject_media(id)?,
+            MediaCommands::Status { id } => match api::inserted_media(&api::Store::new()?, id)? {
+                Some(p) => println!("inserted: {}", p.display()),
+                None => println!("no media inserted"),
+            },
+        },
+        Commands::Image { command } => match command {
+            ImageCommands::List => {
+                println!("{:-24} IMAGE", "NAME");
+                for (name, entry) in ImageRepo::new()?.catalog()? {
+                    println!("{:-24} {}", name, entry);
+                }
+            }
+            ImageCommands::Tag { name, image } => {
+                let entry = ImageRepo::new()?.tag(name, image)?;
+                println!("{} -> {}", name, entry);
+            }
+            ImageCommands::Untag { name } => ImageRepo::new()?.untag(name)?,
+            ImageCommands::Delete { id, flatten } => {
+                for m in api::delete_image(id, *flatten)? {
+                    println!("flattened disk of {}", m);
+                }
+            }
+        },
+        Commands::Watch {
+            dir: Some(dir),
+            override_freeze,
+            preempt,
+            set,
+            values,
+            ..
+        } => {
+            let opts = api::ApplyOptions {
+                override_freeze: *override_freeze,
+                preempt: *preempt,
+                values: specfile::Values::new(set, values.as_deref())?,
+                ..Default::default()
+            };
+            reconcile::run(dir, &opts)?;
+        }
+        Commands::Watch {
+            dir: None,
+            since,
+            initial,
+            ..
+        } => {
+            let mut since = *since;
+            if *initial {
+                let version = api::resource_version()?;
+                for m in api::Store::new()?.list_machines()? {
+                    let event = api::MachineEvent {
+                        action: bus::Action::Added,
+                        resource_version: version,
+                        name: m.name.clone(),
+                        machine: Some(m),
+                    };
+                    println!("{}", serde_json::to_string(&event)?);
+                }
+                since = Some(version);
+            }
+            let mut watch = api::watch_machines(since)?;
+            loop {
+                for event in watch.next(None)? {
+                    println!("{}", serde_json::to_string(&event)?);
+                }
+            }
+        }
+        Commands::Events { follow, kind, json } => {
+            let kind = kind
+                .as_deref()
+                .map(|k| serde_json::from_value::<bus::Kind>(k.into()))
+                .transpose()
+                .map_err(|_| format!("unknown event kind '{}'", kind.as_deref().unwrap_or("")))?;
+            let print = |n: &bus::Notification| -> Result<(), Box<dyn std::error::Error>> {
+                if kind.is_some_and(|k| k != n.kind) {
+                    return Ok(());
+                }
+                match json {
+                    true => println!("{}", serde_json::to_string(n)?),
+                    false => println!("{}", n),
+                }
+                Ok(())
+            };
+            let bus = bus::Bus::default();
+            // subscribe first, so nothing falls between history and follow
+            let mut sub = match follow {
+                true => Some(bus.subscribe()?),
+                false => None,
+            };
+            let mut seen = 0;
+            for n in bus.history()? {
+                seen = n.version;
+                print(&n)?;
+            }
+            if let Some(sub) = sub.as_mut() {
+                loop {
+                    for n in sub.next(None)? {
+                        if n.version > seen {
+                            print(&n)?;
+                        }
+                    }
+                }
+            }
+        }
+        Commands::Mirror { command } => match command {
+            MirrorCommands::Status { id } => {
+                let s = api::mirror_status(id)?;
+                println!("mirror: {}", s.target.display());
+                if s.root_disk == s.target {
+                    println!("switched over, running from the mirror");
+                } else if s.running {
+                    match s.job {
+                        Some(j) if j.in_sync() => println!("in sync"),
+                        Some(j) => println!(
+                            "syncing: {} of {} ({})",
+                            fmt_bytes(j.offset),
+                            fmt_bytes(j.len),
+                            j.status
+                        ),
+                        None => {
+                            println!("not mirroring");
+                            std::process::exit(Category::NotRunning.exit_code());
+                        }
+                    }
+                } else {
+                    match s.identical {
+                        Some(true) => println!("stopped, mirror identical"),
+                        Some(false) => println!("stopped, mirror differs"),
+                        None => println!("stopped, no mirror yet"),
+                    }
+                }
+            }
+            MirrorCommands::Switch { id, force } => api::switch_to_mirror(id, *force)?,
+        },
+        Commands::Network { command } => match command {
+            NetworkCommands::Show => {
+                let s = network::summary()?;
+                println!("name: {}", s.name.as_deref().unwrap_or("-"));
+                println!("cidr: {}", s.cidr);
+                println!("mode: {:?}", s.mode);
+                match s.gateway {
+                    Some(gw) => println!("gateway: {}", gw),
+                    None => println!("gateway: -"),
+                }
+                match s.capacity {
+                    Some(cap) => println!(
+                        "allocated: {}/{} ({:.1}%)",
+                        s.allocated,
+                        cap,
+                        100.0 * s.allocated as f64 / cap.max(1) as f64
+                    ),
+                    None => println!("allocated: {}", s.allocated),
+                }
+                println!("leased: {}", s.leased);
+            }
+            NetworkCommands::Reservations => {
+                println!(
+                    "{:-17} {:-15} {:-20} {:-9} {:-6}",
+                    "MAC", "IP", "HOSTNAME", "ALLOCATED", "LEASED"
+                );
+                for r in network::reservations()? {
+                    println!(
+                        "{:-17} {:-15} {:-20} {:-9} {:-6}",
+                        r.mac,
+                        if r.ip.is_empty() { "-" } else { &r.ip },
+                        r.hostname,
+                        if r.is_allocated() { "yes" } else { "no" },
+                        if r.is_leased() { "yes" } else { "no" }
+                    );
+                }
+            }
+            NetworkCommands::Reserve { hostname, ip, mac } => {
+                let r = network::new_reservation(hostname, ip.as_deref(), mac.as_deref())?;
+                println!("{} {} {}", r.mac, r.ip, r.hostname);
+            }
+            NetworkCommands::Release { hostname } => {
+                if api::get_machine_by_id(hostname)?.is_some() {
+                    return Err(format!(
+                        "{} is reserved for a machine, delete the machine instead",
+                        hostname
+                    )
+                    .into());
+                }
+                network::remove_reservation(hostname)?;
+            }
+            NetworkCommands::Forwards { sync } => {
+                if *sync {
+                    host::nat::sync()?;
+                }
+                println!(
+                    "{:>9} {:-5} {:-20} {:-21}",
+                    "HOST PORT", "PROTO", "MACHINE", "DESTINATION"
+                );
+                for f in host::nat::list()? {
+                    println!(
+                        "{:>9} {:-5} {:-20} {:-21}",
+                        f.forward.host_port,
+                        f.forward.protocol.as_str(),
+                        f.machine,
+                        format!("{}:{}", f.ip, f.forward.port)
+                    );
+                }
+            }
+            NetworkCommands::Leases => {
+                for r in network::reap_leases()? {
+                    eprintln!("cleared expired lease of {} for {}", r.ip, r.mac);
+                }
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                println!(
+                    "{:-17} {:-15} {:-20} {:>8} {:>8}",
+                    "MAC", "IP", "HOSTNAME", "AGE", "EXPIRES"
+                );
+                for r in network::leases()? {
+                    let age = r
+                        .leased_at
+                        .map(|t| fmt_secs(now.saturating_sub(t)))
+                        .unwrap_or_else(|| "-".to_string());
+                    let expires = r
+                        .expires
+                        .map(|t| fmt_secs(t.saturating_sub(now)))
+                        .unwrap_or_else(|| "never".to_string());
+                    println!(
+                        "{:-17} {:-15} {:-20} {:>8} {:>8}",
+                        r.mac, r.ip, r.hostname, age, expires
+                    );
+                }
+            }
+        },
+        Commands::Docs(docs) => docs.run(Cli::command())?,
+        Commands::Host { command } => match command {
+            HostCommands::List => {
+                println!(
+                    "{:-20} {:>5} {:>12} {:>12} {:>8}  POWER",
+                    "NAME", "CPUS", "MEMORY", "USED", "MACHINES"
+                );
+                for h in api::list_hosts()? {
+                    println!(
+                        "{:-20} {:>5} {:>12} {:>12} {:>8}  {}",
+                        h.name,
+                        h.cpus,
+                        models::from_size(h.memory),
+                        models::from_size(h.used_memory),
+                        h.machines.len(),
+                        if h.asleep { "asleep" } else { "on" }
+                    );
+                }
+            }
+            HostCommands::Consolidate { dry_run } => {
+                let drains = hostpower::consolidate(*dry_run)?;
+                if drains.is_empty() {
+                    println!("no host can be emptied");
+                }
+                for d in drains {
+                    for (machine, to) in &d.moves {
+                        println!("{} -> {}", machine, to);
+                    }
+                    match dry_run {
+                        true => println!("{} would power down", d.host),
+                        false => println!("{} powered down", d.host),
+                    }
+                }
+            }
+            HostCommands::Wake { name } => {
+                hostpower::wake_host(name)?;
+                println!("{} is up", name);
+            }
+            HostCommands::Net => {
+                println!(
+                    "{:-16} {:-9} {:-8} {:-12} {:-8} ADDRESSES",
+                    "NAME", "TYPE", "STATE", "MASTER", "MANAGED"
+                );
+                for i in host::net::interfaces()? {
+                    let kind = match &i.vlan {
+                        Some((parent, id)) => format!("vlan {}@{}", id, parent),
+                        None => format!("{:?}", i.kind).to_lowercase(),
+                    };
+                    println!(
+                        "{:-16} {:-9} {:-8} {:-12} {:-8} {}",
+                        i.name,
+                        kind,
+                        i.state,
+                        i.master.as_deref().unwrap_or("-"),
+                        if i.managed { "yes" } else { "no" },
+                        i.addresses.join(",")
+                    );
+                    if !i.slaves.is_empty() {
+                        println!("  members: {}", i.slaves.join(", "));
+                    }
+                }
+            }
+        },
     }
 
     Ok(())
 }
+
+// ask a yes/no question on the terminal, anything but y or yes is a no
+fn confirm(question: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+fn stop_dhcp(purge: bool) -> Result<(), Box<dyn std::error::Error>> {
+    dnsmasq::Dnsmasq::new().stop()?;
+    if purge {
+        let p = api::purge_dhcp_state()?;
+        for h in &p.host_records {
+            println!("removed host record {}", h);
+        }
+        for l in &p.leases {
+            println!("removed lease {} {} {}", l.ip, l.mac, l.hostname);
+        }
+    }
+    Ok(())
+}
+
+// rounded to one decimal of the largest binary unit that fits
+fn fmt_bytes(bytes: u64) -> String {
+    let units = ["B", "Ki", "Mi", "Gi", "Ti"];
+    let mut v = bytes as f64;
+    let mut unit = 0;
+    while v >= 1024.0 && unit < units.len() - 1 {
+        v /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{}B", bytes),
+        u => format!("{:.1}{}", v, units[u]),
+    }
+}
+
+// a rough human readable duration, e.g. 3h12m
+fn fmt_secs(secs: u64) -> String {
+    match secs {
+        s if s < 60 => format!("{}s", s),
+        s if s < 60 * 60 => format!("{}m{}s", s / 60, s % 60),
+        s if s < 24 * 60 * 60 => format!("{}h{}m", s / 3600, s % 3600 / 60),
+        s => format!("{}d{}h", s / 86400, s % 86400 / 3600),
+    }
+}
@@ -0,0 +1,215 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Live mirror of a machine's root disk on a second device. While the
+// machine runs qemu keeps a drive-mirror job on the disk in write-blocking
+// mode, so once the job is ready a guest write only completes when it is on
+// both copies. If the primary device dies the machine can run on from the
+// mirror, which has no backing file of its own.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+use tracing::{debug, info};
+
+use crate::error::Error;
+use crate::libvirt;
+use crate::qemu;
+use crate::store::get_unique_id;
+
+const JOB_ID: &str = "bigiron-mirror";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    pub len: u64,
+    pub offset: u64,
+    // the initial copy is done and writes go to both images
+    pub ready: bool,
+    pub status: String,
+}
+
+impl Job {
+    pub fn in_sync(&self) -> bool {
+        self.ready && self.offset == self.len
+    }
+}
+
+// the mirror image of a machine in its spec's mirror directory
+pub fn target(dir: &Path, machine: &str) -> PathBuf {
+    dir.join(format!("{}.qcow2", get_unique_id(machine)))
+}
+
+// Start mirroring `image` of a running machine into `target`, created
+// afresh since whatever it held is stale once the machine was stopped.
+pub fn start(name: &str, image: &Path, target: &Path) -> Result<(), Error> {
+    if job(name)?.is_some() {
+        return Ok(());
+    }
+    let size = qemu::Image {
+        path: image.to_path_buf(),
+    }
+    .virtual_size()?;
+    if let Some(dir) = target.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut cmd = Command::new("/usr/bin/qemu-img");
+    cmd.arg("create")
+        .arg("-q")
+        .arg("-f")
+        .arg("qcow2")
+        .arg(target)
+        .arg(size.to_string());
+    debug!("Running: {:?}", cmd);
+    if !cmd.status()?.success() {
+        return Err(format!("failed to create mirror image {}", target.display()).into());
+    }
+
+    let blocks = libvirt::monitor_command(name, "query-block", None)?;
+    let node = block_node(&blocks, image)
+        .ok_or_else(|| format!("no block device for {} in {}", image.display(), name))?;
+    libvirt::monitor_command(
+        name,
+        "drive-mirror",
+        Some(json!({
+            "job-id": JOB_ID,
+            "device": node,
+            "target": target,
+            "format": "qcow2",
+            "mode": "existing",
+            "sync": "full",
+            "copy-mode": "write-blocking",
+        })),
+    )?;
+    info!("mirroring {} of {} to {}", node, name, target.display());
+    Ok(())
+}
+
+// the mirror job of a running machine, if there is one
+pub fn job(name: &str) -> Result<Option<Job>, Error> {
+    Ok(parse_job(&libvirt::monitor_command(
+        name,
+        "query-block-jobs",
+        None,
+    )?))
+}
+
+// Pivot a running machine onto its mirror, which must be in sync. The job
+// ends with the pivot, qemu no longer touches the primary afterwards.
+pub fn complete(name: &str) -> Result<(), Error> {
+    match job(name)? {
+        Some(j) if j.in_sync() => {}
+        Some(j) => {
+            return Err(format!(
+                "mirror of {} is not in sync ({} of {} bytes, {})",
+                name, j.offset, j.len, j.status
+            )
+            .into())
+        }
+        None => return Err(format!("{} has no mirror job", name).into()),
+    }
+    libvirt::monitor_command(
+        name,
+        "block-job-complete",
+        Some(json!({ "device": JOB_ID })),
+    )?;
+
+    let start = Instant::now();
+    while job(name)?.is_some() {
+        if start.elapsed() > Duration::from_secs(30) {
+            return Err(format!("timed out switching {} to its mirror", name).into());
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    Ok(())
+}
+
+// Node name of the block device backed by `image`, or its device name
+// where qemu runs without -blockdev, from a query-block return.
+fn block_node(blocks: &Value, image: &Path) -> Option<String> {
+    let image = image.to_str()?;
+    blocks.as_array()?.iter().find_map(|b| {
+        let inserted = b.get("inserted")?;
+        if inserted.get("file")?.as_str()? != image {
+            return None;
+        }
+        let name = |v: Option<&Value>| {
+            v.and_then(|n| n.as_str())
+                .filter(|n| !n.is_empty())
+                .map(|n| n.to_string())
+        };
+        name(inserted.get("node-name")).or_else(|| name(b.get("device")))
+    })
+}
+
+fn parse_job(jobs: &Value) -> Option<Job> {
+    let j = jobs
+        .as_array()?
+        .iter()
+        .find(|j| j.get("device").and_then(|d| d.as_str()) == Some(JOB_ID))?;
+    Some(Job {
+        len: j.get("len").and_then(|v| v.as_u64()).unwrap_or(0),
+        offset: j.get("offset").and_then(|v| v.as_u64()).unwrap_or(0),
+        ready: j.get("ready").and_then(|v| v.as_bool()).unwrap_or(false),
+        status: j
+            .get("status")
+            .and_then(|v| v.as_str())
+            .unwrap_or("unknown")
+            .to_string(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_block_node() {
+        let blocks = json!([
+            {"device": "", "qdev": "/machine/peripheral/virtio-disk0/virtio-backend",
+             "inserted": {"file": "/var/lib/bigiron/libvirt/web1/image.qcow2",
+                          "node-name": "libvirt-2-format"}},
+            {"device": "drive-ide0-0-0", "qdev": "ide0-0-0", "inserted": {"file": "/srv/iso/install.iso", "node-name": ""}},
+            {"device": "sd0"}
+        ]);
+        assert_eq!(
+            block_node(
+                &blocks,
+                Path::new("/var/lib/bigiron/libvirt/web1/image.qcow2")
+            ),
+            Some("libvirt-2-format".to_string())
+        );
+        assert_eq!(
+            block_node(&blocks, Path::new("/srv/iso/install.iso")),
+            Some("drive-ide0-0-0".to_string())
+        );
+        assert_eq!(block_node(&blocks, Path::new("/nope")), None);
+    }
+
+    #[test]
+    fn test_parse_job() {
+        let jobs = json!([
+            {"device": "other", "type": "stream", "len": 10, "offset": 1, "ready": false, "status": "running"},
+            {"device": JOB_ID, "type": "mirror", "len": 4096, "offset": 4096, "ready": true, "status": "ready"}
+        ]);
+        let job = parse_job(&jobs).unwrap();
+        assert!(job.in_sync());
+        assert_eq!(job.status, "ready");
+        assert_eq!(parse_job(&json!([])), None);
+    }
+}
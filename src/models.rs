@@ -15,21 +15,40 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
+use std::collections::BTreeMap;
+use std::fmt;
 use std::path::PathBuf;
+use std::str::FromStr;
 
-use serde::{Deserialize, Serialize};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Metadata, Schema, SchemaObject};
+use schemars::JsonSchema;
+use serde::de;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::error::Error;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// only ever held briefly while applying a document, so not worth boxing
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "kind")]
 pub enum Resource {
     Machine(Machine),
+    MachineTemplate(MachineTemplate),
+    Network(Network),
+    BareMetal(BareMetal),
+    Image(CatalogImage),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Machine {
     pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    // free form key/values to pick machines by, see Selector
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
     pub status: Option<String>,
     pub spec: Spec,
 }
@@ -41,67 +60,859 @@ impl Machine {
     }
 }
 
-pub type SizeString = String;
+// label the machines of a template carry, with the template's name
+pub const TEMPLATE_LABEL: &str = "bigiron/template";
 
+// A spec for `replicas` machines named <name>-1 to <name>-N, each with its
+// own disks and network reservation. Lowering replicas leaves the extra
+// machines in place, they are deleted by name.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct MachineTemplate {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    // for each machine, along with the template label
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    #[serde(alias = "count")]
+    pub replicas: u32,
+    pub spec: Spec,
+}
+
+impl MachineTemplate {
+    pub fn machines(&self) -> Result<Vec<Machine>, Error> {
+        // settings that can only go to one machine
+        let spec = &self.spec;
+        let fixed = [
+            ("ip", spec.ip.is_some()),
+            ("mac", spec.mac.is_some()),
+            (
+                "portForwards",
+                spec.port_forwards.as_ref().is_some_and(|f| !f.is_empty()),
+            ),
+            (
+                "graphics.port",
+                spec.graphics.as_ref().is_some_and(|g| g.port.is_some()),
+            ),
+            (
+                "sol.port",
+                spec.sol.as_ref().is_some_and(|s| s.port.is_some()),
+            ),
+        ];
+        if let Some((field, _)) = fixed.iter().find(|(_, set)| *set) {
+            return Err(format!(
+                "template '{}' sets {}, which can't be shared by its machines",
+                self.name, field
+            )
+            .into());
+        }
+
+        let mut labels = self.labels.clone();
+        labels.insert(TEMPLATE_LABEL.to_string(), self.name.clone());
+        check_labels(&labels)?;
+        (1..=self.replicas)
+            .map(|i| {
+                let name = format!("{}-{}", self.name, i);
+                check_name(&name)?;
+                Ok(Machine {
+                    name,
+                    project: self.project.clone(),
+                    labels: labels.clone(),
+                    status: None,
+                    spec: self.spec.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+// Machine names end up in file paths, dhcp host files, libvirt xml and dns,
+// so they follow the rules for a hostname label: up to 63 lowercase letters,
+// digits and dashes, starting and ending with a letter or digit.
+pub fn check_name(name: &str) -> Result<(), Error> {
+    let valid = (1..=63).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !name.starts_with('-')
+        && !name.ends_with('-');
+    match valid {
+        true => Ok(()),
+        false => Err(format!(
+            "invalid machine name '{}', use up to 63 lowercase letters, digits and dashes, \
+             starting and ending with a letter or digit",
+            name
+        )
+        .into()),
+    }
+}
+
+// Keys like env or example.com/team, values like test or web-1, both
+// short enough to type in a selector.
+pub fn check_labels(labels: &BTreeMap<String, String>) -> Result<(), Error> {
+    let valid = |s: &str, extra: &str| {
+        s.len() <= 63
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c) || extra.contains(c))
+    };
+    for (k, v) in labels {
+        if k.is_empty() || !valid(k, "/") {
+            return Err(format!("invalid label key '{}'", k).into());
+        }
+        if !valid(v, "") {
+            return Err(format!("invalid value '{}' for label {}", v, k).into());
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    Exists(String),
+    NotExists(String),
+}
+
+// A label selector as in `bigiron list -l env=test,tier!=db`: comma
+// separated requirements that all have to hold, each `key=value`,
+// `key!=value`, `key` for having the label or `!key` for not having it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selector(Vec<Requirement>);
+
+impl Selector {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let mut reqs = Vec::new();
+        for part in s.split(',').map(str::trim) {
+            let req = if let Some((k, v)) = part.split_once("!=") {
+                Requirement::NotEquals(k.trim().into(), v.trim().into())
+            } else if let Some((k, v)) = part.split_once('=') {
+                // == is accepted too
+                let v = v.strip_prefix('=').unwrap_or(v);
+                Requirement::Equals(k.trim().into(), v.trim().into())
+            } else if let Some(k) = part.strip_prefix('!') {
+                Requirement::NotExists(k.trim().into())
+            } else {
+                Requirement::Exists(part.into())
+            };
+            let (k, v) = match &req {
+                Requirement::Equals(k, v) | Requirement::NotEquals(k, v) => (k, v.as_str()),
+                Requirement::Exists(k) | Requirement::NotExists(k) => (k, ""),
+            };
+            check_labels(&BTreeMap::from([(k.clone(), v.to_string())]))
+                .map_err(|e| format!("invalid selector '{}': {}", s, e))?;
+            reqs.push(req);
+        }
+        Ok(Self(reqs))
+    }
+
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.0.iter().all(|r| match r {
+            Requirement::Equals(k, v) => labels.get(k) == Some(v),
+            Requirement::NotEquals(k, v) => labels.get(k) != Some(v),
+            Requirement::Exists(k) => labels.contains_key(k),
+            Requirement::NotExists(k) => !labels.contains_key(k),
+        })
+    }
+}
+
+// A number of bytes, written as in 512Mi or 20G. It is checked when a spec
+// is read and always written back in canonical form, see from_size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Size(pub u64);
+
+impl Size {
+    pub fn bytes(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(&from_size(self.0))
+    }
+}
+
+impl FromStr for Size {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(Size(to_size(s)?))
+    }
+}
+
+impl Serialize for Size {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&from_size(self.0))
+    }
+}
+
+// a string with a unit, or a plain number of bytes
+impl<'de> Deserialize<'de> for Size {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct SizeVisitor;
+
+        impl<'de> de::Visitor<'de> for SizeVisitor {
+            type Value = Size;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a size such as 512Mi, 20G or a number of bytes")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Size, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Size, E> {
+                Ok(Size(v))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Size, E> {
+                u64::try_from(v)
+                    .map(Size)
+                    .map_err(|_| E::custom(format!("invalid size {}", v)))
+            }
+        }
+
+        d.deserialize_any(SizeVisitor)
+    }
+}
+
+impl JsonSchema for Size {
+    fn schema_name() -> String {
+        "Size".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(vec![InstanceType::String, InstanceType::Integer].into()),
+            metadata: Some(Box::new(Metadata {
+                description: Some("a size such as 512Mi, 20G or a number of bytes".to_string()),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+// Parse a size such as 100M, 20G, 12Gi or 8GiB into bytes. Plain numbers
+// are bytes, the i suffixes are powers of 1024 and the rest of 1000.
 pub fn to_size(s: &str) -> Result<u64, Error> {
-    let mut last = &s[s.len() - 1..];
-    let nlast = &s[s.len() - 2..s.len() - 1];
-    let mut co: u64 = 1000;
-    let mut num = &s[..s.len() - 1];
-
-    if last == "i" {
-        // binary byte mode
-        co = 1024;
-        last = nlast;
-        num = &s[..s.len() - 2];
-    }
-
-    let exp = match last {
-        "T" | "t" => 3,
-        "G" | "g" => 3,
-        "M" | "m" => 2,
-        "K" | "k" => 1,
-        _ => 0,
+    let s = s.trim();
+    let s = s.strip_suffix('B').unwrap_or(s);
+    let (s, co) = match s.strip_suffix('i') {
+        Some(s) => (s, 1024u64),
+        None => (s, 1000u64),
     };
 
-    let scalar = num.parse::<u64>()?;
-    Ok(scalar * co.pow(exp))
+    let (num, exp) = match s.chars().last() {
+        Some('T' | 't') => (&s[..s.len() - 1], 4),
+        Some('G' | 'g') => (&s[..s.len() - 1], 3),
+        Some('M' | 'm') => (&s[..s.len() - 1], 2),
+        Some('K' | 'k') => (&s[..s.len() - 1], 1),
+        _ if co == 1024 => return Err(format!("invalid size '{}'", s).into()),
+        _ => (s, 0),
+    };
+
+    let scalar = num
+        .parse::<u64>()
+        .map_err(|_| format!("invalid size '{}'", s))?;
+    scalar
+        .checked_mul(co.pow(exp))
+        .ok_or_else(|| format!("size '{}' is too large", s).into())
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// The canonical string for a number of bytes, in whichever unit gives the
+// shortest exact number, binary on a tie. 8G and 8000000000 both become 8G.
+pub fn from_size(bytes: u64) -> String {
+    let mut best = bytes.to_string();
+    let mut digits = best.len();
+    if bytes == 0 {
+        return best;
+    }
+    for (co, suffix) in [(1024u64, "i"), (1000, "")] {
+        for (exp, unit) in [(4, "T"), (3, "G"), (2, "M"), (1, "K")] {
+            let div = co.pow(exp);
+            let (n, rem) = (bytes / div, bytes % div);
+            if rem == 0 && n.to_string().len() < digits {
+                digits = n.to_string().len();
+                best = format!("{}{}{}", n, unit, suffix);
+            }
+        }
+    }
+    best
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct Spec {
+    // guest architecture, defaults to the host architecture
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
     pub cpu: u32,
-    pub memory: SizeString,
+    pub memory: Size,
+    // upper bounds for live resizing, defaulting to cpu/memory when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_cpu: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_memory: Option<Size>,
     pub image: Image,
     pub storage: Option<Vec<StorageKind>>,
     pub network: Option<Vec<NetKind>>,
+    // fixed address and mac on the management network, allocated when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    // host ports forwarded to services on the machine
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port_forwards: Option<Vec<PortForward>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub graphics: Option<Graphics>,
+    // what the guest's clock keeps: utc (the default), localtime for the
+    // host's zone, or a zone such as Europe/Berlin for guests like windows
+    // that expect the rtc in local time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    // machine type such as q35, pc or a versioned pc-q35-7.2
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub machine_type: Option<String>,
+    // host-passthrough, host-model or a named model like Skylake-Server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_model: Option<String>,
+    // feature flags, `vmx` or `+vmx` to require and `-vmx` to disable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_features: Option<Vec<String>>,
+    // back guest memory with hugepages of this size, e.g. 2Mi or 1Gi
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hugepages: Option<Size>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numa: Option<Numa>,
+    // host pci devices passed through to the guest
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub devices: Option<Vec<Device>>,
+    // host directories shared into the guest
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shares: Option<Vec<Share>>,
+    // internal snapshot of the disk to go back to whenever the machine starts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revert_on_boot: Option<String>,
+    // directory on a second device to keep a live mirror of the root disk in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<PathBuf>,
+    // scheduling priority, see the scheduling section of the host config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority_class: Option<String>,
+    // which of the configured hosts the machine may land on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub placement: Option<Placement>,
+    // boot from the network, served by `bigiron boot-server`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub netboot: Option<Netboot>,
+    // serial console served over tcp for tools that expect SOL
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sol: Option<Sol>,
+    // second serial port whose output is kept in oops.log, for the guest
+    // kernel to log crashes to with console=ttyS1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crash_console: Option<bool>,
+    // what happens when the guest powers off or crashes, never by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restart_policy: Option<RestartPolicy>,
+    // limits that keep a busy machine from starving others on the host
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qos: Option<Qos>,
+    // configured storage pool for the machine's disks, the host's default
+    // pool if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage_pool: Option<String>,
+    // installer media in the cdrom drive of a new machine, a local path or
+    // file:// url of an ISO image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cdrom: Option<String>,
+    // Devices the firmware tries in turn. Installs put the cdrom first, an
+    // empty drive falls through to the disk once the media is ejected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot_order: Option<Vec<BootDevice>>,
+    // virtio-rng device fed from the host's /dev/urandom, on unless false
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rng: Option<bool>,
+    // emulated TPM 2.0 with state kept across restarts, for measured boot
+    // and guests such as windows 11 that require one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tpm: Option<bool>,
+    // expose vmx or svm so the guest can run vms itself, the host's kvm
+    // module has to have nested virtualization on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nested: Option<bool>,
+}
+
+impl Spec {
+    // the disk, then the network for netboot machines, unless a boot order
+    // is given, in which a device only counts the first time
+    pub fn boot_devices(&self) -> Vec<BootDevice> {
+        match &self.boot_order {
+            Some(order) => {
+                let mut devices = Vec::new();
+                for d in order {
+                    if !devices.contains(d) {
+                        devices.push(*d);
+                    }
+                }
+                devices
+            }
+            None if self.netboot.is_some() => vec![BootDevice::Disk, BootDevice::Network],
+            None => vec![BootDevice::Disk],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BootDevice {
+    Disk,
+    Cdrom,
+    Network,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    // a guest that powers off or crashes stays off
+    #[default]
+    Never,
+    // restart after a guest kernel panic
+    OnFailure,
+    // restart after a panic or a power off not asked for with `power soft`,
+    // and start with the host
+    Always,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Qos {
+    // operations per second on each disk, reads and writes together
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_iops: Option<u64>,
+    // bytes per second on each disk, e.g. 100M, reads and writes together
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_bandwidth: Option<Size>,
+    // bytes per second each way on the management nic
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub net_bandwidth: Option<Size>,
+}
+
+// Scheduling hints, the affinities are label selectors over the other
+// machines, e.g. to keep the members of a cluster on separate hosts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Placement {
+    // only this host
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    // a host running a matching machine, any host while none runs anywhere
+    #[serde(
+        default,
+        deserialize_with = "deserialize_selector",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub affinity: Option<String>,
+    // never a host running a matching machine
+    #[serde(
+        default,
+        deserialize_with = "deserialize_selector",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub anti_affinity: Option<String>,
+}
+
+fn deserialize_selector<'de, D: Deserializer<'de>>(d: D) -> Result<Option<String>, D::Error> {
+    let s = String::deserialize(d)?;
+    Selector::parse(&s).map_err(serde::de::Error::custom)?;
+    Ok(Some(s))
+}
+
+// placement of a guest on host numa nodes and cpus
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Numa {
+    // host node to allocate memory from, unpinned vcpus also stay on it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node: Option<u32>,
+    // host cpu for each vcpu, indexed by vcpu number
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_pinning: Option<Vec<u32>>,
+}
+
+// split a cpu feature flag into its name and whether it is enabled
+pub fn parse_cpu_feature(flag: &str) -> (&str, bool) {
+    match flag.strip_prefix('-') {
+        Some(name) => (name, false),
+        None => (flag.strip_prefix('+').unwrap_or(flag), true),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PortForward {
+    pub host_port: u16,
+    // port on the machine
+    pub port: u16,
+    #[serde(default)]
+    pub protocol: Protocol,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum GraphicsKind {
+    Vnc,
+    Spice,
+}
+
+impl GraphicsKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GraphicsKind::Vnc => "vnc",
+            GraphicsKind::Spice => "spice",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Graphics {
+    #[serde(rename = "type")]
+    pub kind: GraphicsKind,
+    // address to listen on, defaults to localhost only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listen: Option<String>,
+    // fixed port, automatically allocated when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    // keyboard layout of the console, e.g. de or fr-ch, qemu uses en-us
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keymap: Option<String>,
+}
+
+impl Graphics {
+    pub fn listen_addr(&self) -> &str {
+        self.listen.as_deref().unwrap_or("127.0.0.1")
+    }
+}
+
+// What an iPXE script loads for a machine. The cmdline and files are
+// templates, with {{ hostname }}, {{ mac }}, {{ ip }}, {{ server }} and
+// {{ base }}, the url the machine's files are under, filled in.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Netboot {
+    pub kernel: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initrd: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cmdline: Option<String>,
+    // kickstarts, preseeds and the like, served under files/<name>
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<BTreeMap<String, PathBuf>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SolProtocol {
+    #[default]
+    Telnet,
+    Raw,
+}
+
+impl SolProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SolProtocol::Telnet => "telnet",
+            SolProtocol::Raw => "tcp",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Sol {
+    #[serde(default)]
+    pub protocol: SolProtocol,
+    // address to listen on, defaults to localhost only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listen: Option<String>,
+    // fixed port, allocated from the port registry when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    // file holding the password clients must give, no auth when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<PathBuf>,
+    // concurrent clients, all see the console output, defaults to 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<u32>,
+}
+
+impl Sol {
+    pub fn listen_addr(&self) -> &str {
+        self.listen.as_deref().unwrap_or("127.0.0.1")
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct Image {
-    pub url: String,
-    pub resize: Option<SizeString>,
+    // name of an image in the catalog, see `bigiron image tag`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    pub resize: Option<Size>,
+    // architecture of the image, guessed from the url when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+    // expected sha256 of the image at url, checked before it is used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    // use a named snapshot of another machine's disk as the base image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from_machine: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<String>,
+}
+
+impl Image {
+    // check the digest of the base image against sha256 when that is set
+    pub fn check_digest(&self, digest: Option<&str>) -> Result<(), Error> {
+        let expected = match &self.sha256 {
+            Some(e) => e.trim(),
+            None => return Ok(()),
+        };
+        match digest {
+            Some(d) if d.eq_ignore_ascii_case(expected) => Ok(()),
+            Some(d) => Err(format!(
+                "image digest mismatch: expected sha256 {}, got {}",
+                expected, d
+            )
+            .into()),
+            None => Err("image.sha256 can only be checked for repo images".into()),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum StorageKind {
     DiskFile(DiskFile),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct DiskFile {
     pub local: PathBuf,
-    pub size: SizeString,
+    pub size: Size,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preallocation: Option<Preallocation>,
+}
+
+// qcow2 preallocation modes, passed through to `qemu-img create`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Preallocation {
+    Off,
+    Metadata,
+    Falloc,
+    Full,
+}
+
+impl Preallocation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Preallocation::Off => "off",
+            Preallocation::Metadata => "metadata",
+            Preallocation::Falloc => "falloc",
+            Preallocation::Full => "full",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum ShareDriver {
+    #[default]
+    #[serde(rename = "virtiofs")]
+    Virtiofs,
+    #[serde(rename = "9p")]
+    NineP,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Share {
+    pub source: PathBuf,
+    // mount tag in the guest, e.g. `mount -t virtiofs <tag> /mnt`
+    pub tag: String,
+    #[serde(default)]
+    pub driver: ShareDriver,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub readonly: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Device {
+    Pci(PciDevice),
+    VfPool(VfPool),
+}
+
+// a specific host pci device such as 0000:3b:00.0
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PciDevice {
+    pub pci: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// any free SR-IOV virtual function of a host network interface
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct VfPool {
+    pub vf_pool: String,
+}
+
+// an image imported into the repo and named in its catalog, for machines
+// to use with image.name
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CatalogImage {
+    pub name: String,
+    pub spec: CatalogImageSpec,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CatalogImageSpec {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    // format of the file at url, e.g. qcow2 or vmdk, checked before import
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+}
+
+// a physical host installed over the network by bigiron
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BareMetal {
+    pub name: String,
+    pub spec: BareMetalSpec,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BareMetalSpec {
+    // mac of the nic the host netboots from
+    pub mac: String,
+    // fixed address, allocated like a machine's when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    pub netboot: Netboot,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Network {
+    pub name: String,
+    pub spec: NetworkSpec,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct NetworkSpec {
+    // defaults to the management bridge
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bridge: Option<String>,
+    pub cidr: String,
+    // defaults to the first host address of the cidr
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<String>,
+    #[serde(default)]
+    pub dhcp: DhcpMode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relay: Option<Relay>,
+    // handed to guests over dhcp, unset falls back to the host config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dns: Option<Dns>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ntp: Option<Vec<String>>,
+    // addresses never allocated automatically, single ones or ranges like
+    // 172.20.0.2-172.20.0.20, for gateways and static infrastructure
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reserved: Option<Vec<String>>,
+    // percent of the pool allocated above which new reservations warn, 90
+    // when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warn_utilization: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Dns {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub servers: Vec<String>,
+    // search domains
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub search: Vec<String>,
+}
+
+// Managed networks have addresses allocated and served by bigiron. Relay
+// networks leave that to an existing DHCP server and only track the
+// leases that are seen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DhcpMode {
+    #[default]
+    Managed,
+    Relay,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Relay {
+    // upstream DHCP server requests are forwarded to
+    pub server: String,
+    // address on the bridge for dnsmasq to relay from, leave unset when
+    // some other relay agent serves the network
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_address: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum NetKind {
     Vlan(Vlan),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Vlan {
     pub vlan: u32,
 }
@@ -110,6 +921,38 @@ pub struct Vlan {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_template() {
+        let yaml = "
+          kind: MachineTemplate
+          name: k8s-node
+          labels:
+            env: test
+          count: 3
+          spec:
+            cpu: 4
+            memory: 8G
+            image:
+              name: ubuntu-22.04
+        ";
+        let t = match serde_yaml::from_str(yaml).unwrap() {
+            Resource::MachineTemplate(t) => t,
+            _ => panic!("expected a MachineTemplate"),
+        };
+        let machines = t.machines().unwrap();
+        let names: Vec<&str> = machines.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, vec!["k8s-node-1", "k8s-node-2", "k8s-node-3"]);
+        assert_eq!(machines[0].labels[TEMPLATE_LABEL], "k8s-node");
+        assert_eq!(machines[2].labels["env"], "test");
+
+        let mut t = t;
+        t.spec.ip = Some("172.20.0.10".into());
+        assert!(t.machines().is_err());
+        t.spec.ip = None;
+        t.name = "a".repeat(62);
+        assert!(t.machines().is_err());
+    }
+
     #[test]
     fn test_deser() {
         let yaml = "
@@ -129,43 +972,263 @@ mod test {
             network:
             - vlan: 208
             - vlan: 209
+            graphics:
+              type: spice
+              listen: 0.0.0.0
+              keymap: fr-ch
+            timezone: localtime
         ";
 
         let r: Resource = serde_yaml::from_str(yaml).unwrap();
         let m = match r {
             Resource::Machine(m) => m,
+            _ => panic!("expected a Machine"),
         };
 
         assert_eq!(m.name, "my-test-vm");
         assert_eq!(m.spec.cpu, 4);
+
+        let g = m.spec.graphics.unwrap();
+        assert_eq!(g.kind, GraphicsKind::Spice);
+        assert_eq!(g.listen_addr(), "0.0.0.0");
+        assert_eq!(g.port, None);
+        assert_eq!(g.keymap.as_deref(), Some("fr-ch"));
+        assert_eq!(m.spec.timezone.as_deref(), Some("localtime"));
+    }
+
+    #[test]
+    fn test_deser_max_resources() {
+        let yaml = "
+          kind: Machine
+          name: my-test-vm
+          spec:
+            cpu: 2
+            memory: 4G
+            maxCpu: 8
+            maxMemory: 16G
+            image:
+              url: cos://us-south/my-bucket/my-image.qcow2
+        ";
+
+        let r: Resource = serde_yaml::from_str(yaml).unwrap();
+        let m = match r {
+            Resource::Machine(m) => m,
+            _ => panic!("expected a Machine"),
+        };
+
+        assert_eq!(m.spec.max_cpu, Some(8));
+        assert_eq!(m.spec.max_memory, Some("16G".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_deser_image_from_snapshot() {
+        let yaml = "
+          kind: Machine
+          name: clone-01
+          spec:
+            cpu: 2
+            memory: 4G
+            image:
+              fromMachine: golden-vm
+              snapshot: provisioned
+        ";
+
+        let r: Resource = serde_yaml::from_str(yaml).unwrap();
+        let m = match r {
+            Resource::Machine(m) => m,
+            _ => panic!("expected a Machine"),
+        };
+
+        assert!(m.spec.image.url.is_none());
+        assert_eq!(m.spec.image.from_machine, Some("golden-vm".to_string()));
+        assert_eq!(m.spec.image.snapshot, Some("provisioned".to_string()));
+    }
+
+    #[test]
+    fn test_check_digest() {
+        let digest = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+        let mut image: Image = serde_yaml::from_str("url: http://example.com/a.img").unwrap();
+        assert!(image.check_digest(None).is_ok());
+
+        image.sha256 = Some(digest.to_uppercase());
+        assert!(image.check_digest(Some(digest)).is_ok());
+        assert!(image.check_digest(Some(&digest.replace('9', "0"))).is_err());
+        assert!(image.check_digest(None).is_err());
+    }
+
+    #[test]
+    fn test_deser_catalog_image() {
+        let yaml = "
+          kind: Image
+          name: jammy
+          spec:
+            url: file:///srv/images/jammy.vmdk
+            format: vmdk
+        ";
+        let i = match serde_yaml::from_str::<Resource>(yaml).unwrap() {
+            Resource::Image(i) => i,
+            _ => panic!("expected an Image"),
+        };
+        assert_eq!(i.name, "jammy");
+        assert_eq!(i.spec.format.as_deref(), Some("vmdk"));
+        assert!(i.spec.sha256.is_none());
+    }
+
+    #[test]
+    fn test_deser_network() {
+        let yaml = "
+          kind: Network
+          name: lab
+          spec:
+            cidr: 10.20.0.0/24
+            dhcp: relay
+            relay:
+              server: 10.0.0.5
+              localAddress: 10.20.0.1
+            dns:
+              servers: [10.0.0.53]
+              search: [lab.example.com]
+            ntp: [10.0.0.123]
+        ";
+
+        let n = match serde_yaml::from_str::<Resource>(yaml).unwrap() {
+            Resource::Network(n) => n,
+            _ => panic!("expected a Network"),
+        };
+        assert_eq!(n.spec.dhcp, DhcpMode::Relay);
+        assert_eq!(n.spec.dns.as_ref().unwrap().search, vec!["lab.example.com"]);
+        assert_eq!(n.spec.ntp.as_deref(), Some(&["10.0.0.123".to_string()][..]));
+        assert_eq!(
+            n.spec.relay.unwrap().local_address.as_deref(),
+            Some("10.20.0.1")
+        );
+
+        let yaml = "
+          kind: Network
+          name: mgmt
+          spec:
+            cidr: 172.20.0.0/24
+        ";
+        let r: Resource = serde_yaml::from_str(yaml).unwrap();
+        assert!(matches!(r, Resource::Network(n) if n.spec.dhcp == DhcpMode::Managed));
+
+        let yaml = "
+          kind: BareMetal
+          name: node1
+          spec:
+            mac: 3c:ec:ef:00:00:01
+            netboot:
+              kernel: /srv/boot/vmlinuz
+              files:
+                ks.cfg: /srv/boot/ks.cfg
+        ";
+        let b = match serde_yaml::from_str::<Resource>(yaml).unwrap() {
+            Resource::BareMetal(b) => b,
+            _ => panic!("expected a BareMetal"),
+        };
+        assert_eq!(b.spec.mac, "3c:ec:ef:00:00:01");
+        assert!(b.spec.netboot.files.unwrap().contains_key("ks.cfg"));
     }
 
     #[test]
     fn test_serde() {
         let m = Machine {
             status: None,
+            project: None,
+            labels: BTreeMap::new(),
             name: "my-test-vm".into(),
             spec: Spec {
+                arch: None,
                 cpu: 4,
-                memory: "8G".into(),
+                memory: "8G".parse().unwrap(),
+                max_cpu: None,
+                max_memory: None,
                 image: Image {
-                    url: "cos://us-south/my-bucket/my-image.qcow2".into(),
-                    resize: Some("100G".into()),
+                    name: None,
+                    url: Some("cos://us-south/my-bucket/my-image.qcow2".into()),
+                    resize: Some("100G".parse().unwrap()),
+                    arch: None,
+                    sha256: None,
+                    from_machine: None,
+                    snapshot: None,
                 },
                 storage: Some(vec![
                     StorageKind::DiskFile(DiskFile {
                         local: "localdisk01.qcow2".into(),
-                        size: "200G".into(),
+                        size: "200G".parse().unwrap(),
+                        preallocation: None,
                     }),
                     StorageKind::DiskFile(DiskFile {
                         local: "localdisk02.qcow2".into(),
-                        size: "200G".into(),
+                        size: "200G".parse().unwrap(),
+                        preallocation: Some(Preallocation::Full),
                     }),
                 ]),
                 network: Some(vec![
                     NetKind::Vlan(Vlan { vlan: 208 }),
                     NetKind::Vlan(Vlan { vlan: 209 }),
                 ]),
+                ip: Some("172.20.0.10".into()),
+                mac: None,
+                port_forwards: Some(vec![PortForward {
+                    host_port: 2222,
+                    port: 22,
+                    protocol: Protocol::Tcp,
+                }]),
+                graphics: Some(Graphics {
+                    kind: GraphicsKind::Vnc,
+                    listen: None,
+                    port: None,
+                    keymap: Some("de".into()),
+                }),
+                timezone: Some("Europe/Berlin".into()),
+                machine_type: Some("q35".into()),
+                cpu_model: Some("host-passthrough".into()),
+                cpu_features: Some(vec!["+vmx".into(), "-hle".into()]),
+                hugepages: Some("1Gi".parse().unwrap()),
+                numa: Some(Numa {
+                    node: Some(0),
+                    cpu_pinning: Some(vec![2, 3, 4, 5]),
+                }),
+                devices: Some(vec![
+                    Device::Pci(PciDevice {
+                        pci: "0000:3b:00.0".into(),
+                    }),
+                    Device::VfPool(VfPool {
+                        vf_pool: "ens1f0".into(),
+                    }),
+                ]),
+                shares: Some(vec![Share {
+                    source: "/home/dev/src".into(),
+                    tag: "src".into(),
+                    driver: ShareDriver::NineP,
+                    readonly: true,
+                }]),
+                revert_on_boot: Some("clean".into()),
+                mirror: None,
+                crash_console: Some(true),
+                priority_class: Some("dev".into()),
+                placement: None,
+                netboot: None,
+                sol: Some(Sol {
+                    protocol: SolProtocol::Telnet,
+                    listen: None,
+                    port: Some(7001),
+                    password_file: Some("/etc/bigiron/sol.pass".into()),
+                    max_connections: None,
+                }),
+                restart_policy: Some(RestartPolicy::OnFailure),
+                qos: Some(Qos {
+                    disk_iops: Some(1000),
+                    disk_bandwidth: None,
+                    net_bandwidth: Some("100M".parse().unwrap()),
+                }),
+                storage_pool: None,
+                cdrom: Some("/srv/iso/install.iso".into()),
+                boot_order: Some(vec![BootDevice::Cdrom, BootDevice::Disk]),
+                rng: None,
+                tpm: None,
+                nested: None,
             },
         };
 
@@ -176,6 +1239,81 @@ mod test {
         println!("{:#?}", r);
 
         assert_eq!(m.name, r.name);
+        match &r.spec.storage.as_ref().unwrap()[1] {
+            StorageKind::DiskFile(d) => assert_eq!(d.preallocation, Some(Preallocation::Full)),
+        }
+        assert!(out.contains("restartPolicy: on-failure"));
+        assert_eq!(r.spec.restart_policy, Some(RestartPolicy::OnFailure));
+        assert!(out.contains("- cdrom"));
+
+        let mut spec = r.spec;
+        spec.boot_order = Some(vec![BootDevice::Cdrom, BootDevice::Disk, BootDevice::Cdrom]);
+        assert_eq!(
+            spec.boot_devices(),
+            vec![BootDevice::Cdrom, BootDevice::Disk]
+        );
+        spec.boot_order = None;
+        assert_eq!(spec.boot_devices(), vec![BootDevice::Disk]);
+    }
+
+    #[test]
+    fn test_selector() {
+        let labels = BTreeMap::from([
+            ("env".to_string(), "test".to_string()),
+            ("tier".to_string(), "web".to_string()),
+        ]);
+        let sel = |s| Selector::parse(s).unwrap();
+        assert!(sel("env=test").matches(&labels));
+        assert!(sel("env==test,tier").matches(&labels));
+        assert!(sel("env=test, tier!=db, !owner").matches(&labels));
+        assert!(!sel("env=prod").matches(&labels));
+        assert!(!sel("env=test,tier=db").matches(&labels));
+        assert!(!sel("!tier").matches(&labels));
+        assert!(sel("owner!=me").matches(&labels));
+
+        assert!(Selector::parse("").is_err());
+        assert!(Selector::parse("env=te st").is_err());
+        assert!(check_labels(&BTreeMap::from([("a b".into(), "c".into())])).is_err());
+    }
+
+    #[test]
+    fn test_check_name() {
+        for name in ["web1", "my-test-vm", "0", &"a".repeat(63)] {
+            assert!(check_name(name).is_ok(), "{}", name);
+        }
+        for name in [
+            "",
+            "Web1",
+            "web_1",
+            "web 1",
+            "web.example.com",
+            "../etc",
+            "-web",
+            "web-",
+            "<web>",
+            &"a".repeat(64),
+        ] {
+            assert!(check_name(name).is_err(), "{}", name);
+        }
+
+        // machines stored before names were checked can still be read
+        let yaml = "
+          kind: Machine
+          name: Web_1
+          spec:
+            cpu: 2
+            memory: 2Gi
+            image:
+              url: http://example.com/image.qcow2
+        ";
+        assert!(serde_yaml::from_str::<Resource>(yaml).is_ok());
+    }
+
+    #[test]
+    fn test_parse_cpu_feature() {
+        assert_eq!(parse_cpu_feature("vmx"), ("vmx", true));
+        assert_eq!(parse_cpu_feature("+vmx"), ("vmx", true));
+        assert_eq!(parse_cpu_feature("-hle"), ("hle", false));
     }
 
     #[test]
@@ -187,5 +1325,57 @@ mod test {
         assert_eq!(to_size("12Gi").unwrap(), 12 * 1024 * 1024 * 1024);
 
         assert!(to_size("12Timmies").is_err());
+        assert_eq!(to_size("2T").unwrap(), 2_000_000_000_000);
+        assert_eq!(to_size("8GiB").unwrap(), 8 * 1024 * 1024 * 1024);
+        assert_eq!(to_size("8000000000").unwrap(), 8_000_000_000);
+        assert_eq!(to_size("5").unwrap(), 5);
+        assert!(to_size("").is_err());
+        assert!(to_size("5i").is_err());
+        assert!(to_size("99999999Ti").is_err());
+    }
+
+    #[test]
+    fn test_size_serde() {
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Doc {
+            size: Size,
+        }
+        let doc: Doc = serde_yaml::from_str("size: 1024Mi").unwrap();
+        assert_eq!(doc.size, Size(1024 * 1024 * 1024));
+        assert_eq!(serde_yaml::to_string(&doc).unwrap().trim(), "size: 1Gi");
+        let doc: Doc = serde_yaml::from_str("size: 4096").unwrap();
+        assert_eq!(doc.size, Size(4096));
+
+        for bad in ["size: 12Timmies", "size: -1", "size: ''", "size: Gi"] {
+            assert!(serde_yaml::from_str::<Doc>(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_size_roundtrip() {
+        for s in [
+            "8G", "8Gi", "512Mi", "1T", "1536Mi", "100M", "4Ki", "1001", "0",
+        ] {
+            assert_eq!(from_size(to_size(s).unwrap()), s);
+        }
+        let canonical = |s: &str| s.parse::<Size>().unwrap().to_string();
+        assert_eq!(canonical("8000000000"), "8G");
+        assert_eq!(canonical("1024Mi"), "1Gi");
+        assert_eq!(canonical("2048m"), "2048M");
+        assert_eq!(canonical("8GiB"), "8Gi");
+        assert_eq!(canonical("4KB"), "4K");
+        // list pads it into columns
+        assert_eq!(format!("{:>6}", Size(2 * 1024 * 1024 * 1024)), "   2Gi");
+
+        for bytes in [
+            1,
+            999,
+            1024,
+            1_000_000,
+            3 * 1024 * 1024 * 1024,
+            123_456_789_000,
+        ] {
+            assert_eq!(to_size(&from_size(bytes)).unwrap(), bytes);
+        }
     }
 }
@@ -15,9 +15,11 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
+use std::collections::{BTreeMap, HashSet};
 use std::fs::File;
 use std::net::Ipv4Addr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use hex;
 use ipnet::Ipv4Net;
@@ -26,40 +28,200 @@ use serde::{Deserialize, Serialize};
 use serde_yaml;
 use tracing::warn;
 
+use crate::bus;
+use crate::config;
 use crate::error::Error;
 use crate::lockfile::{LockFile, LockFileGuard};
+use crate::models::{DhcpMode, Dns, NetworkSpec, Relay};
+use crate::store;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+mod sqlite;
+
+// bridge that carries the bigiron managed DHCP network
+pub const MANAGEMENT_BRIDGE: &str = "br0";
+
+pub fn netstate_path() -> PathBuf {
+    store::data_dir().join("netstate")
+}
+
+pub fn netstate_db() -> PathBuf {
+    store::data_dir().join("netstate.db")
+}
+
+pub fn netstate_lock() -> PathBuf {
+    store::data_dir().join("netstate.lock")
+}
+
+// Failures of the network state callers may want to tell apart, e.g. to
+// fail one machine of an apply. Boxed into Error like any other.
+#[derive(Debug)]
+pub enum NetworkError {
+    // every assignable address of the network is reserved
+    Exhausted { cidr: String },
+    // no network configured and nothing reserved yet
+    NoState,
+    // the netstate can't be read back
+    Corrupt { path: PathBuf, reason: String },
+    // a requested ip or mac held by another hostname
+    InUse { what: String, hostname: String },
+}
+
+impl std::fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Exhausted { cidr } => write!(f, "no more free addresses on network {}", cidr),
+            Self::NoState => write!(f, "no netstate found, no network has been configured"),
+            Self::Corrupt { path, reason } => {
+                write!(f, "netstate {} is corrupt: {}", path.display(), reason)
+            }
+            Self::InUse { what, hostname } => {
+                write!(f, "{} is already in use by '{}'", what, hostname)
+            }
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct NetInfo {
     pub mac: String,
     pub ip: String,
     pub hostname: String,
     allocated: bool,
     leased: bool,
+    // unix times the current lease was first seen and runs out, an
+    // expiry of None never runs out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leased_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl NetInfo {
+    pub fn is_leased(&self) -> bool {
+        self.leased
+    }
+
+    // held for a machine or by `network reserve`, rather than only leased
+    pub fn is_allocated(&self) -> bool {
+        self.allocated
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct NetState {
+    // name of the Network resource this state was configured from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
     cidr: String,
+    #[serde(default)]
+    mode: DhcpMode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    relay: Option<Relay>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dns: Option<Dns>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ntp: Option<Vec<String>>,
+    // see NetworkSpec
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    gateway: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    reserved: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    warn_utilization: Option<u8>,
+    // unix time each address last went back to the pool
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    released: BTreeMap<String, u64>,
     reservations: Vec<NetInfo>,
 }
 
 impl NetState {
     fn new() -> Self {
         Self {
+            name: None,
             cidr: "172.20.0.0/24".to_string(),
+            mode: DhcpMode::Managed,
+            relay: None,
+            dns: None,
+            ntp: None,
+            gateway: None,
+            reserved: Vec::new(),
+            warn_utilization: None,
+            released: BTreeMap::new(),
             reservations: Vec::new(),
         }
     }
 
-    fn from_file<P: AsRef<Path>>(path: P) -> Self {
-        let f = File::open(&path).expect("error opening netstate file");
-        serde_yaml::from_reader(&f).unwrap()
+    // Drop the reservations that are neither allocated nor leased, their
+    // addresses go back to the pool.
+    fn release_unused(&mut self, now: u64) {
+        for r in &self.reservations {
+            if !r.allocated && !r.leased && !r.ip.is_empty() {
+                self.released.insert(r.ip.clone(), now);
+            }
+        }
+        self.reservations.retain(|r| r.allocated || r.leased);
+    }
+}
+
+const DEFAULT_WARN_UTILIZATION: u8 = 90;
+
+// Storage for the netstate. Callers hold the netstate lock around a
+// load/save pair, backends only need to make each write atomic.
+trait Backend {
+    fn exists(&self) -> bool;
+    fn load(&self) -> Result<NetState, Error>;
+    fn write(&self, state: &NetState) -> Result<(), Error>;
+
+    fn save(&self, state: &NetState) -> Result<(), Error> {
+        self.write(state)?;
+        bus::publish(
+            bus::Kind::Netstate,
+            state.name.as_deref().unwrap_or("default"),
+        );
+        Ok(())
+    }
+}
+
+struct YamlBackend {
+    path: PathBuf,
+}
+
+impl Backend for YamlBackend {
+    fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    fn load(&self) -> Result<NetState, Error> {
+        let f = File::open(&self.path)?;
+        serde_yaml::from_reader(&f).map_err(|e| {
+            NetworkError::Corrupt {
+                path: self.path.clone(),
+                reason: e.to_string(),
+            }
+            .into()
+        })
     }
 
-    fn save<P: AsRef<Path>>(&self, path: P) {
-        let buf = serde_yaml::to_string(&self).expect("error while serializing netstate");
-        std::fs::write(path.as_ref(), &buf).expect("error while writing netstate file");
+    fn write(&self, state: &NetState) -> Result<(), Error> {
+        let buf = serde_yaml::to_string(state)?;
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, buf)?;
+        std::fs::rename(tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+// the sqlite database once `migrate-netstate` has been run, the yaml file otherwise
+fn backend() -> Box<dyn Backend> {
+    let db = netstate_db();
+    if db.exists() {
+        Box::new(sqlite::SqliteBackend::new(db))
+    } else {
+        Box::new(YamlBackend {
+            path: netstate_path(),
+        })
     }
 }
 
@@ -79,18 +241,186 @@ pub fn generate_mac() -> String {
     mac_string
 }
 
-pub fn new_reservation(hostname: &str) -> NetInfo {
-    let np = Path::new("/var/lib/bigiron/netstate");
+// Configure the management network from a Network resource. Only a single
+// network is supported for now.
+pub fn configure(name: &str, spec: &NetworkSpec) -> Result<(), Error> {
+    if let Some(bridge) = &spec.bridge {
+        if bridge != MANAGEMENT_BRIDGE {
+            return Err(format!(
+                "network bridge must be {}, other bridges are not supported yet",
+                MANAGEMENT_BRIDGE
+            )
+            .into());
+        }
+    }
 
-    // acquire lockfile
-    let lf = LockFile::new("/var/lib/bigiron/netstate.lock");
-    let _lock = lf.acquire();
+    let net: Ipv4Net = spec.cidr.parse()?;
+    match (spec.dhcp, &spec.relay) {
+        (DhcpMode::Relay, None) => {
+            return Err("relay mode needs relay.server to be set".into());
+        }
+        (DhcpMode::Relay, Some(relay)) => {
+            relay.server.parse::<Ipv4Addr>()?;
+            if let Some(local) = &relay.local_address {
+                let local: Ipv4Addr = local.parse()?;
+                if !net.contains(&local) {
+                    return Err(format!("relay localAddress {} is not in {}", local, net).into());
+                }
+            }
+        }
+        (DhcpMode::Managed, Some(_)) => {
+            warn!("ignoring relay settings on managed network {}", name)
+        }
+        (DhcpMode::Managed, None) => {}
+    }
+    check_guest_settings(spec.dns.as_ref(), spec.ntp.as_deref())?;
+    let gateway = match &spec.gateway {
+        Some(gw) => {
+            let gw: Ipv4Addr = gw
+                .parse()
+                .map_err(|_| format!("gateway '{}' is not an IPv4 address", gw))?;
+            if !net.hosts().any(|a| a == gw) {
+                return Err(format!("gateway {} is not a host address of {}", gw, net).into());
+            }
+            Some(gw)
+        }
+        None => default_gateway(&net),
+    };
+    for r in spec.reserved.iter().flatten() {
+        let (first, last) = parse_range(r)?;
+        if !net.contains(&first) || !net.contains(&last) {
+            return Err(format!("reserved range {} is not in {}", r, net).into());
+        }
+    }
+    if spec.warn_utilization.is_some_and(|w| w > 100) {
+        return Err("warnUtilization is a percentage of at most 100".into());
+    }
+    if spec.dhcp == DhcpMode::Relay && (spec.dns.is_some() || spec.ntp.is_some()) {
+        warn!(
+            "dns and ntp settings of relay network {} are left to the upstream dhcp server",
+            name
+        );
+    }
+
+    let store = backend();
+    let lf = LockFile::new(netstate_lock());
+    let _lock = lf.acquire_timeout(LOCK_TIMEOUT)?;
+
+    let mut netstate = match store.exists() {
+        true => store.load()?,
+        false => NetState::new(),
+    };
+
+    if let Some(existing) = &netstate.name {
+        if existing != name {
+            return Err(format!(
+                "network '{}' is already configured, only one network is supported",
+                existing
+            )
+            .into());
+        }
+    }
+
+    // addresses already handed out must stay valid
+    for r in &netstate.reservations {
+        if let Ok(ip) = r.ip.parse::<Ipv4Addr>() {
+            if !net.contains(&ip) {
+                return Err(
+                    format!("{} ({}) would be outside of {}", r.ip, r.hostname, net).into(),
+                );
+            }
+            if Some(ip) == gateway {
+                return Err(format!("{} is the gateway but held by {}", r.ip, r.hostname).into());
+            }
+        }
+    }
+
+    netstate.name = Some(name.to_string());
+    netstate.cidr = net.to_string();
+    netstate.mode = spec.dhcp;
+    netstate.relay = match spec.dhcp {
+        DhcpMode::Relay => spec.relay.clone(),
+        DhcpMode::Managed => None,
+    };
+    netstate.dns = spec.dns.clone();
+    netstate.ntp = spec.ntp.clone();
+    netstate.gateway = spec.gateway.clone();
+    netstate.reserved = spec.reserved.clone().unwrap_or_default();
+    netstate.warn_utilization = spec.warn_utilization;
+    store.save(&netstate)
+}
+
+// an address, or a range of them like 172.20.0.2-172.20.0.20
+fn parse_range(s: &str) -> Result<(Ipv4Addr, Ipv4Addr), Error> {
+    let parse = |a: &str| {
+        a.trim()
+            .parse::<Ipv4Addr>()
+            .map_err(|_| format!("'{}' is not an IPv4 address or range", s))
+    };
+    let (first, last) = match s.split_once('-') {
+        Some((a, b)) => (parse(a)?, parse(b)?),
+        None => (parse(s)?, parse(s)?),
+    };
+    if first > last {
+        return Err(format!("range {} ends before it starts", s).into());
+    }
+    Ok((first, last))
+}
+
+fn check_guest_settings(dns: Option<&Dns>, ntp: Option<&[String]>) -> Result<(), Error> {
+    let dns_servers = dns.map(|d| &d.servers[..]).unwrap_or_default();
+    for server in dns_servers.iter().chain(ntp.unwrap_or_default()) {
+        server
+            .parse::<Ipv4Addr>()
+            .map_err(|_| format!("'{}' is not an IPv4 address", server))?;
+    }
+    for domain in dns.map(|d| &d.search[..]).unwrap_or_default() {
+        if domain.is_empty() || domain.contains(|c: char| c == ',' || c.is_whitespace()) {
+            return Err(format!("'{}' is not a valid search domain", domain).into());
+        }
+    }
+    Ok(())
+}
+
+// DNS and NTP settings for guests, from the network or the host config
+pub fn guest_settings() -> Result<(Option<Dns>, Option<Vec<String>>), Error> {
+    let store = backend();
+    let netstate = match store.exists() {
+        true => store.load()?,
+        false => NetState::new(),
+    };
+    let defaults = config::load()?.network;
+    Ok((netstate.dns.or(defaults.dns), netstate.ntp.or(defaults.ntp)))
+}
+
+// how DHCP is served on the management network
+pub fn dhcp_mode() -> Result<(DhcpMode, Option<Relay>), Error> {
+    let store = backend();
+    if !store.exists() {
+        return Ok((DhcpMode::Managed, None));
+    }
+    let netstate = store.load()?;
+    Ok((netstate.mode, netstate.relay))
+}
+
+// Reserve an address for a hostname, or get back the one reserved earlier.
+// A requested ip or mac is used instead of allocating one.
+pub fn new_reservation(
+    hostname: &str,
+    ip: Option<&str>,
+    mac: Option<&str>,
+) -> Result<NetInfo, Error> {
+    let store = backend();
+
+    let lf = LockFile::new(netstate_lock());
+    let _lock = lf.acquire_timeout(LOCK_TIMEOUT)?;
 
     // read any current state or create new
-    let mut netstate = match np.exists() {
-        true => NetState::from_file(&np),
+    let mut netstate = match store.exists() {
+        true => store.load()?,
         false => NetState::new(),
     };
+    let (ip, mac) = check_static(&netstate, hostname, ip, mac)?;
 
     // return a reservations for this hostname if it already exists
     if let Some(netinfo) = netstate
@@ -99,100 +429,343 @@ pub fn new_reservation(hostname: &str) -> NetInfo {
         .find(|x| x.hostname == hostname)
     {
         netinfo.allocated = true;
-        let res = netinfo.clone();
-        netstate.save(&np);
-        return res;
-    }
-
-    // loop through IPs in CIDR mask, check if free
-    let mut free: Option<Ipv4Addr> = None;
-    let net: Ipv4Net = netstate.cidr.parse().unwrap();
-    for addr in net.hosts() {
-        if addr.to_string().ends_with(".1") || addr.to_string().ends_with(".255") {
-            // skip gateway and broadcast ranges
-            continue;
+        if let Some(mac) = mac {
+            netinfo.mac = mac;
         }
-
-        let mut inuse = false;
-        for r in &netstate.reservations {
-            let ip = r.ip.parse::<Ipv4Addr>().unwrap();
-            if ip == addr {
-                inuse = true;
-                break;
+        if let Some(ip) = ip.map(|a| a.to_string()) {
+            if netinfo.ip != ip {
+                // the guest picks the new address up on its next renewal
+                netinfo.ip = ip;
+                netinfo.leased = false;
+                netinfo.leased_at = None;
+                netinfo.expires = None;
             }
         }
-
-        if !inuse {
-            free = Some(addr);
-            break;
-        }
-    }
-
-    if free.is_none() {
-        panic!("No more free addresses on network");
+        let res = netinfo.clone();
+        store.save(&netstate)?;
+        return Ok(res);
     }
 
-    // generate mac and check
-    let mut unique = false;
-    let mut mac = String::new();
-    while !unique {
-        mac = generate_mac();
-        unique = true;
-        for r in &netstate.reservations {
-            if mac == r.mac {
-                unique = false;
-                break;
+    let mac = match mac {
+        Some(mac) => mac,
+        None => {
+            let mut mac = generate_mac();
+            while netstate.reservations.iter().any(|r| r.mac == mac) {
+                mac = generate_mac();
             }
+            mac
         }
-    }
+    };
+
+    // on relay networks the address is assigned elsewhere, we only track
+    // the mac until a lease for it is seen
+    let ip = match (netstate.mode, ip) {
+        (DhcpMode::Relay, _) => String::new(),
+        (DhcpMode::Managed, Some(ip)) => ip.to_string(),
+        (DhcpMode::Managed, None) => next_free(&netstate)?.to_string(),
+    };
+    netstate.released.remove(&ip);
 
     // insert reservation, write to disk
     let new_res = NetInfo {
-        mac: mac,
-        ip: free.unwrap().to_string(),
+        mac,
+        ip,
         hostname: hostname.to_string(),
         allocated: true,
         leased: false,
+        leased_at: None,
+        expires: None,
     };
     netstate.reservations.push(new_res.clone());
-    netstate.save(&np);
+    store.save(&netstate)?;
+    // the reservation is made by now, whatever goes wrong here
+    if let Err(e) = warn_utilization(&netstate) {
+        warn!("error checking the utilization of the network: {}", e);
+    }
 
     // return net info
-    new_res
+    Ok(new_res)
 }
 
-fn get_netstate_locked<'a, P: AsRef<Path>>(
-    path: P,
-    lf: &'a LockFile,
-) -> (NetState, LockFileGuard<'a>) {
-    if !path.as_ref().exists() {
-        panic!("no netstate file found");
+fn warn_utilization(state: &NetState) -> Result<(), Error> {
+    let s = summarize(state)?;
+    let capacity = match s.capacity {
+        Some(c) if c > 0 => c,
+        _ => return Ok(()),
+    };
+    let percent = s.allocated * 100 / capacity;
+    let threshold = state.warn_utilization.unwrap_or(DEFAULT_WARN_UTILIZATION);
+    if percent >= threshold as usize {
+        warn!(
+            "network {} is {}% allocated, {} of {} addresses",
+            s.cidr, percent, s.allocated, capacity
+        );
     }
+    Ok(())
+}
 
-    let lock = lf.acquire();
+// The addresses automatic allocation picks from, the network's hosts less
+// the reserved ranges. Reserved addresses can still be asked for explicitly.
+fn pool(state: &NetState) -> Result<Vec<Ipv4Addr>, Error> {
+    let reserved = state
+        .reserved
+        .iter()
+        .map(|r| parse_range(r))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(assignable(state)?
+        .filter(|a| !reserved.iter().any(|(first, last)| first <= a && a <= last))
+        .collect())
+}
 
-    (NetState::from_file(path.as_ref()), lock)
+// A free address of the pool, one never handed out if there is any, else
+// the one released longest ago. Guests and peers may still have recently
+// released addresses cached.
+fn next_free(state: &NetState) -> Result<Ipv4Addr, Error> {
+    // reservations from relay mode may not have an address yet
+    let inuse: HashSet<Ipv4Addr> = state
+        .reservations
+        .iter()
+        .filter_map(|r| r.ip.parse().ok())
+        .collect();
+    let free = pool(state)?.into_iter().filter(|a| !inuse.contains(a));
+    match free.min_by_key(|a| state.released.get(&a.to_string()).copied()) {
+        Some(addr) => Ok(addr),
+        None => Err(NetworkError::Exhausted {
+            cidr: state.cidr.clone(),
+        }
+        .into()),
+    }
 }
 
-pub fn remove_reservation(hostname: &str) -> Result<(), Error> {
-    let np = Path::new("/var/lib/bigiron/netstate");
-    let lf = LockFile::new("/var/lib/bigiron/netstate.lock");
-    let (mut netstate, _lock) = get_netstate_locked(&np, &lf);
+// check a machine's requested address before anything is created for it
+pub fn check_reservation(hostname: &str, ip: Option<&str>, mac: Option<&str>) -> Result<(), Error> {
+    if ip.is_none() && mac.is_none() {
+        return Ok(());
+    }
+    let store = backend();
+    let netstate = match store.exists() {
+        true => store.load()?,
+        false => NetState::new(),
+    };
+    check_static(&netstate, hostname, ip, mac).map(|_| ())
+}
 
-    let mut entry = None;
-    for (i, r) in netstate.reservations.iter_mut().enumerate() {
-        if r.hostname == hostname {
-            r.allocated = false;
-            entry = Some((i, r));
-            break;
+// Parse a requested ip and mac, they must be usable on the network and not
+// reserved for another hostname.
+fn check_static(
+    state: &NetState,
+    hostname: &str,
+    ip: Option<&str>,
+    mac: Option<&str>,
+) -> Result<(Option<Ipv4Addr>, Option<String>), Error> {
+    let ip = match ip {
+        Some(_) if state.mode == DhcpMode::Relay => {
+            return Err(
+                "addresses on relay networks are assigned by the upstream dhcp server".into(),
+            )
         }
-    }
+        Some(ip) => {
+            let addr: Ipv4Addr = ip
+                .parse()
+                .map_err(|_| format!("'{}' is not an IPv4 address", ip))?;
+            if !assignable(state)?.any(|a| a == addr) {
+                return Err(
+                    format!("{} is not an assignable address of {}", addr, state.cidr).into(),
+                );
+            }
+            Some(addr)
+        }
+        None => None,
+    };
+    let mac = match mac {
+        Some(mac) => Some(parse_mac(mac)?),
+        None => None,
+    };
 
-    if let Some((i, r)) = entry {
-        if !r.allocated && !r.leased {
-            let _ = netstate.reservations.remove(i);
+    for r in state.reservations.iter().filter(|r| r.hostname != hostname) {
+        let what = if ip.is_some_and(|a| r.ip.parse::<Ipv4Addr>().ok() == Some(a)) {
+            &r.ip
+        } else if mac
+            .as_deref()
+            .is_some_and(|m| r.mac.eq_ignore_ascii_case(m))
+        {
+            &r.mac
+        } else {
+            continue;
+        };
+        return Err(NetworkError::InUse {
+            what: what.clone(),
+            hostname: r.hostname.clone(),
         }
-        netstate.save(&np);
+        .into());
+    }
+    Ok((ip, mac))
+}
+
+// normalize a unicast mac address to lowercase
+fn parse_mac(s: &str) -> Result<String, Error> {
+    let octets = s
+        .split(':')
+        .map(|o| match o.len() {
+            2 => u8::from_str_radix(o, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<Vec<u8>>>()
+        .filter(|o| o.len() == 6)
+        .ok_or_else(|| format!("'{}' is not a mac address", s))?;
+    if octets[0] & 1 == 1 {
+        return Err(format!("{} is a multicast mac address", s).into());
+    }
+    Ok(s.to_lowercase())
+}
+
+// Addresses of a network reservations are made from, every host address
+// but the gateway. hosts() already leaves out the network and broadcast
+// addresses.
+fn assignable(state: &NetState) -> Result<impl Iterator<Item = Ipv4Addr>, Error> {
+    let net: Ipv4Net = state.cidr.parse()?;
+    let gateway = gateway(state)?;
+    Ok(net.hosts().filter(move |a| Some(*a) != gateway))
+}
+
+fn gateway(state: &NetState) -> Result<Option<Ipv4Addr>, Error> {
+    match &state.gateway {
+        Some(gw) => Ok(Some(gw.parse()?)),
+        None => Ok(default_gateway(&state.cidr.parse()?)),
+    }
+}
+
+// the first host address, unless the network is too small to spare one
+fn default_gateway(net: &Ipv4Net) -> Option<Ipv4Addr> {
+    match net.prefix_len() {
+        0..=30 => net.hosts().next(),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub name: Option<String>,
+    pub cidr: String,
+    pub mode: DhcpMode,
+    pub gateway: Option<Ipv4Addr>,
+    // addresses reservations can be made from, None on relay networks
+    // where addresses are assigned upstream
+    pub capacity: Option<usize>,
+    pub allocated: usize,
+    pub leased: usize,
+}
+
+// the management network and how much of it is in use
+pub fn summary() -> Result<Summary, Error> {
+    let store = backend();
+    let netstate = match store.exists() {
+        true => store.load()?,
+        false => NetState::new(),
+    };
+    summarize(&netstate)
+}
+
+fn summarize(state: &NetState) -> Result<Summary, Error> {
+    Ok(Summary {
+        name: state.name.clone(),
+        cidr: state.cidr.clone(),
+        mode: state.mode,
+        gateway: gateway(state)?,
+        capacity: match state.mode {
+            DhcpMode::Managed => Some(pool(state)?.len()),
+            DhcpMode::Relay => None,
+        },
+        allocated: state.reservations.iter().filter(|r| r.allocated).count(),
+        leased: state.reservations.iter().filter(|r| r.leased).count(),
+    })
+}
+
+// every reservation, whether allocated, leased or both
+pub fn reservations() -> Result<Vec<NetInfo>, Error> {
+    let store = backend();
+    if !store.exists() {
+        return Ok(Vec::new());
+    }
+    Ok(store.load()?.reservations)
+}
+
+pub fn reservation(hostname: &str) -> Result<Option<NetInfo>, Error> {
+    Ok(reservations()?.into_iter().find(|r| r.hostname == hostname))
+}
+
+// how long lease updates wait on the netstate lock before giving up
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn get_netstate_locked<'a>(
+    store: &dyn Backend,
+    lf: &'a LockFile,
+) -> Result<(NetState, LockFileGuard<'a>), Error> {
+    if !store.exists() {
+        return Err(NetworkError::NoState.into());
+    }
+
+    let lock = lf.acquire_timeout(LOCK_TIMEOUT)?;
+
+    Ok((store.load()?, lock))
+}
+
+// address of a machine once a lease for its reservation has been seen
+pub fn leased_address(hostname: &str) -> Result<Option<String>, Error> {
+    let store = backend();
+    if !store.exists() {
+        return Ok(None);
+    }
+    Ok(store
+        .load()?
+        .reservations
+        .into_iter()
+        .find(|r| r.hostname == hostname && r.leased && !r.ip.is_empty())
+        .map(|r| r.ip))
+}
+
+// hand a reservation over to a new hostname, keeping its address and mac
+pub fn rename_reservation(hostname: &str, new: &str) -> Result<Option<NetInfo>, Error> {
+    let store = backend();
+    if !store.exists() {
+        return Ok(None);
+    }
+    let lf = LockFile::new(netstate_lock());
+    let (mut netstate, _lock) = get_netstate_locked(store.as_ref(), &lf)?;
+
+    if netstate.reservations.iter().any(|r| r.hostname == new) {
+        return Err(format!("{} already has a reservation", new).into());
+    }
+    let r = match netstate
+        .reservations
+        .iter_mut()
+        .find(|r| r.hostname == hostname)
+    {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    r.hostname = new.to_string();
+    let renamed = r.clone();
+    store.save(&netstate)?;
+    Ok(Some(renamed))
+}
+
+pub fn remove_reservation(hostname: &str) -> Result<(), Error> {
+    let store = backend();
+    if !store.exists() {
+        return Ok(());
+    }
+    let lf = LockFile::new(netstate_lock());
+    let (mut netstate, _lock) = get_netstate_locked(store.as_ref(), &lf)?;
+
+    let entry = netstate
+        .reservations
+        .iter_mut()
+        .find(|r| r.hostname == hostname);
+    if let Some(r) = entry {
+        r.allocated = false;
+        netstate.release_unused(now());
+        store.save(&netstate)?;
     } else {
         warn!("no reservation for {} found to remove", hostname);
     }
@@ -200,25 +773,42 @@ pub fn remove_reservation(hostname: &str) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn add_lease(mac: &str, addr: &str, hostname: Option<String>) {
-    let np = Path::new("/var/lib/bigiron/netstate");
-    let lf = LockFile::new("/var/lib/bigiron/netstate.lock");
-    let (mut netstate, _lock) = get_netstate_locked(&np, &lf);
+pub fn add_lease(
+    mac: &str,
+    addr: &str,
+    hostname: Option<String>,
+    expires: Option<u64>,
+) -> Result<(), Error> {
+    let store = backend();
+    let lf = LockFile::new(netstate_lock());
+    let (mut netstate, _lock) = get_netstate_locked(store.as_ref(), &lf)?;
 
-    // need to mark the IP address as leased
-    if let Some(netinfo) = netstate.reservations.iter_mut().find(|x| x.ip == addr) {
+    // need to mark the IP address as leased, reservations on relay networks
+    // learn their address from the first lease seen for their mac
+    let mut acquired = true;
+    if let Some(netinfo) = netstate
+        .reservations
+        .iter_mut()
+        .find(|x| x.ip == addr || (x.ip.is_empty() && x.mac == mac))
+    {
+        // renewals keep the time the lease started
+        acquired = !netinfo.leased || netinfo.ip != addr;
+        if acquired {
+            netinfo.leased_at = Some(now());
+        }
+        netinfo.ip = addr.to_string();
         netinfo.leased = true;
+        netinfo.expires = expires;
         if netinfo.mac != mac {
             warn!(
                 "new lease mac='{}' didn't match reservation='{:?}'",
                 mac, netinfo
             );
         }
-        if hostname.is_some() && netinfo.hostname != hostname.as_ref().unwrap().as_str() {
+        if let Some(h) = hostname.filter(|h| *h != netinfo.hostname) {
             warn!(
                 "new lease hostname='{}' didn't match reservation='{:?}'",
-                hostname.unwrap(),
-                netinfo
+                h, netinfo
             );
         }
     } else {
@@ -235,36 +825,259 @@ pub fn add_lease(mac: &str, addr: &str, hostname: Option<String>) {
             hostname: host,
             allocated: false,
             leased: true,
+            leased_at: Some(now()),
+            expires,
         };
         netstate.reservations.push(new_res);
     }
 
-    netstate.save(&np);
+    store.save(&netstate)?;
+    if acquired {
+        bus::publish_action(bus::Kind::Lease, addr, bus::Action::Acquired);
+    }
+    Ok(())
+}
+
+pub fn del_lease(_mac: &str, addr: &str, _hostname: Option<String>) -> Result<(), Error> {
+    let store = backend();
+    let lf = LockFile::new(netstate_lock());
+    let (mut netstate, _lock) = get_netstate_locked(store.as_ref(), &lf)?;
+
+    if let Some(r) = netstate.reservations.iter_mut().find(|r| r.ip == addr) {
+        r.leased = false;
+        r.leased_at = None;
+        r.expires = None;
+        netstate.release_unused(now());
+        store.save(&netstate)?;
+        bus::publish_action(bus::Kind::Lease, addr, bus::Action::Expired);
+    }
+    Ok(())
 }
 
-pub fn del_lease(_mac: &str, addr: &str, _hostname: Option<String>) {
-    let np = Path::new("/var/lib/bigiron/netstate");
-    let lf = LockFile::new("/var/lib/bigiron/netstate.lock");
-    let (mut netstate, _lock) = get_netstate_locked(&np, &lf);
+// Clear leases that ran out without dnsmasq telling us, e.g. because it
+// was killed. Returns the leases that were cleared.
+pub fn reap_leases() -> Result<Vec<NetInfo>, Error> {
+    let store = backend();
+    if !store.exists() {
+        return Ok(Vec::new());
+    }
+    let lf = LockFile::new(netstate_lock());
+    let (mut netstate, _lock) = get_netstate_locked(store.as_ref(), &lf)?;
 
-    let mut entry = None;
-    for (i, r) in netstate.reservations.iter_mut().enumerate() {
-        if r.ip == addr {
+    let reaped = expire_leases(&mut netstate, now());
+    if !reaped.is_empty() {
+        store.save(&netstate)?;
+    }
+    for r in &reaped {
+        bus::publish_action(bus::Kind::Lease, &r.ip, bus::Action::Expired);
+    }
+    Ok(reaped)
+}
+
+fn expire_leases(state: &mut NetState, now: u64) -> Vec<NetInfo> {
+    let mut reaped = Vec::new();
+    for r in state.reservations.iter_mut() {
+        if r.leased && r.expires.is_some_and(|e| e <= now) {
+            reaped.push(r.clone());
             r.leased = false;
-            entry = Some((i, r));
-            break;
+            r.leased_at = None;
+            r.expires = None;
         }
     }
+    // entries only there to keep a leased address from being reused
+    state.release_unused(now);
+    reaped
+}
 
-    if let Some((i, r)) = entry {
-        if !r.allocated && !r.leased {
-            let _ = netstate.reservations.remove(i);
+// reservations with an active lease
+pub fn leases() -> Result<Vec<NetInfo>, Error> {
+    let store = backend();
+    if !store.exists() {
+        return Ok(Vec::new());
+    }
+    let mut r = store.load()?.reservations;
+    r.retain(|r| r.leased);
+    Ok(r)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// an entry of the dnsmasq lease file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    // unix time, 0 for leases that never expire
+    pub expires: u64,
+    pub mac: String,
+    pub ip: String,
+    pub hostname: Option<String>,
+}
+
+// parse `<expiry> <mac> <ip> <hostname> <client-id>` lines
+pub fn parse_leases(buf: &str) -> Result<Vec<Lease>, Error> {
+    let mut leases = Vec::new();
+    for (n, line) in buf.lines().enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() || fields[0] == "duid" {
+            continue;
         }
+        if fields.len() < 4 {
+            return Err(format!("malformed lease on line {}: {}", n + 1, line).into());
+        }
+        leases.push(Lease {
+            expires: fields[0].parse()?,
+            mac: fields[1].to_lowercase(),
+            ip: fields[2].to_string(),
+            hostname: match fields[3] {
+                "*" => None,
+                h => Some(h.to_string()),
+            },
+        });
+    }
+    Ok(leases)
+}
 
-        netstate.save(&np);
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub reservations: usize,
+    // leases that had no reservation and were added as leased entries
+    pub imported_leases: usize,
+    // inconsistencies that prevent the migration
+    pub problems: Vec<String>,
+}
+
+// fold dnsmasq leases into the netstate the same way lease events would
+fn merge_leases(state: &mut NetState, leases: &[Lease], report: &mut MigrationReport) {
+    for lease in leases {
+        match state
+            .reservations
+            .iter_mut()
+            .find(|r| r.ip == lease.ip || (r.ip.is_empty() && r.mac == lease.mac))
+        {
+            Some(r) if r.mac != lease.mac => report.problems.push(format!(
+                "{} is leased to {} but reserved for {} ({})",
+                lease.ip, lease.mac, r.mac, r.hostname
+            )),
+            Some(r) => {
+                r.ip = lease.ip.clone();
+                r.leased = true;
+                r.expires = Some(lease.expires).filter(|e| *e != 0);
+            }
+            None => {
+                state.reservations.push(NetInfo {
+                    mac: lease.mac.clone(),
+                    ip: lease.ip.clone(),
+                    hostname: lease.hostname.clone().unwrap_or_default(),
+                    allocated: false,
+                    leased: true,
+                    leased_at: None,
+                    expires: Some(lease.expires).filter(|e| *e != 0),
+                });
+                report.imported_leases += 1;
+            }
+        }
+    }
+}
+
+// every address in the network at most once and every mac on one address
+fn check_consistency(state: &NetState, report: &mut MigrationReport) {
+    let net: Ipv4Net = match state.cidr.parse() {
+        Ok(n) => n,
+        Err(e) => {
+            report
+                .problems
+                .push(format!("invalid cidr {}: {}", state.cidr, e));
+            return;
+        }
+    };
+
+    let mut ips = HashSet::new();
+    let mut macs = HashSet::new();
+    for r in &state.reservations {
+        if !macs.insert(&r.mac) {
+            report
+                .problems
+                .push(format!("mac {} has more than one reservation", r.mac));
+        }
+        if r.ip.is_empty() {
+            continue;
+        }
+        match r.ip.parse::<Ipv4Addr>() {
+            Ok(ip) if !net.contains(&ip) => report
+                .problems
+                .push(format!("{} ({}) is outside of {}", r.ip, r.mac, net)),
+            Ok(_) => {}
+            Err(_) => report
+                .problems
+                .push(format!("invalid address '{}' for {}", r.ip, r.mac)),
+        }
+        if !ips.insert(&r.ip) {
+            report
+                .problems
+                .push(format!("{} has more than one reservation", r.ip));
+        }
     }
 }
 
+// Move the yaml netstate, plus any leases dnsmasq knows about that it is
+// missing, into the sqlite backend. The database is written next to its
+// final location and renamed into place, so lease events see either the
+// old or the new backend and never a partial one.
+pub fn migrate_netstate(leasefile: &Path, dry_run: bool) -> Result<MigrationReport, Error> {
+    let db = netstate_db();
+    if db.exists() {
+        return Err(format!("netstate is already stored in {}", db.display()).into());
+    }
+
+    let lf = LockFile::new(netstate_lock());
+    let _lock = lf.acquire_timeout(LOCK_TIMEOUT)?;
+
+    let yaml = YamlBackend {
+        path: netstate_path(),
+    };
+    let mut state = match yaml.exists() {
+        true => yaml.load()?,
+        false => NetState::new(),
+    };
+
+    let leases = match std::fs::read_to_string(leasefile) {
+        Ok(buf) => parse_leases(&buf)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut report = MigrationReport::default();
+    merge_leases(&mut state, &leases, &mut report);
+    check_consistency(&state, &mut report);
+    report.reservations = state.reservations.len();
+
+    if dry_run || !report.problems.is_empty() {
+        return Ok(report);
+    }
+
+    let tmp = db.with_extension("db.tmp");
+    if tmp.exists() {
+        std::fs::remove_file(&tmp)?;
+    }
+    let sql = sqlite::SqliteBackend::new(&tmp);
+    sql.save(&state)?;
+    if sql.load()? != state {
+        return Err("netstate read back from the new database does not match".into());
+    }
+
+    std::fs::rename(&tmp, db)?;
+    if yaml.exists() {
+        // keep the old file around in case the upgrade needs undoing
+        std::fs::rename(&yaml.path, yaml.path.with_extension("migrated"))?;
+    }
+
+    Ok(report)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -275,4 +1088,208 @@ mod test {
         eprintln!("{}", mac);
         assert!(mac.starts_with("00:16:3e"));
     }
+
+    #[test]
+    fn test_expire_leases() {
+        let mut state = NetState::new();
+        for (ip, allocated, expires) in [
+            ("172.20.0.2", true, Some(100)),
+            ("172.20.0.3", false, Some(100)),
+            ("172.20.0.4", true, Some(300)),
+            ("172.20.0.5", true, None),
+        ] {
+            state.reservations.push(NetInfo {
+                mac: generate_mac(),
+                ip: ip.into(),
+                hostname: String::new(),
+                allocated,
+                leased: true,
+                leased_at: Some(50),
+                expires,
+            });
+        }
+
+        let reaped = expire_leases(&mut state, 200);
+        assert_eq!(reaped.len(), 2);
+        // the unallocated entry only existed for its lease
+        assert_eq!(state.reservations.len(), 3);
+        assert!(!state.reservations[0].leased);
+        assert!(state.reservations[1].leased && state.reservations[2].leased);
+    }
+
+    #[test]
+    fn test_check_static() {
+        let mut state = NetState::new();
+        state.reservations.push(NetInfo {
+            mac: "00:16:3e:00:00:01".into(),
+            ip: "172.20.0.2".into(),
+            hostname: "web1".into(),
+            allocated: true,
+            leased: false,
+            leased_at: None,
+            expires: None,
+        });
+
+        let (ip, mac) = check_static(
+            &state,
+            "web2",
+            Some("172.20.0.10"),
+            Some("00:16:3E:00:00:02"),
+        )
+        .unwrap();
+        assert_eq!(ip, Some("172.20.0.10".parse().unwrap()));
+        assert_eq!(mac.as_deref(), Some("00:16:3e:00:00:02"));
+
+        // taken by another hostname, but fine for the one holding it
+        assert!(check_static(&state, "web2", Some("172.20.0.2"), None).is_err());
+        assert!(check_static(&state, "web2", None, Some("00:16:3E:00:00:01")).is_err());
+        assert!(check_static(&state, "web1", Some("172.20.0.2"), None).is_ok());
+
+        assert!(check_static(&state, "web2", Some("172.20.1.2"), None).is_err());
+        assert!(check_static(&state, "web2", Some("172.20.0.1"), None).is_err());
+        assert!(check_static(&state, "web2", None, Some("01:16:3e:00:00:02")).is_err());
+        assert!(check_static(&state, "web2", None, Some("00:16:3e:00:02")).is_err());
+
+        state.mode = DhcpMode::Relay;
+        assert!(check_static(&state, "web2", Some("172.20.0.10"), None).is_err());
+    }
+
+    #[test]
+    fn test_next_free() {
+        let mut state = NetState::new();
+        state.cidr = "172.20.0.0/29".into();
+        assert_eq!(
+            next_free(&state).unwrap(),
+            "172.20.0.2".parse::<Ipv4Addr>().unwrap()
+        );
+
+        for i in 2..7 {
+            state.reservations.push(NetInfo {
+                mac: format!("00:16:3e:00:00:0{}", i),
+                ip: format!("172.20.0.{}", i),
+                hostname: format!("web{}", i),
+                allocated: true,
+                leased: false,
+                leased_at: None,
+                expires: None,
+            });
+        }
+        // released addresses are reused oldest first, after fresh ones
+        state
+            .reservations
+            .retain(|r| r.ip != "172.20.0.4" && r.ip != "172.20.0.5");
+        state.released.insert("172.20.0.4".into(), 200);
+        state.released.insert("172.20.0.5".into(), 100);
+        assert_eq!(
+            next_free(&state).unwrap(),
+            "172.20.0.5".parse::<Ipv4Addr>().unwrap()
+        );
+        state.released.remove("172.20.0.4");
+        assert_eq!(
+            next_free(&state).unwrap(),
+            "172.20.0.4".parse::<Ipv4Addr>().unwrap()
+        );
+
+        state.reserved = vec!["172.20.0.4-172.20.0.5".into()];
+        let err = next_free(&state).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<NetworkError>(),
+            Some(NetworkError::Exhausted { .. })
+        ));
+    }
+
+    #[test]
+    fn test_summarize() {
+        let mut state = NetState::new();
+        for (ip, allocated, leased) in [("172.20.0.2", true, true), ("172.20.0.3", true, false)] {
+            state.reservations.push(NetInfo {
+                mac: generate_mac(),
+                ip: ip.into(),
+                hostname: String::new(),
+                allocated,
+                leased,
+                leased_at: None,
+                expires: None,
+            });
+        }
+
+        let s = summarize(&state).unwrap();
+        assert_eq!(s.gateway, Some("172.20.0.1".parse().unwrap()));
+        // .0 and .255 aren't hosts, .1 is the gateway
+        assert_eq!(s.capacity, Some(253));
+        assert_eq!((s.allocated, s.leased), (2, 1));
+
+        state.mode = DhcpMode::Relay;
+        assert_eq!(summarize(&state).unwrap().capacity, None);
+    }
+
+    #[test]
+    fn test_assignable() {
+        let mut state = NetState::new();
+        state.cidr = "10.1.0.0/23".into();
+        let hosts: Vec<Ipv4Addr> = assignable(&state).unwrap().collect();
+        // only the network, broadcast and gateway addresses are skipped
+        assert_eq!(hosts.len(), 509);
+        assert_eq!(hosts[0], "10.1.0.2".parse::<Ipv4Addr>().unwrap());
+        assert!(hosts.contains(&"10.1.0.255".parse().unwrap()));
+        assert!(hosts.contains(&"10.1.1.1".parse().unwrap()));
+        assert_eq!(hosts[508], "10.1.1.254".parse::<Ipv4Addr>().unwrap());
+
+        state.gateway = Some("10.1.1.254".into());
+        let hosts: Vec<Ipv4Addr> = assignable(&state).unwrap().collect();
+        assert_eq!(hosts.len(), 509);
+        assert!(hosts.contains(&"10.1.0.1".parse().unwrap()));
+        assert!(!hosts.contains(&"10.1.1.254".parse().unwrap()));
+        assert_eq!(
+            summarize(&state).unwrap().gateway,
+            Some("10.1.1.254".parse().unwrap())
+        );
+
+        // point to point links have no address to spare for a gateway
+        state.cidr = "10.1.0.0/31".into();
+        state.gateway = None;
+        assert_eq!(assignable(&state).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_merge_leases() {
+        let leases = parse_leases(
+            "1700000000 00:16:3e:00:00:01 172.20.0.2 web1 *\n\
+             1700000000 00:16:3E:00:00:02 172.20.0.3 * 01:00:16:3e:00:00:02\n\
+             1700000000 00:16:3e:00:00:09 172.20.0.4 db1 *\n",
+        )
+        .unwrap();
+        assert_eq!(leases[1].mac, "00:16:3e:00:00:02");
+        assert_eq!(leases[1].hostname, None);
+
+        let mut state = NetState::new();
+        state.reservations.push(NetInfo {
+            mac: "00:16:3e:00:00:01".into(),
+            ip: "172.20.0.2".into(),
+            hostname: "web1".into(),
+            allocated: true,
+            leased: false,
+            leased_at: None,
+            expires: None,
+        });
+        state.reservations.push(NetInfo {
+            mac: "00:16:3e:00:00:03".into(),
+            ip: "172.20.0.4".into(),
+            hostname: "db1".into(),
+            allocated: true,
+            leased: false,
+            leased_at: None,
+            expires: None,
+        });
+
+        let mut report = MigrationReport::default();
+        merge_leases(&mut state, &leases, &mut report);
+        check_consistency(&state, &mut report);
+
+        assert!(state.reservations[0].leased);
+        assert_eq!(report.imported_leases, 1);
+        // 172.20.0.4 is leased to a different mac than it's reserved for
+        assert_eq!(report.problems.len(), 1);
+        assert!(report.problems[0].contains("172.20.0.4"));
+    }
 }
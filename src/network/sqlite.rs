@@ -0,0 +1,290 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::{Backend, NetInfo, NetState};
+use crate::error::Error;
+use crate::models::{DhcpMode, Relay};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS network (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    name TEXT,
+    cidr TEXT NOT NULL,
+    mode TEXT NOT NULL,
+    relay_server TEXT,
+    relay_local_address TEXT
+);
+CREATE TABLE IF NOT EXISTS reservations (
+    id INTEGER PRIMARY KEY,
+    mac TEXT NOT NULL,
+    ip TEXT NOT NULL,
+    hostname TEXT NOT NULL,
+    allocated INTEGER NOT NULL,
+    leased INTEGER NOT NULL,
+    leased_at INTEGER,
+    expires INTEGER
+);
+CREATE TABLE IF NOT EXISTS options (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+";
+
+pub struct SqliteBackend {
+    path: PathBuf,
+}
+
+impl SqliteBackend {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    fn connect(&self) -> Result<Connection, Error> {
+        let conn = Connection::open(&self.path)?;
+        conn.busy_timeout(Duration::from_secs(10))?;
+        conn.execute_batch(SCHEMA)?;
+        upgrade(&conn)?;
+        Ok(conn)
+    }
+}
+
+// add the lease times to databases created before they were tracked
+fn upgrade(conn: &Connection) -> Result<(), Error> {
+    let mut stmt = conn.prepare("PRAGMA table_info(reservations)")?;
+    let columns = stmt
+        .query_map(params![], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?;
+    if !columns.iter().any(|c| c == "expires") {
+        conn.execute_batch(
+            "ALTER TABLE reservations ADD COLUMN leased_at INTEGER;
+             ALTER TABLE reservations ADD COLUMN expires INTEGER;",
+        )?;
+    }
+    Ok(())
+}
+
+impl Backend for SqliteBackend {
+    fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    fn load(&self) -> Result<NetState, Error> {
+        let conn = self.connect()?;
+
+        let network = conn
+            .query_row(
+                "SELECT name, cidr, mode, relay_server, relay_local_address FROM network WHERE id = 0",
+                params![],
+                |row| {
+                    Ok((
+                        row.get::<_, Option<String>>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, Option<String>>(3)?,
+                        row.get::<_, Option<String>>(4)?,
+                    ))
+                },
+            )
+            .optional()?;
+
+        let mut state = NetState::new();
+        if let Some((name, cidr, mode, server, local_address)) = network {
+            state.name = name;
+            state.cidr = cidr;
+            state.mode = match mode.as_str() {
+                "relay" => DhcpMode::Relay,
+                _ => DhcpMode::Managed,
+            };
+            state.relay = server.map(|server| Relay {
+                server,
+                local_address,
+            });
+        }
+
+        // guest settings are kept as json, keyed by their netstate field
+        let option = |key: &str| {
+            conn.query_row(
+                "SELECT value FROM options WHERE key = ?1",
+                params![key],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()
+        };
+        if let Some(v) = option("dns")? {
+            state.dns = Some(serde_json::from_str(&v)?);
+        }
+        if let Some(v) = option("ntp")? {
+            state.ntp = Some(serde_json::from_str(&v)?);
+        }
+        if let Some(v) = option("gateway")? {
+            state.gateway = Some(v);
+        }
+        if let Some(v) = option("reserved")? {
+            state.reserved = serde_json::from_str(&v)?;
+        }
+        if let Some(v) = option("warnUtilization")? {
+            state.warn_utilization = Some(serde_json::from_str(&v)?);
+        }
+        if let Some(v) = option("released")? {
+            state.released = serde_json::from_str(&v)?;
+        }
+
+        let mut stmt = conn
+            .prepare("SELECT mac, ip, hostname, allocated, leased, leased_at, expires FROM reservations ORDER BY id")?;
+        let rows = stmt.query_map(params![], |row| {
+            Ok(NetInfo {
+                mac: row.get(0)?,
+                ip: row.get(1)?,
+                hostname: row.get(2)?,
+                allocated: row.get(3)?,
+                leased: row.get(4)?,
+                leased_at: row.get(5)?,
+                expires: row.get(6)?,
+            })
+        })?;
+        for r in rows {
+            state.reservations.push(r?);
+        }
+
+        Ok(state)
+    }
+
+    fn write(&self, state: &NetState) -> Result<(), Error> {
+        let mut conn = self.connect()?;
+        let tx = conn.transaction()?;
+
+        let mode = match state.mode {
+            DhcpMode::Managed => "managed",
+            DhcpMode::Relay => "relay",
+        };
+        tx.execute(
+            "INSERT OR REPLACE INTO network (id, name, cidr, mode, relay_server, relay_local_address)
+             VALUES (0, ?1, ?2, ?3, ?4, ?5)",
+            params![
+                state.name,
+                state.cidr,
+                mode,
+                state.relay.as_ref().map(|r| r.server.clone()),
+                state.relay.as_ref().and_then(|r| r.local_address.clone()),
+            ],
+        )?;
+
+        tx.execute("DELETE FROM options", params![])?;
+        if let Some(dns) = &state.dns {
+            tx.execute(
+                "INSERT INTO options (key, value) VALUES ('dns', ?1)",
+                params![serde_json::to_string(dns)?],
+            )?;
+        }
+        if let Some(ntp) = &state.ntp {
+            tx.execute(
+                "INSERT INTO options (key, value) VALUES ('ntp', ?1)",
+                params![serde_json::to_string(ntp)?],
+            )?;
+        }
+        if let Some(gw) = &state.gateway {
+            tx.execute(
+                "INSERT INTO options (key, value) VALUES ('gateway', ?1)",
+                params![gw],
+            )?;
+        }
+        if !state.reserved.is_empty() {
+            tx.execute(
+                "INSERT INTO options (key, value) VALUES ('reserved', ?1)",
+                params![serde_json::to_string(&state.reserved)?],
+            )?;
+        }
+        if let Some(w) = state.warn_utilization {
+            tx.execute(
+                "INSERT INTO options (key, value) VALUES ('warnUtilization', ?1)",
+                params![w.to_string()],
+            )?;
+        }
+        if !state.released.is_empty() {
+            tx.execute(
+                "INSERT INTO options (key, value) VALUES ('released', ?1)",
+                params![serde_json::to_string(&state.released)?],
+            )?;
+        }
+
+        tx.execute("DELETE FROM reservations", params![])?;
+        for r in &state.reservations {
+            tx.execute(
+                "INSERT INTO reservations (mac, ip, hostname, allocated, leased, leased_at, expires)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    r.mac,
+                    r.ip,
+                    r.hostname,
+                    r.allocated,
+                    r.leased,
+                    r.leased_at,
+                    r.expires
+                ],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_sqlite_roundtrip() {
+        let dir = TestDir::new("netstate");
+        let path = dir.join("netstate.db");
+        let b = SqliteBackend::new(&path);
+
+        let mut state = NetState::new();
+        state.name = Some("lab".into());
+        state.mode = DhcpMode::Relay;
+        state.relay = Some(Relay {
+            server: "10.0.0.5".into(),
+            local_address: None,
+        });
+        state.ntp = Some(vec!["10.0.0.123".into()]);
+        state.reservations.push(NetInfo {
+            mac: "00:16:3e:00:00:01".into(),
+            ip: String::new(),
+            hostname: "web1".into(),
+            allocated: true,
+            leased: false,
+            leased_at: None,
+            expires: None,
+        });
+        b.save(&state).unwrap();
+        assert_eq!(b.load().unwrap(), state);
+
+        state.reservations[0].ip = "172.20.0.2".into();
+        state.reservations[0].leased = true;
+        state.reservations[0].expires = Some(1700000000);
+        b.save(&state).unwrap();
+        assert_eq!(b.load().unwrap(), state);
+    }
+}
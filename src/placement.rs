@@ -0,0 +1,687 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Placement of machines onto hosts: the local host and the remote libvirt
+// hosts of the config, with the host each machine landed on remembered so
+// later calls for it go to the right connection.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::config;
+use crate::error::Error;
+use crate::freeze;
+use crate::host::{HostAgent, Topology};
+use crate::hostpower;
+use crate::lockfile::LockFile;
+use crate::models::{self, Machine, Selector};
+use crate::store;
+
+const ASSIGNMENTS_FILE: &str = "placements.yaml";
+
+// what a host has and what is already committed on it
+#[derive(Debug, Clone)]
+pub struct HostState {
+    pub name: String,
+    // the host bigiron runs on, which alone has the devices and sockets it
+    // sets up for a machine
+    pub local: bool,
+    pub cpus: u32,
+    pub memory: u64,
+    pub used_memory: u64,
+    pub topology: Topology,
+    // names and labels of the machines on it, for the affinities
+    pub machines: Vec<(String, BTreeMap<String, String>)>,
+}
+
+fn labels(machines: &[Machine]) -> Vec<(String, BTreeMap<String, String>)> {
+    machines
+        .iter()
+        .map(|m| (m.name.clone(), m.labels.clone()))
+        .collect()
+}
+
+impl HostState {
+    // the local host, with the memory of `machines` counted as used
+    pub fn local(machines: &[Machine]) -> Result<Self, Error> {
+        let mut agent = HostAgent::new();
+        let (cpus, memory) = agent.capacity();
+        let name = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|h| h.trim().to_string())
+            .unwrap_or_else(|_| "localhost".to_string());
+
+        let mut used_memory = 0;
+        for m in machines {
+            used_memory += m.spec.memory.bytes();
+        }
+
+        Ok(Self {
+            name,
+            local: true,
+            cpus,
+            memory,
+            used_memory,
+            topology: agent.topology()?,
+            machines: labels(machines),
+        })
+    }
+
+    // a configured remote host, with the memory of `machines` counted as used.
+    // Its numa layout and hugepages are not known, so machines asking for
+    // either only fit on the local host.
+    pub fn remote(host: &config::HostConfig, machines: &[Machine]) -> Result<Self, Error> {
+        let mut used_memory = 0;
+        for m in machines {
+            used_memory += m.spec.memory.bytes();
+        }
+        Ok(Self {
+            name: host.name.clone(),
+            local: false,
+            cpus: host.cpus,
+            memory: host.memory.bytes(),
+            used_memory,
+            topology: Topology::default(),
+            machines: labels(machines),
+        })
+    }
+}
+
+// every awake host a machine can go to, the local one first, each with the
+// memory of the given machines placed on it counted as used
+pub fn hosts(machines: &[Machine]) -> Result<Vec<HostState>, Error> {
+    let cfg = config::load()?;
+    let assigned = Assignments::default().load()?;
+    let on = |host: Option<&str>| -> Vec<Machine> {
+        machines
+            .iter()
+            .filter(|m| assigned.get(&m.name).map(|h| h.as_str()) == host)
+            .cloned()
+            .collect()
+    };
+
+    // powered down hosts take no machines until woken
+    let asleep = hostpower::Sleeping::default().load()?;
+    let mut hosts = vec![HostState::local(&on(None))?];
+    for h in cfg.hosts.iter().filter(|h| !asleep.contains_key(&h.name)) {
+        hosts.push(HostState::remote(h, &on(Some(&h.name)))?);
+    }
+    Ok(hosts)
+}
+
+// libvirt on this host: the system instance for root, the user's own
+// session otherwise
+pub fn local_uri() -> &'static str {
+    match store::unprivileged() {
+        true => "qemu:///session",
+        false => "",
+    }
+}
+
+// libvirt uri of the host a machine was placed on, local_uri() for the
+// local host
+pub fn uri(machine: &str) -> Result<String, Error> {
+    let host = match Assignments::default().host_of(machine)? {
+        Some(h) => h,
+        None => return Ok(local_uri().to_string()),
+    };
+    match config::load()?.host(&host) {
+        Some(h) => Ok(h.uri.clone()),
+        None => Err(format!(
+            "machine {} is placed on host {}, which is no longer configured",
+            machine, host
+        )
+        .into()),
+    }
+}
+
+// Which remote host each machine runs on. Machines on the local host have
+// no entry, so an unconfigured setup never writes this file.
+pub struct Assignments {
+    path: PathBuf,
+}
+
+impl Default for Assignments {
+    fn default() -> Self {
+        Self::new(store::data_dir().join(ASSIGNMENTS_FILE))
+    }
+}
+
+impl Assignments {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    fn lock(&self) -> LockFile {
+        LockFile::new(self.path.with_extension("lock"))
+    }
+
+    pub fn load(&self) -> Result<BTreeMap<String, String>, Error> {
+        if !self.path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        let buf = std::fs::read_to_string(&self.path)?;
+        Ok(serde_yaml::from_str(&buf)?)
+    }
+
+    fn save(&self, hosts: &BTreeMap<String, String>) -> Result<(), Error> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&self.path, serde_yaml::to_string(hosts)?)?;
+        Ok(())
+    }
+
+    pub fn host_of(&self, machine: &str) -> Result<Option<String>, Error> {
+        Ok(self.load()?.remove(machine))
+    }
+
+    pub fn rename(&self, machine: &str, new: &str) -> Result<(), Error> {
+        let lf = self.lock();
+        let _lock = lf.acquire();
+
+        let mut hosts = self.load()?;
+        if let Some(h) = hosts.remove(machine) {
+            hosts.insert(new.to_string(), h);
+            self.save(&hosts)?;
+        }
+        Ok(())
+    }
+
+    // record the host a machine was placed on, `None` for the local host
+    pub fn assign(&self, machine: &str, host: Option<&str>) -> Result<(), Error> {
+        let lf = self.lock();
+        let _lock = lf.acquire();
+
+        let mut hosts = self.load()?;
+        let changed = match host {
+            Some(h) => hosts.insert(machine.to_string(), h.to_string()).as_deref() != Some(h),
+            None => hosts.remove(machine).is_some(),
+        };
+        if changed {
+            self.save(&hosts)?;
+        }
+        Ok(())
+    }
+}
+
+// outcome of one check against one host, Err holds the rejection reason
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub result: Result<String, String>,
+}
+
+#[derive(Debug)]
+pub struct Decision {
+    pub machine: String,
+    // host the machine lands on, if any accepted it
+    pub host: Option<String>,
+    pub candidates: Vec<(String, Vec<Check>)>,
+}
+
+// other machines on the host matching a selector
+fn matching<'a>(machine: &Machine, host: &'a HostState, sel: &Selector) -> Vec<&'a str> {
+    host.machines
+        .iter()
+        .filter(|(name, labels)| *name != machine.name && sel.matches(labels))
+        .map(|(name, _)| name.as_str())
+        .collect()
+}
+
+// the checks of the machine's placement hints, `anywhere` telling whether
+// its affinity matches a machine on any host
+fn check_hints(machine: &Machine, host: &HostState, anywhere: bool) -> Vec<Check> {
+    let hints = match &machine.spec.placement {
+        Some(p) => p,
+        None => return Vec::new(),
+    };
+    let mut checks = Vec::new();
+
+    if let Some(pinned) = &hints.host {
+        checks.push(Check {
+            name: "host",
+            result: match *pinned == host.name {
+                true => Ok(format!("pinned to {}", pinned)),
+                false => Err(format!("pinned to {}", pinned)),
+            },
+        });
+    }
+    if let Some(s) = &hints.affinity {
+        checks.push(Check {
+            name: "affinity",
+            result: Selector::parse(s)
+                .map_err(|e| e.to_string())
+                .and_then(|sel| match matching(machine, host, &sel)[..] {
+                    [] if anywhere => Err(format!("no machine matching {} here", s)),
+                    [] => Ok(format!("no machine matching {} anywhere yet", s)),
+                    ref m => Ok(format!("with {}", m.join(", "))),
+                }),
+        });
+    }
+    if let Some(s) = &hints.anti_affinity {
+        checks.push(Check {
+            name: "antiAffinity",
+            result: Selector::parse(s)
+                .map_err(|e| e.to_string())
+                .and_then(|sel| match matching(machine, host, &sel)[..] {
+                    [] => Ok(format!("no machine matching {} here", s)),
+                    ref m => Err(format!("{} match {}", m.join(", "), s)),
+                }),
+        });
+    }
+    checks
+}
+
+// whether a failed check is one of the placement hints, which unlike
+// capacity can't be overcommitted
+pub fn is_hint(check: &Check) -> bool {
+    matches!(check.name, "host" | "affinity" | "antiAffinity")
+}
+
+fn check_host(machine: &Machine, host: &HostState) -> Vec<Check> {
+    let spec = &machine.spec;
+    let mut checks = Vec::new();
+
+    // a frozen host is treated as cordoned
+    checks.push(Check {
+        name: "cordon",
+        result: freeze::check("apply", machine.project.as_deref(), false)
+            .map(|_| "not frozen".to_string())
+            .map_err(|e| e.to_string()),
+    });
+
+    let vcpus = spec.max_cpu.unwrap_or(spec.cpu).max(spec.cpu);
+    checks.push(Check {
+        name: "cpu",
+        result: if vcpus <= host.cpus {
+            Ok(format!("{} vcpus, host has {}", vcpus, host.cpus))
+        } else {
+            Err(format!("{} vcpus but host only has {}", vcpus, host.cpus))
+        },
+    });
+
+    let free = host.memory.saturating_sub(host.used_memory);
+    checks.push(Check {
+        name: "memory",
+        result: match spec.memory.bytes() <= free {
+            true => Ok(format!("{} requested, {} bytes free", spec.memory, free)),
+            false => Err(format!(
+                "{} requested but only {} bytes free",
+                spec.memory, free
+            )),
+        },
+    });
+
+    if spec.hugepages.is_some() || spec.numa.is_some() {
+        checks.push(Check {
+            name: "topology",
+            result: host
+                .topology
+                .validate(spec)
+                .map(|_| "hugepages and numa placement fit".to_string())
+                .map_err(|e| e.to_string()),
+        });
+    }
+
+    let local = local_only(spec);
+    if !local.is_empty() {
+        checks.push(Check {
+            name: "local",
+            result: match host.local {
+                true => Ok(format!("{} set up on this host", local.join(", "))),
+                false => Err(format!("{} only work on the local host", local.join(", "))),
+            },
+        });
+    }
+
+    checks
+}
+
+// Features bigiron sets up on the host it runs on: passed through pci
+// devices, consoles served from unix sockets in the machine directory and
+// the swtpm process behind an emulated tpm.
+fn local_only(spec: &models::Spec) -> Vec<&'static str> {
+    let mut r = Vec::new();
+    if spec.devices.as_ref().is_some_and(|d| !d.is_empty()) {
+        r.push("devices");
+    }
+    if spec.sol.is_some() {
+        r.push("sol");
+    }
+    if spec.crash_console == Some(true) {
+        r.push("crashConsole");
+    }
+    if spec.tpm == Some(true) {
+        r.push("tpm");
+    }
+    r
+}
+
+// Place a machine on the first host passing every check and commit its
+// memory and labels there, so later machines in the same plan see the
+// reduced capacity and honor their affinities to it.
+pub fn place(machine: &Machine, hosts: &mut [HostState]) -> Decision {
+    let mut decision = Decision {
+        machine: machine.name.clone(),
+        host: None,
+        candidates: Vec::new(),
+    };
+    let affinity = machine
+        .spec
+        .placement
+        .as_ref()
+        .and_then(|p| p.affinity.as_deref())
+        .and_then(|s| Selector::parse(s).ok());
+    let anywhere =
+        affinity.is_some_and(|sel| hosts.iter().any(|h| !matching(machine, h, &sel).is_empty()));
+
+    for host in hosts.iter_mut() {
+        let mut checks = check_host(machine, host);
+        checks.extend(check_hints(machine, host, anywhere));
+        if decision.host.is_none() && checks.iter().all(|c| c.result.is_ok()) {
+            decision.host = Some(host.name.clone());
+            host.used_memory += machine.spec.memory.bytes();
+            host.machines
+                .push((machine.name.clone(), machine.labels.clone()));
+        }
+        decision.candidates.push((host.name.clone(), checks));
+    }
+    decision
+}
+
+// a running machine that could make way for a new one
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub name: String,
+    pub priority: i32,
+    pub preemptible: bool,
+    pub memory: u64,
+}
+
+// priority of a machine and whether it can be preempted
+pub fn priority(spec: &models::Spec, sched: &config::Scheduling) -> Result<(i32, bool), Error> {
+    match &spec.priority_class {
+        Some(name) => sched.priority_class(name).map(|c| (c.value, c.preemptible)),
+        None => Ok((config::DEFAULT_PRIORITY, false)),
+    }
+}
+
+// Pick preemptible machines of a lower priority than `priority` to free at
+// least `need` bytes, lowest priority and then largest first so as few as
+// possible go. None if even all of them wouldn't be enough.
+pub fn preemption_victims(
+    need: u64,
+    priority: i32,
+    candidates: &[Candidate],
+) -> Option<Vec<String>> {
+    let mut eligible: Vec<&Candidate> = candidates
+        .iter()
+        .filter(|c| c.preemptible && c.priority < priority)
+        .collect();
+    eligible.sort_by(|a, b| a.priority.cmp(&b.priority).then(b.memory.cmp(&a.memory)));
+
+    let mut freed = 0;
+    let mut victims = Vec::new();
+    for c in eligible {
+        if freed >= need {
+            break;
+        }
+        freed += c.memory;
+        victims.push(c.name.clone());
+    }
+    match freed >= need {
+        true => Some(victims),
+        false => None,
+    }
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.host {
+            Some(h) => writeln!(f, "{} -> {}", self.machine, h)?,
+            None => writeln!(f, "{} -> unschedulable", self.machine)?,
+        }
+        for (host, checks) in &self.candidates {
+            let ok = checks.iter().all(|c| c.result.is_ok());
+            writeln!(f, "  {} ({})", host, if ok { "fits" } else { "rejected" })?;
+            for c in checks {
+                match &c.result {
+                    Ok(m) => writeln!(f, "    ok   {}: {}", c.name, m)?,
+                    Err(m) => writeln!(f, "    FAIL {}: {}", c.name, m)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_place() {
+        let host = HostState {
+            name: "h1".into(),
+            local: false,
+            cpus: 4,
+            memory: 8 * 1024 * 1024 * 1024,
+            used_memory: 0,
+            topology: Topology::default(),
+            machines: Vec::new(),
+        };
+        let mut hosts = vec![
+            host.clone(),
+            HostState {
+                name: "h2".into(),
+                ..host
+            },
+        ];
+
+        let mut m: Machine = serde_yaml::from_str(
+            "
+            name: web1
+            status: null
+            spec:
+              cpu: 2
+              memory: 6Gi
+              image:
+                url: file:///images/jammy.qcow2
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(place(&m, &mut hosts).host.as_deref(), Some("h1"));
+        // h1 no longer has room for a second one
+        m.name = "web2".into();
+        let d = place(&m, &mut hosts);
+        assert_eq!(d.host.as_deref(), Some("h2"));
+        assert!(d.to_string().contains("FAIL memory"));
+
+        m.spec.cpu = 8;
+        assert!(place(&m, &mut hosts).host.is_none());
+    }
+
+    #[test]
+    fn test_place_hints() {
+        let host = HostState {
+            name: "h1".into(),
+            local: false,
+            cpus: 4,
+            memory: 64 * 1024 * 1024 * 1024,
+            used_memory: 0,
+            topology: Topology::default(),
+            machines: Vec::new(),
+        };
+        let mut hosts = vec![
+            host.clone(),
+            HostState {
+                name: "h2".into(),
+                ..host
+            },
+        ];
+        let machine = |name: &str, placement: &str| -> Machine {
+            serde_yaml::from_str(&format!(
+                "
+                name: {}
+                labels:
+                  cluster: etcd
+                status: null
+                spec:
+                  cpu: 1
+                  memory: 1Gi
+                  image:
+                    url: file:///images/jammy.qcow2
+                  placement: {}
+                ",
+                name, placement
+            ))
+            .unwrap()
+        };
+
+        // members of a cluster spread out until no host is left
+        let spread = "{antiAffinity: cluster=etcd}";
+        assert_eq!(
+            place(&machine("etcd1", spread), &mut hosts).host.as_deref(),
+            Some("h1")
+        );
+        assert_eq!(
+            place(&machine("etcd2", spread), &mut hosts).host.as_deref(),
+            Some("h2")
+        );
+        let d = place(&machine("etcd3", spread), &mut hosts);
+        assert!(d.host.is_none());
+        assert!(d
+            .to_string()
+            .contains("FAIL antiAffinity: etcd1 match cluster=etcd"));
+
+        let d = place(&machine("etcd3", "{host: h2}"), &mut hosts);
+        assert_eq!(d.host.as_deref(), Some("h2"));
+        assert!(d.candidates[0]
+            .1
+            .iter()
+            .any(|c| c.name == "host" && c.result.is_err()));
+
+        // with the machines it has an affinity to, anywhere for the first
+        let near = "{affinity: cluster=etcd}";
+        hosts[0].machines.clear();
+        assert_eq!(
+            place(&machine("web1", near), &mut hosts).host.as_deref(),
+            Some("h2")
+        );
+        hosts[1].machines.clear();
+        assert_eq!(
+            place(&machine("web2", near), &mut hosts).host.as_deref(),
+            Some("h1")
+        );
+
+        assert!(serde_yaml::from_str::<models::Placement>("affinity: 'a b'").is_err());
+    }
+
+    #[test]
+    fn test_place_local() {
+        let host = HostState {
+            name: "h1".into(),
+            local: false,
+            cpus: 4,
+            memory: 8 * 1024 * 1024 * 1024,
+            used_memory: 0,
+            topology: Topology::default(),
+            machines: Vec::new(),
+        };
+        let mut hosts = vec![
+            host.clone(),
+            HostState {
+                name: "h2".into(),
+                local: true,
+                ..host
+            },
+        ];
+        let m: Machine = serde_yaml::from_str(
+            "
+            name: web1
+            status: null
+            spec:
+              cpu: 1
+              memory: 1Gi
+              image:
+                url: file:///images/jammy.qcow2
+              crashConsole: true
+            ",
+        )
+        .unwrap();
+
+        let d = place(&m, &mut hosts);
+        assert_eq!(d.host.as_deref(), Some("h2"));
+        assert!(d
+            .to_string()
+            .contains("FAIL local: crashConsole only work on the local host"));
+    }
+
+    #[test]
+    fn test_assignments() {
+        let dir = TestDir::new("placements");
+        let path = dir.join("placements.yaml");
+        let a = Assignments::new(&path);
+
+        // local machines leave no trace
+        a.assign("web1", None).unwrap();
+        assert!(!path.exists());
+        assert_eq!(a.host_of("web1").unwrap(), None);
+
+        a.assign("web1", Some("hv2")).unwrap();
+        a.assign("web2", Some("hv3")).unwrap();
+        assert_eq!(a.host_of("web1").unwrap().as_deref(), Some("hv2"));
+        a.assign("web1", None).unwrap();
+        assert_eq!(a.host_of("web1").unwrap(), None);
+        assert_eq!(a.load().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_preemption_victims() {
+        let gib = 1024 * 1024 * 1024;
+        let c = |name: &str, priority, preemptible, memory| Candidate {
+            name: name.into(),
+            priority,
+            preemptible,
+            memory,
+        };
+        let candidates = vec![
+            c("ci1", 0, true, 2 * gib),
+            c("ci2", 0, true, 4 * gib),
+            c("dev1", 100, false, 8 * gib),
+            c("batch1", 50, true, 8 * gib),
+        ];
+
+        assert_eq!(preemption_victims(0, 100, &candidates), Some(vec![]));
+        assert_eq!(
+            preemption_victims(3 * gib, 100, &candidates),
+            Some(vec!["ci2".to_string()])
+        );
+        assert_eq!(
+            preemption_victims(10 * gib, 100, &candidates),
+            Some(vec!["ci2".into(), "ci1".into(), "batch1".into()])
+        );
+        // nothing below its own priority
+        assert_eq!(preemption_victims(gib, 0, &candidates), None);
+        assert_eq!(preemption_victims(20 * gib, 100, &candidates), None);
+    }
+}
@@ -0,0 +1,173 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Registry of host tcp ports handed out to machines, so services started
+// for different machines never fight over the same port.
+
+use std::collections::BTreeMap;
+use std::net::TcpListener;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::lockfile::LockFile;
+use crate::store;
+
+const STATE_FILE: &str = "ports.yaml";
+
+// automatically allocated ports come from here
+pub const RANGE: Range<u16> = 7000..8000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Registration {
+    pub owner: String,
+    pub service: String,
+}
+
+pub struct Registry {
+    path: PathBuf,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new(store::data_dir().join(STATE_FILE))
+    }
+}
+
+impl Registry {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    fn lock(&self) -> LockFile {
+        LockFile::new(self.path.with_extension("lock"))
+    }
+
+    fn load(&self) -> Result<BTreeMap<u16, Registration>, Error> {
+        if !self.path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        let buf = std::fs::read_to_string(&self.path)?;
+        Ok(serde_yaml::from_str(&buf)?)
+    }
+
+    fn save(&self, ports: &BTreeMap<u16, Registration>) -> Result<(), Error> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&self.path, serde_yaml::to_string(ports)?)?;
+        Ok(())
+    }
+
+    // Register a port for an owner's service, the fixed port if given or
+    // else the first free one in RANGE. Registering again returns the port
+    // already held.
+    pub fn reserve(&self, owner: &str, service: &str, fixed: Option<u16>) -> Result<u16, Error> {
+        let lf = self.lock();
+        let _lock = lf.acquire();
+
+        let mut ports = self.load()?;
+        let reg = Registration {
+            owner: owner.to_string(),
+            service: service.to_string(),
+        };
+        if let Some((port, _)) = ports.iter().find(|(_, r)| **r == reg) {
+            if fixed.is_none() || fixed == Some(*port) {
+                return Ok(*port);
+            }
+        }
+        ports.retain(|_, r| *r != reg);
+
+        let port = match fixed {
+            Some(p) => match ports.get(&p) {
+                Some(r) => {
+                    return Err(format!(
+                        "port {} is already used by {} of {}",
+                        p, r.service, r.owner
+                    )
+                    .into())
+                }
+                None => p,
+            },
+            None => RANGE
+                .clone()
+                .find(|p| !ports.contains_key(p) && TcpListener::bind(("0.0.0.0", *p)).is_ok())
+                .ok_or("no free ports left to allocate")?,
+        };
+        ports.insert(port, reg);
+        self.save(&ports)?;
+        Ok(port)
+    }
+
+    pub fn lookup(&self, owner: &str, service: &str) -> Result<Option<u16>, Error> {
+        Ok(self
+            .load()?
+            .into_iter()
+            .find(|(_, r)| r.owner == owner && r.service == service)
+            .map(|(p, _)| p))
+    }
+
+    // drop every port held by an owner
+    pub fn release(&self, owner: &str) -> Result<(), Error> {
+        let lf = self.lock();
+        let _lock = lf.acquire();
+
+        let mut ports = self.load()?;
+        let before = ports.len();
+        ports.retain(|_, r| r.owner != owner);
+        if ports.len() != before {
+            self.save(&ports)?;
+        }
+        Ok(())
+    }
+
+    pub fn list(&self) -> Result<BTreeMap<u16, Registration>, Error> {
+        self.load()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_registry() {
+        let dir = TestDir::new("ports");
+        let path = dir.join("ports.yaml");
+        let r = Registry::new(&path);
+
+        let a = r.reserve("web1", "sol", None).unwrap();
+        assert!(RANGE.contains(&a));
+        assert_eq!(r.reserve("web1", "sol", None).unwrap(), a);
+        let b = r.reserve("web2", "sol", None).unwrap();
+        assert_ne!(a, b);
+
+        assert!(r.reserve("web3", "sol", Some(a)).is_err());
+        assert_eq!(r.reserve("web3", "sol", Some(6999)).unwrap(), 6999);
+        assert_eq!(r.lookup("web3", "sol").unwrap(), Some(6999));
+
+        r.release("web1").unwrap();
+        assert_eq!(r.lookup("web1", "sol").unwrap(), None);
+        assert_eq!(r.reserve("web3", "sol", Some(a)).unwrap(), a);
+        assert_eq!(r.list().unwrap().len(), 2);
+    }
+}
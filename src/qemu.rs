@@ -17,81 +17,631 @@
 
 use std::fs::File;
 use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
 use std::os::unix::net::UnixStream;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-
-use std::os::unix::io::AsRawFd;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
 
 use fork::Fork;
 use serde_json::{json, Value};
-use tracing::{debug, info, trace};
+use tracing::{debug, info, trace, warn};
 
+pub mod agent;
+pub mod probe;
 mod qmp;
+pub mod tap;
 
 use crate::error::Error;
+use crate::host::cgroup;
+use crate::imagerepo;
+use crate::machinelog;
+use crate::models::{
+    parse_cpu_feature, BootDevice, Graphics, GraphicsKind, Share, ShareDriver, Spec,
+};
+use crate::network;
 
 pub struct Image {
     pub path: PathBuf,
 }
 
+impl Image {
+    fn info(&self) -> Result<String, Error> {
+        let out = Command::new("/usr/bin/qemu-img")
+            .arg("info")
+            .arg("-U")
+            .arg("--output=json")
+            .arg(&self.path)
+            .output()?;
+        if !out.status.success() {
+            return Err(format!("failed to read image info of {}", self.path.display()).into());
+        }
+        Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+    }
+
+    // format as detected by qemu-img, raw for anything it doesn't recognize
+    pub fn format(&self) -> Result<String, Error> {
+        let v: Value = serde_json::from_str(&self.info()?)?;
+        v.get("format")
+            .and_then(|f| f.as_str())
+            .map(|f| f.to_string())
+            .ok_or_else(|| format!("no format for {}", self.path.display()).into())
+    }
+
+    // write a qcow2 copy of the image to `dest`
+    pub fn convert_to_qcow2(&self, format: &str, dest: &Path) -> Result<(), Error> {
+        let mut cmd = Command::new("/usr/bin/qemu-img");
+        cmd.arg("convert")
+            .arg("-q")
+            .arg("-f")
+            .arg(format)
+            .arg("-O")
+            .arg("qcow2")
+            .arg(&self.path)
+            .arg(dest);
+        debug!("Running: {:?}", cmd);
+        if !cmd.status()?.success() {
+            return Err(format!("failed to convert {} to qcow2", self.path.display()).into());
+        }
+        Ok(())
+    }
+
+    // whether the guest sees the same data in both images
+    pub fn same_content(&self, other: &Path) -> Result<bool, Error> {
+        let status = Command::new("/usr/bin/qemu-img")
+            .arg("compare")
+            .arg("-q")
+            .arg("-U")
+            .arg(&self.path)
+            .arg(other)
+            .status()?;
+        match status.code() {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            _ => Err(format!(
+                "failed to compare {} with {}",
+                self.path.display(),
+                other.display()
+            )
+            .into()),
+        }
+    }
+
+    // Copy everything the image reads from its backing file into it and
+    // drop the backing file. The image must not be in use.
+    pub fn flatten(&self) -> Result<(), Error> {
+        let mut cmd = Command::new("/usr/bin/qemu-img");
+        cmd.arg("rebase")
+            .arg("-q")
+            .arg("-f")
+            .arg("qcow2")
+            .arg("-b")
+            .arg("")
+            .arg(&self.path);
+        debug!("Running: {:?}", cmd);
+        if !cmd.status()?.success() {
+            return Err(format!("failed to flatten {}", self.path.display()).into());
+        }
+        Ok(())
+    }
+
+    // Point the image at a different copy of its backing file. The data is
+    // taken to be the same and not compared.
+    pub fn rebase(&self, backing: &Path, format: &str) -> Result<(), Error> {
+        let mut cmd = Command::new("/usr/bin/qemu-img");
+        cmd.arg("rebase")
+            .arg("-q")
+            .arg("-u")
+            .arg("-f")
+            .arg("qcow2")
+            .arg("-F")
+            .arg(format)
+            .arg("-b")
+            .arg(backing)
+            .arg(&self.path);
+        debug!("Running: {:?}", cmd);
+        if !cmd.status()?.success() {
+            return Err(format!("failed to rebase {}", self.path.display()).into());
+        }
+        Ok(())
+    }
+
+    // names of the internal snapshots in a qcow2 image
+    pub fn snapshots(&self) -> Result<Vec<String>, Error> {
+        parse_snapshot_names(&self.info()?)
+    }
+
+    // When a snapshot was taken as <secs>.<nsecs>, None if there is no such
+    // snapshot. A snapshot deleted and taken again under the same name gets
+    // a new one.
+    pub fn snapshot_taken(&self, snapshot: &str) -> Result<Option<String>, Error> {
+        parse_snapshot_taken(&self.info()?, snapshot)
+    }
+
+    // the file the image is layered on, None for standalone images
+    pub fn backing_file(&self) -> Result<Option<PathBuf>, Error> {
+        let v: Value = serde_json::from_str(&self.info()?)?;
+        Ok(v.get("full-backing-filename")
+            .or_else(|| v.get("backing-filename"))
+            .and_then(|b| b.as_str())
+            .map(PathBuf::from))
+    }
+
+    // copy the state of an internal qcow2 snapshot out into a standalone image
+    pub fn export_snapshot(&self, snapshot: &str, dest: &Path) -> Result<(), Error> {
+        let mut cmd = Command::new("/usr/bin/qemu-img");
+        cmd.arg("convert")
+            .arg("-q")
+            // the source machine may be running, the snapshot itself is immutable
+            .arg("-U")
+            .arg("-O")
+            .arg("qcow2")
+            .arg("-l")
+            .arg(format!("snapshot.name={}", snapshot))
+            .arg(&self.path)
+            .arg(dest);
+        debug!("Running: {:?}", cmd);
+        if !cmd.status()?.success() {
+            return Err(format!("failed to export snapshot '{}'", snapshot).into());
+        }
+        Ok(())
+    }
+
+    // size of the disk as the guest sees it
+    pub fn virtual_size(&self) -> Result<u64, Error> {
+        let v: Value = serde_json::from_str(&self.info()?)?;
+        v.get("virtual-size")
+            .and_then(|s| s.as_u64())
+            .ok_or_else(|| format!("no virtual size for {}", self.path.display()).into())
+    }
+
+    pub fn delete_snapshot(&self, snapshot: &str) -> Result<(), Error> {
+        let mut cmd = Command::new("/usr/bin/qemu-img");
+        cmd.arg("snapshot").arg("-d").arg(snapshot).arg(&self.path);
+        debug!("Running: {:?}", cmd);
+        if !cmd.status()?.success() {
+            return Err(format!(
+                "failed to delete snapshot '{}' of {}",
+                snapshot,
+                self.path.display()
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    // Go back to an internal snapshot, dropping everything written since.
+    // The image must not be in use.
+    pub fn revert(&self, snapshot: &str) -> Result<(), Error> {
+        let mut cmd = Command::new("/usr/bin/qemu-img");
+        cmd.arg("snapshot").arg("-a").arg(snapshot).arg(&self.path);
+        debug!("Running: {:?}", cmd);
+        if !cmd.status()?.success() {
+            return Err(format!(
+                "failed to revert {} to snapshot '{}'",
+                self.path.display(),
+                snapshot
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    // revert to `snapshot` if the image has it, true if it did
+    pub fn revert_on_boot(&self, snapshot: &str) -> Result<bool, Error> {
+        if !self.snapshots()?.iter().any(|s| s == snapshot) {
+            // not taken yet, e.g. the first boot of a new machine
+            warn!(
+                "{} has no snapshot '{}' to revert to, booting as is",
+                self.path.display(),
+                snapshot
+            );
+            return Ok(false);
+        }
+        info!(
+            "reverting {} to snapshot '{}'",
+            self.path.display(),
+            snapshot
+        );
+        self.revert(snapshot)?;
+        Ok(true)
+    }
+}
+
+fn parse_snapshot_names(info: &str) -> Result<Vec<String>, Error> {
+    let v: Value = serde_json::from_str(info)?;
+    Ok(v.get("snapshots")
+        .and_then(|s| s.as_array())
+        .map(|s| {
+            s.iter()
+                .filter_map(|s| s.get("name").and_then(|n| n.as_str()))
+                .map(|n| n.to_string())
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+fn parse_snapshot_taken(info: &str, snapshot: &str) -> Result<Option<String>, Error> {
+    let v: Value = serde_json::from_str(info)?;
+    Ok(v.get("snapshots")
+        .and_then(|s| s.as_array())
+        .and_then(|s| {
+            s.iter()
+                .find(|s| s.get("name").and_then(|n| n.as_str()) == Some(snapshot))
+        })
+        .map(|s| {
+            let field = |f| s.get(f).and_then(|d| d.as_u64()).unwrap_or(0);
+            format!("{}.{:09}", field("date-sec"), field("date-nsec"))
+        }))
+}
+
+// virtual hardware of a guest
+#[derive(Debug, Clone, Default)]
+pub struct Hardware {
+    // guest architecture, the host's when unset
+    pub arch: Option<String>,
+    // found by probe, the default kvm binary until then
+    pub emulator: Option<PathBuf>,
+    pub cpus: u32,
+    pub memory_mb: u64,
+    pub machine_type: Option<String>,
+    pub cpu_model: Option<String>,
+    pub cpu_features: Vec<String>,
+    pub graphics: Option<Graphics>,
+    // hugetlbfs mount to back guest memory with
+    pub mem_path: Option<PathBuf>,
+    pub shares: Vec<Share>,
+    // utc, localtime or a zone name, see models::Spec
+    pub timezone: Option<String>,
+    // disk throttling, operations and bytes per second
+    pub disk_iops: Option<u64>,
+    pub disk_bps: Option<u64>,
+    // image in the cdrom drive, which is there empty otherwise
+    pub cdrom: Option<PathBuf>,
+    pub boot_order: Vec<BootDevice>,
+    pub rng: bool,
+}
+
+impl Hardware {
+    // the parts of a machine spec that plain qemu can render
+    pub fn from_spec(spec: &Spec) -> Result<Self, Error> {
+        Ok(Self {
+            arch: spec.arch.as_deref().map(imagerepo::normalize_arch),
+            emulator: None,
+            cpus: spec.cpu,
+            memory_mb: spec.memory.bytes() / (1024 * 1024),
+            machine_type: spec.machine_type.clone(),
+            cpu_model: spec.cpu_model.clone(),
+            cpu_features: spec
+                .cpu_features
+                .iter()
+                .flatten()
+                .cloned()
+                .chain(crate::host::nested_cpu_feature(spec)?.map(String::from))
+                .collect(),
+            graphics: spec.graphics.clone(),
+            // pages come from the default hugetlbfs mount, whose page
+            // size has to match the one asked for
+            mem_path: spec
+                .hugepages
+                .as_ref()
+                .map(|_| PathBuf::from(HUGEPAGES_PATH)),
+            shares: spec.shares.clone().unwrap_or_default(),
+            timezone: spec.timezone.clone(),
+            disk_iops: spec.qos.as_ref().and_then(|q| q.disk_iops),
+            disk_bps: spec
+                .qos
+                .as_ref()
+                .and_then(|q| q.disk_bandwidth)
+                .map(|s| s.bytes()),
+            cdrom: None,
+            boot_order: spec.boot_devices(),
+            rng: spec.rng.unwrap_or(true),
+        })
+    }
+
+    // ,bootindex= for a device the firmware may boot from, strict boot
+    // leaves out the others
+    fn bootindex(&self, device: BootDevice) -> String {
+        match self.boot_order.iter().position(|d| *d == device) {
+            Some(i) => format!(",bootindex={}", i + 1),
+            None => String::new(),
+        }
+    }
+
+    // -drive and -device arguments of the cdrom drive, where libvirt would
+    // put it: hdc on the piix ide bus, sda on q35's ahci and on a
+    // virtio-scsi controller on s390x, which has no ide
+    fn cdrom_args(&self) -> Vec<String> {
+        let media = match &self.cdrom {
+            Some(p) => format!(",file={},format=raw", p.display()),
+            None => String::new(),
+        };
+        let (device, bus) = match (self.is_s390x(), self.is_q35()) {
+            (true, _) => ("scsi-cd", "scsi0.0"),
+            (false, true) => ("ide-cd", "ide.0"),
+            (false, false) => ("ide-cd", "ide.1"),
+        };
+        let mut args = Vec::new();
+        // qemu creates devices in order, the controller has to come first
+        if self.is_s390x() {
+            args.push("-device".into());
+            args.push("virtio-scsi-ccw,id=scsi0".into());
+        }
+        args.extend([
+            "-drive".into(),
+            format!(
+                "if=none,id=drive-{},media=cdrom,readonly=on{}",
+                CDROM_ID, media
+            ),
+            "-device".into(),
+            format!(
+                "{},bus={},drive=drive-{},id={}{}",
+                device,
+                bus,
+                CDROM_ID,
+                CDROM_ID,
+                self.bootindex(BootDevice::Cdrom)
+            ),
+        ]);
+        args
+    }
+
+    // -device for the serial console, the sclp console on s390x
+    fn console_args(&self) -> [&str; 2] {
+        match self.is_s390x() {
+            true => ["-device", "sclpconsole,chardev=charserial0,id=serial0"],
+            false => ["-device", "isa-serial,chardev=charserial0,id=serial0"],
+        }
+    }
+
+    // the display adapter for graphics, s390x has no vga
+    fn video_args<'a>(&self, vga: &'a str) -> [&'a str; 2] {
+        match self.is_s390x() {
+            true => ["-device", "virtio-gpu-ccw"],
+            false => ["-vga", vga],
+        }
+    }
+
+    // Device model of a virtio device, which sits on pci except on s390x,
+    // where devices attach through channel i/o.
+    fn virtio(&self, device: &str) -> String {
+        match self.is_s390x() {
+            true => format!("{}-ccw", device),
+            false => format!("{}-pci", device),
+        }
+    }
+
+    // ,bus= and ,addr= of a pci device in a fixed slot, nothing on s390x
+    // where qemu assigns the ccw device numbers
+    fn slot(&self, addr: &str) -> String {
+        match (self.is_s390x(), self.is_q35()) {
+            (true, _) => String::new(),
+            (false, true) => format!(",bus=pcie.0,addr={}", addr),
+            (false, false) => format!(",bus=pci.0,addr={}", addr),
+        }
+    }
+
+    // -drive options limiting the disk, empty when unlimited
+    fn throttle_opts(&self) -> String {
+        let mut opts = String::new();
+        if let Some(iops) = self.disk_iops {
+            opts.push_str(&format!(",throttling.iops-total={}", iops));
+        }
+        if let Some(bps) = self.disk_bps {
+            opts.push_str(&format!(",throttling.bps-total={}", bps));
+        }
+        opts
+    }
+
+    fn has_virtiofs(&self) -> bool {
+        self.shares
+            .iter()
+            .any(|s| s.driver == ShareDriver::Virtiofs)
+    }
+
+    // base for -rtc, and the TZ qemu runs with for zones other than the
+    // host's, since qemu only knows utc and its own local time
+    fn rtc(&self) -> (&str, Option<&str>) {
+        match self.timezone.as_deref() {
+            None | Some("utc" | "UTC") => ("utc", None),
+            Some("localtime") => ("localtime", None),
+            Some(zone) => ("localtime", Some(zone)),
+        }
+    }
+
+    fn arch(&self) -> String {
+        imagerepo::guest_arch(self.arch.as_deref())
+    }
+
+    fn is_s390x(&self) -> bool {
+        self.arch() == "s390x"
+    }
+
+    // kvm for guests of the host's architecture, others are emulated
+    fn is_native(&self) -> bool {
+        self.arch() == imagerepo::host_arch()
+    }
+
+    fn emulator(&self) -> &Path {
+        self.emulator.as_deref().unwrap_or(Path::new(EMULATOR))
+    }
+
+    fn machine_type(&self) -> &str {
+        match self.is_s390x() {
+            true => self.machine_type.as_deref().unwrap_or("s390-ccw-virtio"),
+            false => self.machine_type.as_deref().unwrap_or("pc-i440fx-3.1"),
+        }
+    }
+
+    fn is_q35(&self) -> bool {
+        let mt = self.machine_type();
+        mt == "q35" || mt.starts_with("pc-q35")
+    }
+
+    // value for -cpu, None leaves the qemu default model
+    fn cpu_arg(&self) -> Option<String> {
+        let model = match self.cpu_model.as_deref() {
+            // qemu has no host-model equivalent, both mean the host cpu
+            Some("host-passthrough") | Some("host-model") => "host",
+            Some(m) => m,
+            None if !self.cpu_features.is_empty() => "host",
+            None => return None,
+        };
+
+        let mut arg = model.to_string();
+        for flag in &self.cpu_features {
+            let (name, enabled) = parse_cpu_feature(flag);
+            arg.push_str(&format!(",{}{}", if enabled { '+' } else { '-' }, name));
+        }
+        Some(arg)
+    }
+
+    // Find the emulator for the guest and pick a machine type if the spec
+    // has none, checking the rest against what the emulator offers.
+    pub fn probe(&mut self) -> Result<(), Error> {
+        let arch = self.arch();
+        let caps = probe::probe(&arch)?;
+        let emulator = caps.emulator.display().to_string();
+        match &self.machine_type {
+            Some(mt) if !caps.has_machine(mt) => {
+                return Err(format!("machine type '{}' not supported by {}", mt, emulator).into());
+            }
+            Some(_) => {}
+            None => self.machine_type = Some(probe::machine_type(&caps, &arch)?),
+        }
+
+        match self.cpu_model.as_deref() {
+            None | Some("host-passthrough") | Some("host-model") => {}
+            Some(model) => {
+                let help = probe::help(&caps.emulator, "-cpu")?;
+                if !help.split_whitespace().any(|t| t == model) {
+                    return Err(
+                        format!("cpu model '{}' not supported by {}", model, emulator).into(),
+                    );
+                }
+            }
+        }
+
+        for device in ["virtio-blk", "virtio-net", "virtio-serial"] {
+            let device = self.virtio(device);
+            if !caps.has_device(&device) {
+                return Err(format!("{} has no {} device", emulator, device).into());
+            }
+        }
+
+        self.emulator = Some(caps.emulator);
+        Ok(())
+    }
+}
+
+pub const HUGEPAGES_PATH: &str = "/dev/hugepages";
+pub const VIRTIOFSD: &str = "/usr/libexec/virtiofsd";
+pub const EMULATOR: &str = "/usr/bin/kvm";
+// both nics join the management bridge
+const BRIDGE: &str = network::MANAGEMENT_BRIDGE;
+// qdev id of the cdrom drive, for ejecting its media
+pub const CDROM_ID: &str = "cdrom0";
+
+// how a VM's qemu last exited, e.g. "exit status: 0" or "signal: 9 (SIGKILL)"
+pub const EXIT_STATUS_FILE: &str = "exit-status";
+
 pub struct Process {
     base_dir: PathBuf,
     name: String,
-    cpus: u32,
-    memory_mb: u64,
     uuid: String,
     image: Image,
+    hw: Hardware,
 }
 
 impl Process {
-    pub fn new<P: AsRef<Path>>(
-        dir: P,
-        name: &str,
-        cpus: u32,
-        memory_mb: u64,
-        uuid: &str,
-        image: Image,
-    ) -> Self {
+    pub fn new<P: AsRef<Path>>(dir: P, name: &str, uuid: &str, image: Image, hw: Hardware) -> Self {
         let base_dir = dir.as_ref().to_path_buf();
 
         Self {
             base_dir,
             name: name.to_string(),
-            cpus,
-            memory_mb,
             uuid: uuid.into(),
             image,
+            hw,
         }
     }
 
-    fn build_cmd(&self, net_fd: i32) -> Command {
-        let emulator = "/usr/bin/kvm";
-        let mut cmd = Command::new(emulator);
+    fn build_cmd(&self, net_fd: i32, mac: &str, display_port: Option<u16>) -> Command {
+        let mut cmd = Command::new(self.hw.emulator());
 
-        let args: Vec<&str> = "-machine pc-i440fx-3.1,accel=kvm,usb=off,dump-guest-core=off \
-            -realtime mlock=off \
+        let args: Vec<&str> = "-realtime mlock=off \
             -display none \
             -no-user-config \
             -nodefaults \
-            -rtc base=utc \
             -no-shutdown \
-            -global PIIX4_PM.disable_s3=1 \
-            -global PIIX4_PM.disable_s4=1 \
             -boot strict=on \
-            -device piix3-usb-uhci,id=usb,bus=pci.0,addr=0x1.0x2 \
-            -device virtio-blk-pci,scsi=off,bus=pci.0,addr=0x2,drive=drive-virtio-disk0,id=virtio-disk0,bootindex=1,write-cache=on \
             -chardev pty,id=charserial0 \
-            -device isa-serial,chardev=charserial0,id=serial0 \
-            -device virtio-balloon-pci,id=balloon0,bus=pci.0,addr=0x3 \
             -sandbox on,obsolete=deny,elevateprivileges=deny,spawn=deny,resourcecontrol=deny \
-            -msg timestamp=on".split(" ").collect();
+            -msg timestamp=on"
+            .split(" ")
+            .collect();
+
+        // q35 has the ICH9 chipset rather than PIIX
+        let pm = match self.hw.is_q35() {
+            true => "ICH9-LPC",
+            false => "PIIX4_PM",
+        };
+
+        let (rtc_base, tz) = self.hw.rtc();
+        cmd.arg("-rtc").arg(format!("base={}", rtc_base));
+        if let Some(tz) = tz {
+            cmd.env("TZ", tz);
+        }
+
+        cmd.arg("-machine").arg(format!(
+            "{},accel={},usb=off,dump-guest-core=off",
+            self.hw.machine_type(),
+            if self.hw.is_native() { "kvm" } else { "tcg" }
+        ));
+        if let Some(cpu) = self.hw.cpu_arg() {
+            cmd.arg("-cpu").arg(cpu);
+        }
+        // the power management chipsets and usb controller are x86 only
+        if !self.hw.is_s390x() {
+            cmd.arg("-global")
+                .arg(format!("{}.disable_s3=1", pm))
+                .arg("-global")
+                .arg(format!("{}.disable_s4=1", pm));
+            if !self.hw.is_q35() {
+                cmd.arg("-device")
+                    .arg("piix3-usb-uhci,id=usb,bus=pci.0,addr=0x1.0x2");
+            }
+        }
+        cmd.args(self.hw.console_args())
+            .arg("-device")
+            .arg(format!(
+                "{},scsi=off{},drive=drive-virtio-disk0,id=virtio-disk0{},write-cache=on",
+                self.hw.virtio("virtio-blk"),
+                self.hw.slot("0x2"),
+                self.hw.bootindex(BootDevice::Disk)
+            ))
+            .arg("-device")
+            .arg(format!(
+                "{},id=balloon0{}",
+                self.hw.virtio("virtio-balloon"),
+                self.hw.slot("0x3")
+            ));
+        if self.hw.rng {
+            cmd.arg("-object")
+                .arg("rng-random,id=rng0,filename=/dev/urandom")
+                .arg("-device")
+                .arg(format!(
+                    "{},rng=rng0,id=rng0dev",
+                    self.hw.virtio("virtio-rng")
+                ));
+        }
 
         let socket_path = self.base_dir.join("monitor.sock");
         let monitor_mode = "control";
         let image_format = "qcow2";
         let pause_on_start = false;
-        let bridge_name = "br0";
 
         if pause_on_start {
             cmd.arg("-S");
@@ -111,34 +661,227 @@ impl Process {
                 monitor_mode
             ))
             .arg("-m")
-            .arg(format!("{}", self.memory_mb))
+            .arg(format!("{}", self.hw.memory_mb))
+            .args(self.mem_args())
             .arg("-smp")
             .arg(format!(
                 "{},sockets=1,cores={},threads=1",
-                self.cpus, self.cpus
+                self.hw.cpus, self.hw.cpus
             ))
             .arg("-uuid")
             .arg(self.uuid.clone())
             .arg("-drive")
             .arg(format!(
-                "file={},format={},if=none,id=drive-virtio-disk0,cache=writeback",
+                "file={},format={},if=none,id=drive-virtio-disk0,cache=writeback{}",
                 self.image.path.display(),
-                image_format
+                image_format,
+                self.hw.throttle_opts()
             ))
+            .args(self.hw.cdrom_args())
             .arg("-device")
-            .arg("virtio-net-pci,netdev=net1,mac=52:54:00:b8:9c:58")
+            .arg(format!(
+                "{},netdev=net1,mac={}",
+                self.hw.virtio("virtio-net"),
+                mac
+            ))
             .arg("-netdev")
             .arg(format!("tap,fd={},id=net1", net_fd))
             .arg("-device")
-            .arg("virtio-net-pci,netdev=net0")
+            .arg(format!("{},netdev=net0", self.hw.virtio("virtio-net")))
             .arg("-netdev")
-            .arg(format!("bridge,br={},id=net0", bridge_name));
+            .arg(format!("bridge,br={},id=net0", BRIDGE))
+            .args(self.share_args())
+            .arg("-chardev")
+            .arg(format!(
+                "socket,id=charagent,path={},server=on,wait=off",
+                self.base_dir.join("agent.sock").display()
+            ))
+            .arg("-device")
+            .arg(format!(
+                "{},id=virtio-serial0",
+                self.hw.virtio("virtio-serial")
+            ))
+            .arg("-device")
+            .arg(format!(
+                "virtserialport,chardev=charagent,name={}",
+                agent::CHANNEL
+            ));
+
+        if let (Some(g), Some(port)) = (&self.hw.graphics, display_port) {
+            if let Some(keymap) = &g.keymap {
+                cmd.arg("-k").arg(keymap);
+            }
+            match g.kind {
+                GraphicsKind::Vnc => {
+                    // vnc takes a display number offset from the base port
+                    cmd.args(self.hw.video_args("std")).arg("-vnc").arg(format!(
+                        "{}:{}",
+                        g.listen_addr(),
+                        port.saturating_sub(5900)
+                    ));
+                }
+                GraphicsKind::Spice => {
+                    cmd.args(self.hw.video_args("qxl"))
+                        .arg("-spice")
+                        .arg(format!(
+                            "port={},addr={},disable-ticketing=on",
+                            port,
+                            g.listen_addr()
+                        ));
+                }
+            }
+        }
 
-        //.arg("virtio-net-pci,netdev=nic,addr=52:54:00:b8:9c:58")
         cmd
     }
 
-    fn run(&self) {
+    fn mem_args(&self) -> Vec<String> {
+        // virtiofsd maps guest memory, which needs an explicit shared backend
+        if self.hw.has_virtiofs() {
+            let backend = match &self.hw.mem_path {
+                Some(p) => format!(
+                    "memory-backend-file,id=mem,size={}M,mem-path={},share=on,prealloc=on",
+                    self.hw.memory_mb,
+                    p.display()
+                ),
+                None => format!(
+                    "memory-backend-memfd,id=mem,size={}M,share=on",
+                    self.hw.memory_mb
+                ),
+            };
+            return vec![
+                "-object".to_string(),
+                backend,
+                "-numa".to_string(),
+                "node,memdev=mem".to_string(),
+            ];
+        }
+
+        match &self.hw.mem_path {
+            // hugepages must be reserved up front or the guest faults later
+            Some(p) => vec![
+                "-mem-path".to_string(),
+                p.display().to_string(),
+                "-mem-prealloc".to_string(),
+            ],
+            None => Vec::new(),
+        }
+    }
+
+    fn share_socket(&self, i: usize) -> PathBuf {
+        self.base_dir.join(format!("virtiofs{}.sock", i))
+    }
+
+    fn share_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for (i, share) in self.hw.shares.iter().enumerate() {
+            match share.driver {
+                ShareDriver::Virtiofs => {
+                    args.push("-chardev".to_string());
+                    args.push(format!(
+                        "socket,id=fs{},path={}",
+                        i,
+                        self.share_socket(i).display()
+                    ));
+                    args.push("-device".to_string());
+                    args.push(format!(
+                        "{},chardev=fs{},tag={}",
+                        self.hw.virtio("vhost-user-fs"),
+                        i,
+                        escape_opt(&share.tag)
+                    ));
+                }
+                ShareDriver::NineP => {
+                    args.push("-fsdev".to_string());
+                    args.push(format!(
+                        "local,id=fs{},path={},security_model=mapped-xattr{}",
+                        i,
+                        escape_opt(&share.source.display().to_string()),
+                        if share.readonly { ",readonly=on" } else { "" }
+                    ));
+                    args.push("-device".to_string());
+                    args.push(format!(
+                        "{},fsdev=fs{},mount_tag={}",
+                        self.hw.virtio("virtio-9p"),
+                        i,
+                        escape_opt(&share.tag)
+                    ));
+                }
+            }
+        }
+        args
+    }
+
+    // Start a virtiofsd per virtiofs share and wait for its socket. They
+    // exit on their own once qemu disconnects.
+    fn start_virtiofsd(&self, log: &File) -> Result<(), Error> {
+        let mut pids = Vec::new();
+        for (i, share) in self.hw.shares.iter().enumerate() {
+            if share.driver != ShareDriver::Virtiofs {
+                continue;
+            }
+            let sock = self.share_socket(i);
+            let _ = std::fs::remove_file(&sock);
+
+            let mut cmd = Command::new(VIRTIOFSD);
+            cmd.arg(format!("--socket-path={}", sock.display()))
+                .arg(format!("--shared-dir={}", share.source.display()))
+                .arg("--cache=auto");
+            if share.readonly {
+                cmd.arg("--readonly");
+            }
+            debug!("Running: {:?}", cmd);
+            let child = cmd
+                .stdin(Stdio::null())
+                .stdout(log.try_clone()?)
+                .stderr(log.try_clone()?)
+                .spawn()?;
+            pids.push(child.id().to_string());
+
+            let mut tries = 0;
+            while !sock.exists() {
+                if tries > 50 {
+                    return Err(format!("virtiofsd for {} did not start", share.tag).into());
+                }
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                tries += 1;
+            }
+        }
+        if !pids.is_empty() {
+            std::fs::write(self.base_dir.join("virtiofsd.pid"), pids.join("\n"))?;
+        }
+        Ok(())
+    }
+
+    // pick the graphics port, asking the kernel for a free one when not fixed
+    fn display_port(&self) -> Option<u16> {
+        let g = self.hw.graphics.as_ref()?;
+        match g.port {
+            Some(p) => Some(p),
+            None => TcpListener::bind((g.listen_addr(), 0))
+                .and_then(|l| l.local_addr())
+                .map(|a| a.port().max(5900))
+                .ok(),
+        }
+    }
+
+    // Move this supervisor into the VM's cgroup, so that qemu and its helpers
+    // start out in it and are limited from their first allocation.
+    fn enter_cgroup(&self, mut log: &File) -> Result<(), Error> {
+        if !cgroup::unified() {
+            let _ = writeln!(
+                log,
+                "no cgroup v2 hierarchy, running without cpu and memory limits"
+            );
+            return Ok(());
+        }
+        let cg = cgroup::VmCgroup::new(&self.uuid);
+        let memory = self.hw.memory_mb * 1024 * 1024;
+        cg.create(&cgroup::Limits::new(self.hw.cpus, memory))?;
+        cg.attach(std::process::id())
+    }
+
+    fn run(&self) -> Option<Child> {
         let log_path = self.base_dir.join("qemu.log");
         let logfile = File::options()
             .append(true)
@@ -146,22 +889,38 @@ impl Process {
             .open(log_path)
             .expect("error opening file");
 
-        use libc::O_RDWR;
-
-        let cpath = std::ffi::CString::new("/dev/tap7").expect("error building cstring");
-        let tap_fd = unsafe { libc::open(cpath.as_ptr(), O_RDWR) };
+        let tap = match tap::open(&self.base_dir, BRIDGE) {
+            Ok(tap) => tap,
+            Err(e) => {
+                let _ = writeln!(&logfile, "error creating tap device: {}", e);
+                return None;
+            }
+        };
 
         // duplicate to high int FD, to avoid conflicting with std{in,out,err} if they are closed
         // prior to the call to this function
-        let dup_fd = unsafe { libc::dup2(tap_fd, 24) };
-        let _ = unsafe { libc::close(tap_fd) };
+        let dup_fd = unsafe { libc::dup2(tap.file.as_raw_fd(), 24) };
+        drop(tap.file);
 
-        let mut cmd = self.build_cmd(dup_fd);
+        if let Err(e) = self.enter_cgroup(&logfile) {
+            let _ = writeln!(&logfile, "error setting up cgroup: {}", e);
+            return None;
+        }
+
+        if let Err(e) = self.start_virtiofsd(&logfile) {
+            let _ = writeln!(&logfile, "error starting virtiofsd: {}", e);
+            stop_virtiofsd(&self.base_dir);
+            return None;
+        }
+
+        let display_port = self.display_port();
+        let mut cmd = self.build_cmd(dup_fd, &tap.mac, display_port);
 
         cmd.stdin(Stdio::null())
             .stderr(logfile.try_clone().unwrap())
             .stdout(logfile);
 
+        let _ = std::fs::remove_file(self.base_dir.join(EXIT_STATUS_FILE));
         let child = cmd.spawn().unwrap();
         let pid = child.id();
 
@@ -171,6 +930,36 @@ impl Process {
         let pid_path = self.base_dir.join("pid");
         let mut pidfile = File::create(pid_path).expect("error opening file");
         write!(&mut pidfile, "{}", pid).unwrap();
+
+        if let (Some(g), Some(port)) = (&self.hw.graphics, display_port) {
+            let display = format!("{}://{}:{}", g.kind.as_str(), g.listen_addr(), port);
+            let _ = std::fs::write(self.base_dir.join("display"), display);
+        }
+        Some(child)
+    }
+
+    // Wait for qemu to exit, then clean up the files that would make the VM
+    // look like it still runs and record how it exited.
+    fn supervise(&self, mut child: Child) {
+        let status = match child.wait() {
+            Ok(s) => s.to_string(),
+            Err(e) => format!("unknown, error waiting for qemu: {}", e),
+        };
+        for f in ["pid", "monitor.sock", "agent.sock", "display"] {
+            let _ = std::fs::remove_file(self.base_dir.join(f));
+        }
+        stop_virtiofsd(&self.base_dir);
+        // qemu held the only fd of the tap, normally it is gone already
+        if let Err(e) = tap::remove(&self.base_dir) {
+            machinelog::record(&self.base_dir, &format!("error removing tap: {}", e));
+        }
+        if cgroup::unified() {
+            if let Err(e) = cgroup::VmCgroup::new(&self.uuid).leave() {
+                machinelog::record(&self.base_dir, &format!("error removing cgroup: {}", e));
+            }
+        }
+        let _ = std::fs::write(self.base_dir.join(EXIT_STATUS_FILE), &status);
+        machinelog::record(&self.base_dir, &format!("qemu exited, {}", status));
     }
 
     pub fn launch(&self) {
@@ -180,7 +969,10 @@ impl Process {
                 fork::chdir().unwrap();
                 fork::close_fd().unwrap();
 
-                self.run();
+                // stay around as qemu's parent to reap it
+                if let Some(child) = self.run() {
+                    self.supervise(child);
+                }
 
                 std::process::exit(0);
             }
@@ -194,39 +986,71 @@ impl Process {
     }
 }
 
+// stop any virtiofsd helpers still left over from a VM
+pub fn stop_virtiofsd<P: AsRef<Path>>(dir: P) {
+    let pidfile = dir.as_ref().join("virtiofsd.pid");
+    if let Ok(buf) = std::fs::read_to_string(&pidfile) {
+        for pid in buf.lines().filter_map(|l| l.trim().parse::<i32>().ok()) {
+            let _ = unsafe { libc::kill(pid, libc::SIGTERM) };
+        }
+        let _ = std::fs::remove_file(pidfile);
+    }
+}
+
+// how long to wait for qemu to answer a monitor command by default
+pub const MONITOR_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct Monitor {
     stream: UnixStream,
+    // bytes read past the last complete message
+    buf: Vec<u8>,
+    next_id: u64,
+    timeout: Duration,
 }
 
 impl Monitor {
     pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        let mut s = UnixStream::connect(path)?;
+        let mut mon = Self {
+            stream: UnixStream::connect(path)?,
+            buf: Vec::new(),
+            next_id: 0,
+            timeout: MONITOR_TIMEOUT,
+        };
 
-        let mut buf = [0u8; 4096];
-        let n = s.read(&mut buf)?;
-        let _greeting: qmp::Greeting = serde_json::from_slice(&mut buf[..n])?;
+        let greeting = mon.next_message(Instant::now() + mon.timeout)?;
+        let _greeting: qmp::Greeting = serde_json::from_value(greeting)?;
 
-        let caps = json!({
-            "execute": "qmp_capabilities",
-            "arguments": {},
-        });
-        write!(&mut s, "{}", caps)?;
+        mon.execute_with_args("qmp_capabilities", Some(json!({})))?;
 
-        let resp = read_response(&mut s)?;
-        if let qmp::Response::Error(err) = resp {
-            return Err(format!("Error from qemu monitor: {:?}", err.desc()).into());
-        }
+        Ok(mon)
+    }
 
-        Ok(Self { stream: s })
+    // how long commands wait for their response before giving up
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
     }
 
     fn execute(&mut self, command: &str) -> Result<qmp::Return, Error> {
-        let caps = json!({
+        self.execute_with_args(command, None)
+    }
+
+    fn execute_with_args(
+        &mut self,
+        command: &str,
+        arguments: Option<Value>,
+    ) -> Result<qmp::Return, Error> {
+        self.next_id += 1;
+        let id = format!("bigiron-{}", self.next_id);
+        let mut caps = json!({
             "execute": command,
+            "id": id,
         });
+        if let Some(args) = arguments {
+            caps["arguments"] = args;
+        }
         write!(&mut self.stream, "{}", caps)?;
 
-        let resp = read_response(&mut self.stream)?;
+        let resp = self.read_response(&id)?;
         match resp {
             qmp::Response::Error(err) => {
                 return Err(format!("Error from qemu monitor: {:?}", err.desc()).into());
@@ -246,7 +1070,7 @@ impl Monitor {
     pub fn status(&mut self) -> Result<String, Error> {
         let ret = self.execute("query-status")?;
         let status = ret
-            .extra
+            .value
             .get("status")
             .unwrap()
             .as_str()
@@ -264,106 +1088,366 @@ impl Monitor {
         self.execute("stop")?;
         Ok(())
     }
-}
 
-fn read_response(s: &mut UnixStream) -> Result<qmp::Response, Error> {
-    let mut buf = [0u8; 4096];
+    // Open the tray and remove the media, forced since installers lock the
+    // tray while they run.
+    pub fn eject(&mut self, device: &str) -> Result<(), Error> {
+        self.execute_with_args("eject", Some(json!({ "id": device, "force": true })))?;
+        Ok(())
+    }
+
+    // request the guest balloon driver to resize guest memory to `size` bytes
+    pub fn balloon(&mut self, size: u64) -> Result<(), Error> {
+        self.execute_with_args("balloon", Some(json!({ "value": size })))?;
+        Ok(())
+    }
 
-    while select::has_data(s.as_raw_fd(), true) {
-        let n = s.read(&mut buf)?;
-        trace!(
-            "From monitor: {:?}",
-            std::str::from_utf8(&buf[..n]).unwrap()
-        );
+    // current guest memory size in bytes as reported by the balloon device
+    pub fn query_balloon(&mut self) -> Result<u64, Error> {
+        let ret = self.execute("query-balloon")?;
+        match ret.value.get("actual").and_then(|v| v.as_u64()) {
+            Some(actual) => Ok(actual),
+            None => Err("no balloon size in query-balloon response".into()),
+        }
+    }
 
-        for val in parse_qapi_stream(&mut buf[..n]) {
+    // the response to the command sent with `id`, logging events and
+    // skipping answers to earlier commands that timed out
+    fn read_response(&mut self, id: &str) -> Result<qmp::Response, Error> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let mut val = self.next_message(deadline)?;
             if val.get("event").is_some() {
                 let event: qmp::Event = serde_json::from_value(val)?;
                 info!("{:?}", event);
-            } else {
-                let resp: qmp::Response = serde_json::from_value(val)?;
-                return Ok(resp);
+                continue;
             }
+            let rid = val.as_object_mut().and_then(|o| o.remove("id"));
+            if rid.as_ref().and_then(|r| r.as_str()) != Some(id) {
+                warn!("discarding stale monitor response {:?}: {}", rid, val);
+                continue;
+            }
+            return Ok(serde_json::from_value(val)?);
         }
     }
 
-    Err("no reponse found".into())
-}
-
-fn parse_qapi_stream(buf: &mut [u8]) -> Vec<Value> {
-    let mut vals = Vec::new();
-    for slice in buf.split_mut(|byt| byt == &b"\r"[0]) {
-        if slice.len() == 0 || (slice.len() == 1 && slice[0] == b"\n"[0]) {
-            continue;
+    // the next message from qemu, read over as many chunks as it takes
+    fn next_message(&mut self, deadline: Instant) -> Result<Value, Error> {
+        if let Some(val) = take_message(&mut self.buf)? {
+            return Ok(val);
+        }
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(timeout_error(self.timeout));
+            }
+            self.stream.set_read_timeout(Some(left))?;
+            let mut chunk = [0u8; 4096];
+            let n = match self.stream.read(&mut chunk) {
+                Ok(0) => return Err("qemu monitor closed the connection".into()),
+                Ok(n) => n,
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    return Err(timeout_error(self.timeout));
+                }
+                Err(e) => return Err(e.into()),
+            };
+            trace!("From monitor: {:?}", String::from_utf8_lossy(&chunk[..n]));
+            self.buf.extend_from_slice(&chunk[..n]);
+            // only a newline in the new chunk can complete a message
+            if chunk[..n].contains(&b'\n') {
+                if let Some(val) = take_message(&mut self.buf)? {
+                    return Ok(val);
+                }
+            }
+            if self.buf.len() > MAX_MESSAGE {
+                return Err(
+                    format!("qemu monitor message larger than {} bytes", MAX_MESSAGE).into(),
+                );
+            }
         }
-
-        let val: Value = serde_json::from_slice(slice).unwrap();
-        vals.push(val);
     }
-    return vals;
 }
 
-mod select {
-    use std::mem::MaybeUninit;
-    use std::ptr;
+// bound on a single message, far above what query commands return
+const MAX_MESSAGE: usize = 64 * 1024 * 1024;
+
+// qemu splits option values on commas, a literal one is doubled
+fn escape_opt(value: &str) -> String {
+    value.replace(',', ",,")
+}
 
-    use libc::{fd_set, select, timeval, FD_SET, FD_ZERO};
+fn timeout_error(timeout: Duration) -> Error {
+    format!(
+        "no response from qemu monitor within {}s",
+        timeout.as_secs_f32()
+    )
+    .into()
+}
 
-    fn new_fd_set() -> fd_set {
-        let mut set = MaybeUninit::uninit();
-        unsafe {
-            FD_ZERO(set.as_mut_ptr());
-            set.assume_init()
+// split the first complete CRLF terminated message off the front of buf,
+// leaving the rest, possibly a message still being received, in it
+fn take_message(buf: &mut Vec<u8>) -> Result<Option<Value>, Error> {
+    while let Some(end) = buf.iter().position(|b| *b == b'\n') {
+        let line: Vec<u8> = buf.drain(..=end).collect();
+        let line = line.trim_ascii();
+        if !line.is_empty() {
+            return Ok(Some(serde_json::from_slice(line)?));
         }
     }
+    Ok(None)
+}
 
-    pub fn has_data(fd: i32, block: bool) -> bool {
-        let mut rfds = new_fd_set();
+#[cfg(test)]
+mod test {
+    use super::*;
 
-        // a tv of 0 means return immediately, or poll
-        let mut tv = timeval {
-            tv_sec: 0,
-            tv_usec: 0,
+    #[test]
+    fn test_cpu_arg() {
+        let hw = Hardware::default();
+        assert_eq!(hw.cpu_arg(), None);
+
+        let hw = Hardware {
+            cpu_model: Some("host-passthrough".into()),
+            cpu_features: vec!["vmx".into(), "-hle".into()],
+            ..Default::default()
         };
+        assert_eq!(hw.cpu_arg(), Some("host,+vmx,-hle".to_string()));
+    }
 
-        unsafe {
-            FD_SET(fd, &mut rfds);
-        }
+    #[test]
+    fn test_from_spec() {
+        let spec: Spec = serde_yaml::from_str(
+            "
+            cpu: 2
+            memory: 1Gi
+            image:
+              url: file:///srv/images/debian.qcow2
+            hugepages: 2Mi
+            cpuFeatures: [vmx]
+            ",
+        )
+        .unwrap();
+        let hw = Hardware::from_spec(&spec).unwrap();
+        assert_eq!(hw.cpus, 2);
+        assert_eq!(hw.memory_mb, 1024);
+        assert_eq!(hw.mem_path, Some(PathBuf::from(HUGEPAGES_PATH)));
+        assert_eq!(hw.cpu_features, vec!["vmx".to_string()]);
+        assert!(hw.shares.is_empty());
+        assert_eq!(hw.throttle_opts(), "");
 
-        let res = unsafe {
-            select(
-                fd + 1,
-                &mut rfds,
-                ptr::null_mut(),
-                ptr::null_mut(),
-                if !block { &mut tv } else { ptr::null_mut() },
-            )
+        let spec: Spec = serde_yaml::from_str(
+            "
+            cpu: 2
+            memory: 1Gi
+            image:
+              url: file:///srv/images/debian.qcow2
+            qos:
+              diskIops: 200
+              diskBandwidth: 50M
+            ",
+        )
+        .unwrap();
+        let hw = Hardware::from_spec(&spec).unwrap();
+        assert_eq!(
+            hw.throttle_opts(),
+            ",throttling.iops-total=200,throttling.bps-total=50000000"
+        );
+        assert_eq!(hw.bootindex(BootDevice::Disk), ",bootindex=1");
+        assert_eq!(hw.bootindex(BootDevice::Cdrom), "");
+
+        let spec: Spec = serde_yaml::from_str(
+            "
+            cpu: 2
+            memory: 1Gi
+            image:
+              url: file:///srv/images/debian.qcow2
+            bootOrder: [cdrom, disk]
+            ",
+        )
+        .unwrap();
+        let mut hw = Hardware::from_spec(&spec).unwrap();
+        hw.cdrom = Some(PathBuf::from("/srv/iso/install.iso"));
+        assert_eq!(hw.bootindex(BootDevice::Disk), ",bootindex=2");
+        let args = hw.cdrom_args();
+        assert!(args[1].ends_with(",file=/srv/iso/install.iso,format=raw"));
+        assert_eq!(
+            args[3],
+            "ide-cd,bus=ide.1,drive=drive-cdrom0,id=cdrom0,bootindex=1"
+        );
+    }
+
+    #[test]
+    fn test_s390x() {
+        let mut hw = Hardware {
+            arch: Some("s390x".into()),
+            ..Default::default()
         };
+        assert_eq!(hw.machine_type(), "s390-ccw-virtio");
+        assert_eq!(hw.virtio("virtio-blk"), "virtio-blk-ccw");
+        assert_eq!(hw.slot("0x2"), "");
+        assert_eq!(
+            hw.console_args()[1],
+            "sclpconsole,chardev=charserial0,id=serial0"
+        );
+        let args = hw.cdrom_args();
+        assert_eq!(args[1], "virtio-scsi-ccw,id=scsi0");
+        assert_eq!(args[5], "scsi-cd,bus=scsi0.0,drive=drive-cdrom0,id=cdrom0");
 
-        if res == -1 {
-            eprintln!("Error in select()");
-        }
+        hw.arch = Some("x86_64".into());
+        assert_eq!(hw.virtio("virtio-blk"), "virtio-blk-pci");
+        assert_eq!(hw.slot("0x2"), ",bus=pci.0,addr=0x2");
+    }
 
-        res == 1
+    #[test]
+    fn test_rtc() {
+        let mut hw = Hardware::default();
+        assert_eq!(hw.rtc(), ("utc", None));
+        hw.timezone = Some("localtime".into());
+        assert_eq!(hw.rtc(), ("localtime", None));
+        hw.timezone = Some("Asia/Tokyo".into());
+        assert_eq!(hw.rtc(), ("localtime", Some("Asia/Tokyo")));
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    #[test]
+    fn test_parse_snapshot_names() {
+        let info = r#"{
+            "filename": "image.qcow2",
+            "format": "qcow2",
+            "snapshots": [
+                {"id": "1", "name": "clean", "vm-state-size": 0},
+                {"id": "2", "name": "lesson-2", "vm-state-size": 0}
+            ]
+        }"#;
+        assert_eq!(
+            parse_snapshot_names(info).unwrap(),
+            vec!["clean", "lesson-2"]
+        );
+        assert!(parse_snapshot_names(r#"{"format": "qcow2"}"#)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_parse_snapshot_taken() {
+        let info = r#"{
+            "format": "qcow2",
+            "snapshots": [
+                {"id": "1", "name": "clean", "date-sec": 1700000000, "date-nsec": 42}
+            ]
+        }"#;
+        assert_eq!(
+            parse_snapshot_taken(info, "clean").unwrap().as_deref(),
+            Some("1700000000.000000042")
+        );
+        assert_eq!(parse_snapshot_taken(info, "other").unwrap(), None);
+    }
 
     #[test]
-    fn test_parse_qapi_stream() {
-        let s = b"{\"timestamp\": {\"seconds\": 1677200460, \"microseconds\": 774479}, \"event\": \"STOP\"}\r\n{\"return\": {}}\r\n";
+    fn test_share_args() {
+        let hw = Hardware {
+            memory_mb: 1024,
+            shares: vec![
+                Share {
+                    source: "/home/dev/src".into(),
+                    tag: "src".into(),
+                    driver: ShareDriver::Virtiofs,
+                    readonly: false,
+                },
+                Share {
+                    source: "/srv/data".into(),
+                    tag: "data".into(),
+                    driver: ShareDriver::NineP,
+                    readonly: true,
+                },
+            ],
+            ..Default::default()
+        };
+        let p = Process::new(
+            "/run/vm",
+            "test",
+            "uuid",
+            Image {
+                path: "/img".into(),
+            },
+            hw,
+        );
+        assert_eq!(
+            p.share_args(),
+            vec![
+                "-chardev",
+                "socket,id=fs0,path=/run/vm/virtiofs0.sock",
+                "-device",
+                "vhost-user-fs-pci,chardev=fs0,tag=src",
+                "-fsdev",
+                "local,id=fs1,path=/srv/data,security_model=mapped-xattr,readonly=on",
+                "-device",
+                "virtio-9p-pci,fsdev=fs1,mount_tag=data",
+            ]
+        );
+        assert_eq!(
+            p.mem_args()[1],
+            "memory-backend-memfd,id=mem,size=1024M,share=on"
+        );
+        assert_eq!(escape_opt("/srv/a,b"), "/srv/a,,b");
+    }
+
+    #[test]
+    fn test_take_message() {
+        let s = b"{\"timestamp\": {\"seconds\": 1677200460, \"microseconds\": 774479}, \"event\": \"STOP\"}\r\n{\"return\": {}, \"id\": \"bigiron-1\"}\r\n{\"ret";
         let mut input = s.to_vec();
-        let vals = parse_qapi_stream(&mut input[..]);
-        eprintln!("{:?}", vals);
 
-        assert_eq!(vals.len(), 2);
         assert_eq!(
-            vals[0],
-            json!({"timestamp": { "seconds": 1677200460, "microseconds": 774479 }, "event": "STOP"})
+            take_message(&mut input).unwrap(),
+            Some(
+                json!({"timestamp": { "seconds": 1677200460, "microseconds": 774479 }, "event": "STOP"})
+            )
         );
-        assert_eq!(vals[1], json!({"return": {}}));
+        assert_eq!(
+            take_message(&mut input).unwrap(),
+            Some(json!({"return": {}, "id": "bigiron-1"}))
+        );
+        // the partial message stays until the rest of it arrives
+        assert_eq!(take_message(&mut input).unwrap(), None);
+        assert_eq!(input, b"{\"ret");
+        input.extend_from_slice(b"urn\": {}}\r\n");
+        assert_eq!(
+            take_message(&mut input).unwrap(),
+            Some(json!({"return": {}}))
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn test_large_response() {
+        let (stream, mut qemu) = UnixStream::pair().unwrap();
+        let mut mon = Monitor {
+            stream,
+            buf: Vec::new(),
+            next_id: 1,
+            timeout: Duration::from_secs(5),
+        };
+
+        let devices: Vec<Value> = (0..500)
+            .map(|i| json!({"device": format!("drive-virtio-disk{}", i)}))
+            .collect();
+        let resp = format!("{}\r\n", json!({"return": devices, "id": "bigiron-1"}));
+        assert!(resp.len() > 4096);
+        let writer = std::thread::spawn(move || {
+            for chunk in resp.as_bytes().chunks(1000) {
+                qemu.write_all(chunk).unwrap();
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        });
+
+        match mon.read_response("bigiron-1").unwrap() {
+            qmp::Response::Return(ret) => {
+                assert_eq!(ret.value.as_array().unwrap().len(), 500)
+            }
+            r => panic!("unexpected response {:?}", r),
+        }
+        writer.join().unwrap();
     }
 }
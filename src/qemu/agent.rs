@@ -0,0 +1,211 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// qemu-guest-agent protocol. The agent speaks QMP style json over a
+// virtio-serial port, but has no greeting and its replies aren't always
+// objects. Commands go either straight to the socket qemu exposes the port
+// on, or through libvirt for libvirt managed domains.
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::error::Error;
+
+// name of the virtio-serial port the agent listens on in the guest
+pub const CHANNEL: &str = "org.qemu.guest_agent.0";
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GuestInterface {
+    pub name: String,
+    #[serde(rename = "hardware-address", default)]
+    pub mac: String,
+    #[serde(rename = "ip-addresses", default)]
+    pub addresses: Vec<GuestAddress>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GuestAddress {
+    #[serde(rename = "ip-address")]
+    pub ip: String,
+    #[serde(rename = "ip-address-type")]
+    pub kind: String,
+    pub prefix: u8,
+}
+
+// what the guest reports about itself
+#[derive(Debug, Clone, Default)]
+pub struct GuestInfo {
+    pub hostname: Option<String>,
+    pub interfaces: Vec<GuestInterface>,
+}
+
+impl GuestInfo {
+    // guest addresses other than loopback and ipv6 link local
+    pub fn addresses(&self) -> Vec<&str> {
+        self.interfaces
+            .iter()
+            .filter(|i| i.name != "lo")
+            .flat_map(|i| &i.addresses)
+            .filter(|a| !a.ip.starts_with("fe80:"))
+            .map(|a| a.ip.as_str())
+            .collect()
+    }
+}
+
+pub fn command(execute: &str, arguments: Option<Value>) -> String {
+    let mut cmd = json!({ "execute": execute });
+    if let Some(args) = arguments {
+        cmd["arguments"] = args;
+    }
+    cmd.to_string()
+}
+
+// the return value of an agent reply, or its error
+pub fn parse_reply(reply: &str) -> Result<Value, Error> {
+    let mut v: Value = serde_json::from_str(reply)?;
+    if let Some(e) = v.get("error") {
+        let desc = e.get("desc").and_then(|d| d.as_str()).unwrap_or("unknown");
+        return Err(format!("Error from guest agent: {}", desc).into());
+    }
+    match v.get_mut("return") {
+        Some(r) => Ok(r.take()),
+        None => Err(format!("unexpected guest agent reply: {}", reply).into()),
+    }
+}
+
+pub fn parse_hostname(ret: &Value) -> Option<String> {
+    ret.get("host-name")
+        .and_then(|h| h.as_str())
+        .map(|h| h.to_string())
+}
+
+pub fn parse_interfaces(ret: Value) -> Result<Vec<GuestInterface>, Error> {
+    Ok(serde_json::from_value(ret)?)
+}
+
+// client for the agent socket of a guest started by bigiron itself
+pub struct Agent {
+    stream: BufReader<UnixStream>,
+}
+
+impl Agent {
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let s = UnixStream::connect(path)?;
+        s.set_read_timeout(Some(TIMEOUT))?;
+        let mut agent = Self {
+            stream: BufReader::new(s),
+        };
+
+        // replies to anything sent by an earlier client may still be queued,
+        // skip everything up to the answer to our sync
+        let id = rand::random::<u32>() as u64;
+        agent.send(&command("guest-sync", Some(json!({ "id": id }))))?;
+        loop {
+            let line = agent.read_line()?;
+            if let Ok(Value::Number(n)) = parse_reply(&line) {
+                if n.as_u64() == Some(id) {
+                    break;
+                }
+            }
+        }
+        Ok(agent)
+    }
+
+    fn read_line(&mut self) -> Result<String, Error> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err("guest agent closed the connection".into());
+        }
+        Ok(line.trim().to_string())
+    }
+
+    fn send(&mut self, cmd: &str) -> Result<(), Error> {
+        writeln!(self.stream.get_mut(), "{}", cmd)?;
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<Value, Error> {
+        let line = self.read_line()?;
+        parse_reply(&line)
+    }
+
+    pub fn execute(&mut self, execute: &str, arguments: Option<Value>) -> Result<Value, Error> {
+        self.send(&command(execute, arguments))?;
+        self.recv()
+    }
+
+    pub fn ping(&mut self) -> Result<(), Error> {
+        self.execute("guest-ping", None)?;
+        Ok(())
+    }
+
+    pub fn info(&mut self) -> Result<GuestInfo, Error> {
+        Ok(GuestInfo {
+            hostname: parse_hostname(&self.execute("guest-get-host-name", None)?),
+            interfaces: parse_interfaces(self.execute("guest-network-get-interfaces", None)?)?,
+        })
+    }
+
+    // freeze guest filesystems, returning how many were frozen
+    pub fn fsfreeze(&mut self) -> Result<u64, Error> {
+        let n = self.execute("guest-fsfreeze-freeze", None)?;
+        Ok(n.as_u64().unwrap_or(0))
+    }
+
+    pub fn fsthaw(&mut self) -> Result<u64, Error> {
+        let n = self.execute("guest-fsfreeze-thaw", None)?;
+        Ok(n.as_u64().unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_interfaces() {
+        let reply = r#"{"return": [
+            {"name": "lo", "hardware-address": "00:00:00:00:00:00",
+             "ip-addresses": [{"ip-address": "127.0.0.1", "ip-address-type": "ipv4", "prefix": 8}]},
+            {"name": "eth0", "hardware-address": "00:16:3e:00:00:01",
+             "ip-addresses": [
+               {"ip-address": "10.20.0.7", "ip-address-type": "ipv4", "prefix": 24},
+               {"ip-address": "fe80::216:3eff:fe00:1", "ip-address-type": "ipv6", "prefix": 64}
+             ]}
+        ]}"#;
+        let info = GuestInfo {
+            hostname: None,
+            interfaces: parse_interfaces(parse_reply(reply).unwrap()).unwrap(),
+        };
+        assert_eq!(info.interfaces[1].mac, "00:16:3e:00:00:01");
+        assert_eq!(info.addresses(), vec!["10.20.0.7"]);
+
+        let err = r#"{"error": {"class": "CommandNotFound", "desc": "not enabled"}}"#;
+        assert!(parse_reply(err).is_err());
+        assert_eq!(
+            parse_hostname(&parse_reply(r#"{"return": {"host-name": "web1"}}"#).unwrap()),
+            Some("web1".to_string())
+        );
+    }
+}
@@ -0,0 +1,276 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Finding the emulator for an architecture and what it supports. Distros
+// name the binary differently, kvm on debian, qemu-kvm in /usr/libexec on
+// rhel and qemu-system-<arch> elsewhere, and ship different machine types,
+// rhel has no i440fx machines for one.
+
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::debug;
+
+use crate::config;
+use crate::error::Error;
+use crate::imagerepo;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MachineType {
+    pub name: String,
+    #[serde(default)]
+    pub alias: Option<String>,
+    #[serde(default, rename = "is-default")]
+    pub default: bool,
+}
+
+// what an emulator offers
+#[derive(Debug, Clone, Default)]
+pub struct Capabilities {
+    pub emulator: PathBuf,
+    pub machines: Vec<MachineType>,
+    pub devices: Vec<String>,
+}
+
+impl Capabilities {
+    pub fn has_machine(&self, name: &str) -> bool {
+        self.machines
+            .iter()
+            .any(|m| m.name == name || m.alias.as_deref() == Some(name))
+    }
+
+    pub fn has_device(&self, name: &str) -> bool {
+        self.devices.iter().any(|d| d == name)
+    }
+
+    // The first of the usual machine types of the architecture the
+    // emulator has, else the emulator's own default.
+    pub fn default_machine_type(&self, arch: &str) -> Option<String> {
+        let preferred: &[&str] = match arch {
+            // the versioned type machines were always started with first
+            "x86_64" => &["pc-i440fx-3.1", "pc", "q35"],
+            "s390x" => &["s390-ccw-virtio"],
+            "aarch64" => &["virt"],
+            "ppc64le" => &["pseries"],
+            _ => &[],
+        };
+        match preferred.iter().find(|m| self.has_machine(m)) {
+            Some(m) => Some(m.to_string()),
+            None => self
+                .machines
+                .iter()
+                .find(|m| m.default)
+                .map(|m| m.name.clone()),
+        }
+    }
+}
+
+// Probe the emulator for guests of `arch`, the configured one for the host's
+// architecture if there is one.
+pub fn probe(arch: &str) -> Result<Capabilities, Error> {
+    let emulator = emulator(arch)?;
+    let machines = match query_machines(&emulator) {
+        Ok(m) => m,
+        Err(e) => {
+            debug!("query-machines failed, parsing -machine help: {}", e);
+            parse_machine_help(&help(&emulator, "-machine")?)
+        }
+    };
+    Ok(Capabilities {
+        devices: parse_device_help(&help(&emulator, "-device")?),
+        machines,
+        emulator,
+    })
+}
+
+// machine type for a guest without one in its spec
+pub fn machine_type(caps: &Capabilities, arch: &str) -> Result<String, Error> {
+    if arch == imagerepo::host_arch() {
+        if let Some(mt) = config::load()?.qemu.machine_type {
+            return Ok(mt);
+        }
+    }
+    caps.default_machine_type(arch).ok_or_else(|| {
+        format!(
+            "{} has no known machine type for {}, set one in the spec",
+            caps.emulator.display(),
+            arch
+        )
+        .into()
+    })
+}
+
+pub fn emulator(arch: &str) -> Result<PathBuf, Error> {
+    let native = arch == imagerepo::host_arch();
+    if native {
+        if let Some(p) = config::load()?.qemu.emulator {
+            return Ok(p);
+        }
+    }
+    let tried = candidates(arch, native);
+    match tried.iter().find(|p| is_executable(p)) {
+        Some(p) => Ok(p.clone()),
+        None => Err(format!(
+            "no emulator for {} found, tried {}",
+            arch,
+            tried
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+        .into()),
+    }
+}
+
+// the kvm wrappers for guests of the host's architecture, then
+// qemu-system-<arch> on the PATH
+fn candidates(arch: &str, native: bool) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if native {
+        for p in ["/usr/bin/kvm", "/usr/bin/qemu-kvm", "/usr/libexec/qemu-kvm"] {
+            paths.push(PathBuf::from(p));
+        }
+    }
+    let path = std::env::var_os("PATH").unwrap_or_else(|| "/usr/bin".into());
+    for dir in std::env::split_paths(&path) {
+        let p = dir.join(format!("qemu-system-{}", arch));
+        if !paths.contains(&p) {
+            paths.push(p);
+        }
+    }
+    paths
+}
+
+fn is_executable(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+pub fn help(emulator: &Path, opt: &str) -> Result<String, Error> {
+    let out = Command::new(emulator).arg(opt).arg("help").output()?;
+    if !out.status.success() {
+        return Err(format!("failed to run {} {} help", emulator.display(), opt).into());
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).to_string())
+}
+
+// ask an emulator with no machine to run for its machine types over qmp
+fn query_machines(emulator: &Path) -> Result<Vec<MachineType>, Error> {
+    let mut child = Command::new(emulator)
+        .args(["-machine", "none", "-nodefaults", "-display", "none"])
+        .args(["-qmp", "stdio"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        for cmd in ["qmp_capabilities", "query-machines", "quit"] {
+            writeln!(stdin, "{}", json!({ "execute": cmd }))?;
+        }
+    }
+    let out = child.wait_with_output()?;
+    parse_query_machines(&String::from_utf8_lossy(&out.stdout))
+}
+
+// the machines out of the qmp session, whose other replies are skipped
+fn parse_query_machines(out: &str) -> Result<Vec<MachineType>, Error> {
+    for line in out.lines() {
+        let v: Value = match serde_json::from_str(line) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        if let Some(ret) = v.get("return").filter(|r| r.is_array()) {
+            return Ok(serde_json::from_value(ret.clone())?);
+        }
+    }
+    Err("no query-machines reply from the emulator".into())
+}
+
+// machine types from `-machine help`, skipping the header line
+fn parse_machine_help(buf: &str) -> Vec<MachineType> {
+    buf.lines()
+        .skip(1)
+        .filter_map(|l| {
+            l.split_whitespace().next().map(|name| MachineType {
+                name: name.to_string(),
+                alias: None,
+                default: l.ends_with("(default)"),
+            })
+        })
+        .collect()
+}
+
+// device models from `-device help`, lines like
+// name "virtio-blk-pci", bus PCI, alias "virtio-blk"
+fn parse_device_help(buf: &str) -> Vec<String> {
+    buf.lines()
+        .filter_map(|l| l.strip_prefix("name \""))
+        .filter_map(|l| l.split('"').next())
+        .map(|d| d.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_machine_help() {
+        let help = "Supported machines are:\n\
+                    pc                   Standard PC (i440FX + PIIX, 1996) (alias of pc-i440fx-7.2)\n\
+                    pc-i440fx-7.2        Standard PC (i440FX + PIIX, 1996) (default)\n\
+                    q35                  Standard PC (Q35 + ICH9, 2009) (alias of pc-q35-7.2)\n";
+        let machines = parse_machine_help(help);
+        let names: Vec<&str> = machines.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, vec!["pc", "pc-i440fx-7.2", "q35"]);
+        assert!(machines[1].default);
+    }
+
+    #[test]
+    fn test_parse_query_machines() {
+        let out = r#"{"QMP": {"version": {}, "capabilities": []}}
+{"return": {}}
+{"return": [{"name": "pc-q35-8.2", "alias": "q35", "cpu-max": 288}, {"name": "pc-i440fx-8.2", "alias": "pc", "is-default": true}]}
+{"return": {}}
+"#;
+        let caps = Capabilities {
+            machines: parse_query_machines(out).unwrap(),
+            ..Default::default()
+        };
+        assert!(caps.has_machine("q35"));
+        assert!(caps.has_machine("pc-q35-8.2"));
+        assert!(!caps.has_machine("pc-i440fx-3.1"));
+        assert_eq!(caps.default_machine_type("x86_64").as_deref(), Some("pc"));
+        assert_eq!(
+            caps.default_machine_type("riscv64").as_deref(),
+            Some("pc-i440fx-8.2")
+        );
+        assert!(parse_query_machines("").is_err());
+    }
+
+    #[test]
+    fn test_parse_device_help() {
+        let help = "Storage devices:\n\
+                    name \"virtio-blk-pci\", bus PCI, alias \"virtio-blk\"\n\
+                    name \"scsi-cd\", bus SCSI, desc \"virtual SCSI CD-ROM\"\n";
+        assert_eq!(parse_device_help(help), vec!["virtio-blk-pci", "scsi-cd"]);
+    }
+}
@@ -54,10 +54,11 @@ pub enum Response {
     Error(Error),
 }
 
+// an object for most commands, a list for queries like query-block
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(transparent)]
 pub struct Return {
-    #[serde(flatten)]
-    pub extra: HashMap<String, Value>,
+    pub value: Value,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -0,0 +1,139 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Tap devices for the nic of qemu driver VMs.
+//
+// The tap is made through /dev/net/tun and lives only as long as a file
+// descriptor holds it, which is qemu's once it started. Its name and the
+// nic's mac are kept in the VM directory, so the guest sees the same nic
+// across restarts.
+
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use crate::error::Error;
+use crate::host::net;
+use crate::network;
+
+const TUN_DEVICE: &str = "/dev/net/tun";
+const SYS_CLASS_NET: &str = "/sys/class/net";
+const TAP_FILE: &str = "tap";
+const MAC_FILE: &str = "mac";
+// the kernel fills in the first free number for new VMs
+const NAME_PATTERN: &str = "bitap%d";
+
+// from linux/if_tun.h
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+const IFF_TAP: libc::c_short = 0x0002;
+const IFF_NO_PI: libc::c_short = 0x1000;
+const IFF_VNET_HDR: libc::c_short = 0x4000;
+
+// struct ifreq, only the name and flags of its union are used
+#[repr(C)]
+struct IfReq {
+    name: [u8; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+#[derive(Debug)]
+pub struct Tap {
+    pub name: String,
+    pub mac: String,
+    pub file: File,
+}
+
+// Create the VM's tap, on the bridge and up, reusing the name it had
+// before when that is still free.
+pub fn open(dir: &Path, bridge: &str) -> Result<Tap, Error> {
+    let saved = read(&dir.join(TAP_FILE));
+    let (file, name) = match saved.as_deref().map(create) {
+        Some(Ok(tap)) => tap,
+        // taken by another VM meanwhile
+        _ => create(NAME_PATTERN)?,
+    };
+    std::fs::write(dir.join(TAP_FILE), &name)?;
+
+    let mac = match read(&dir.join(MAC_FILE)) {
+        Some(mac) => mac,
+        None => {
+            let mac = network::generate_mac();
+            std::fs::write(dir.join(MAC_FILE), &mac)?;
+            mac
+        }
+    };
+
+    net::attach(&name, bridge)?;
+    Ok(Tap { name, mac, file })
+}
+
+// Delete the tap of a VM that exited, if anything kept it around.
+pub fn remove(dir: &Path) -> Result<(), Error> {
+    match read(&dir.join(TAP_FILE)) {
+        Some(name) if Path::new(SYS_CLASS_NET).join(&name).exists() => net::delete_link(&name),
+        _ => Ok(()),
+    }
+}
+
+fn create(name: &str) -> Result<(File, String), Error> {
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(format!("tap name {} is too long", name).into());
+    }
+    let file = File::options().read(true).write(true).open(TUN_DEVICE)?;
+    let mut req = IfReq {
+        name: [0; libc::IFNAMSIZ],
+        flags: IFF_TAP | IFF_NO_PI | IFF_VNET_HDR,
+        _pad: [0; 22],
+    };
+    req.name[..name.len()].copy_from_slice(name.as_bytes());
+    if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF, &mut req as *mut IfReq) } < 0 {
+        return Err(format!(
+            "error creating tap {}: {}",
+            name,
+            std::io::Error::last_os_error()
+        )
+        .into());
+    }
+    Ok((file, ifname(&req.name)))
+}
+
+fn ifname(buf: &[u8]) -> String {
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+fn read(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ifname() {
+        assert_eq!(std::mem::size_of::<IfReq>(), 40);
+        let mut buf = [0u8; libc::IFNAMSIZ];
+        buf[..6].copy_from_slice(b"bitap3");
+        assert_eq!(ifname(&buf), "bitap3");
+        assert!(create("a-name-well-over-ifnamsiz").is_err());
+    }
+}
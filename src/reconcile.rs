@@ -0,0 +1,318 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// `bigiron watch <dir>` keeps the host in line with a directory of spec
+// files. Whenever a file is added or changes it is applied, and machines
+// that were in a file that is removed, or that a file no longer has, are
+// deleted. Machines that stay in a changed file are scaled to its cpu and
+// memory, other changes to their spec need them deleted and applied again.
+//
+// The machines each file created are recorded under the data dir, so files
+// removed while nothing was watching are still cleaned up on the next run.
+// Machines that existed before a file named them are left alone.
+// A file that fails to apply is retried on every pass until it goes through.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{error, info, warn};
+
+use crate::api::{self, ApplyOptions, Store};
+use crate::bus::Inotify;
+use crate::error::Error;
+use crate::models::Resource;
+use crate::specfile;
+use crate::store;
+
+// how often to go over the directory when nothing happens, to retry files
+// that failed to apply
+const RESYNC: Duration = Duration::from_secs(60);
+// editors write a file in several steps, let them finish first
+const SETTLE: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Applied {
+    // sha256 of the rendered documents
+    digest: String,
+    // created by applying the file, deleted once it no longer has them
+    machines: Vec<String>,
+}
+
+// a file's documents as they render now
+struct Rendered {
+    digest: String,
+    machines: Vec<String>,
+}
+
+// what was last applied from each file of a directory
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    files: BTreeMap<PathBuf, Applied>,
+}
+
+impl State {
+    fn path(dir: &Path) -> PathBuf {
+        let id = hex::encode(Sha256::digest(dir.to_string_lossy().as_bytes()));
+        store::data_dir()
+            .join("watch")
+            .join(format!("{}.json", &id[..16]))
+    }
+
+    fn load(dir: &Path) -> Result<Self, Error> {
+        match std::fs::read(Self::path(dir)) {
+            Ok(buf) => Ok(serde_json::from_slice(&buf)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, dir: &Path) -> Result<(), Error> {
+        let path = Self::path(dir);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+// Reconcile `dir` until killed. Problems with single files are logged and
+// don't stop the others.
+pub fn run(dir: &Path, opts: &ApplyOptions) -> Result<(), Error> {
+    let dir = dir.canonicalize()?;
+    let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_MOVED_FROM | libc::IN_DELETE;
+    let inotify = Inotify::watch(&dir, mask)?;
+    let mut state = State::load(&dir)?;
+    info!("watching {}", dir.display());
+    loop {
+        reconcile(&dir, opts, &mut state);
+        state.save(&dir)?;
+        inotify.wait(Some(RESYNC))?;
+        std::thread::sleep(SETTLE);
+        inotify.wait(Some(Duration::ZERO))?;
+    }
+}
+
+fn reconcile(dir: &Path, opts: &ApplyOptions, state: &mut State) {
+    let files = match spec_files(dir) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("error listing {}: {}", dir.display(), e);
+            return;
+        }
+    };
+
+    let mut claimed = BTreeSet::new();
+    for path in &files {
+        let rendered = match render(path, opts) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("{}: {}", path.display(), e);
+                // keep what the file had until it can be read again
+                if let Some(old) = state.files.get(path) {
+                    claimed.extend(old.machines.iter().cloned());
+                }
+                continue;
+            }
+        };
+        claimed.extend(rendered.machines.iter().cloned());
+        if state
+            .files
+            .get(path)
+            .is_some_and(|a| a.digest == rendered.digest)
+        {
+            continue;
+        }
+
+        info!("applying {}", path.display());
+        let created = match api::apply_specfile(path, opts) {
+            Ok(created) => created,
+            Err(e) => {
+                error!("applying {} failed: {}", path.display(), e);
+                if let Some(old) = state.files.get(path) {
+                    claimed.extend(old.machines.iter().cloned());
+                }
+                continue;
+            }
+        };
+        if let Err(e) = scale(path, opts) {
+            warn!("{}: {}", path.display(), e);
+        }
+        let applied = state.files.entry(path.clone()).or_default();
+        applied.digest = rendered.digest;
+        for name in created {
+            if !applied.machines.contains(&name) {
+                applied.machines.push(name);
+            }
+        }
+        // apply moves on from machines that fail to create, try them again
+        // on the next pass
+        if !all_exist(&rendered.machines) {
+            applied.digest.clear();
+        }
+    }
+
+    // machines no file has any more, whether their file went away or
+    // just stopped listing them
+    let mut gone = Vec::new();
+    for (path, applied) in state.files.iter_mut() {
+        let (keep, dropped): (Vec<String>, Vec<String>) = applied
+            .machines
+            .drain(..)
+            .partition(|m| claimed.contains(m));
+        applied.machines = keep;
+        for name in dropped {
+            info!("deleting {}, it is no longer in {}", name, path.display());
+            if let Err(e) = delete(&name, opts) {
+                error!("deleting {} failed: {}", name, e);
+                applied.machines.push(name);
+            }
+        }
+        if !files.contains(path) && applied.machines.is_empty() {
+            gone.push(path.clone());
+        }
+    }
+    for path in gone {
+        state.files.remove(&path);
+    }
+}
+
+fn all_exist(machines: &[String]) -> bool {
+    let store = match Store::new() {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+    machines
+        .iter()
+        .all(|m| store.get_machine(m).is_ok_and(|m| m.is_some()))
+}
+
+fn delete(name: &str, opts: &ApplyOptions) -> Result<(), Error> {
+    match Store::new()?.get_machine(name)? {
+        Some(_) => api::delete_machine(name, opts.override_freeze),
+        None => Ok(()),
+    }
+}
+
+// bring cpu and memory of machines that already existed in line with the file
+fn scale(path: &Path, opts: &ApplyOptions) -> Result<(), Error> {
+    let store = Store::new()?;
+    for m in machines(&specfile::load(path, &opts.values)?)? {
+        let current = match store.get_machine(&m.name)? {
+            Some(c) => c,
+            None => continue,
+        };
+        let cpu = Some(m.spec.cpu).filter(|c| *c != current.spec.cpu);
+        let memory = Some(m.spec.memory).filter(|s| *s != current.spec.memory);
+        if cpu.is_some() || memory.is_some() {
+            info!("scaling {}", m.name);
+            api::scale_machine(&m.name, cpu, memory, opts.override_freeze)?;
+        }
+    }
+    Ok(())
+}
+
+fn render(path: &Path, opts: &ApplyOptions) -> Result<Rendered, Error> {
+    let docs = specfile::load(path, &opts.values)?;
+    let mut digest = Sha256::new();
+    for doc in &docs {
+        digest.update(serde_yaml::to_string(doc)?);
+    }
+    Ok(Rendered {
+        digest: hex::encode(digest.finalize()),
+        machines: machines(&docs)?.into_iter().map(|m| m.name).collect(),
+    })
+}
+
+fn machines(docs: &[serde_yaml::Value]) -> Result<Vec<crate::models::Machine>, Error> {
+    let mut machines = Vec::new();
+    for doc in docs {
+        match serde_yaml::from_value::<Resource>(doc.clone())? {
+            Resource::Machine(m) => machines.push(m),
+            Resource::MachineTemplate(t) => machines.extend(t.machines()?),
+            _ => {}
+        }
+    }
+    Ok(machines)
+}
+
+// the spec files directly in dir, skipping hidden ones such as editor swap
+// files
+fn spec_files(dir: &Path) -> Result<BTreeSet<PathBuf>, Error> {
+    let mut files = BTreeSet::new();
+    for entry in dir.read_dir()? {
+        let path = entry?.path();
+        let hidden = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.starts_with('.'));
+        let yaml = path.extension().is_some_and(|e| e == "yaml" || e == "yml");
+        if yaml && !hidden && path.is_file() {
+            files.insert(path);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_spec_files() {
+        let dir = TestDir::new("reconcile");
+        for name in [
+            "web.yaml",
+            "db.yml",
+            ".web.yaml.swp",
+            ".hidden.yaml",
+            "notes.txt",
+        ] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let files: Vec<PathBuf> = spec_files(&dir).unwrap().into_iter().collect();
+        assert_eq!(files, vec![dir.join("db.yml"), dir.join("web.yaml")]);
+    }
+
+    #[test]
+    fn test_render() {
+        let dir = TestDir::new("render");
+        let path = dir.join("web.yaml");
+        std::fs::write(
+            &path,
+            "kind: MachineTemplate\nname: web\nreplicas: 2\nspec:\n  cpu: 2\n  memory: 2Gi\n  image:\n    name: debian-12\n\
+             ---\nkind: Image\nname: debian\nspec:\n  url: https://example.com/debian.qcow2\n",
+        )
+        .unwrap();
+        let opts = ApplyOptions::default();
+        let first = render(&path, &opts).unwrap();
+        assert_eq!(first.machines, vec!["web-1", "web-2"]);
+
+        std::fs::write(
+            &path,
+            "kind: MachineTemplate\nname: web\nreplicas: 2\nspec:\n  cpu: 4\n  memory: 2Gi\n  image:\n    name: debian-12\n",
+        )
+        .unwrap();
+        let second = render(&path, &opts).unwrap();
+        assert_eq!(second.machines, first.machines);
+        assert_ne!(second.digest, first.digest);
+    }
+}
@@ -0,0 +1,378 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// gRPC control API, see proto/bigiron.proto. The server runs the same api
+// functions as the command line, on blocking threads, and the generated
+// client is exported for other Rust tools to drive bigiron with. Who may
+// call what is up to authz.
+
+use std::ffi::CString;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::transport::server::UdsConnectInfo;
+use tonic::transport::{Identity, Server, ServerTlsConfig};
+use tonic::{Request, Response, Status};
+use tracing::{info, warn};
+
+use crate::api::{self, ApplyOptions, PowerAction, Store};
+use crate::authz::{self, Caller};
+use crate::config::{self, ApiConfig};
+use crate::error::{Category, Error};
+use crate::imagerepo::ImageRepo;
+use crate::models;
+use crate::network;
+use crate::specfile;
+
+pub mod proto {
+    tonic::include_proto!("bigiron.v1");
+}
+
+pub use proto::bigiron_client::BigironClient as Client;
+
+use proto::bigiron_server::{Bigiron, BigironServer};
+use proto::power_request::Action;
+
+// serve the api on the unix socket, and on tcp at `listen` if given, until
+// killed
+pub fn serve(socket: &Path, listen: Option<&str>) -> Result<(), Error> {
+    let cfg = config::load()?.api;
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let local = Server::builder()
+            .add_service(BigironServer::new(Service { cfg: cfg.clone() }))
+            .serve_with_incoming(UnixListenerStream::new(bind(socket, &cfg.group)?));
+        info!("serving the gRPC api on {}", socket.display());
+        match listen {
+            Some(listen) => {
+                let addr: SocketAddr = listen.parse()?;
+                let mut builder = Server::builder();
+                match &cfg.tls {
+                    Some(tls) => {
+                        let identity =
+                            Identity::from_pem(std::fs::read(&tls.cert)?, std::fs::read(&tls.key)?);
+                        builder = builder.tls_config(ServerTlsConfig::new().identity(identity))?;
+                    }
+                    // tokens would go over the network in the clear
+                    None if !addr.ip().is_loopback() => {
+                        return Err(format!(
+                            "set api.tls to serve the gRPC api on {}, without it only \
+                             loopback addresses are served",
+                            addr
+                        )
+                        .into());
+                    }
+                    None => {}
+                }
+                let remote = builder
+                    .add_service(BigironServer::new(Service { cfg }))
+                    .serve(addr);
+                info!("serving the gRPC api on {}, with tokens", addr);
+                tokio::try_join!(local, remote)?;
+            }
+            None => local.await?,
+        }
+        Ok::<(), Error>(())
+    })
+}
+
+// a socket only root and the api group can connect to
+fn bind(path: &Path, group: &str) -> Result<UnixListener, Error> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let listener = UnixListener::bind(path)?;
+
+    let name = CString::new(group)?;
+    let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+    let mode = match gr.is_null() {
+        true => {
+            warn!("no group {}, only root can use {}", group, path.display());
+            0o600
+        }
+        false => {
+            std::os::unix::fs::chown(path, Some(0), Some(unsafe { (*gr).gr_gid }))?;
+            0o660
+        }
+    };
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+struct Service {
+    cfg: ApiConfig,
+}
+
+impl Service {
+    fn authorize<T>(&self, req: &Request<T>, op: &str) -> Result<(), Status> {
+        let caller = match req.extensions().get::<UdsConnectInfo>() {
+            Some(info) => match &info.peer_cred {
+                Some(cred) => Caller::Local { uid: cred.uid() },
+                None => return Err(Status::permission_denied("unknown peer")),
+            },
+            None => Caller::Remote {
+                token: req
+                    .metadata()
+                    .get("authorization")
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.strip_prefix("Bearer ")),
+            },
+        };
+        match authz::check(&self.cfg, &caller, op) {
+            Ok(who) => {
+                if !authz::READ_OPERATIONS.contains(&op) {
+                    info!("{} called {}", who, op);
+                }
+                Ok(())
+            }
+            Err(e) => Err(Status::permission_denied(e.to_string())),
+        }
+    }
+}
+
+fn status(e: &Error) -> Status {
+    let msg = e.to_string();
+    match Category::of(e) {
+        Some(Category::NotFound) => Status::not_found(msg),
+        Some(Category::Unavailable) => Status::unavailable(msg),
+        Some(Category::Frozen) => Status::failed_precondition(msg),
+        Some(Category::Network) => Status::resource_exhausted(msg),
+        Some(Category::NotRunning) | None => Status::internal(msg),
+    }
+}
+
+// run a blocking api call off the async threads
+async fn blocking<T, F>(f: F) -> Result<T, Status>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || f().map_err(|e| status(&e)))
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+}
+
+fn machine(m: models::Machine) -> Result<proto::Machine, Error> {
+    Ok(proto::Machine {
+        running: api::power_status(&m.name)?,
+        spec: serde_yaml::to_string(&m.spec)?,
+        name: m.name,
+        project: m.project,
+        labels: m.labels.into_iter().collect(),
+        status: m.status,
+    })
+}
+
+#[tonic::async_trait]
+impl Bigiron for Service {
+    async fn list_machines(
+        &self,
+        req: Request<proto::ListMachinesRequest>,
+    ) -> Result<Response<proto::ListMachinesResponse>, Status> {
+        self.authorize(&req, "ListMachines")?;
+        let selector = req.into_inner().selector;
+        let machines = blocking(move || {
+            let store = Store::new()?;
+            let machines = match selector.is_empty() {
+                true => store.list_machines()?,
+                false => store.select_machines(&models::Selector::parse(&selector)?)?,
+            };
+            machines.into_iter().map(machine).collect()
+        })
+        .await?;
+        Ok(Response::new(proto::ListMachinesResponse { machines }))
+    }
+
+    async fn get_machine(
+        &self,
+        req: Request<proto::GetMachineRequest>,
+    ) -> Result<Response<proto::Machine>, Status> {
+        self.authorize(&req, "GetMachine")?;
+        let name = req.into_inner().name;
+        let found =
+            blocking(move || api::get_machine_by_id(&name)?.map(machine).transpose()).await?;
+        match found {
+            Some(m) => Ok(Response::new(m)),
+            None => Err(Status::not_found("no such machine")),
+        }
+    }
+
+    async fn apply(
+        &self,
+        req: Request<proto::ApplyRequest>,
+    ) -> Result<Response<proto::ApplyResponse>, Status> {
+        self.authorize(&req, "Apply")?;
+        let req = req.into_inner();
+        let report = blocking(move || apply(req)).await?;
+        Ok(Response::new(proto::ApplyResponse { report }))
+    }
+
+    async fn delete_machine(
+        &self,
+        req: Request<proto::DeleteMachineRequest>,
+    ) -> Result<Response<proto::DeleteMachineResponse>, Status> {
+        self.authorize(&req, "DeleteMachine")?;
+        let req = req.into_inner();
+        blocking(move || api::delete_machine(&req.name, req.override_freeze)).await?;
+        Ok(Response::new(proto::DeleteMachineResponse {}))
+    }
+
+    async fn power(
+        &self,
+        req: Request<proto::PowerRequest>,
+    ) -> Result<Response<proto::PowerResponse>, Status> {
+        self.authorize(&req, "Power")?;
+        let req = req.into_inner();
+        let action = match Action::try_from(req.action) {
+            Ok(Action::On) => PowerAction::On,
+            Ok(Action::Off) => PowerAction::Off,
+            Ok(Action::Soft) => PowerAction::Soft,
+            Ok(Action::Cycle) => PowerAction::Cycle,
+            Err(_) => return Err(Status::invalid_argument("unknown power action")),
+        };
+        blocking(move || api::power(&req.name, action, req.override_freeze)).await?;
+        Ok(Response::new(proto::PowerResponse {}))
+    }
+
+    async fn list_images(
+        &self,
+        req: Request<proto::ListImagesRequest>,
+    ) -> Result<Response<proto::ListImagesResponse>, Status> {
+        self.authorize(&req, "ListImages")?;
+        let images = blocking(|| {
+            Ok(ImageRepo::new()?
+                .catalog()?
+                .into_iter()
+                .map(|(name, entry)| proto::Image {
+                    name,
+                    target: entry.to_string(),
+                })
+                .collect())
+        })
+        .await?;
+        Ok(Response::new(proto::ListImagesResponse { images }))
+    }
+
+    async fn get_network(
+        &self,
+        req: Request<proto::GetNetworkRequest>,
+    ) -> Result<Response<proto::Network>, Status> {
+        self.authorize(&req, "GetNetwork")?;
+        let s = blocking(network::summary).await?;
+        Ok(Response::new(proto::Network {
+            name: s.name,
+            cidr: s.cidr,
+            mode: format!("{:?}", s.mode).to_lowercase(),
+            gateway: s.gateway.map(|g| g.to_string()),
+            capacity: s.capacity.map(|c| c as u64),
+            allocated: s.allocated as u64,
+            leased: s.leased as u64,
+        }))
+    }
+
+    async fn list_reservations(
+        &self,
+        req: Request<proto::ListReservationsRequest>,
+    ) -> Result<Response<proto::ListReservationsResponse>, Status> {
+        self.authorize(&req, "ListReservations")?;
+        let reservations = blocking(|| {
+            Ok(network::reservations()?
+                .into_iter()
+                .map(|r| proto::Reservation {
+                    allocated: r.is_allocated(),
+                    leased: r.is_leased(),
+                    mac: r.mac,
+                    ip: r.ip,
+                    hostname: r.hostname,
+                })
+                .collect())
+        })
+        .await?;
+        Ok(Response::new(proto::ListReservationsResponse {
+            reservations,
+        }))
+    }
+
+    async fn list_hosts(
+        &self,
+        req: Request<proto::ListHostsRequest>,
+    ) -> Result<Response<proto::ListHostsResponse>, Status> {
+        self.authorize(&req, "ListHosts")?;
+        let hosts = blocking(|| {
+            Ok(api::list_hosts()?
+                .into_iter()
+                .map(|h| proto::Host {
+                    name: h.name,
+                    cpus: h.cpus,
+                    memory: h.memory,
+                    used_memory: h.used_memory,
+                    machines: h.machines,
+                })
+                .collect())
+        })
+        .await?;
+        Ok(Response::new(proto::ListHostsResponse { hosts }))
+    }
+}
+
+// Apply a spec file sent over the api. It goes through a scratch directory
+// like one from disk would, but with untrusted values, so neither !include
+// nor the environment can be used.
+fn apply(req: proto::ApplyRequest) -> Result<String, Error> {
+    let dir = std::env::temp_dir().join(format!(
+        "bigiron-grpc-{}-{}",
+        std::process::id(),
+        uuid::Uuid::new_v4()
+    ));
+    std::fs::create_dir_all(&dir)?;
+    let r = apply_in(&dir, req);
+    let _ = std::fs::remove_dir_all(&dir);
+    r
+}
+
+fn apply_in(dir: &Path, req: proto::ApplyRequest) -> Result<String, Error> {
+    let path = dir.join("spec.yaml");
+    let report = dir.join("report.json");
+    std::fs::write(&path, &req.specfile)?;
+    let set: Vec<String> = req
+        .values
+        .into_iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect();
+    let opts = ApplyOptions {
+        override_freeze: req.override_freeze,
+        report: Some(report.clone()),
+        values: specfile::Values::untrusted(&set)?,
+        ..Default::default()
+    };
+    let r = api::apply_specfile(&path, &opts);
+    // the report is written whether or not apply got through
+    match (r, std::fs::read_to_string(&report)) {
+        (Ok(_), Ok(report)) => Ok(report),
+        (Err(e), Ok(report)) => Err(format!("{}\n{}", e, report).into()),
+        (Err(e), Err(_)) => Err(e),
+        (Ok(_), Err(e)) => Err(e.into()),
+    }
+}
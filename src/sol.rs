@@ -0,0 +1,367 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Serial-over-LAN: the machine's serial console, which libvirt exposes as a
+// unix socket, served to tcp clients by a small proxy process per machine.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, warn};
+
+use crate::error::Error;
+use crate::models::{Sol, SolProtocol};
+
+// name the endpoint is registered under in the port registry
+pub const SERVICE: &str = "sol";
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const ECHO: u8 = 1;
+const SGA: u8 = 3;
+
+const AUTH_ATTEMPTS: u32 = 3;
+// how long a client has to log in, it holds a connection slot meanwhile
+const AUTH_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub fn socket_path(machine_dir: &Path) -> PathBuf {
+    machine_dir.join("serial.sock")
+}
+
+fn pid_path(machine_dir: &Path) -> PathBuf {
+    machine_dir.join("sol.pid")
+}
+
+// Start the proxy for a machine unless it is already running. It outlives
+// the cli, reconnecting to the console whenever the machine restarts.
+pub fn spawn(id: &str, machine_dir: &Path) -> Result<(), Error> {
+    if running(machine_dir).is_some() {
+        return Ok(());
+    }
+    let log = File::options()
+        .append(true)
+        .create(true)
+        .open(machine_dir.join("sol.log"))?;
+    let child = Command::new(std::env::current_exe()?)
+        .arg("sol-serve")
+        .arg(id)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .spawn()?;
+    std::fs::write(pid_path(machine_dir), child.id().to_string())?;
+    Ok(())
+}
+
+pub fn stop(machine_dir: &Path) {
+    if let Some(pid) = running(machine_dir) {
+        unsafe { libc::kill(pid, libc::SIGTERM) };
+    }
+    let _ = std::fs::remove_file(pid_path(machine_dir));
+}
+
+fn running(machine_dir: &Path) -> Option<i32> {
+    let pid = std::fs::read_to_string(pid_path(machine_dir))
+        .ok()?
+        .trim()
+        .parse::<i32>()
+        .ok()?;
+    match unsafe { libc::kill(pid, 0) } {
+        0 => Some(pid),
+        _ => None,
+    }
+}
+
+struct Console {
+    socket: PathBuf,
+    telnet: bool,
+    upstream: Mutex<Option<UnixStream>>,
+    clients: Mutex<Vec<(usize, TcpStream)>>,
+    connections: AtomicUsize,
+}
+
+// Serve a console socket on a tcp port until killed. Every client sees the
+// console output and anything typed by any of them goes to the guest.
+pub fn serve(sol: &Sol, port: u16, socket: &Path) -> Result<(), Error> {
+    let password = match &sol.password_file {
+        Some(p) => Some(std::fs::read_to_string(p)?.trim_end().to_string()),
+        None => None,
+    };
+    let max = sol.max_connections.unwrap_or(1) as usize;
+    let listener = TcpListener::bind((sol.listen_addr(), port))?;
+
+    let console = Arc::new(Console {
+        socket: socket.to_path_buf(),
+        telnet: sol.protocol == SolProtocol::Telnet,
+        upstream: Mutex::new(None),
+        clients: Mutex::new(Vec::new()),
+        connections: AtomicUsize::new(0),
+    });
+    {
+        let console = console.clone();
+        thread::spawn(move || console.pump());
+    }
+
+    for (id, stream) in listener.incoming().enumerate() {
+        let mut stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("error accepting sol connection: {}", e);
+                continue;
+            }
+        };
+        if console.connections.fetch_add(1, Ordering::SeqCst) >= max {
+            console.connections.fetch_sub(1, Ordering::SeqCst);
+            let _ = stream.write_all(b"too many connections\r\n");
+            continue;
+        }
+
+        let console = console.clone();
+        let password = password.clone();
+        thread::spawn(move || {
+            if let Err(e) = console.session(id, stream, password.as_deref()) {
+                debug!("sol session ended: {}", e);
+            }
+            console.clients.lock().unwrap().retain(|(c, _)| *c != id);
+            console.connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    Ok(())
+}
+
+impl Console {
+    // copy console output to the clients, for as long as the process runs
+    fn pump(&self) {
+        loop {
+            if let Ok(mut s) = UnixStream::connect(&self.socket) {
+                *self.upstream.lock().unwrap() = s.try_clone().ok();
+                let mut buf = [0u8; 4096];
+                while let Ok(n) = s.read(&mut buf) {
+                    if n == 0 {
+                        break;
+                    }
+                    self.broadcast(&buf[..n]);
+                }
+                *self.upstream.lock().unwrap() = None;
+            }
+            // machine is off or restarting
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    fn broadcast(&self, data: &[u8]) {
+        let data = match self.telnet {
+            true => escape(data),
+            false => data.to_vec(),
+        };
+        // a client that can't keep up hits its write timeout and is dropped
+        self.clients
+            .lock()
+            .unwrap()
+            .retain_mut(|(_, s)| s.write_all(&data).is_ok());
+    }
+
+    fn session(
+        &self,
+        id: usize,
+        mut stream: TcpStream,
+        password: Option<&str>,
+    ) -> Result<(), Error> {
+        let mut filter = Telnet::default();
+        if self.telnet {
+            // character at a time, with the guest doing the echoing
+            stream.write_all(&[IAC, WILL, ECHO, IAC, WILL, SGA])?;
+        }
+
+        if let Some(password) = password {
+            let deadline = Instant::now() + AUTH_TIMEOUT;
+            let mut ok = false;
+            for _ in 0..AUTH_ATTEMPTS {
+                stream.write_all(b"Password: ")?;
+                let line = self.read_line(&mut stream, &mut filter, deadline)?;
+                stream.write_all(b"\r\n")?;
+                if line == password {
+                    ok = true;
+                    break;
+                }
+                thread::sleep(Duration::from_secs(1));
+            }
+            if !ok {
+                stream.write_all(b"Authentication failed\r\n")?;
+                return Ok(());
+            }
+            stream.set_read_timeout(None)?;
+        }
+
+        stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+        self.clients.lock().unwrap().push((id, stream.try_clone()?));
+
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            let data = match self.telnet {
+                true => filter.input(&buf[..n]),
+                false => buf[..n].to_vec(),
+            };
+            if let Some(up) = self.upstream.lock().unwrap().as_mut() {
+                if let Err(e) = up.write_all(&data) {
+                    warn!("error writing to console: {}", e);
+                }
+            }
+        }
+    }
+
+    // read a line a byte at a time, so nothing typed after it is lost
+    fn read_line(
+        &self,
+        stream: &mut TcpStream,
+        filter: &mut Telnet,
+        deadline: Instant,
+    ) -> Result<String, Error> {
+        let mut line = Vec::new();
+        let mut b = [0u8; 1];
+        while line.len() < 256 {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err("timed out waiting for the password".into());
+            }
+            stream.set_read_timeout(Some(left))?;
+            let n = stream.read(&mut b).map_err(|e| match e.kind() {
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+                    "timed out waiting for the password".into()
+                }
+                _ => Error::from(e),
+            })?;
+            if n == 0 {
+                return Err("connection closed".into());
+            }
+            let data = match self.telnet {
+                true => filter.input(&b),
+                false => b.to_vec(),
+            };
+            for c in data {
+                match c {
+                    b'\r' | b'\n' => return Ok(String::from_utf8_lossy(&line).into_owned()),
+                    c => line.push(c),
+                }
+            }
+        }
+        Err("line too long".into())
+    }
+}
+
+// escape console output for a telnet client
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &b in data {
+        if b == IAC {
+            out.push(IAC);
+        }
+        out.push(b);
+    }
+    out
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum State {
+    #[default]
+    Data,
+    Cr,
+    Iac,
+    Option,
+    Sub,
+    SubIac,
+}
+
+// Strips telnet commands and option negotiation out of client input. Kept
+// across reads since a command can be split between them.
+#[derive(Debug, Default)]
+struct Telnet {
+    state: State,
+}
+
+impl Telnet {
+    fn input(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for &b in data {
+            self.state = match (self.state, b) {
+                (State::Iac, IAC) => {
+                    out.push(IAC);
+                    State::Data
+                }
+                (State::Iac, WILL..=DONT) => State::Option,
+                (State::Iac, SB) => State::Sub,
+                (State::Iac, _) | (State::Option, _) => State::Data,
+                (State::Sub, IAC) => State::SubIac,
+                (State::Sub, _) => State::Sub,
+                (State::SubIac, SE) => State::Data,
+                (State::SubIac, _) => State::Sub,
+                (_, IAC) => State::Iac,
+                // enter arrives as CR LF or CR NUL, the guest only wants CR
+                (State::Cr, b'\n' | 0) => State::Data,
+                (_, b'\r') => {
+                    out.push(b);
+                    State::Cr
+                }
+                (_, b) => {
+                    out.push(b);
+                    State::Data
+                }
+            };
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_telnet_filter() {
+        let mut t = Telnet::default();
+        // negotiation, a subnegotiation and an escaped 0xff around text
+        let input = [
+            IAC, 253, ECHO, b'l', b's', IAC, SB, 31, 0, 80, 0, 24, IAC, SE, IAC, IAC, b'\r', 0,
+        ];
+        assert_eq!(t.input(&input), vec![b'l', b's', IAC, b'\r']);
+
+        // commands split across reads
+        assert_eq!(t.input(&[b'a', IAC]), vec![b'a']);
+        assert_eq!(t.input(&[WILL, SGA, b'b', b'\r']), vec![b'b', b'\r']);
+        assert_eq!(t.input(b"\nc"), b"c".to_vec());
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape(&[b'a', IAC, b'b']), vec![b'a', IAC, IAC, b'b']);
+    }
+}
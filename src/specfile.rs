@@ -0,0 +1,556 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Spec files are rendered before apply reads them. ${VAR}, or
+// ${VAR:-default}, is replaced with a value from --set, a --values file or
+// the environment, in that order, $${ is a literal ${. Variables are
+// replaced in the keys and strings of the parsed documents, so a value can't
+// change a document's structure. A string that is just one variable becomes
+// a number or bool when its value reads as one. An `!include
+// file.yaml` document is replaced by the documents of that file, and an
+// `!include` value within a document by the one document of its file.
+// Included paths are relative to the including file. Specs sent over the
+// api get neither the environment nor includes.
+//
+// `bigiron validate` reads a spec file the same way and reports what apply
+// would reject, unknown fields, bad sizes and image urls that don't parse.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{DeserializeSeed, IgnoredAny, IntoDeserializer, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use serde_yaml::Value;
+use url::Url;
+
+use crate::error::Error;
+use crate::models::Resource;
+
+#[derive(Debug, Clone, Default)]
+pub struct Values {
+    vars: BTreeMap<String, String>,
+    // specs from api clients only get their own values, not this host's
+    // environment or files
+    untrusted: bool,
+}
+
+impl Values {
+    // `set` holds KEY=VALUE pairs, which win over the values file
+    pub fn new(set: &[String], file: Option<&Path>) -> Result<Self, Error> {
+        let mut vars = BTreeMap::new();
+        if let Some(file) = file {
+            let buf = std::fs::read_to_string(file)?;
+            let values: BTreeMap<String, Value> = serde_yaml::from_str(&buf)
+                .map_err(|e| format!("error parsing {}: {}", file.display(), e))?;
+            for (k, v) in values {
+                let v = match v {
+                    Value::String(s) => s,
+                    Value::Number(n) => n.to_string(),
+                    Value::Bool(b) => b.to_string(),
+                    _ => {
+                        return Err(
+                            format!("value of {} in {} is not a scalar", k, file.display()).into(),
+                        )
+                    }
+                };
+                vars.insert(k, v);
+            }
+        }
+        for kv in set {
+            let (k, v) = kv
+                .split_once('=')
+                .ok_or_else(|| format!("'{}' is not KEY=VALUE", kv))?;
+            vars.insert(k.to_string(), v.to_string());
+        }
+        Ok(Self {
+            vars,
+            untrusted: false,
+        })
+    }
+
+    // for specs sent over the api: just `set`, without the environment
+    // and without includes
+    pub fn untrusted(set: &[String]) -> Result<Self, Error> {
+        Ok(Self {
+            untrusted: true,
+            ..Self::new(set, None)?
+        })
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        match self.vars.get(name) {
+            Some(v) => Some(v.clone()),
+            None if self.untrusted => None,
+            None => std::env::var(name).ok(),
+        }
+    }
+
+    fn include(&self, dir: &Path, value: &Value) -> Result<PathBuf, Error> {
+        if self.untrusted {
+            return Err("!include can't be used in specs sent over the api".into());
+        }
+        Ok(dir.join(include_path(value)?))
+    }
+}
+
+// the documents of a spec file, with includes and variables resolved
+pub fn load(path: &Path, values: &Values) -> Result<Vec<Value>, Error> {
+    load_file(path, values, &mut Vec::new())
+}
+
+fn load_file(path: &Path, values: &Values, stack: &mut Vec<PathBuf>) -> Result<Vec<Value>, Error> {
+    let buf = read(path, stack)?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut docs = Vec::new();
+    for (i, de) in serde_yaml::Deserializer::from_str(&buf).enumerate() {
+        let doc = Value::deserialize(de).map_err(|e| {
+            format!(
+                "Error reading document at index {} of {}: {}",
+                i,
+                path.display(),
+                e
+            )
+        })?;
+        match doc {
+            Value::Null => {}
+            Value::Tagged(t) if t.tag == "include" => {
+                let file = values.include(dir, &expand(t.value, path, values, stack)?)?;
+                docs.extend(load_file(&file, values, stack)?);
+            }
+            doc => docs.push(expand(doc, path, values, stack)?),
+        }
+    }
+
+    stack.pop();
+    Ok(docs)
+}
+
+// read a file onto the include stack, the caller pops it when done
+fn read(path: &Path, stack: &mut Vec<PathBuf>) -> Result<String, Error> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if stack.contains(&canonical) {
+        return Err(format!("{} includes itself", path.display()).into());
+    }
+    stack.push(canonical);
+
+    Ok(std::fs::read_to_string(path)?)
+}
+
+// Check the resources of a spec file and the files it includes against
+// the models, without applying anything. Each problem is reported with the
+// file and, unless it came in through an included value, its line.
+pub fn validate(path: &Path, values: &Values) -> Result<Vec<String>, Error> {
+    let mut problems = Vec::new();
+    validate_file(path, values, &mut Vec::new(), &mut problems)?;
+    Ok(problems)
+}
+
+fn validate_file(
+    path: &Path,
+    values: &Values,
+    stack: &mut Vec<PathBuf>,
+    problems: &mut Vec<String>,
+) -> Result<(), Error> {
+    let buf = read(path, stack)?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut docs = Vec::new();
+    for de in serde_yaml::Deserializer::from_str(&buf) {
+        match Value::deserialize(de) {
+            Ok(doc) => docs.push(doc),
+            // the rest of the file can't be read past a syntax error
+            Err(e) => {
+                problems.push(format!("{}: {}", path.display(), e));
+                break;
+            }
+        }
+    }
+
+    // documents are read a second time straight into the models, so that
+    // errors carry their line
+    for (doc, de) in docs
+        .into_iter()
+        .zip(serde_yaml::Deserializer::from_str(&buf))
+    {
+        let resource = match doc {
+            Value::Null => continue,
+            Value::Tagged(t) if t.tag == "include" => {
+                let file = values.include(dir, &expand(t.value, path, values, stack)?)?;
+                validate_file(&file, values, stack, problems)?;
+                continue;
+            }
+            doc => {
+                // what variables and includes put in has no line to point to
+                let rendered = expand(doc.clone(), path, values, stack)?;
+                match (rendered == doc, doc.get("kind").and_then(Value::as_str)) {
+                    (false, _) => serde_yaml::from_value::<Resource>(rendered),
+                    (true, Some(kind)) => deserialize_kind(kind, de),
+                    (true, None) => Resource::deserialize(de),
+                }
+            }
+        };
+        match resource {
+            Ok(r) => {
+                for (field, url) in urls(&r) {
+                    if let Err(e) = Url::parse(url) {
+                        let line = buf.lines().position(|l| {
+                            l.split_once(": ").is_some_and(|(_, v)| {
+                                v.trim().trim_matches(|c| c == '"' || c == '\'') == url
+                            })
+                        });
+                        problems.push(match line {
+                            Some(n) => format!(
+                                "{}: {}: invalid url '{}': {} at line {}",
+                                path.display(),
+                                field,
+                                url,
+                                e,
+                                n + 1
+                            ),
+                            None => format!(
+                                "{}: {}: invalid url '{}': {}",
+                                path.display(),
+                                field,
+                                url,
+                                e
+                            ),
+                        });
+                    }
+                }
+            }
+            Err(e) => problems.push(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    stack.pop();
+    Ok(())
+}
+
+// Read a document straight into the model of its kind. Going through
+// Resource would buffer the document to find its kind tag, and errors from
+// the buffered copy have no line.
+fn deserialize_kind<'de, D: Deserializer<'de>>(kind: &str, de: D) -> Result<Resource, D::Error> {
+    match kind {
+        "Machine" => de
+            .deserialize_map(SkipKind(PhantomData))
+            .map(Resource::Machine),
+        "MachineTemplate" => de
+            .deserialize_map(SkipKind(PhantomData))
+            .map(Resource::MachineTemplate),
+        "Network" => de
+            .deserialize_map(SkipKind(PhantomData))
+            .map(Resource::Network),
+        "BareMetal" => de
+            .deserialize_map(SkipKind(PhantomData))
+            .map(Resource::BareMetal),
+        "Image" => de
+            .deserialize_map(SkipKind(PhantomData))
+            .map(Resource::Image),
+        // let serde name the kinds there are
+        _ => Resource::deserialize(de),
+    }
+}
+
+// a mapping as T, without its kind key
+struct SkipKind<T>(PhantomData<T>);
+
+impl<'de, T: Deserialize<'de>> Visitor<'de> for SkipKind<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a mapping")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<T, A::Error> {
+        T::deserialize(MapAccessDeserializer::new(WithoutKind(map)))
+    }
+}
+
+struct WithoutKind<A>(A);
+
+impl<'de, A: MapAccess<'de>> MapAccess<'de> for WithoutKind<A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, A::Error> {
+        while let Some(key) = self.0.next_key::<String>()? {
+            if key != "kind" {
+                return seed.deserialize(key.into_deserializer()).map(Some);
+            }
+            self.0.next_value::<IgnoredAny>()?;
+        }
+        Ok(None)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, A::Error> {
+        self.0.next_value_seed(seed)
+    }
+}
+
+// the urls of a resource that are fetched from when it is applied
+fn urls(resource: &Resource) -> Vec<(&'static str, &str)> {
+    let image = match resource {
+        Resource::Machine(m) => &m.spec.image,
+        Resource::MachineTemplate(t) => &t.spec.image,
+        Resource::Image(i) => return vec![("spec.url", i.spec.url.as_str())],
+        Resource::Network(_) | Resource::BareMetal(_) => return Vec::new(),
+    };
+    image
+        .url
+        .iter()
+        .map(|u| ("spec.image.url", u.as_str()))
+        .collect()
+}
+
+// the JSON Schema of spec file documents, for editors and other tooling
+pub fn schema() -> Result<String, Error> {
+    Ok(serde_json::to_string_pretty(&schemars::schema_for!(
+        Resource
+    ))?)
+}
+
+fn include_path(value: &Value) -> Result<&str, Error> {
+    value
+        .as_str()
+        .ok_or_else(|| "!include takes a file name".into())
+}
+
+// replace includes and variables within a document of the file at `path`
+fn expand(
+    value: Value,
+    path: &Path,
+    values: &Values,
+    stack: &mut Vec<PathBuf>,
+) -> Result<Value, Error> {
+    match value {
+        Value::Tagged(t) if t.tag == "include" => {
+            let dir = path.parent().unwrap_or(Path::new("."));
+            let file = values.include(dir, &expand(t.value, path, values, stack)?)?;
+            let mut docs = load_file(&file, values, stack)?;
+            match docs.len() {
+                1 => Ok(docs.remove(0)),
+                _ => Err(format!(
+                    "{} has to hold a single document to be included in another",
+                    file.display()
+                )
+                .into()),
+            }
+        }
+        Value::Tagged(mut t) => {
+            t.value = expand(t.value, path, values, stack)?;
+            Ok(Value::Tagged(t))
+        }
+        Value::String(s) => {
+            scalar(&s, values).map_err(|e| format!("{}: {}", path.display(), e).into())
+        }
+        Value::Mapping(m) => Ok(Value::Mapping(
+            m.into_iter()
+                .map(|(k, v)| {
+                    Ok((
+                        expand(k, path, values, stack)?,
+                        expand(v, path, values, stack)?,
+                    ))
+                })
+                .collect::<Result<_, Error>>()?,
+        )),
+        Value::Sequence(s) => Ok(Value::Sequence(
+            s.into_iter()
+                .map(|v| expand(v, path, values, stack))
+                .collect::<Result<_, Error>>()?,
+        )),
+        v => Ok(v),
+    }
+}
+
+// a string with its variables substituted, typed like a plain yaml scalar
+// if it is just one variable
+fn scalar(s: &str, values: &Values) -> Result<Value, Error> {
+    let v = substitute(s, values)?;
+    let whole = s.starts_with("${") && s[2..].find('}') == Some(s.len() - 3);
+    if whole {
+        if let Ok(typed @ (Value::Number(_) | Value::Bool(_))) = serde_yaml::from_str(&v) {
+            return Ok(typed);
+        }
+    }
+    Ok(Value::String(v))
+}
+
+fn substitute(text: &str, values: &Values) -> Result<String, Error> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        out.push_str(&rest[..start]);
+        let expr = &rest[start + 2..];
+        let end = expr.find('}').ok_or("unterminated ${")?;
+        let (name, default) = match expr[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&expr[..end], None),
+        };
+        let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(format!("invalid variable name '{}'", name).into());
+        }
+        match values.get(name).or_else(|| default.map(str::to_string)) {
+            Some(v) => out.push_str(&v),
+            None => return Err(format!("variable {} is not set", name).into()),
+        }
+        rest = &expr[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_substitute() {
+        let values = Values::new(&["ENV=prod".into(), "CPUS=8".into()], None).unwrap();
+        assert_eq!(
+            substitute("name: web-${ENV}\ncpu: ${CPUS}\n", &values).unwrap(),
+            "name: web-prod\ncpu: 8\n"
+        );
+        assert_eq!(
+            substitute("memory: ${BIGIRON_TEST_UNSET:-4Gi}", &values).unwrap(),
+            "memory: 4Gi"
+        );
+        assert_eq!(
+            substitute("cmd: echo $${HOME}", &values).unwrap(),
+            "cmd: echo ${HOME}"
+        );
+        assert!(substitute("cpu: ${BIGIRON_TEST_UNSET}", &values).is_err());
+        assert!(substitute("cpu: ${CPUS", &values).is_err());
+        assert!(Values::new(&["ENV".into()], None).is_err());
+
+        // the server's environment stays out of specs sent over the api
+        assert!(substitute("path: ${PATH}", &values).is_ok());
+        let untrusted = Values::untrusted(&["CPUS=8".into()]).unwrap();
+        assert!(substitute("path: ${PATH}", &untrusted).is_err());
+        assert_eq!(substitute("cpu: ${CPUS}", &untrusted).unwrap(), "cpu: 8");
+    }
+
+    #[test]
+    fn test_render() {
+        let dir = TestDir::new("render");
+        let path = dir.join("machine.yaml");
+        std::fs::write(
+            &path,
+            "# set ${BIGIRON_TEST_UNSET} to nothing, comments are left alone\n\
+             kind: Machine\nname: web-${ENV}\ndescription: ${NOTE}\n\
+             spec:\n  cpu: ${CPUS}\n  memory: \"${MEMORY:-2Gi}\"\n",
+        )
+        .unwrap();
+
+        let values = Values::new(
+            &[
+                "ENV=prod".into(),
+                "CPUS=8".into(),
+                "NOTE=owner: ops # not a comment\nmemory: 64Gi".into(),
+            ],
+            None,
+        )
+        .unwrap();
+        let docs = load(&path, &values).unwrap();
+        assert_eq!(docs.len(), 1);
+        let doc = &docs[0];
+        assert_eq!(doc["name"].as_str(), Some("web-prod"));
+        // values stay within their scalar
+        assert_eq!(
+            doc["description"].as_str(),
+            Some("owner: ops # not a comment\nmemory: 64Gi")
+        );
+        assert!(doc.get("owner").is_none() && doc.get("memory").is_none());
+        assert_eq!(doc["spec"]["cpu"].as_u64(), Some(8));
+        assert_eq!(doc["spec"]["memory"].as_str(), Some("2Gi"));
+
+        std::fs::write(&path, "kind: Machine\nname: ${BIGIRON_TEST_UNSET}\n").unwrap();
+        let e = load(&path, &values).unwrap_err().to_string();
+        assert!(
+            e.contains("variable BIGIRON_TEST_UNSET is not set"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = TestDir::new("specfile");
+        std::fs::write(
+            dir.join("spec.yaml"),
+            "cpu: ${CPUS}\nmemory: 2Gi\nimage:\n  name: debian-12\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("machines.yaml"),
+            "kind: Machine\nname: web-1\nspec: !include spec.yaml\n---\n!include net.yaml\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("net.yaml"),
+            "kind: Network\nname: lab\nspec: {}\n---\nkind: Network\nname: lab2\nspec: {}\n",
+        )
+        .unwrap();
+
+        let values = Values::new(&["CPUS=2".into()], None).unwrap();
+        let docs = load(&dir.join("machines.yaml"), &values).unwrap();
+        assert_eq!(docs.len(), 3);
+        assert_eq!(docs[0]["spec"]["cpu"].as_u64(), Some(2));
+        assert_eq!(docs[2]["name"].as_str(), Some("lab2"));
+        // nor files
+        let untrusted = Values::untrusted(&["CPUS=2".into()]).unwrap();
+        assert!(load(&dir.join("machines.yaml"), &untrusted).is_err());
+
+        std::fs::write(dir.join("net.yaml"), "!include machines.yaml\n").unwrap();
+        assert!(load(&dir.join("machines.yaml"), &values).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let dir = TestDir::new("validate");
+        let path = dir.join("machines.yaml");
+        std::fs::write(
+            &path,
+            "kind: Machine\nname: web-1\nspec:\n  cpu: 2\n  memory: 2Gi\n  image:\n    url: https://example.com/debian.qcow2\n\
+             ---\nkind: Machine\nname: web-2\nspec:\n  cpu: 2\n  memroy: 2Gi\n  image:\n    name: debian-12\n\
+             ---\nkind: Machine\nname: web-3\nspec:\n  cpu: 2\n  memory: 2Q\n  image:\n    name: debian-12\n\
+             ---\nkind: Image\nname: debian\nspec:\n  url: debian.qcow2\n",
+        )
+        .unwrap();
+
+        let problems = validate(&path, &Values::default()).unwrap();
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[0].contains("unknown field `memroy`") && problems[0].contains("line 13"));
+        assert!(problems[1].contains("invalid size") && problems[1].contains("line 21"));
+        assert!(
+            problems[2].contains("invalid url 'debian.qcow2'") && problems[2].contains("line 28")
+        );
+    }
+}
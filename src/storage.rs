@@ -0,0 +1,582 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Storage pools for machine disks. The built in dir pool keeps qcow2 files
+// in the machine's directory, as bigiron always has. LVM thin pools and ZFS
+// give each disk a raw block device instead, with snapshots done by the
+// volume manager.
+//
+// A machine's disks are volumes named after it and the disk: root for the
+// boot disk, the `local` file name for each entry of spec.storage.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use crate::config::{self, PoolDriver};
+use crate::error::Error;
+use crate::models::{Preallocation, Spec, StorageKind};
+use crate::qemu;
+use crate::store;
+
+pub const ROOT_VOLUME: &str = "root";
+// the pool machines without a storagePool have their disks in
+pub const DIR_POOL: &str = "dir";
+
+pub trait Pool {
+    // where volume `name` of `machine` lives
+    fn path(&self, machine: &str, name: &str) -> PathBuf;
+    // Create a volume of `size` bytes. A file volume is layered on the base
+    // image, a block volume gets a copy of it and is grown to fit it.
+    fn create(
+        &self,
+        machine: &str,
+        name: &str,
+        size: Option<u64>,
+        base: Option<(&Path, &str)>,
+        preallocation: Option<Preallocation>,
+    ) -> Result<PathBuf, Error>;
+    fn delete(&self, machine: &str, name: &str) -> Result<(), Error>;
+    // grow a volume, its guest has to grow its partitions itself
+    fn resize(&self, machine: &str, name: &str, size: u64) -> Result<(), Error>;
+    fn snapshot(&self, machine: &str, name: &str, snapshot: &str) -> Result<(), Error>;
+    // move a volume over to the machine's new name
+    fn rename(&self, machine: &str, new: &str, name: &str) -> Result<(), Error>;
+    // take a standalone qcow2 image, e.g. from a machine bundle, as the volume
+    fn import(&self, machine: &str, name: &str, image: &Path) -> Result<PathBuf, Error>;
+}
+
+// The pool a machine's disks are in. Machines from before pools existed
+// have no storagePool and are in the dir pool.
+pub fn pool(spec: &Spec) -> Result<Box<dyn Pool>, Error> {
+    match spec.storage_pool.as_deref() {
+        None | Some(DIR_POOL) => Ok(Box::new(DirPool::new())),
+        Some(name) => open(name),
+    }
+}
+
+fn open(name: &str) -> Result<Box<dyn Pool>, Error> {
+    let cfg = config::load()?;
+    let pool = cfg
+        .storage_pool(name)
+        .ok_or_else(|| format!("no storage pool named '{}' is configured", name))?;
+    Ok(match &pool.driver {
+        PoolDriver::Dir => Box::new(DirPool::new()),
+        PoolDriver::Lvm {
+            volume_group,
+            thin_pool,
+        } => Box::new(LvmPool {
+            volume_group: volume_group.clone(),
+            thin_pool: thin_pool.clone(),
+        }),
+        PoolDriver::Zfs { dataset } => Box::new(ZfsPool {
+            dataset: dataset.clone(),
+        }),
+    })
+}
+
+// block pools hand out devices, which libvirt and qemu take as raw disks
+pub fn is_block(path: &Path) -> bool {
+    path.starts_with("/dev")
+}
+
+// names of a machine's volumes, the boot disk first
+pub fn volumes(spec: &Spec) -> Vec<String> {
+    let mut names = vec![ROOT_VOLUME.to_string()];
+    for storage in spec.storage.iter().flatten() {
+        match storage {
+            StorageKind::DiskFile(d) => names.push(d.local.display().to_string()),
+        }
+    }
+    names
+}
+
+// What the machine's pool can't do: block volumes have no internal
+// snapshots to revert to and aren't files to mirror.
+pub fn check(spec: &Spec) -> Result<(), Error> {
+    let block = match spec.storage_pool.as_deref() {
+        None | Some(DIR_POOL) => false,
+        Some(name) => {
+            let cfg = config::load()?;
+            let pool = cfg
+                .storage_pool(name)
+                .ok_or_else(|| format!("no storage pool named '{}' is configured", name))?;
+            pool.driver != PoolDriver::Dir
+        }
+    };
+    if !block {
+        return Ok(());
+    }
+    if spec.revert_on_boot.is_some() {
+        return Err("revertOnBoot needs the dir storage pool".into());
+    }
+    if spec.mirror.is_some() {
+        return Err("mirror needs the dir storage pool".into());
+    }
+    check_volumes(spec)
+}
+
+// every disk has to map to a volume of its own
+fn check_volumes(spec: &Spec) -> Result<(), Error> {
+    let mut stems = BTreeSet::new();
+    for name in volumes(spec) {
+        let stem = volume_stem(&name)?;
+        if !stems.insert(stem.to_string()) {
+            return Err(format!("disk '{}' maps to the same volume as another disk", name).into());
+        }
+    }
+    Ok(())
+}
+
+// the boot disk of the machine in `dir`, which a switch to its mirror
+// points elsewhere
+pub fn root_disk(dir: &Path) -> Result<PathBuf, Error> {
+    match std::fs::read_to_string(dir.join("rootdisk")) {
+        Ok(p) => Ok(PathBuf::from(p.trim())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(dir.join("image.qcow2")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn run(mut cmd: Command) -> Result<(), Error> {
+    debug!("Running: {:?}", cmd);
+    let r = cmd.status()?;
+    match r.success() {
+        true => Ok(()),
+        false => Err(format!("{:?} failed with {}", cmd.get_program(), r).into()),
+    }
+}
+
+fn qemu_img() -> Command {
+    let mut cmd = Command::new("/usr/bin/qemu-img");
+    cmd.arg("-q");
+    cmd
+}
+
+pub struct DirPool {
+    // the Store's directory, holding one directory per machine
+    dir: PathBuf,
+}
+
+impl DirPool {
+    pub fn new() -> Self {
+        Self {
+            dir: store::data_dir().join("libvirt"),
+        }
+    }
+}
+
+impl Default for DirPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pool for DirPool {
+    fn path(&self, machine: &str, name: &str) -> PathBuf {
+        let dir = self.dir.join(store::get_unique_id(machine));
+        match name {
+            ROOT_VOLUME => root_disk(&dir).unwrap_or_else(|_| dir.join("image.qcow2")),
+            _ => dir.join(name),
+        }
+    }
+
+    fn create(
+        &self,
+        machine: &str,
+        name: &str,
+        size: Option<u64>,
+        base: Option<(&Path, &str)>,
+        preallocation: Option<Preallocation>,
+    ) -> Result<PathBuf, Error> {
+        let path = self.path(machine, name);
+        let mut cmd = Command::new("/usr/bin/qemu-img");
+        cmd.arg("create");
+        cmd.arg("-q");
+
+        if let Some((bf, format)) = base {
+            cmd.arg("-b");
+            cmd.arg(bf);
+            // never probed, a raw image could pass itself off as qcow2
+            cmd.arg("-F");
+            cmd.arg(format);
+        }
+
+        if let Some(p) = preallocation {
+            cmd.arg("-o");
+            cmd.arg(format!("preallocation={}", p.as_str()));
+        }
+
+        cmd.arg("-f");
+        cmd.arg("qcow2");
+        cmd.arg(&path);
+
+        if let Some(size) = size {
+            cmd.arg(size.to_string());
+        }
+        run(cmd).map_err(|_| "failed to create new image")?;
+        Ok(path)
+    }
+
+    fn delete(&self, machine: &str, name: &str) -> Result<(), Error> {
+        match std::fs::remove_file(self.path(machine, name)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn resize(&self, machine: &str, name: &str, size: u64) -> Result<(), Error> {
+        let mut cmd = qemu_img();
+        cmd.arg("resize")
+            .arg(self.path(machine, name))
+            .arg(size.to_string());
+        run(cmd)
+    }
+
+    fn snapshot(&self, machine: &str, name: &str, snapshot: &str) -> Result<(), Error> {
+        let mut cmd = qemu_img();
+        cmd.arg("snapshot")
+            .arg("-c")
+            .arg(snapshot)
+            .arg(self.path(machine, name));
+        run(cmd)
+    }
+
+    // the files move along with the machine's directory
+    fn rename(&self, _machine: &str, _new: &str, _name: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn import(&self, machine: &str, name: &str, image: &Path) -> Result<PathBuf, Error> {
+        let path = self.path(machine, name);
+        if std::fs::rename(image, &path).is_err() {
+            std::fs::copy(image, &path)?;
+        }
+        Ok(path)
+    }
+}
+
+// Volume names in block pools are the machine name and the disk name up to
+// its extension, root for the boot disk. Machine names have no underscores,
+// so they can't run into each other.
+fn volume_name(machine: &str, name: &str) -> Result<String, Error> {
+    Ok(format!("{}_{}", machine, volume_stem(name)?))
+}
+
+fn volume_stem(name: &str) -> Result<&str, Error> {
+    let stem = name.split('.').next().unwrap_or_default();
+    let valid = !stem.is_empty()
+        && stem
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    match valid {
+        true => Ok(stem),
+        false => Err(format!("disk '{}' can't be named as a volume", name).into()),
+    }
+}
+
+// copy a base image onto a block device
+fn copy_base(base: (&Path, &str), dev: &Path) -> Result<(), Error> {
+    let mut cmd = qemu_img();
+    cmd.arg("convert")
+        .arg("-n")
+        .arg("-f")
+        .arg(base.1)
+        .arg("-O")
+        .arg("raw")
+        .arg(base.0)
+        .arg(dev);
+    run(cmd)
+}
+
+// size of a block volume: what was asked for, but never smaller than the
+// image copied onto it
+fn block_size(size: Option<u64>, base: Option<(&Path, &str)>) -> Result<u64, Error> {
+    let image = match base {
+        Some((path, _)) => qemu::Image {
+            path: path.to_path_buf(),
+        }
+        .virtual_size()?,
+        None => 0,
+    };
+    match size.unwrap_or(0).max(image) {
+        0 => Err("a volume without a base image needs a size".into()),
+        n => Ok(n),
+    }
+}
+
+// thin volumes in an lvm thin pool, allocated as the guest writes
+pub struct LvmPool {
+    volume_group: String,
+    thin_pool: String,
+}
+
+impl LvmPool {
+    fn lv(&self, machine: &str, name: &str) -> Result<String, Error> {
+        Ok(format!(
+            "{}/{}",
+            self.volume_group,
+            volume_name(machine, name)?
+        ))
+    }
+}
+
+impl Pool for LvmPool {
+    fn path(&self, machine: &str, name: &str) -> PathBuf {
+        let lv = volume_name(machine, name).unwrap_or_else(|_| name.to_string());
+        Path::new("/dev").join(&self.volume_group).join(lv)
+    }
+
+    // preallocation has no meaning for thin volumes
+    fn create(
+        &self,
+        machine: &str,
+        name: &str,
+        size: Option<u64>,
+        base: Option<(&Path, &str)>,
+        _preallocation: Option<Preallocation>,
+    ) -> Result<PathBuf, Error> {
+        let mut cmd = Command::new("lvcreate");
+        cmd.arg("-q")
+            .arg("-T")
+            .arg(format!("{}/{}", self.volume_group, self.thin_pool))
+            .arg("-V")
+            .arg(format!("{}b", block_size(size, base)?))
+            .arg("-n")
+            .arg(volume_name(machine, name)?);
+        run(cmd)?;
+
+        let dev = self.path(machine, name);
+        if let Some(base) = base {
+            if let Err(e) = copy_base(base, &dev) {
+                let _ = self.delete(machine, name);
+                return Err(e);
+            }
+        }
+        Ok(dev)
+    }
+
+    // thin snapshots outlive their origin, so they go first
+    fn delete(&self, machine: &str, name: &str) -> Result<(), Error> {
+        let lv = volume_name(machine, name)?;
+        let mut cmd = Command::new("lvremove");
+        cmd.arg("-q")
+            .arg("-y")
+            .arg("-S")
+            .arg(format!("origin={}", lv))
+            .arg(&self.volume_group);
+        run(cmd)?;
+        let mut cmd = Command::new("lvremove");
+        cmd.arg("-q").arg("-y").arg(self.lv(machine, name)?);
+        run(cmd)
+    }
+
+    fn resize(&self, machine: &str, name: &str, size: u64) -> Result<(), Error> {
+        let mut cmd = Command::new("lvextend");
+        cmd.arg("-q")
+            .arg("-L")
+            .arg(format!("{}b", size))
+            .arg(self.lv(machine, name)?);
+        run(cmd)
+    }
+
+    fn snapshot(&self, machine: &str, name: &str, snapshot: &str) -> Result<(), Error> {
+        let mut cmd = Command::new("lvcreate");
+        cmd.arg("-q")
+            .arg("-s")
+            .arg("-n")
+            .arg(format!("{}-{}", volume_name(machine, name)?, snapshot))
+            .arg(self.lv(machine, name)?);
+        run(cmd)
+    }
+
+    fn rename(&self, machine: &str, new: &str, name: &str) -> Result<(), Error> {
+        let mut cmd = Command::new("lvrename");
+        cmd.arg(&self.volume_group)
+            .arg(volume_name(machine, name)?)
+            .arg(volume_name(new, name)?);
+        run(cmd)
+    }
+
+    fn import(&self, machine: &str, name: &str, image: &Path) -> Result<PathBuf, Error> {
+        self.create(machine, name, None, Some((image, "qcow2")), None)
+    }
+}
+
+// zvols under a dataset, sparse unless fully preallocated
+pub struct ZfsPool {
+    dataset: String,
+}
+
+// zvol sizes have to be a multiple of the volume block size, which is at
+// most 128K
+const ZVOL_ALIGN: u64 = 128 * 1024;
+
+impl ZfsPool {
+    fn zvol(&self, machine: &str, name: &str) -> Result<String, Error> {
+        Ok(format!("{}/{}", self.dataset, volume_name(machine, name)?))
+    }
+}
+
+impl Pool for ZfsPool {
+    fn path(&self, machine: &str, name: &str) -> PathBuf {
+        let vol = volume_name(machine, name).unwrap_or_else(|_| name.to_string());
+        Path::new("/dev/zvol").join(&self.dataset).join(vol)
+    }
+
+    fn create(
+        &self,
+        machine: &str,
+        name: &str,
+        size: Option<u64>,
+        base: Option<(&Path, &str)>,
+        preallocation: Option<Preallocation>,
+    ) -> Result<PathBuf, Error> {
+        let size = block_size(size, base)?.div_ceil(ZVOL_ALIGN) * ZVOL_ALIGN;
+        let mut cmd = Command::new("zfs");
+        cmd.arg("create");
+        if preallocation != Some(Preallocation::Full) {
+            cmd.arg("-s");
+        }
+        cmd.arg("-V")
+            .arg(size.to_string())
+            .arg(self.zvol(machine, name)?);
+        run(cmd)?;
+
+        // udev creates the device node a little after zfs returns
+        let dev = self.path(machine, name);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !dev.exists() {
+            if Instant::now() > deadline {
+                let _ = self.delete(machine, name);
+                return Err(format!("zvol {} did not appear", dev.display()).into());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        if let Some(base) = base {
+            if let Err(e) = copy_base(base, &dev) {
+                let _ = self.delete(machine, name);
+                return Err(e);
+            }
+        }
+        Ok(dev)
+    }
+
+    // along with its snapshots
+    fn delete(&self, machine: &str, name: &str) -> Result<(), Error> {
+        let mut cmd = Command::new("zfs");
+        cmd.arg("destroy").arg("-r").arg(self.zvol(machine, name)?);
+        run(cmd)
+    }
+
+    fn resize(&self, machine: &str, name: &str, size: u64) -> Result<(), Error> {
+        let size = size.div_ceil(ZVOL_ALIGN) * ZVOL_ALIGN;
+        let mut cmd = Command::new("zfs");
+        cmd.arg("set")
+            .arg(format!("volsize={}", size))
+            .arg(self.zvol(machine, name)?);
+        run(cmd)
+    }
+
+    fn snapshot(&self, machine: &str, name: &str, snapshot: &str) -> Result<(), Error> {
+        let mut cmd = Command::new("zfs");
+        cmd.arg("snapshot")
+            .arg(format!("{}@{}", self.zvol(machine, name)?, snapshot));
+        run(cmd)
+    }
+
+    fn rename(&self, machine: &str, new: &str, name: &str) -> Result<(), Error> {
+        let mut cmd = Command::new("zfs");
+        cmd.arg("rename")
+            .arg(self.zvol(machine, name)?)
+            .arg(self.zvol(new, name)?);
+        run(cmd)
+    }
+
+    fn import(&self, machine: &str, name: &str, image: &Path) -> Result<PathBuf, Error> {
+        self.create(machine, name, None, Some((image, "qcow2")), None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::{DiskFile, Size};
+
+    #[test]
+    fn test_volume_paths() {
+        let lvm = LvmPool {
+            volume_group: "vg0".into(),
+            thin_pool: "thin".into(),
+        };
+        assert_eq!(
+            lvm.path("web1", ROOT_VOLUME),
+            PathBuf::from("/dev/vg0/web1_root")
+        );
+        assert_eq!(
+            lvm.path("web1", "localdisk01.qcow2"),
+            PathBuf::from("/dev/vg0/web1_localdisk01")
+        );
+        let zfs = ZfsPool {
+            dataset: "tank/bigiron".into(),
+        };
+        assert_eq!(
+            zfs.path("web1", ROOT_VOLUME),
+            PathBuf::from("/dev/zvol/tank/bigiron/web1_root")
+        );
+        assert!(is_block(&zfs.path("web1", ROOT_VOLUME)));
+        assert!(!is_block(Path::new(
+            "/var/lib/bigiron/libvirt/x/image.qcow2"
+        )));
+
+        assert!(volume_name("web1", "../x").is_err());
+        assert!(volume_name("web1", "data disk.qcow2").is_err());
+    }
+
+    #[test]
+    fn test_check_volumes() {
+        let mut spec: Spec = serde_yaml::from_str(
+            "
+            cpu: 1
+            memory: 1Gi
+            image:
+              url: file:///images/jammy.qcow2
+            storage:
+              - local: data.qcow2
+                size: 10Gi
+              - local: logs.qcow2
+                size: 10Gi
+            ",
+        )
+        .unwrap();
+        check_volumes(&spec).unwrap();
+
+        // the same stem as a disk, or as the boot disk
+        for local in ["data.raw", "root.qcow2"] {
+            spec.storage.as_mut().unwrap()[1] = StorageKind::DiskFile(DiskFile {
+                local: local.into(),
+                size: Size(10 << 30),
+                preallocation: None,
+            });
+            let e = check_volumes(&spec).unwrap_err().to_string();
+            assert!(e.contains(local), "{}", e);
+        }
+    }
+}
@@ -0,0 +1,733 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// Persistence of machine specs and image metadata. Disk images and other
+// per-machine files always live on the filesystem, only the records are
+// kept by the backend.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use hex;
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+
+use crate::config::{self, StoreKind};
+use crate::error::Error;
+use crate::imagerepo::Image;
+use crate::lockfile::{LockFile, LockFileGuard};
+use crate::models::Machine;
+
+const SYSTEM_DATA_DIR: &str = "/var/lib/bigiron";
+
+// true when not running as root: state is kept per user and machines run
+// under the user's libvirt session with user-mode networking
+pub fn unprivileged() -> bool {
+    unsafe { libc::getuid() != 0 }
+}
+
+// Where bigiron keeps its state, /var/lib/bigiron for root and
+// ~/.config/bigiron for everyone else.
+pub fn data_dir() -> PathBuf {
+    match std::env::var_os("HOME") {
+        Some(home) if unprivileged() => Path::new(&home).join(".config/bigiron"),
+        _ => PathBuf::from(SYSTEM_DATA_DIR),
+    }
+}
+
+pub fn default_db() -> PathBuf {
+    data_dir().join("state.db")
+}
+
+pub trait StoreBackend {
+    fn get_machine(&self, name: &str) -> Result<Option<Machine>, Error>;
+    fn list_machines(&self) -> Result<Vec<Machine>, Error>;
+    // fails if a machine with the same name exists
+    fn insert_machine(&self, machine: &Machine) -> Result<(), Error>;
+    fn update_machine(&self, machine: &Machine) -> Result<(), Error>;
+    fn remove_machine(&self, name: &str) -> Result<(), Error>;
+    // re-key the record of `old` under the name of `machine`, failing if
+    // that name is taken
+    fn rename_machine(&self, old: &str, machine: &Machine) -> Result<(), Error>;
+
+    fn get_image(&self, id: &str) -> Result<Option<Image>, Error>;
+    fn list_images(&self) -> Result<Vec<Image>, Error>;
+    fn put_image(&self, image: &Image) -> Result<(), Error>;
+    fn remove_image(&self, id: &str) -> Result<(), Error>;
+
+    // the repo image a machine's disk is layered on, None once flattened
+    fn get_backing(&self, machine: &str) -> Result<Option<String>, Error>;
+    fn set_backing(&self, machine: &str, image: Option<&str>) -> Result<(), Error>;
+    // machines with disks layered on the image
+    fn image_users(&self, id: &str) -> Result<Vec<String>, Error>;
+}
+
+// the backend selected in the config file
+pub fn open() -> Result<Box<dyn StoreBackend>, Error> {
+    let cfg = config::load()?;
+    Ok(match cfg.store.backend {
+        StoreKind::File => Box::new(FileBackend::new(data_dir())),
+        StoreKind::Sqlite => {
+            let path = cfg.store.path.unwrap_or_else(default_db);
+            Box::new(SqliteBackend::open(path)?)
+        }
+    })
+}
+
+pub fn get_unique_id(name: &str) -> String {
+    let mut h = Sha256::new();
+    h.update(name.as_bytes());
+    let r = h.finalize();
+    let mut h = hex::encode(r);
+
+    // truncate to 128 bits (32 chars) so we can interoperate with UUIDs.
+    // this does not affect the uniqueness enough to be an issue here.
+    // obviously, do not do this if used for crypto
+    h.truncate(32);
+    h
+}
+
+// serializes mutations of the file backend, under the data directory
+pub const LOCK_FILE: &str = "store.lock";
+
+// how long store mutations wait for other writers
+const LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The original layout: a directory per machine holding spec.yaml, and a
+// <id>.json file next to each image in the image repo. Mutations are
+// serialized with a lockfile, readers never see a half written machine as
+// its directory is only renamed into place once complete.
+pub struct FileBackend {
+    machines: PathBuf,
+    images: PathBuf,
+    lock: LockFile,
+}
+
+impl FileBackend {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            machines: root.as_ref().join("libvirt"),
+            images: root.as_ref().join("images"),
+            lock: LockFile::new(root.as_ref().join(LOCK_FILE)),
+        }
+    }
+
+    fn lock(&self) -> Result<LockFileGuard<'_>, Error> {
+        if let Some(parent) = self.machines.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        self.lock.acquire_timeout(LOCK_TIMEOUT)
+    }
+
+    // scratch directory next to the machines, on the same filesystem
+    fn tmp_path(&self, name: &str) -> PathBuf {
+        self.machines.join(format!(
+            ".{}.{}.tmp",
+            get_unique_id(name),
+            std::process::id()
+        ))
+    }
+
+    fn spec_path(&self, name: &str) -> PathBuf {
+        self.machines.join(get_unique_id(name)).join("spec.yaml")
+    }
+
+    fn backing_path(&self, name: &str) -> PathBuf {
+        self.machines.join(get_unique_id(name)).join("backing")
+    }
+
+    // replace the spec file atomically
+    fn write_machine(&self, machine: &Machine) -> Result<(), Error> {
+        let buf = serde_yaml::to_string(machine)?;
+        let sp = self.spec_path(&machine.name);
+        let tmp = sp.with_extension("yaml.tmp");
+        std::fs::write(&tmp, buf)?;
+        std::fs::rename(tmp, sp)?;
+        Ok(())
+    }
+}
+
+fn machine_from_file(path: &Path) -> Result<Machine, Error> {
+    let buf = std::fs::read_to_string(path)?;
+    serde_yaml::from_str(&buf)
+        .map_err(|e| format!("error parsing {}: {}", path.display(), e).into())
+}
+
+impl StoreBackend for FileBackend {
+    fn get_machine(&self, name: &str) -> Result<Option<Machine>, Error> {
+        let sp = self.spec_path(name);
+        if !sp.exists() {
+            return Ok(None);
+        }
+        Ok(Some(machine_from_file(&sp)?))
+    }
+
+    fn list_machines(&self) -> Result<Vec<Machine>, Error> {
+        let mut r = Vec::new();
+        if !self.machines.exists() {
+            return Ok(r);
+        }
+        for entry in self.machines.read_dir()? {
+            let entry = entry?;
+            // skip creates and removes in progress
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let sp = entry.path().join("spec.yaml");
+            // directories without a spec are leftovers of failed creates
+            if sp.exists() {
+                r.push(machine_from_file(&sp)?);
+            }
+        }
+        Ok(r)
+    }
+
+    fn insert_machine(&self, machine: &Machine) -> Result<(), Error> {
+        let _lock = self.lock()?;
+        if self.get_machine(&machine.name)?.is_some() {
+            return Err("Machine with name already exists".into());
+        }
+
+        let tmp = self.tmp_path(&machine.name);
+        if tmp.exists() {
+            std::fs::remove_dir_all(&tmp)?;
+        }
+        std::fs::create_dir_all(&tmp)?;
+        std::fs::write(tmp.join("spec.yaml"), serde_yaml::to_string(machine)?)?;
+
+        // rename only replaces an empty directory, a leftover from a failed
+        // create with files in it has to be cleaned up by hand
+        let dest = self.machines.join(get_unique_id(&machine.name));
+        if let Err(e) = std::fs::rename(&tmp, &dest) {
+            let _ = std::fs::remove_dir_all(&tmp);
+            return Err(format!("error creating {}: {}", dest.display(), e).into());
+        }
+        Ok(())
+    }
+
+    fn update_machine(&self, machine: &Machine) -> Result<(), Error> {
+        let _lock = self.lock()?;
+        if self.get_machine(&machine.name)?.is_none() {
+            return Err(format!("No machine with id='{}'", &machine.name).into());
+        }
+        self.write_machine(machine)
+    }
+
+    fn remove_machine(&self, name: &str) -> Result<(), Error> {
+        let _lock = self.lock()?;
+        if !self.spec_path(name).exists() {
+            return Err(format!("No machine with id='{}'", name).into());
+        }
+
+        // move it out of the way first so it disappears in one step
+        let tmp = self.tmp_path(name);
+        std::fs::rename(self.machines.join(get_unique_id(name)), &tmp)?;
+        std::fs::remove_dir_all(tmp)?;
+        Ok(())
+    }
+
+    // the record shares its directory with the machine's disks, so they
+    // move along with it
+    fn rename_machine(&self, old: &str, machine: &Machine) -> Result<(), Error> {
+        let _lock = self.lock()?;
+        if !self.spec_path(old).exists() {
+            return Err(format!("No machine with id='{}'", old).into());
+        }
+        let dest = self.machines.join(get_unique_id(&machine.name));
+        if dest.exists() {
+            return Err("Machine with name already exists".into());
+        }
+        std::fs::rename(self.machines.join(get_unique_id(old)), &dest)?;
+        self.write_machine(machine)
+    }
+
+    fn get_image(&self, id: &str) -> Result<Option<Image>, Error> {
+        let imf = self.images.join(format!("{}.json", id));
+        if !imf.exists() {
+            return Ok(None);
+        }
+        let f = std::fs::File::open(&imf)?;
+        Ok(Some(serde_yaml::from_reader(&f)?))
+    }
+
+    fn list_images(&self) -> Result<Vec<Image>, Error> {
+        let mut r = Vec::new();
+        if !self.images.exists() {
+            return Ok(r);
+        }
+        for entry in self.images.read_dir()? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some("json") {
+                let f = std::fs::File::open(&path)?;
+                r.push(serde_yaml::from_reader(&f)?);
+            }
+        }
+        Ok(r)
+    }
+
+    fn put_image(&self, image: &Image) -> Result<(), Error> {
+        let _lock = self.lock()?;
+        std::fs::create_dir_all(&self.images)?;
+        let imf = self.images.join(format!("{}.json", image.id));
+        std::fs::write(imf, serde_yaml::to_string(image)?)?;
+        Ok(())
+    }
+
+    fn remove_image(&self, id: &str) -> Result<(), Error> {
+        let _lock = self.lock()?;
+        let imf = self.images.join(format!("{}.json", id));
+        if !imf.exists() {
+            return Err(format!("No image with id='{}'", id).into());
+        }
+        std::fs::remove_file(imf)?;
+        Ok(())
+    }
+
+    fn get_backing(&self, machine: &str) -> Result<Option<String>, Error> {
+        match std::fs::read_to_string(self.backing_path(machine)) {
+            Ok(id) => Ok(Some(id.trim().to_string())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set_backing(&self, machine: &str, image: Option<&str>) -> Result<(), Error> {
+        let _lock = self.lock()?;
+        if !self.spec_path(machine).exists() {
+            return Err(format!("No machine with id='{}'", machine).into());
+        }
+        let path = self.backing_path(machine);
+        match image {
+            Some(id) => std::fs::write(path, id)?,
+            None if path.exists() => std::fs::remove_file(path)?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn image_users(&self, id: &str) -> Result<Vec<String>, Error> {
+        let mut r = Vec::new();
+        for m in self.list_machines()? {
+            if self.get_backing(&m.name)?.as_deref() == Some(id) {
+                r.push(m.name);
+            }
+        }
+        r.sort();
+        Ok(r)
+    }
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS machines (
+    name TEXT PRIMARY KEY,
+    project TEXT,
+    spec TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS machines_project ON machines (project);
+CREATE TABLE IF NOT EXISTS images (
+    id TEXT PRIMARY KEY,
+    path TEXT NOT NULL,
+    origin TEXT NOT NULL,
+    format TEXT NOT NULL,
+    arch TEXT
+);
+CREATE TABLE IF NOT EXISTS backing (
+    machine TEXT PRIMARY KEY,
+    image TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS backing_image ON backing (image);
+";
+
+// Machines are kept as their serialized yaml so new spec fields need no
+// schema changes, columns are only broken out for lookups.
+pub struct SqliteBackend {
+    path: PathBuf,
+}
+
+impl SqliteBackend {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let b = Self {
+            path: path.as_ref().to_path_buf(),
+        };
+        b.connect()?;
+        Ok(b)
+    }
+
+    fn connect(&self) -> Result<Connection, Error> {
+        let conn = Connection::open(&self.path)?;
+        conn.busy_timeout(Duration::from_secs(10))?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(conn)
+    }
+}
+
+impl StoreBackend for SqliteBackend {
+    fn get_machine(&self, name: &str) -> Result<Option<Machine>, Error> {
+        let conn = self.connect()?;
+        let spec = conn
+            .query_row(
+                "SELECT spec FROM machines WHERE name = ?1",
+                params![name],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+        match spec {
+            Some(buf) => Ok(Some(serde_yaml::from_str(&buf)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn list_machines(&self) -> Result<Vec<Machine>, Error> {
+        let conn = self.connect()?;
+        let mut stmt = conn.prepare("SELECT spec FROM machines ORDER BY name")?;
+        let rows = stmt.query_map(params![], |row| row.get::<_, String>(0))?;
+        let mut r = Vec::new();
+        for buf in rows {
+            r.push(serde_yaml::from_str(&buf?)?);
+        }
+        Ok(r)
+    }
+
+    fn insert_machine(&self, machine: &Machine) -> Result<(), Error> {
+        let conn = self.connect()?;
+        // the primary key makes this atomic against concurrent applies
+        let n = conn.execute(
+            "INSERT OR IGNORE INTO machines (name, project, spec) VALUES (?1, ?2, ?3)",
+            params![
+                machine.name,
+                machine.project,
+                serde_yaml::to_string(machine)?
+            ],
+        )?;
+        if n == 0 {
+            return Err("Machine with name already exists".into());
+        }
+        Ok(())
+    }
+
+    fn update_machine(&self, machine: &Machine) -> Result<(), Error> {
+        let conn = self.connect()?;
+        let n = conn.execute(
+            "UPDATE machines SET project = ?2, spec = ?3 WHERE name = ?1",
+            params![
+                machine.name,
+                machine.project,
+                serde_yaml::to_string(machine)?
+            ],
+        )?;
+        if n == 0 {
+            return Err(format!("No machine with id='{}'", &machine.name).into());
+        }
+        Ok(())
+    }
+
+    fn remove_machine(&self, name: &str) -> Result<(), Error> {
+        let conn = self.connect()?;
+        let n = conn.execute("DELETE FROM machines WHERE name = ?1", params![name])?;
+        if n == 0 {
+            return Err(format!("No machine with id='{}'", name).into());
+        }
+        conn.execute("DELETE FROM backing WHERE machine = ?1", params![name])?;
+        Ok(())
+    }
+
+    fn rename_machine(&self, old: &str, machine: &Machine) -> Result<(), Error> {
+        let mut conn = self.connect()?;
+        let tx = conn.transaction()?;
+        // the primary key refuses a name that is taken
+        let n = tx.execute(
+            "UPDATE machines SET name = ?2, project = ?3, spec = ?4 WHERE name = ?1",
+            params![
+                old,
+                machine.name,
+                machine.project,
+                serde_yaml::to_string(machine)?
+            ],
+        )?;
+        if n == 0 {
+            return Err(format!("No machine with id='{}'", old).into());
+        }
+        tx.execute(
+            "UPDATE backing SET machine = ?2 WHERE machine = ?1",
+            params![old, machine.name],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_image(&self, id: &str) -> Result<Option<Image>, Error> {
+        let conn = self.connect()?;
+        Ok(conn
+            .query_row(
+                "SELECT id, path, origin, format, arch FROM images WHERE id = ?1",
+                params![id],
+                image_from_row,
+            )
+            .optional()?)
+    }
+
+    fn list_images(&self) -> Result<Vec<Image>, Error> {
+        let conn = self.connect()?;
+        let mut stmt =
+            conn.prepare("SELECT id, path, origin, format, arch FROM images ORDER BY id")?;
+        let rows = stmt.query_map(params![], image_from_row)?;
+        let mut r = Vec::new();
+        for img in rows {
+            r.push(img?);
+        }
+        Ok(r)
+    }
+
+    fn put_image(&self, image: &Image) -> Result<(), Error> {
+        let conn = self.connect()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO images (id, path, origin, format, arch) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                image.id,
+                image.path.to_string_lossy().to_string(),
+                image.origin,
+                image.format,
+                image.arch
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn remove_image(&self, id: &str) -> Result<(), Error> {
+        let conn = self.connect()?;
+        let n = conn.execute("DELETE FROM images WHERE id = ?1", params![id])?;
+        if n == 0 {
+            return Err(format!("No image with id='{}'", id).into());
+        }
+        Ok(())
+    }
+
+    fn get_backing(&self, machine: &str) -> Result<Option<String>, Error> {
+        let conn = self.connect()?;
+        Ok(conn
+            .query_row(
+                "SELECT image FROM backing WHERE machine = ?1",
+                params![machine],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn set_backing(&self, machine: &str, image: Option<&str>) -> Result<(), Error> {
+        let conn = self.connect()?;
+        match image {
+            Some(id) => {
+                // only for machines that exist, like the file backend
+                let n = conn.execute(
+                    "INSERT OR REPLACE INTO backing (machine, image) \
+                     SELECT name, ?2 FROM machines WHERE name = ?1",
+                    params![machine, id],
+                )?;
+                if n == 0 {
+                    return Err(format!("No machine with id='{}'", machine).into());
+                }
+            }
+            None => {
+                conn.execute("DELETE FROM backing WHERE machine = ?1", params![machine])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn image_users(&self, id: &str) -> Result<Vec<String>, Error> {
+        let conn = self.connect()?;
+        let mut stmt =
+            conn.prepare("SELECT machine FROM backing WHERE image = ?1 ORDER BY machine")?;
+        let rows = stmt.query_map(params![id], |row| row.get::<_, String>(0))?;
+        let mut r = Vec::new();
+        for name in rows {
+            r.push(name?);
+        }
+        Ok(r)
+    }
+}
+
+fn image_from_row(row: &rusqlite::Row) -> rusqlite::Result<Image> {
+    Ok(Image {
+        id: row.get(0)?,
+        path: PathBuf::from(row.get::<_, String>(1)?),
+        origin: row.get(2)?,
+        format: row.get(3)?,
+        arch: row.get(4)?,
+    })
+}
+
+// Copy every record from the file layout into a new sqlite database. The
+// database is built next to `db` and renamed into place once complete.
+pub fn migrate_to_sqlite<P: AsRef<Path>>(
+    from: &dyn StoreBackend,
+    db: P,
+) -> Result<(usize, usize), Error> {
+    let db = db.as_ref();
+    if db.exists() {
+        return Err(format!("{} already exists", db.display()).into());
+    }
+
+    let tmp = db.with_extension("db.tmp");
+    if tmp.exists() {
+        std::fs::remove_file(&tmp)?;
+    }
+    let to = SqliteBackend::open(&tmp)?;
+
+    let machines = from.list_machines()?;
+    for m in &machines {
+        to.insert_machine(m)?;
+        to.set_backing(&m.name, from.get_backing(&m.name)?.as_deref())?;
+    }
+    let images = from.list_images()?;
+    for img in &images {
+        to.put_image(img)?;
+    }
+
+    if to.list_machines()?.len() != machines.len() || to.list_images()?.len() != images.len() {
+        return Err("records read back from the new database do not match".into());
+    }
+
+    std::fs::rename(&tmp, db)?;
+    Ok((machines.len(), images.len()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_get_unique_id() {
+        let name = "test1234";
+        let id = get_unique_id(name);
+        assert_eq!(id, "937e8d5fbb48bd4949536cd65b8d35c4");
+
+        let name = "test1324";
+        let id = get_unique_id(name);
+        assert_eq!(id, "9884aab1d7385f53a0e96bac13b6d7b5");
+    }
+
+    #[test]
+    fn test_concurrent_insert() {
+        let base = TestDir::new("race");
+        std::fs::create_dir_all(base.join("libvirt")).unwrap();
+
+        let m: Machine = serde_yaml::from_str(
+            "
+            name: web1
+            status: null
+            spec:
+              cpu: 1
+              memory: 1G
+              image:
+                url: file:///images/jammy.qcow2
+            ",
+        )
+        .unwrap();
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let (base, m) = (base.to_path_buf(), m.clone());
+                std::thread::spawn(move || FileBackend::new(base).insert_machine(&m).is_ok())
+            })
+            .collect();
+        let created = handles
+            .into_iter()
+            .filter_map(|h| h.join().unwrap().then_some(()))
+            .count();
+        assert_eq!(created, 1);
+
+        let backend = FileBackend::new(&base);
+        assert_eq!(backend.list_machines().unwrap().len(), 1);
+
+        let mut renamed = m.clone();
+        renamed.name = "web2".into();
+        backend.rename_machine("web1", &renamed).unwrap();
+        assert!(backend.get_machine("web1").unwrap().is_none());
+        assert_eq!(backend.get_machine("web2").unwrap().unwrap().name, "web2");
+        backend.insert_machine(&m).unwrap();
+        assert!(backend.rename_machine("web1", &renamed).is_err());
+        backend.remove_machine("web2").unwrap();
+
+        backend.remove_machine("web1").unwrap();
+        assert!(backend.get_machine("web1").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_migrate_to_sqlite() {
+        let base = TestDir::new("store");
+        let files = FileBackend::new(&base);
+
+        let m: Machine = serde_yaml::from_str(
+            "
+            name: web1
+            project: lab
+            status: null
+            spec:
+              cpu: 2
+              memory: 4G
+              image:
+                url: file:///images/jammy.qcow2
+            ",
+        )
+        .unwrap();
+        files.insert_machine(&m).unwrap();
+        assert!(files.insert_machine(&m).is_err());
+        files
+            .put_image(&Image {
+                id: "abc".into(),
+                path: "/var/lib/bigiron/images/abc".into(),
+                origin: "file:///images/jammy.qcow2".into(),
+                format: "qcow2".into(),
+                arch: Some("x86_64".into()),
+            })
+            .unwrap();
+        files.set_backing("web1", Some("abc")).unwrap();
+        assert_eq!(files.image_users("abc").unwrap(), vec!["web1"]);
+
+        let db = base.join("state.db");
+        assert_eq!(migrate_to_sqlite(&files, &db).unwrap(), (1, 1));
+        assert!(migrate_to_sqlite(&files, &db).is_err());
+
+        let sql = SqliteBackend::open(&db).unwrap();
+        assert_eq!(
+            sql.get_machine("web1").unwrap().unwrap().project.as_deref(),
+            Some("lab")
+        );
+        assert!(sql.insert_machine(&m).is_err());
+        assert_eq!(sql.image_users("abc").unwrap(), vec!["web1"]);
+        let mut renamed = sql.get_machine("web1").unwrap().unwrap();
+        renamed.name = "web3".into();
+        sql.rename_machine("web1", &renamed).unwrap();
+        assert!(sql.get_machine("web1").unwrap().is_none());
+        assert_eq!(sql.image_users("abc").unwrap(), vec!["web3"]);
+        renamed.name = "web1".into();
+        sql.rename_machine("web3", &renamed).unwrap();
+        sql.set_backing("web1", None).unwrap();
+        assert!(sql.image_users("abc").unwrap().is_empty());
+        assert_eq!(
+            sql.get_image("abc").unwrap().unwrap().arch.as_deref(),
+            Some("x86_64")
+        );
+        sql.remove_image("abc").unwrap();
+        assert!(sql.get_image("abc").unwrap().is_none());
+
+        sql.remove_machine("web1").unwrap();
+        assert!(sql.get_machine("web1").unwrap().is_none());
+    }
+}
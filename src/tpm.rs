@@ -0,0 +1,110 @@
+//  Copyright (C) 2022 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+// TPM 2.0 emulation: a swtpm process per machine, keeping the TPM's state in
+// the machine directory and serving it to qemu over a unix socket. Like the
+// sol proxy it outlives the cli and restarts of the guest, and is stopped
+// when the machine goes away.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::error::Error;
+
+const SWTPM: &str = "swtpm";
+
+pub fn socket_path(machine_dir: &Path) -> PathBuf {
+    machine_dir.join("swtpm.sock")
+}
+
+// where swtpm keeps the TPM's persistent state, the machine's keys and
+// measurements survive restarts of the machine this way
+pub fn state_dir(machine_dir: &Path) -> PathBuf {
+    machine_dir.join("tpm")
+}
+
+fn pid_path(machine_dir: &Path) -> PathBuf {
+    machine_dir.join("swtpm.pid")
+}
+
+// Start swtpm for a machine unless it is already running, returning the
+// socket for its tpm device. Waits for the socket, qemu fails without it.
+pub fn spawn(machine_dir: &Path) -> Result<PathBuf, Error> {
+    let socket = socket_path(machine_dir);
+    if running(machine_dir).is_some() {
+        return Ok(socket);
+    }
+    let state = state_dir(machine_dir);
+    std::fs::create_dir_all(&state)?;
+    let _ = std::fs::remove_file(&socket);
+
+    let log_path = machine_dir.join("swtpm.log");
+    let log = File::options().append(true).create(true).open(&log_path)?;
+    let mut child = Command::new(SWTPM)
+        .arg("socket")
+        .arg("--tpm2")
+        .arg("--tpmstate")
+        .arg(format!("dir={}", state.display()))
+        .arg("--ctrl")
+        .arg(format!("type=unixio,path={}", socket.display()))
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .spawn()
+        .map_err(|e| format!("error starting {}: {}", SWTPM, e))?;
+    std::fs::write(pid_path(machine_dir), child.id().to_string())?;
+
+    for _ in 0..50 {
+        if socket.exists() {
+            return Ok(socket);
+        }
+        if let Some(status) = child.try_wait()? {
+            let _ = std::fs::remove_file(pid_path(machine_dir));
+            return Err(format!(
+                "{} exited with {}, see {}",
+                SWTPM,
+                status,
+                log_path.display()
+            )
+            .into());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Err(format!("{} did not create {}", SWTPM, socket.display()).into())
+}
+
+// stop swtpm, keeping the state for when the machine starts again
+pub fn stop(machine_dir: &Path) {
+    if let Some(pid) = running(machine_dir) {
+        unsafe { libc::kill(pid, libc::SIGTERM) };
+    }
+    let _ = std::fs::remove_file(pid_path(machine_dir));
+}
+
+fn running(machine_dir: &Path) -> Option<i32> {
+    let pid = std::fs::read_to_string(pid_path(machine_dir))
+        .ok()?
+        .trim()
+        .parse::<i32>()
+        .ok()?;
+    match unsafe { libc::kill(pid, 0) } {
+        0 => Some(pid),
+        _ => None,
+    }
+}
@@ -16,16 +16,26 @@
 //  USA
 
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::Read;
 use std::path::{Path, PathBuf};
 
-use libc;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use serde_json;
-use tracing::warn;
+use tracing::{info, warn};
+use url::Url;
 use uuid::Uuid;
 
 use crate::error::Error;
+use crate::host::cgroup;
+use crate::machinelog;
+use crate::models::{check_name, Graphics, Image, Machine, Share, Size, Spec};
+use crate::store;
+
+const SPEC_FILE: &str = "spec.yaml";
+// vms defined before admin took machine specs, see migrate_legacy
+const LEGACY_SPEC_FILE: &str = "spec.json";
+// path of the image in the cdrom drive, absent when empty
+const MEDIA_FILE: &str = "vmedia";
 
 #[derive(Debug, Clone)]
 pub struct VMSet {
@@ -54,65 +64,225 @@ impl VMSet {
 
     pub fn get(&self, id: &str) -> Result<VM, Error> {
         let vmpath = self.path.join(&id);
-        let specpath = vmpath.join("spec.json");
+        let specpath = vmpath.join(SPEC_FILE);
 
+        if vmpath.exists() && !specpath.exists() && vmpath.join(LEGACY_SPEC_FILE).exists() {
+            migrate_legacy(&vmpath)?;
+        }
         if !vmpath.exists() || !specpath.exists() {
             return Err(format!("No VM for id={} found", id).into());
         }
 
-        let r = File::open(&specpath)?;
-        let mut vm: VM = serde_json::from_reader(&r)?;
-        vm.path = vmpath;
+        let machine: Machine = serde_yaml::from_str(&std::fs::read_to_string(&specpath)?)?;
 
-        Ok(vm)
+        Ok(VM {
+            id: id.to_string(),
+            machine,
+            path: vmpath,
+        })
     }
 
-    pub fn define(&self, spec: Spec) -> VM {
+    pub fn define(&self, mut machine: Machine) -> Result<VM, Error> {
+        validate(&machine)?;
+        // qemu runs from /, a relative path would not resolve there
+        let image = image_path(&machine.spec)?.canonicalize()?;
+        machine.spec.image.url = Some(file_url(&image)?);
+        let media = match &machine.spec.cdrom {
+            Some(cdrom) => Some(local_path(cdrom)?.canonicalize()?),
+            None => None,
+        };
+        let ignored = unsupported(&machine.spec);
+        if !ignored.is_empty() {
+            warn!(
+                "ignoring fields not supported by the qemu driver: {}",
+                ignored.join(", ")
+            );
+        }
+
         let id = Uuid::new_v4().to_string();
         let path = self.path.join(&id);
-        let vm = VM { id, spec, path };
+        let vm = VM { id, machine, path };
 
         std::fs::create_dir_all(&vm.path()).expect("error creating vm directory");
+        vm.save()?;
+        // in the drive until ejected, across restarts
+        if let Some(media) = media {
+            std::fs::write(vm.path.join(MEDIA_FILE), media.display().to_string())?;
+        }
 
-        let mut f = File::options()
-            .create(true)
-            .write(true)
-            .open(&vm.spec_path())
-            .unwrap();
-        write!(f, "{}", serde_json::to_string(&vm).unwrap()).unwrap();
+        Ok(vm)
+    }
+}
 
-        vm
+fn validate(machine: &Machine) -> Result<(), Error> {
+    check_name(&machine.name)?;
+    if machine.spec.cpu == 0 || machine.spec.memory.bytes() == 0 {
+        return Err("vm needs at least one cpu and some memory".into());
     }
+    let image = image_path(&machine.spec)?;
+    if !image.is_file() {
+        return Err(format!("image {} does not exist", image.display()).into());
+    }
+    if let Some(cdrom) = &machine.spec.cdrom {
+        let media = local_path(cdrom)?;
+        if !media.is_file() {
+            return Err(format!("media image {} does not exist", media.display()).into());
+        }
+    }
+    Ok(())
+}
+
+// the qemu driver boots a local image, given as a path or file:// url
+pub fn image_path(spec: &Spec) -> Result<PathBuf, Error> {
+    let image = spec
+        .image
+        .url
+        .as_deref()
+        .ok_or("the qemu driver needs a local image url")?;
+    local_path(image)
+}
+
+fn local_path(image: &str) -> Result<PathBuf, Error> {
+    match Url::parse(image) {
+        Ok(url) if url.scheme() == "file" => Ok(url
+            .to_file_path()
+            .map_err(|_| format!("invalid file url {}", image))?),
+        Ok(url) => Err(format!(
+            "image url scheme not supported by the qemu driver: {}",
+            url.scheme()
+        )
+        .into()),
+        Err(_) => Ok(PathBuf::from(image)),
+    }
+}
+
+fn file_url(path: &Path) -> Result<String, Error> {
+    Url::from_file_path(path)
+        .map(|u| u.to_string())
+        .map_err(|_| format!("image path {} is not absolute", path.display()).into())
+}
+
+// spec fields that only the libvirt driver acts on
+fn unsupported(spec: &Spec) -> Vec<&'static str> {
+    let fields = [
+        ("maxCpu", spec.max_cpu.is_some()),
+        ("maxMemory", spec.max_memory.is_some()),
+        ("image.name", spec.image.name.is_some()),
+        ("image.resize", spec.image.resize.is_some()),
+        ("image.sha256", spec.image.sha256.is_some()),
+        ("image.fromMachine", spec.image.from_machine.is_some()),
+        ("storage", spec.storage.is_some()),
+        ("network", spec.network.is_some()),
+        ("ip", spec.ip.is_some()),
+        ("mac", spec.mac.is_some()),
+        ("portForwards", spec.port_forwards.is_some()),
+        ("numa", spec.numa.is_some()),
+        ("devices", spec.devices.is_some()),
+        ("mirror", spec.mirror.is_some()),
+        ("priorityClass", spec.priority_class.is_some()),
+        ("placement", spec.placement.is_some()),
+        ("netboot", spec.netboot.is_some()),
+        ("sol", spec.sol.is_some()),
+        ("crashConsole", spec.crash_console.is_some()),
+        ("tpm", spec.tpm.is_some()),
+        ("restartPolicy", spec.restart_policy.is_some()),
+        ("storagePool", spec.storage_pool.is_some()),
+        (
+            "qos.netBandwidth",
+            spec.qos.as_ref().is_some_and(|q| q.net_bandwidth.is_some()),
+        ),
+    ];
+    fields
+        .into_iter()
+        .filter(|(_, set)| *set)
+        .map(|(name, _)| name)
+        .collect()
+}
+
+// rewrite a spec.json from before admin took machine specs into a spec.yaml,
+// keeping the old file aside as spec.json.orig
+fn migrate_legacy(vmpath: &Path) -> Result<(), Error> {
+    let legacy = vmpath.join(LEGACY_SPEC_FILE);
+    let vm: LegacyVM = serde_json::from_reader(File::open(&legacy)?)?;
+    let machine = vm.spec.into_machine()?;
+
+    std::fs::write(vmpath.join(SPEC_FILE), machine.to_yaml()?)?;
+    std::fs::rename(&legacy, vmpath.join("spec.json.orig"))?;
+    info!("migrated {} to {}", legacy.display(), SPEC_FILE);
+    Ok(())
 }
 
 impl Default for VMSet {
     fn default() -> Self {
-        let uid = unsafe { libc::getuid() };
-        let mut path = PathBuf::new();
-        if uid != 0 {
-            path.push(&std::env::var("HOME").unwrap());
-            path.push(".config/bigiron");
-        } else {
-            path.push("/var/lib/bigiron");
-        }
-        Self::new(path)
+        Self::new(store::data_dir())
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Spec {
-    pub name: String,
-    pub cpus: u32,
-    pub memory_mb: u64,
-    pub image: PathBuf,
+#[derive(Deserialize)]
+struct LegacyVM {
+    spec: LegacySpec,
+}
+
+#[derive(Deserialize)]
+struct LegacySpec {
+    name: String,
+    cpus: u32,
+    memory_mb: u64,
+    image: PathBuf,
+    #[serde(default)]
+    graphics: Option<Graphics>,
+    #[serde(default)]
+    machine_type: Option<String>,
+    #[serde(default)]
+    cpu_model: Option<String>,
+    #[serde(default)]
+    cpu_features: Vec<String>,
+    // back memory with hugepages from the default hugetlbfs mount
+    #[serde(default)]
+    hugepages: bool,
+    #[serde(default)]
+    shares: Vec<Share>,
+    // internal snapshot of the image to revert to on every start
+    #[serde(default)]
+    revert_on_boot: Option<String>,
+    #[serde(default)]
+    timezone: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl LegacySpec {
+    fn into_machine(self) -> Result<Machine, Error> {
+        let spec = Spec {
+            cpu: self.cpus,
+            memory: Size(self.memory_mb * 1024 * 1024),
+            image: Image {
+                url: Some(file_url(&self.image)?),
+                ..Default::default()
+            },
+            graphics: self.graphics,
+            machine_type: self.machine_type,
+            cpu_model: self.cpu_model,
+            cpu_features: Some(self.cpu_features).filter(|f| !f.is_empty()),
+            // the old flag used the default hugetlbfs mount, 2Mi pages on x86
+            hugepages: self.hugepages.then_some(Size(2 * 1024 * 1024)),
+            shares: Some(self.shares).filter(|s| !s.is_empty()),
+            revert_on_boot: self.revert_on_boot,
+            timezone: self.timezone,
+            ..Default::default()
+        };
+        Ok(Machine {
+            name: self.name,
+            project: None,
+            labels: Default::default(),
+            status: None,
+            spec,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct VM {
     id: String,
-    spec: Spec,
-
-    #[serde(skip)]
+    machine: Machine,
     path: PathBuf,
 }
 
@@ -124,18 +294,22 @@ impl VM {
             return Err("VM already started".into());
         }
 
-        let p = qemu::Process::new(
-            &self.path,
-            &self.spec.name,
-            self.spec.cpus,
-            self.spec.memory_mb,
-            &self.id,
-            qemu::Image {
-                path: self.spec.image.clone(),
-            },
-        );
+        let spec = &self.machine.spec;
+        let mut hw = qemu::Hardware::from_spec(spec)?;
+        hw.cdrom = self.media()?;
+        hw.probe()?;
+
+        let image = qemu::Image {
+            path: image_path(spec)?,
+        };
+        if let Some(snapshot) = &spec.revert_on_boot {
+            image.revert_on_boot(snapshot)?;
+        }
+
+        let p = qemu::Process::new(&self.path, &self.machine.name, &self.id, image, hw);
 
         p.launch();
+        machinelog::record(&self.path, "started");
         Ok(())
     }
 
@@ -153,11 +327,20 @@ impl VM {
         let _ = pfile.read_to_string(&mut pst).unwrap();
         let pid = pst.parse::<u32>().expect("error parsing PID file contents");
 
-        if !PathBuf::new().join(format!("/proc/{}", pid)).exists() {
-            return false;
+        // the pid may belong to another process by now if qemu exited
+        // without its supervisor cleaning up
+        match std::fs::read(format!("/proc/{}/cmdline", pid)) {
+            Ok(cmdline) => String::from_utf8_lossy(&cmdline).contains(&self.id),
+            Err(_) => false,
         }
+    }
 
-        true
+    // how qemu exited the last time the VM stopped, None while it runs
+    pub fn exit_status(&self) -> Option<String> {
+        if self.running() {
+            return None;
+        }
+        std::fs::read_to_string(self.path.join(qemu::EXIT_STATUS_FILE)).ok()
     }
 
     fn monitor(&self) -> Result<qemu::Monitor, Error> {
@@ -168,20 +351,95 @@ impl VM {
         qemu::Monitor::connect(monp)
     }
 
+    // the guest agent, if one is running in the guest
+    pub fn agent(&self) -> Result<qemu::agent::Agent, Error> {
+        if !self.running() {
+            return Err("VM not started".into());
+        }
+        qemu::agent::Agent::connect(self.path.join("agent.sock"))
+    }
+
     pub fn destroy(&self) -> Result<(), Error> {
-        self.monitor()?.quit()
+        self.monitor()?.quit()?;
+        qemu::stop_virtiofsd(&self.path);
+        machinelog::record(&self.path, "destroyed");
+        Ok(())
     }
 
     pub fn stop(&self) -> Result<(), Error> {
-        self.monitor()?.stop()
+        self.monitor()?.stop()?;
+        machinelog::record(&self.path, "paused");
+        Ok(())
     }
 
     pub fn cont(&self) -> Result<(), Error> {
-        self.monitor()?.cont()
+        self.monitor()?.cont()?;
+        machinelog::record(&self.path, "resumed");
+        Ok(())
     }
 
+    // lifecycle events and qemu output, oldest first
+    pub fn logs(&self) -> Result<Vec<machinelog::Entry>, Error> {
+        self.sync_logs()?;
+        machinelog::read(&self.path)
+    }
+
+    pub fn follow_logs<F: FnMut(&machinelog::Entry)>(&self, out: F) -> Result<(), Error> {
+        machinelog::follow(&self.path, || self.sync_logs(), out)
+    }
+
+    fn sync_logs(&self) -> Result<(), Error> {
+        machinelog::sync(&self.path, "qemu", &self.path.join("qemu.log"))
+    }
+
+    // qemu's run state, with cpu time and memory use from the VM's cgroup
     pub fn status(&self) -> Result<String, Error> {
-        return self.monitor()?.status();
+        let status = self.monitor()?.status()?;
+        match self.usage()? {
+            Some(u) => Ok(format!(
+                "{} (cpu {:.1}s, memory {}Mi)",
+                status,
+                u.cpu_usec as f64 / 1e6,
+                u.memory >> 20
+            )),
+            None => Ok(status),
+        }
+    }
+
+    // None when the VM runs without a cgroup of its own
+    pub fn usage(&self) -> Result<Option<cgroup::Usage>, Error> {
+        cgroup::VmCgroup::new(&self.id).usage()
+    }
+
+    // image in the cdrom drive, if any
+    pub fn media(&self) -> Result<Option<PathBuf>, Error> {
+        match std::fs::read_to_string(self.path.join(MEDIA_FILE)) {
+            Ok(p) => Ok(Some(PathBuf::from(p.trim()))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // take the media out of the cdrom drive, for good: the next start
+    // boots without it
+    pub fn eject(&self) -> Result<(), Error> {
+        if self.running() {
+            self.monitor()?.eject(qemu::CDROM_ID)?;
+        }
+        let state = self.path.join(MEDIA_FILE);
+        if state.exists() {
+            std::fs::remove_file(state)?;
+            machinelog::record(&self.path, "media ejected");
+        }
+        Ok(())
+    }
+
+    pub fn balloon(&self, size: u64) -> Result<(), Error> {
+        self.monitor()?.balloon(size)
+    }
+
+    pub fn balloon_size(&self) -> Result<u64, Error> {
+        self.monitor()?.query_balloon()
     }
 
     pub fn id(&self) -> String {
@@ -189,7 +447,16 @@ impl VM {
     }
 
     pub fn name(&self) -> String {
-        self.spec.name.clone()
+        self.machine.name.clone()
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    // address of the graphical console, if the VM was started with one
+    pub fn display(&self) -> Option<String> {
+        std::fs::read_to_string(self.path().join("display")).ok()
     }
 
     pub fn pid(&self) -> Option<u32> {
@@ -214,16 +481,65 @@ impl VM {
     }
 
     fn spec_path(&self) -> PathBuf {
-        self.path().join("spec.json")
+        self.path().join(SPEC_FILE)
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        std::fs::write(self.spec_path(), self.machine.to_yaml()?)?;
+        Ok(())
     }
 
     pub fn undefine(self) -> Result<(), Error> {
         if self.running() {
             self.destroy()?;
         }
+        qemu::stop_virtiofsd(self.path());
 
         std::fs::remove_dir_all(self.path()).unwrap();
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_migrate_legacy() {
+        let dir = TestDir::new("legacy");
+        let vmpath = dir.join("web1");
+        std::fs::create_dir_all(&vmpath).unwrap();
+        std::fs::write(
+            vmpath.join(LEGACY_SPEC_FILE),
+            r#"{"spec": {
+                "name": "web1",
+                "cpus": 2,
+                "memory_mb": 2048,
+                "image": "/var/lib/images/web1.qcow2",
+                "hugepages": true,
+                "revert_on_boot": "clean"
+            }}"#,
+        )
+        .unwrap();
+
+        let vm = VMSet::new(&*dir).get("web1").unwrap();
+        let spec = &vm.machine.spec;
+        assert_eq!(vm.machine.name, "web1");
+        assert_eq!(spec.cpu, 2);
+        assert_eq!(spec.memory, Size(2 << 30));
+        assert_eq!(
+            spec.image.url.as_deref(),
+            Some("file:///var/lib/images/web1.qcow2")
+        );
+        assert_eq!(spec.hugepages, Some(Size(2 << 20)));
+        assert_eq!(spec.revert_on_boot.as_deref(), Some("clean"));
+        assert!(spec.shares.is_none());
+
+        // the old file is kept aside, and the next get reads the new one
+        assert!(!vmpath.join(LEGACY_SPEC_FILE).exists());
+        assert!(vmpath.join("spec.json.orig").exists());
+        assert_eq!(VMSet::new(&*dir).get("web1").unwrap().machine.spec.cpu, 2);
+    }
+}